@@ -3,12 +3,34 @@
 #include <stdint.h>
 #include <stdlib.h>
 
+typedef enum DiscoveryEventKind {
+  DiscoveryEventKind_Light,
+  DiscoveryEventKind_IntegrationFailed,
+  DiscoveryEventKind_IntegrationFinished,
+  DiscoveryEventKind_Finished,
+} DiscoveryEventKind;
+
 typedef struct FramePtr FramePtr;
 
 typedef struct LightDiscovererPtr LightDiscovererPtr;
 
 typedef struct LightPtr LightPtr;
 
+/**
+ * One discovery event. The strings are null when the event has none and
+ * only valid during the callback. `light` is only set for `Light` events
+ * and belongs to the callback, free it with `light_free`.
+ */
+typedef struct DiscoveryEvent {
+  enum DiscoveryEventKind kind;
+  const char *integration;
+  struct LightPtr *light;
+  const char *error;
+  uintptr_t lights;
+} DiscoveryEvent;
+
+typedef void (*DiscoveryCallback)(const struct DiscoveryEvent *event, void *user_data);
+
 bool light_set_on(struct LightPtr *l, bool on);
 
 bool light_set_color(struct LightPtr *l, uint8_t red, uint8_t green, uint8_t blue);
@@ -39,6 +61,17 @@ struct LightPtr *light_discoverer_next(struct LightDiscovererPtr *ld);
 
 void light_discoverer_free(struct LightDiscovererPtr *ld);
 
+/**
+ * Discovers lights with the default config, calling `callback` for every
+ * light as soon as its integration hands it over. Returns after the
+ * `Finished` event.
+ *
+ * `callback` runs on the calling thread while no discovery work is in
+ * progress, so it may call back into the library, for example to turn
+ * the light on with `light_set_on`.
+ */
+void light_discoverer_stream(DiscoveryCallback callback, void *user_data);
+
 struct FramePtr *frame_new(void);
 
 void frame_clear(struct FramePtr *f);
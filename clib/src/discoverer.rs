@@ -1,3 +1,7 @@
+use std::ffi::{c_char, c_void, CString};
+
+use cute_lights::Discovery;
+
 use crate::{light::LightPtr, utils::synchronize};
 
 #[repr(C)]
@@ -37,3 +41,85 @@ pub extern "C" fn light_discoverer_free(ld: *mut LightDiscovererPtr) {
         let _ = Box::from_raw(ld);
     }
 }
+
+/// cbindgen:prefix-with-name
+#[repr(C)]
+pub enum DiscoveryEventKind {
+    Light,
+    IntegrationFailed,
+    IntegrationFinished,
+    Finished,
+}
+
+/// One discovery event. The strings are null when the event has none and
+/// only valid during the callback. `light` is only set for `Light` events
+/// and belongs to the callback, free it with `light_free`.
+#[repr(C)]
+pub struct DiscoveryEvent {
+    pub kind: DiscoveryEventKind,
+    pub integration: *const c_char,
+    pub light: *mut LightPtr,
+    pub error: *const c_char,
+    pub lights: usize,
+}
+
+pub type DiscoveryCallback = extern "C" fn(event: *const DiscoveryEvent, user_data: *mut c_void);
+
+/// Discovers lights with the default config, calling `callback` for every
+/// light as soon as its integration hands it over. Returns after the
+/// `Finished` event.
+///
+/// `callback` runs on the calling thread while no discovery work is in
+/// progress, so it may call back into the library, for example to turn
+/// the light on with `light_set_on`.
+#[no_mangle]
+pub extern "C" fn light_discoverer_stream(callback: DiscoveryCallback, user_data: *mut c_void) {
+    // The discovery tasks have to be spawned on the runtime that runs them
+    let mut events = synchronize(async { Discovery::from_default_config().stream() });
+    while let Some(event) = synchronize(events.recv()) {
+        let (kind, integration, light, error, lights) = match event {
+            cute_lights::DiscoveryEvent::Light { integration, light } => (
+                DiscoveryEventKind::Light,
+                Some(integration),
+                Box::into_raw(Box::new(LightPtr { inner: light })),
+                None,
+                0,
+            ),
+            cute_lights::DiscoveryEvent::IntegrationFailed { integration, error } => (
+                DiscoveryEventKind::IntegrationFailed,
+                Some(integration),
+                std::ptr::null_mut(),
+                Some(error.to_string()),
+                0,
+            ),
+            cute_lights::DiscoveryEvent::IntegrationFinished {
+                integration,
+                lights,
+            } => (
+                DiscoveryEventKind::IntegrationFinished,
+                Some(integration),
+                std::ptr::null_mut(),
+                None,
+                lights,
+            ),
+            cute_lights::DiscoveryEvent::Finished => (
+                DiscoveryEventKind::Finished,
+                None,
+                std::ptr::null_mut(),
+                None,
+                0,
+            ),
+        };
+
+        let integration = integration.and_then(|s| CString::new(s).ok());
+        let error = error.and_then(|s| CString::new(s).ok());
+        let event = DiscoveryEvent {
+            kind,
+            integration: integration.as_ref().map_or(std::ptr::null(), |s| s.as_ptr()),
+            light,
+            error: error.as_ref().map_or(std::ptr::null(), |s| s.as_ptr()),
+            lights,
+        };
+        callback(&event, user_data);
+    }
+}
@@ -11,6 +11,13 @@ if typing.TYPE_CHECKING:
         brightness: int
         name: str
         id: int
+        supports_color_temperature: bool
+        effects: typing.List[str]
+        segment_count: int
+        manufacturer: str
+        model: typing.Optional[str]
+        firmware_version: typing.Optional[str]
+        hardware_version: typing.Optional[str]
 
         def set_on(self, on: bool):
             pass
@@ -21,6 +28,18 @@ if typing.TYPE_CHECKING:
         def set_color(self, r: int, g: int, b: int):
             pass
 
+        def set_color_temperature(self, kelvin: int):
+            pass
+
+        def set_effect(self, effect: str):
+            pass
+
+        def set_segment_colors(self, colors: typing.List[typing.Tuple[int, int, int]]):
+            pass
+
+        def set_realtime(self, enabled: bool):
+            pass
+
     class Frame:
         def set_on(self, light: Light, on: bool):
             pass
@@ -46,9 +65,19 @@ if typing.TYPE_CHECKING:
         def clear(self):
             pass
 
+    class DiscoveryEvent:
+        kind: str
+        integration: typing.Optional[str]
+        light: typing.Optional[Light]
+        error: typing.Optional[str]
+        lights: int
+
     def discover_lights() -> typing.List[Light]:
         pass
 
+    def discover_lights_stream() -> typing.Iterator[DiscoveryEvent]:
+        pass
+
 else:
     from .cute_light import *
     import asyncio
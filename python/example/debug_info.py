@@ -5,6 +5,8 @@ lights = cute_light.discover_lights()
 for light in lights:
     print(f"Light {light.name}")
     print(f"  Id: {light.id}")
+    print(f"  Model: {light.manufacturer} {light.model}")
+    print(f"  Firmware: {light.firmware_version}")
     print(f"  Supports Color: {light.supports_color}")
     print(f"  Is On: {light.is_on}")
     print(f"  Red: {light.red}")
@@ -0,0 +1,7 @@
+import cute_light
+
+for event in cute_light.discover_lights_stream():
+    if event.kind == "light":
+        print(f"{event.integration}: {event.light.name}")
+    elif event.kind == "integration_failed":
+        print(f"{event.integration} failed: {event.error}")
@@ -13,3 +13,98 @@ pub fn discover_lights() -> PyResult<Vec<Light>> {
 
     Ok(py_lights)
 }
+
+#[pyclass]
+pub struct DiscoveryEvent {
+    /// "light", "integration_failed", "integration_finished" or "finished"
+    #[pyo3(get)]
+    kind: String,
+    #[pyo3(get)]
+    integration: Option<String>,
+    light: Option<Py<Light>>,
+    #[pyo3(get)]
+    error: Option<String>,
+    /// How many lights the integration found, for "integration_finished"
+    #[pyo3(get)]
+    lights: usize,
+}
+
+#[pymethods]
+impl DiscoveryEvent {
+    #[getter]
+    fn light(&self, py: Python<'_>) -> Option<Py<Light>> {
+        self.light.as_ref().map(|light| light.clone_ref(py))
+    }
+
+    fn __repr__(&self) -> String {
+        match &self.integration {
+            Some(integration) => format!("DiscoveryEvent({}, {})", self.kind, integration),
+            None => format!("DiscoveryEvent({})", self.kind),
+        }
+    }
+}
+
+/// Yields discovery events as the integrations report them.
+#[pyclass]
+pub struct DiscoveryIterator {
+    events: cute_lights::DiscoveryStream,
+}
+
+#[pymethods]
+impl DiscoveryIterator {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(&mut self, py: Python<'_>) -> PyResult<Option<DiscoveryEvent>> {
+        // Let other Python threads run while the integrations discover
+        let events = &mut self.events;
+        let Some(event) = py.allow_threads(|| synchronize(events.recv())) else {
+            return Ok(None);
+        };
+
+        let event = match event {
+            cute_lights::DiscoveryEvent::Light { integration, light } => DiscoveryEvent {
+                kind: "light".to_string(),
+                integration: Some(integration),
+                light: Some(Py::new(py, Light::new(light))?),
+                error: None,
+                lights: 0,
+            },
+            cute_lights::DiscoveryEvent::IntegrationFailed { integration, error } => {
+                DiscoveryEvent {
+                    kind: "integration_failed".to_string(),
+                    integration: Some(integration),
+                    light: None,
+                    error: Some(error.to_string()),
+                    lights: 0,
+                }
+            }
+            cute_lights::DiscoveryEvent::IntegrationFinished {
+                integration,
+                lights,
+            } => DiscoveryEvent {
+                kind: "integration_finished".to_string(),
+                integration: Some(integration),
+                light: None,
+                error: None,
+                lights,
+            },
+            cute_lights::DiscoveryEvent::Finished => DiscoveryEvent {
+                kind: "finished".to_string(),
+                integration: None,
+                light: None,
+                error: None,
+                lights: 0,
+            },
+        };
+        Ok(Some(event))
+    }
+}
+
+#[pyfunction]
+pub fn discover_lights_stream() -> DiscoveryIterator {
+    // The discovery tasks have to be spawned on the runtime that runs them
+    let events = synchronize(async { cute_lights::Discovery::from_default_config().stream() });
+    DiscoveryIterator { events }
+}
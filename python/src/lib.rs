@@ -16,6 +16,9 @@ fn sum_as_string(a: usize, b: usize) -> PyResult<String> {
 fn cute_light(m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_function(wrap_pyfunction!(sum_as_string, m)?)?;
     m.add_function(wrap_pyfunction!(discover::discover_lights, m)?)?;
+    m.add_function(wrap_pyfunction!(discover::discover_lights_stream, m)?)?;
+    m.add_class::<discover::DiscoveryEvent>()?;
+    m.add_class::<discover::DiscoveryIterator>()?;
     m.add_class::<frame::Frame>()?;
     m.add_class::<light::Light>()?;
 
@@ -44,6 +44,77 @@ impl Light {
         }
     }
 
+    fn set_color_temperature(&mut self, kelvin: u16) -> PyResult<()> {
+        match synchronize(self.inner.set_color_temperature(kelvin)) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                e.to_string(),
+            )),
+        }
+    }
+
+    fn set_effect(&mut self, effect: &str) -> PyResult<()> {
+        match synchronize(self.inner.set_effect(effect)) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                e.to_string(),
+            )),
+        }
+    }
+
+    fn set_segment_colors(&mut self, colors: Vec<(u8, u8, u8)>) -> PyResult<()> {
+        match synchronize(self.inner.set_segment_colors(&colors)) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                e.to_string(),
+            )),
+        }
+    }
+
+    fn set_realtime(&mut self, enabled: bool) -> PyResult<()> {
+        match synchronize(self.inner.set_realtime(enabled)) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                e.to_string(),
+            )),
+        }
+    }
+
+    #[getter]
+    fn supports_color_temperature(&self) -> bool {
+        self.inner.supports_color_temperature()
+    }
+
+    #[getter]
+    fn effects(&self) -> Vec<String> {
+        self.inner.effects()
+    }
+
+    #[getter]
+    fn manufacturer(&self) -> String {
+        self.inner.device_info().manufacturer
+    }
+
+    #[getter]
+    fn model(&self) -> Option<String> {
+        self.inner.device_info().model
+    }
+
+    #[getter]
+    fn firmware_version(&self) -> Option<String> {
+        self.inner.device_info().firmware_version
+    }
+
+    #[getter]
+    fn hardware_version(&self) -> Option<String> {
+        self.inner.device_info().hardware_version
+    }
+
+    #[getter]
+    fn segment_count(&self) -> usize {
+        self.inner.segment_count()
+    }
+
     #[getter]
     fn is_on(&self) -> bool {
         self.inner.is_on()
@@ -1,12 +1,55 @@
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
-use crate::integrations::{govee::GoveeConfig, hue::HueConfig, kasa::KasaConfig};
+use crate::integrations::{
+    deconz::DeconzConfig, dmx::DmxConfig, elgato::ElgatoConfig, esphome::EsphomeConfig,
+    govee::GoveeConfig, home_assistant::HomeAssistantConfig, hue::HueConfig, kasa::KasaConfig,
+    lifx::LifxConfig, magic_home::MagicHomeConfig, mqtt::MqttConfig, nanoleaf::NanoleafConfig,
+    openrgb::OpenRgbConfig, shelly::ShellyConfig, tasmota::TasmotaConfig, tuya::TuyaConfig,
+    virtual_lights::VirtualConfig, wiz::WizConfig, wled::WledConfig, yeelight::YeelightConfig,
+};
 
 #[derive(Debug, Deserialize, Clone, Serialize, Default)]
 pub struct CuteLightsConfig {
     pub kasa: KasaConfig,
     pub govee: GoveeConfig,
     pub hue: HueConfig,
+    #[serde(default)]
+    pub lifx: LifxConfig,
+    #[serde(default)]
+    pub wiz: WizConfig,
+    #[serde(default)]
+    pub yeelight: YeelightConfig,
+    #[serde(default)]
+    pub wled: WledConfig,
+    #[serde(default)]
+    pub nanoleaf: NanoleafConfig,
+    #[serde(default)]
+    pub elgato: ElgatoConfig,
+    #[serde(default)]
+    pub tasmota: TasmotaConfig,
+    #[serde(default)]
+    pub shelly: ShellyConfig,
+    #[serde(default)]
+    pub magic_home: MagicHomeConfig,
+    #[serde(default)]
+    pub tuya: TuyaConfig,
+    #[serde(default)]
+    pub mqtt: MqttConfig,
+    #[serde(default)]
+    pub deconz: DeconzConfig,
+    #[serde(default)]
+    pub home_assistant: HomeAssistantConfig,
+    #[serde(default)]
+    pub esphome: EsphomeConfig,
+    #[serde(default)]
+    pub openrgb: OpenRgbConfig,
+    #[serde(default)]
+    pub dmx: DmxConfig,
+    #[serde(default, rename = "virtual")]
+    pub virtual_lights: VirtualConfig,
+    /// Sections for integrations registered by applications
+    #[serde(flatten)]
+    pub extra: toml::Table,
 }
 
 impl CuteLightsConfig {
@@ -29,5 +72,22 @@ impl CuteLightsConfig {
         let config = std::fs::read_to_string(file).unwrap();
         toml::from_str(&config).unwrap()
     }
-}
 
+    /// Reads the `[name]` section of a registered integration, its defaults
+    /// when there is none.
+    pub fn section<T: DeserializeOwned + Default>(&self, name: &str) -> anyhow::Result<T> {
+        match self.extra.get(name) {
+            Some(section) => section
+                .clone()
+                .try_into()
+                .map_err(|e| anyhow::anyhow!("Invalid [{}] config: {}", name, e)),
+            None => Ok(T::default()),
+        }
+    }
+
+    pub fn set_section<T: Serialize>(&mut self, name: &str, section: &T) -> anyhow::Result<()> {
+        self.extra
+            .insert(name.to_string(), toml::Value::try_from(section)?);
+        Ok(())
+    }
+}
@@ -1,48 +1,625 @@
+use futures_util::Stream;
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{sync::mpsc, task::JoinHandle};
+
 use crate::{
     config::CuteLightsConfig,
-    integrations::{govee::GoveeIntegration, hue::HueIntegration, kasa::KasaIntegration, Integration, Light},
+    integrations::{
+        deconz::DeconzIntegration, dmx::DmxIntegration, elgato::ElgatoIntegration,
+        esphome::EsphomeIntegration, govee::GoveeIntegration,
+        home_assistant::HomeAssistantIntegration, hue::HueIntegration, kasa::KasaIntegration,
+        lifx::LifxIntegration, magic_home::MagicHomeIntegration, mqtt::MqttIntegration,
+        nanoleaf::NanoleafIntegration, openrgb::OpenRgbIntegration, shelly::ShellyIntegration,
+        tasmota::TasmotaIntegration, tuya::TuyaIntegration, virtual_lights::VirtualIntegration,
+        wiz::WizIntegration, wled::WledIntegration, yeelight::YeelightIntegration, Integration,
+        Light, LightSink,
+    },
     utils::future::FutureBatch,
 };
 
-struct Discoverer {
-    config: &'static CuteLightsConfig,
-    batch: FutureBatch<Vec<Box<dyn Light>>>,
+// ANCHOR - LightRegistry
+
+/// The integrations discovery goes through. The default registry has all the
+/// built in ones, applications register their own on top of it.
+#[derive(Clone)]
+pub struct LightRegistry {
+    integrations: Vec<Arc<dyn Integration>>,
+}
+
+impl LightRegistry {
+    /// A registry without any integrations.
+    pub fn empty() -> Self {
+        Self {
+            integrations: Vec::new(),
+        }
+    }
+
+    /// Adds an integration, replacing the one registered under the same name.
+    pub fn register(&mut self, integration: impl Integration + 'static) -> &mut Self {
+        let integration: Arc<dyn Integration> = Arc::new(integration);
+        let name = integration.name();
+        match self.integrations.iter_mut().find(|i| i.name() == name) {
+            Some(existing) => *existing = integration,
+            None => self.integrations.push(integration),
+        }
+        self
+    }
+
+    pub fn with(mut self, integration: impl Integration + 'static) -> Self {
+        self.register(integration);
+        self
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.integrations.iter().map(|i| i.name()).collect()
+    }
+
+    /// Discovers with every registered integration, see [`Discovery`] for
+    /// more control.
+    pub async fn discover(&self, config: impl Into<Arc<CuteLightsConfig>>) -> Vec<Box<dyn Light>> {
+        Discovery::new(config).registry(self.clone()).run().await
+    }
+}
+
+impl Default for LightRegistry {
+    fn default() -> Self {
+        Self::empty()
+            .with(KasaIntegration)
+            .with(HueIntegration)
+            .with(GoveeIntegration)
+            .with(LifxIntegration)
+            .with(WizIntegration)
+            .with(YeelightIntegration)
+            .with(WledIntegration)
+            .with(NanoleafIntegration)
+            .with(ElgatoIntegration)
+            .with(TasmotaIntegration)
+            .with(ShellyIntegration)
+            .with(MagicHomeIntegration)
+            .with(TuyaIntegration)
+            .with(MqttIntegration)
+            .with(DeconzIntegration)
+            .with(HomeAssistantIntegration)
+            .with(EsphomeIntegration)
+            .with(OpenRgbIntegration)
+            .with(DmxIntegration)
+            .with(VirtualIntegration)
+    }
 }
 
-impl Discoverer {
-    fn new(config: &'static CuteLightsConfig) -> Self {
+// ANCHOR - Discovery
+
+type LightFilter = Arc<dyn Fn(&dyn Light) -> bool + Send + Sync>;
+
+/// A discovery run over a config the caller owns.
+///
+/// ```no_run
+/// # async fn run() {
+/// use cute_lights::{CuteLightsConfig, Discovery};
+/// use std::time::Duration;
+///
+/// let lights = Discovery::new(CuteLightsConfig::load_from_file("lights.toml"))
+///     .only(["hue", "wled"])
+///     .timeout(Duration::from_secs(2))
+///     .filter(|light| light.supports_color())
+///     .run()
+///     .await;
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct Discovery {
+    config: Arc<CuteLightsConfig>,
+    registry: LightRegistry,
+    only: Option<Vec<String>>,
+    timeout: Option<Duration>,
+    filters: Vec<LightFilter>,
+}
+
+impl Discovery {
+    pub fn new(config: impl Into<Arc<CuteLightsConfig>>) -> Self {
         Self {
-            config,
-            batch: FutureBatch::new(),
+            config: config.into(),
+            registry: LightRegistry::default(),
+            only: None,
+            timeout: None,
+            filters: Vec::new(),
         }
     }
 
-    fn register<I: Integration + Send + Sync + 'static>(&mut self) {
-        let config = self.config;
-        if I::preflight(&self.config) {
-            self.batch.push(async move {
-                if let Ok(lights) = I::discover(&config).await {
-                    lights
-                } else {
-                    eprintln!("Failed to discover lights for {}", I::name());
-                    Vec::new()
+    /// Discovery with the config file from `CUTE_LIGHTS_CONFIG_PATH` or
+    /// `~/.config/cute_lights/lights.toml`.
+    pub fn from_default_config() -> Self {
+        Self::new(CuteLightsConfig::load_default())
+    }
+
+    /// Replaces the built in integrations.
+    pub fn registry(mut self, registry: LightRegistry) -> Self {
+        self.registry = registry;
+        self
+    }
+
+    /// Adds an integration, replacing the one registered under the same name.
+    pub fn integration(mut self, integration: impl Integration + 'static) -> Self {
+        self.registry.register(integration);
+        self
+    }
+
+    /// Only runs the integrations with these names, others are skipped even
+    /// when enabled.
+    pub fn only<I, S>(mut self, names: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.only = Some(names.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Gives up on integrations still discovering after `timeout`, keeping
+    /// the lights found by then.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Keeps only the lights `filter` accepts. Filters add up, a light has to
+    /// pass all of them.
+    pub fn filter(mut self, filter: impl Fn(&dyn Light) -> bool + Send + Sync + 'static) -> Self {
+        self.filters.push(Arc::new(filter));
+        self
+    }
+
+    /// The integrations that will run, those selected that pass their preflight.
+    fn integrations(&self) -> Vec<Arc<dyn Integration>> {
+        self.registry
+            .integrations
+            .iter()
+            .filter(|integration| match &self.only {
+                Some(names) => names.contains(&integration.name()),
+                None => true,
+            })
+            .filter(|integration| integration.preflight(&self.config))
+            .cloned()
+            .collect()
+    }
+
+    /// Runs the selected integrations at once. Failing integrations are
+    /// reported and contribute the lights they found before failing.
+    pub async fn run(&self) -> Vec<Box<dyn Light>> {
+        let mut events = self.stream();
+        let mut lights = Vec::new();
+        while let Some(event) = events.recv().await {
+            match event {
+                DiscoveryEvent::Light { light, .. } => lights.push(light),
+                DiscoveryEvent::IntegrationFailed { integration, error } => {
+                    eprintln!("Failed to discover lights for {}: {}", integration, error);
                 }
+                _ => {}
+            }
+        }
+        lights
+    }
+
+    /// Runs the selected integrations at once in the background, reporting
+    /// every light as soon as its integration hands it over instead of
+    /// waiting for the slowest. Has to be called from within a tokio runtime.
+    pub fn stream(&self) -> DiscoveryStream {
+        let (events, receiver) = mpsc::unbounded_channel();
+        let mut batch = FutureBatch::new();
+        for integration in self.integrations() {
+            let config = self.config.clone();
+            let timeout = self.timeout;
+            let filters = self.filters.clone();
+            let events = events.clone();
+            batch.push(async move {
+                let name = integration.name();
+                let found = AtomicUsize::new(0);
+                let sink = |light: Box<dyn Light>| {
+                    if filters.iter().all(|filter| filter(light.as_ref())) {
+                        found.fetch_add(1, Ordering::Relaxed);
+                        let _ = events.send(DiscoveryEvent::Light {
+                            integration: name.clone(),
+                            light,
+                        });
+                    }
+                };
+
+                let discovered = discover_with(integration.as_ref(), &config, timeout, &sink).await;
+                let event = match discovered {
+                    Ok(()) => DiscoveryEvent::IntegrationFinished {
+                        integration: name.clone(),
+                        lights: found.load(Ordering::Relaxed),
+                    },
+                    Err(error) => DiscoveryEvent::IntegrationFailed {
+                        integration: name.clone(),
+                        error,
+                    },
+                };
+                let _ = events.send(event);
             });
         }
+
+        let driver = tokio::spawn(async move {
+            batch.run().await;
+            let _ = events.send(DiscoveryEvent::Finished);
+        });
+        DiscoveryStream {
+            events: receiver,
+            driver,
+        }
     }
+}
 
-    async fn run(self) -> Vec<Box<dyn Light>> {
-        self.batch.run().await.into_iter().flatten().collect()
+// ANCHOR - DiscoveryStream
+
+/// What a streaming discovery reports. Every integration ends with either
+/// `IntegrationFinished` or `IntegrationFailed`, and `Finished` comes last.
+#[derive(Debug)]
+pub enum DiscoveryEvent {
+    Light {
+        integration: String,
+        light: Box<dyn Light>,
+    },
+    IntegrationFailed {
+        integration: String,
+        error: anyhow::Error,
+    },
+    /// An integration is done, `lights` being how many it found
+    IntegrationFinished {
+        integration: String,
+        lights: usize,
+    },
+    Finished,
+}
+
+/// The events of a discovery started with [`Discovery::stream`]. Dropping it
+/// stops the integrations that are still discovering.
+pub struct DiscoveryStream {
+    events: mpsc::UnboundedReceiver<DiscoveryEvent>,
+    driver: JoinHandle<()>,
+}
+
+impl DiscoveryStream {
+    /// The next event, `None` once `Finished` was received.
+    pub async fn recv(&mut self) -> Option<DiscoveryEvent> {
+        self.events.recv().await
+    }
+}
+
+impl Stream for DiscoveryStream {
+    type Item = DiscoveryEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.events.poll_recv(cx)
     }
 }
 
+impl Drop for DiscoveryStream {
+    fn drop(&mut self) {
+        self.driver.abort();
+    }
+}
+
+async fn discover_with(
+    integration: &dyn Integration,
+    config: &CuteLightsConfig,
+    timeout: Option<Duration>,
+    sink: &LightSink<'_>,
+) -> anyhow::Result<()> {
+    let Some(timeout) = timeout else {
+        return integration.discover_into(config, sink).await;
+    };
+    tokio::time::timeout(timeout, integration.discover_into(config, sink))
+        .await
+        .map_err(|_| anyhow::anyhow!("Timed out after {:?}", timeout))?
+}
+
 pub async fn discover_lights() -> Vec<Box<dyn Light>> {
-    let config = Box::leak(Box::new(CuteLightsConfig::load_default()));
-    let mut discoverer = Discoverer::new(config);
+    Discovery::from_default_config().run().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Default, serde::Deserialize, serde::Serialize)]
+    struct MirrorConfig {
+        enabled: bool,
+        copies: usize,
+    }
+
+    /// Reports the virtual lights as many times as its section says.
+    struct MirrorIntegration;
+
+    #[async_trait::async_trait]
+    impl Integration for MirrorIntegration {
+        fn name(&self) -> String {
+            "mirror".to_string()
+        }
+
+        async fn discover(&self, config: &CuteLightsConfig) -> anyhow::Result<Vec<Box<dyn Light>>> {
+            let mirror: MirrorConfig = config.section("mirror")?;
+            let mut lights = Vec::new();
+            for _ in 0..mirror.copies {
+                lights.extend(VirtualIntegration.discover(config).await?);
+            }
+            Ok(lights)
+        }
+
+        fn preflight(&self, config: &CuteLightsConfig) -> bool {
+            config
+                .section::<MirrorConfig>("mirror")
+                .is_ok_and(|mirror| mirror.enabled)
+        }
+    }
+
+    fn config(mirror: &str) -> CuteLightsConfig {
+        toml::from_str(&format!(
+            "[kasa]\nenabled = false\naddresses = []\n\
+             [govee]\nenabled = false\naddresses = []\n\
+             [hue]\nenabled = false\n\
+             [virtual]\nenabled = false\n\
+             [[virtual.lights]]\nname = \"Lamp\"\n\
+             [[virtual.lights]]\nname = \"Strip\"\nsegments = 10\n\
+             {}",
+            mirror
+        ))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn discovers_registered_integrations() {
+        let registry = LightRegistry::default().with(MirrorIntegration);
+        assert_eq!(registry.names().last().map(String::as_str), Some("mirror"));
+
+        let lights = registry
+            .discover(config("[mirror]\nenabled = true\ncopies = 2"))
+            .await;
+        assert_eq!(lights.len(), 4);
+        assert_eq!(
+            lights.iter().filter(|light| light.name() == "Lamp").count(),
+            2
+        );
+
+        assert!(registry.discover(config("")).await.is_empty());
+        assert!(registry
+            .discover(config("[mirror]\nenabled = true\ncopies = \"two\""))
+            .await
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn replaces_integrations_by_name() {
+        struct Disabled;
+
+        #[async_trait::async_trait]
+        impl Integration for Disabled {
+            fn name(&self) -> String {
+                "virtual".to_string()
+            }
+
+            async fn discover(
+                &self,
+                _config: &CuteLightsConfig,
+            ) -> anyhow::Result<Vec<Box<dyn Light>>> {
+                Ok(Vec::new())
+            }
+
+            fn preflight(&self, _config: &CuteLightsConfig) -> bool {
+                false
+            }
+        }
+
+        let mut config = config("");
+        config.virtual_lights.enabled = true;
+        let config = Arc::new(config);
+
+        assert_eq!(
+            LightRegistry::default()
+                .discover(config.clone())
+                .await
+                .len(),
+            2
+        );
+        let mut registry = LightRegistry::default();
+        registry.register(Disabled);
+        assert_eq!(
+            registry.names().len(),
+            LightRegistry::default().names().len()
+        );
+        assert!(registry.discover(config).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn selects_and_filters() {
+        let mut config = config("[mirror]\nenabled = true\ncopies = 1");
+        config.virtual_lights.enabled = true;
+        let config = Arc::new(config);
+        let discovery = || Discovery::new(config.clone()).integration(MirrorIntegration);
+
+        assert_eq!(discovery().run().await.len(), 4);
+        assert_eq!(discovery().only(["virtual"]).run().await.len(), 2);
+        assert!(discovery().only(["kasa"]).run().await.is_empty());
+
+        let lights = discovery()
+            .only(vec!["mirror".to_string()])
+            .filter(|light| light.segment_count() > 0)
+            .filter(|light| light.id().starts_with("virtual::"))
+            .run()
+            .await;
+        assert_eq!(lights.len(), 1);
+        assert_eq!(lights[0].name(), "Strip");
+    }
 
-    discoverer.register::<KasaIntegration>();
-    discoverer.register::<HueIntegration>();
-    discoverer.register::<GoveeIntegration>();
+    #[tokio::test]
+    async fn gives_up_on_slow_integrations() {
+        let mut config = config("[mirror]\nenabled = true\ncopies = 1");
+        config.virtual_lights.enabled = true;
+        config.virtual_lights.discovery_latency = 5000;
 
-    discoverer.run().await
+        let started = std::time::Instant::now();
+        let lights = Discovery::new(config)
+            .timeout(Duration::from_millis(100))
+            .run()
+            .await;
+        assert!(lights.is_empty());
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn streams_lights_as_integrations_finish() {
+        use futures_util::StreamExt;
+
+        struct Broken;
+
+        #[async_trait::async_trait]
+        impl Integration for Broken {
+            fn name(&self) -> String {
+                "broken".to_string()
+            }
+
+            async fn discover(
+                &self,
+                _config: &CuteLightsConfig,
+            ) -> anyhow::Result<Vec<Box<dyn Light>>> {
+                Err(anyhow::anyhow!("No bridge"))
+            }
+
+            fn preflight(&self, _config: &CuteLightsConfig) -> bool {
+                true
+            }
+        }
+
+        let mut config = config("");
+        config.virtual_lights.enabled = true;
+        config.virtual_lights.discovery_latency = 200;
+
+        let started = std::time::Instant::now();
+        let mut events = Discovery::new(config)
+            .only(["virtual", "broken"])
+            .integration(Broken)
+            .stream();
+
+        match events.next().await {
+            Some(DiscoveryEvent::IntegrationFailed { integration, error }) => {
+                assert_eq!(integration, "broken");
+                assert_eq!(error.to_string(), "No bridge");
+            }
+            event => panic!("Expected the failure first, got {:?}", event),
+        }
+        assert!(started.elapsed() < Duration::from_millis(200));
+
+        let mut names = Vec::new();
+        while let Some(DiscoveryEvent::Light { integration, light }) = events.next().await {
+            assert_eq!(integration, "virtual");
+            names.push(light.name());
+            if names.len() == 2 {
+                break;
+            }
+        }
+        names.sort();
+        assert_eq!(names, ["Lamp", "Strip"]);
+
+        assert!(matches!(
+            events.next().await,
+            Some(DiscoveryEvent::IntegrationFinished { lights: 2, .. })
+        ));
+        assert!(matches!(
+            events.next().await,
+            Some(DiscoveryEvent::Finished)
+        ));
+        assert!(events.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn streams_lights_before_integrations_finish() {
+        /// Hands over one light right away and takes its time with the rest.
+        struct Trickle;
+
+        #[async_trait::async_trait]
+        impl Integration for Trickle {
+            fn name(&self) -> String {
+                "trickle".to_string()
+            }
+
+            async fn discover(
+                &self,
+                config: &CuteLightsConfig,
+            ) -> anyhow::Result<Vec<Box<dyn Light>>> {
+                VirtualIntegration.discover(config).await
+            }
+
+            async fn discover_into(
+                &self,
+                config: &CuteLightsConfig,
+                sink: &LightSink<'_>,
+            ) -> anyhow::Result<()> {
+                let mut lights = self.discover(config).await?.into_iter();
+                if let Some(light) = lights.next() {
+                    sink(light);
+                }
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                lights.for_each(sink);
+                Ok(())
+            }
+
+            fn preflight(&self, _config: &CuteLightsConfig) -> bool {
+                true
+            }
+        }
+
+        let discovery = Discovery::new(config(""))
+            .only(["trickle"])
+            .integration(Trickle)
+            .timeout(Duration::from_millis(200));
+
+        let started = std::time::Instant::now();
+        let mut events = discovery.stream();
+        match events.recv().await {
+            Some(DiscoveryEvent::Light { integration, light }) => {
+                assert_eq!(integration, "trickle");
+                assert_eq!(light.name(), "Lamp");
+            }
+            event => panic!("Expected a light first, got {:?}", event),
+        }
+        assert!(started.elapsed() < Duration::from_millis(200));
+        assert!(matches!(
+            events.recv().await,
+            Some(DiscoveryEvent::IntegrationFailed { .. })
+        ));
+        assert!(matches!(
+            events.recv().await,
+            Some(DiscoveryEvent::Finished)
+        ));
+
+        // The light found before the timeout is kept
+        let lights = discovery.run().await;
+        assert_eq!(lights.len(), 1);
+    }
+
+    #[test]
+    fn round_trips_sections() {
+        let mut config = CuteLightsConfig::default();
+        config
+            .set_section(
+                "mirror",
+                &MirrorConfig {
+                    enabled: true,
+                    copies: 3,
+                },
+            )
+            .unwrap();
+        let config: CuteLightsConfig = toml::from_str(&toml::to_string(&config).unwrap()).unwrap();
+        let mirror: MirrorConfig = config.section("mirror").unwrap();
+        assert!(mirror.enabled);
+        assert_eq!(mirror.copies, 3);
+    }
 }
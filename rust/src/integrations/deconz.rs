@@ -0,0 +1,696 @@
+use crate::{
+    config::CuteLightsConfig,
+    utils::{color, json},
+};
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::Message;
+
+use super::{
+    hue::{check_errors, parse_light, HueState},
+    DeviceInfo, Integration, Light,
+};
+
+// The ct range deCONZ falls back to when a light does not report one
+const DEFAULT_CT_MIN: u16 = 153;
+const DEFAULT_CT_MAX: u16 = 500;
+
+// ANCHOR - DeconzLight
+pub struct DeconzLight {
+    gateway: Arc<Gateway>,
+    id: String,
+    unique_id: Option<String>,
+    name: String,
+    supports_color: bool,
+    supports_color_temperature: bool,
+    ct_range: (u16, u16),
+    manufacturer: Option<String>,
+    model: Option<String>,
+    sw_version: Option<String>,
+}
+
+impl DeconzLight {
+    async fn set_state(
+        &mut self,
+        body: serde_json::Value,
+        color_mode: Option<&str>,
+    ) -> anyhow::Result<()> {
+        self.gateway
+            .put(&format!("lights/{}/state", self.id), &body)
+            .await?;
+        // The event for the change follows, apply it now for callers that
+        // read the state right away
+        if let Some(state) = self.gateway.lock_states()?.get_mut(&self.id) {
+            state.update(&body);
+            if let Some(color_mode) = color_mode {
+                state.color_mode = Some(color_mode.to_string());
+            }
+        }
+        Ok(())
+    }
+
+    fn state(&self) -> Option<HueState> {
+        self.gateway.lock_states().ok()?.get(&self.id).cloned()
+    }
+
+    fn rgb(&self) -> (u8, u8, u8) {
+        let Some(state) = self.state().filter(|_| self.supports_color) else {
+            return (255, 255, 255);
+        };
+        match (state.color_mode.as_deref(), state.xy) {
+            (Some("xy"), Some((x, y))) => color::xy_to_rgb(x, y),
+            _ => color::hsb_to_rgb(
+                state.hue.clamp(0, 360) as u16,
+                state.saturation.clamp(0, 100) as u8,
+                100,
+            ),
+        }
+    }
+}
+
+#[async_trait]
+impl Light for DeconzLight {
+    async fn set_on(&mut self, on: bool) -> anyhow::Result<()> {
+        self.set_state(json!({ "on": on }), None).await
+    }
+
+    async fn set_color(&mut self, red: u8, green: u8, blue: u8) -> anyhow::Result<()> {
+        if !self.supports_color {
+            return Err(anyhow::anyhow!("{} does not support color", self.name()));
+        }
+        let (x, y) = color::rgb_to_xy(red, green, blue);
+        self.set_state(json!({ "on": true, "xy": [x, y] }), Some("xy"))
+            .await
+    }
+
+    async fn set_brightness(&mut self, brightness: u8) -> anyhow::Result<()> {
+        let bri = (brightness.min(100) as f64 / 100.0 * 254.0).round() as u8;
+        self.set_state(json!({ "bri": bri }), None).await
+    }
+
+    fn id(&self) -> String {
+        match &self.unique_id {
+            Some(unique_id) => format!("deconz::{}", unique_id),
+            None => format!("deconz::{}", self.id),
+        }
+    }
+
+    fn is_on(&self) -> bool {
+        self.state().map(|state| state.on).unwrap_or(false)
+    }
+
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn supports_color(&self) -> bool {
+        self.supports_color
+    }
+
+    fn red(&self) -> u8 {
+        self.rgb().0
+    }
+
+    fn green(&self) -> u8 {
+        self.rgb().1
+    }
+
+    fn blue(&self) -> u8 {
+        self.rgb().2
+    }
+
+    fn brightness(&self) -> u8 {
+        self.state().map(|state| state.brightness).unwrap_or(0)
+    }
+
+    fn device_info(&self) -> DeviceInfo {
+        DeviceInfo {
+            manufacturer: self
+                .manufacturer
+                .clone()
+                .unwrap_or_else(|| "dresden elektronik".to_string()),
+            model: self.model.clone(),
+            firmware_version: self.sw_version.clone(),
+            hardware_version: None,
+        }
+    }
+
+    fn supports_color_temperature(&self) -> bool {
+        self.supports_color_temperature
+    }
+
+    async fn set_color_temperature(&mut self, kelvin: u16) -> anyhow::Result<()> {
+        if !self.supports_color_temperature {
+            return Err(anyhow::anyhow!(
+                "{} does not support color temperature",
+                self.name()
+            ));
+        }
+        let (min, max) = self.ct_range;
+        let mireds = (1_000_000 / kelvin.max(1) as u32).clamp(min as u32, max as u32);
+        self.set_state(json!({ "on": true, "ct": mireds }), Some("ct"))
+            .await
+    }
+}
+
+// ANCHOR - Gateway
+
+/// One REST client and WebSocket event feed shared by every light of a
+/// gateway.
+struct Gateway {
+    client: reqwest::Client,
+    base_url: String,
+    states: Arc<Mutex<HashMap<String, HueState>>>,
+    events: Option<JoinHandle<()>>,
+}
+
+impl Gateway {
+    async fn get(&self, path: &str) -> anyhow::Result<serde_json::Value> {
+        let body = self
+            .client
+            .get(format!("{}/{}", self.base_url, path))
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        let value: serde_json::Value = serde_json::from_str(&body)?;
+        check_errors(&value, "deCONZ")?;
+        Ok(value)
+    }
+
+    async fn put(&self, path: &str, body: &serde_json::Value) -> anyhow::Result<()> {
+        let body = self
+            .client
+            .put(format!("{}/{}", self.base_url, path))
+            .body(body.to_string())
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        check_errors(&serde_json::from_str(&body)?, "deCONZ")
+    }
+
+    fn lock_states(&self) -> anyhow::Result<std::sync::MutexGuard<'_, HashMap<String, HueState>>> {
+        self.states
+            .lock()
+            .map_err(|_| anyhow::anyhow!("deCONZ state poisoned"))
+    }
+}
+
+impl Drop for Gateway {
+    fn drop(&mut self) {
+        if let Some(events) = &self.events {
+            events.abort();
+        }
+    }
+}
+
+/// Applies `changed` events for lights from the WebSocket, reconnecting when
+/// the gateway drops it.
+async fn read_events(url: String, states: Arc<Mutex<HashMap<String, HueState>>>) {
+    loop {
+        match tokio_tungstenite::connect_async(url.as_str()).await {
+            Ok((mut socket, _)) => {
+                while let Some(message) = socket.next().await {
+                    let text = match message {
+                        Ok(Message::Text(text)) => text,
+                        Ok(_) => continue,
+                        Err(e) => {
+                            eprintln!("deCONZ event feed failed: {}", e);
+                            break;
+                        }
+                    };
+                    let Ok(event) = serde_json::from_str::<serde_json::Value>(&text) else {
+                        continue;
+                    };
+                    if event["e"] != "changed" || event["r"] != "lights" {
+                        continue;
+                    }
+                    let (Some(id), Ok(mut states)) = (event["id"].as_str(), states.lock()) else {
+                        continue;
+                    };
+                    if let Some(state) = states.get_mut(id) {
+                        state.update(&event["state"]);
+                    }
+                }
+            }
+            Err(e) => eprintln!("Failed to connect to deCONZ event feed: {}", e),
+        }
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    }
+}
+
+// ANCHOR - Pairing
+
+/// Requests a new API key. The gateway only hands one out in the 60 seconds
+/// after "Authenticate app" was pressed in Phoscon under Gateway > Advanced.
+pub async fn pair(host: &str) -> anyhow::Result<String> {
+    let client = reqwest::Client::new();
+    let body = client
+        .post(format!("http://{}/api", host))
+        .body(json!({ "devicetype": "cute_lights" }).to_string())
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    let value: serde_json::Value = serde_json::from_str(&body)?;
+    if value[0]["error"]["type"] == 101 {
+        return Err(anyhow::anyhow!(
+            "deCONZ at {} is locked, press Authenticate app in Phoscon under Gateway > Advanced",
+            host
+        ));
+    }
+    check_errors(&value, "deCONZ")?;
+    value[0]["success"]["username"]
+        .as_str()
+        .map(str::to_string)
+        .ok_or_else(|| anyhow::anyhow!("deCONZ at {} sent no API key", host))
+}
+
+// ANCHOR - DeconzConfig
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct DeconzConfig {
+    pub enabled: bool,
+    /// The gateway as `host` or `host:port`
+    #[serde(default)]
+    pub host: Option<String>,
+    /// Left out to pair on the next discovery, the new key is printed so it
+    /// can be added here
+    #[serde(default)]
+    pub api_key: Option<String>,
+    /// Keep state current from the gateway's WebSocket events
+    #[serde(default = "default_events")]
+    pub events: bool,
+    #[serde(default = "default_request_timeout")]
+    pub request_timeout: u64,
+}
+
+impl Default for DeconzConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            host: None,
+            api_key: None,
+            events: default_events(),
+            request_timeout: default_request_timeout(),
+        }
+    }
+}
+
+fn default_events() -> bool {
+    true
+}
+
+fn default_request_timeout() -> u64 {
+    2000
+}
+
+// ANCHOR - DeconzIntegration
+
+pub struct DeconzIntegration;
+
+#[async_trait]
+impl Integration for DeconzIntegration {
+    fn name(&self) -> String {
+        "deconz".to_string()
+    }
+
+    fn preflight(&self, config: &CuteLightsConfig) -> bool {
+        if !config.deconz.enabled {
+            return false;
+        }
+
+        if config.deconz.host.is_none() {
+            eprintln!("deCONZ gateway not configured");
+            return false;
+        }
+
+        true
+    }
+
+    async fn discover(&self, config: &CuteLightsConfig) -> anyhow::Result<Vec<Box<dyn Light>>> {
+        let host = config
+            .deconz
+            .host
+            .as_deref()
+            .ok_or_else(|| anyhow::anyhow!("deCONZ gateway not configured"))?;
+        let api_key = match &config.deconz.api_key {
+            Some(api_key) => api_key.clone(),
+            None => {
+                let api_key = pair(host).await?;
+                eprintln!(
+                    "Paired with deCONZ at {}, add api_key = \"{}\" to its config",
+                    host, api_key
+                );
+                api_key
+            }
+        };
+
+        let mut gateway = Gateway {
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_millis(config.deconz.request_timeout))
+                .build()?,
+            base_url: format!("http://{}/api/{}", host, api_key),
+            states: Arc::new(Mutex::new(HashMap::new())),
+            events: None,
+        };
+
+        let lights = gateway.get("lights").await?;
+        let mut found = Vec::new();
+        for (id, value) in json::object(&lights)? {
+            let light = match parse_light(value) {
+                Ok(light) => light,
+                Err(e) => {
+                    eprintln!("Skipping deCONZ light {}: {}", id, e);
+                    continue;
+                }
+            };
+            let ct_range = (
+                value["ctmin"].as_u64().unwrap_or(DEFAULT_CT_MIN as u64) as u16,
+                value["ctmax"].as_u64().unwrap_or(DEFAULT_CT_MAX as u64) as u16,
+            );
+            gateway
+                .lock_states()?
+                .insert(id.clone(), light.state.clone());
+            found.push((id.clone(), light, ct_range));
+        }
+
+        if config.deconz.events {
+            let settings = gateway.get("config").await?;
+            match settings["websocketport"].as_u64() {
+                Some(port) => {
+                    let hostname = host.rsplit_once(':').map_or(host, |(hostname, _)| hostname);
+                    let url = format!("ws://{}:{}", hostname, port);
+                    gateway.events = Some(tokio::spawn(read_events(url, gateway.states.clone())));
+                }
+                None => eprintln!("deCONZ at {} has no event feed", host),
+            }
+        }
+
+        let gateway = Arc::new(gateway);
+        Ok(found
+            .into_iter()
+            .map(|(id, light, ct_range)| {
+                Box::new(DeconzLight {
+                    gateway: gateway.clone(),
+                    id,
+                    unique_id: light.unique_id,
+                    name: light.name,
+                    supports_color: light.supports_color,
+                    supports_color_temperature: light.supports_color_temperature,
+                    ct_range,
+                    manufacturer: light.manufacturer,
+                    model: light.model,
+                    sw_version: light.sw_version,
+                }) as Box<dyn Light>
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::http::{FakeHttpServer, HttpRequest};
+    use futures_util::SinkExt;
+    use serde_json::Value;
+    use tokio::net::TcpListener;
+
+    const API_KEY: &str = "0A1B2C3D4E";
+
+    fn lights() -> Value {
+        json!({
+            "1": {
+                "name": "Ceiling",
+                "hascolor": true,
+                "ctmin": 200,
+                "ctmax": 454,
+                "manufacturername": "Signify Netherlands B.V.",
+                "modelid": "LCT015",
+                "swversion": "1.88.1",
+                "uniqueid": "00:17:88:01:02:1a:2b:3c-0b",
+                "state": {
+                    "on": true, "bri": 127, "hue": 21845, "sat": 254,
+                    "xy": [0.3, 0.6], "ct": 300, "colormode": "hs"
+                }
+            },
+            "2": {
+                "name": "Hallway",
+                "hascolor": true,
+                "state": { "on": false, "bri": 254, "ct": 370, "colormode": "ct" }
+            },
+            "3": { "hascolor": false, "state": { "on": true } }
+        })
+    }
+
+    /// Answers the REST API like a gateway, applying `state` PUTs to `lights`.
+    fn answer(lights: &mut Value, request: &HttpRequest) -> (u16, Value) {
+        let prefix = format!("/api/{}/", API_KEY);
+        let Some(path) = request.route().strip_prefix(&prefix) else {
+            return (
+                200,
+                json!([{ "error": { "type": 1, "description": "unauthorized user" } }]),
+            );
+        };
+        let segments: Vec<_> = path.split('/').collect();
+        match (request.method.as_str(), &segments[..]) {
+            ("GET", ["lights"]) => (200, lights.clone()),
+            ("PUT", ["lights", id, "state"]) => {
+                let body = request.body.clone().unwrap();
+                for (key, value) in body.as_object().unwrap() {
+                    lights[*id]["state"][key] = value.clone();
+                }
+                (200, json!([{ "success": body }]))
+            }
+            _ => (404, json!([])),
+        }
+    }
+
+    async fn gateway() -> (FakeHttpServer, Vec<Box<dyn Light>>) {
+        let lights = Arc::new(Mutex::new(lights()));
+        let server =
+            FakeHttpServer::start(move |request| answer(&mut lights.lock().unwrap(), request))
+                .await
+                .unwrap();
+        let config = CuteLightsConfig {
+            deconz: DeconzConfig {
+                enabled: true,
+                host: Some(server.address().to_string()),
+                api_key: Some(API_KEY.to_string()),
+                events: false,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut found = DeconzIntegration.discover(&config).await.unwrap();
+        found.sort_by_key(|light| light.name());
+        (server, found)
+    }
+
+    #[tokio::test]
+    async fn parses_lights() {
+        let (_server, lights) = gateway().await;
+        assert_eq!(lights.len(), 2);
+
+        let ceiling = &lights[0];
+        assert_eq!(ceiling.id(), "deconz::00:17:88:01:02:1a:2b:3c-0b");
+        assert_eq!(ceiling.name(), "Ceiling");
+        assert!(ceiling.is_on());
+        assert_eq!(ceiling.brightness(), 50);
+        assert!(ceiling.supports_color());
+        assert!(ceiling.supports_color_temperature());
+        assert_eq!(ceiling.device_info().manufacturer, "Signify Netherlands B.V.");
+        assert_eq!(
+            ceiling.device_info().firmware_version.as_deref(),
+            Some("1.88.1")
+        );
+        // In hs mode the color comes from hue and saturation at full value
+        assert_eq!(
+            (ceiling.red(), ceiling.green(), ceiling.blue()),
+            (0, 255, 0)
+        );
+
+        let hallway = &lights[1];
+        assert_eq!(hallway.id(), "deconz::2");
+        assert!(!hallway.is_on());
+        assert!(!hallway.supports_color());
+        assert_eq!(
+            (hallway.red(), hallway.green(), hallway.blue()),
+            (255, 255, 255)
+        );
+        assert_eq!(hallway.device_info().manufacturer, "dresden elektronik");
+    }
+
+    #[tokio::test]
+    async fn clamps_color_temperature_to_the_reported_or_default_range() {
+        let (server, mut lights) = gateway().await;
+        lights[0].set_color_temperature(6500).await.unwrap();
+        lights[0].set_color_temperature(2000).await.unwrap();
+        // Hallway reports no ctmin or ctmax
+        lights[1].set_color_temperature(10000).await.unwrap();
+        lights[1].set_color_temperature(1000).await.unwrap();
+
+        let sent: Vec<_> = server
+            .requests()
+            .into_iter()
+            .filter(|request| request.method == "PUT")
+            .map(|request| {
+                (
+                    request.route().to_string(),
+                    request.body.unwrap()["ct"].clone(),
+                )
+            })
+            .collect();
+        let path = |id: &str| format!("/api/{}/lights/{}/state", API_KEY, id);
+        assert_eq!(
+            sent,
+            [
+                (path("1"), json!(200)),
+                (path("1"), json!(454)),
+                (path("2"), json!(DEFAULT_CT_MIN)),
+                (path("2"), json!(DEFAULT_CT_MAX)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn set_state_round_trips() {
+        let (server, mut lights) = gateway().await;
+        let ceiling = &mut lights[0];
+
+        ceiling.set_on(false).await.unwrap();
+        ceiling.set_brightness(100).await.unwrap();
+        ceiling.set_color(255, 0, 0).await.unwrap();
+
+        let bodies: Vec<_> = server
+            .requests()
+            .into_iter()
+            .filter(|request| request.method == "PUT")
+            .map(|request| request.body.unwrap())
+            .collect();
+        let (x, y) = color::rgb_to_xy(255, 0, 0);
+        assert_eq!(
+            bodies,
+            [
+                json!({ "on": false }),
+                json!({ "bri": 254 }),
+                json!({ "on": true, "xy": [x, y] }),
+            ]
+        );
+        assert!(ceiling.is_on());
+        assert_eq!(ceiling.brightness(), 100);
+        let (red, green, blue) = (ceiling.red(), ceiling.green(), ceiling.blue());
+        assert!(
+            red > 200 && green < 60 && blue < 60,
+            "{:?}",
+            (red, green, blue)
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_failed_requests() {
+        let server = FakeHttpServer::start(|request| match request.method.as_str() {
+            "GET" => (
+                200,
+                json!({ "1": { "name": "Desk", "state": { "on": true } } }),
+            ),
+            _ => (503, json!("Service Unavailable")),
+        })
+        .await
+        .unwrap();
+        let config = CuteLightsConfig {
+            deconz: DeconzConfig {
+                enabled: true,
+                host: Some(server.address().to_string()),
+                api_key: Some(API_KEY.to_string()),
+                events: false,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut lights = DeconzIntegration.discover(&config).await.unwrap();
+
+        let error = lights[0].set_on(false).await.unwrap_err();
+        assert!(error.to_string().contains("503"), "{}", error);
+        assert!(lights[0].is_on());
+    }
+
+    #[tokio::test]
+    async fn pairing_reports_a_locked_gateway() {
+        let server = FakeHttpServer::start(|_| {
+            (
+                200,
+                json!([{ "error": { "type": 101, "address": "/", "description": "link button not pressed" } }]),
+            )
+        })
+        .await
+        .unwrap();
+
+        let error = pair(&server.address().to_string()).await.unwrap_err();
+        assert!(error.to_string().contains("is locked"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn pairing_returns_the_api_key() {
+        let server =
+            FakeHttpServer::start(|_| (200, json!([{ "success": { "username": API_KEY } }])))
+                .await
+                .unwrap();
+
+        assert_eq!(pair(&server.address().to_string()).await.unwrap(), API_KEY);
+        let requests = server.requests();
+        assert_eq!(requests[0].method, "POST");
+        assert_eq!(requests[0].route(), "/api");
+        assert_eq!(
+            requests[0].body,
+            Some(json!({ "devicetype": "cute_lights" }))
+        );
+    }
+
+    #[tokio::test]
+    async fn applies_light_events() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let states = Arc::new(Mutex::new(HashMap::new()));
+        let light =
+            parse_light(&json!({ "name": "Desk", "state": { "on": false, "bri": 254 } })).unwrap();
+        states.lock().unwrap().insert("1".to_string(), light.state);
+        let events = tokio::spawn(read_events(url, states.clone()));
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut socket = tokio_tungstenite::accept_async(stream).await.unwrap();
+        for event in [
+            // Ignored, not a change or not a light
+            json!({ "e": "added", "r": "lights", "id": "1", "state": { "on": true } }),
+            json!({ "e": "changed", "r": "groups", "id": "1", "state": { "on": true } }),
+            json!({ "e": "changed", "r": "lights", "id": "1", "state": { "on": true, "bri": 127 } }),
+        ] {
+            socket.send(Message::Text(event.to_string())).await.unwrap();
+        }
+
+        let state = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let state = states.lock().unwrap()["1"].clone();
+                if state.on {
+                    return state;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(state.brightness, 50);
+        events.abort();
+    }
+}
@@ -0,0 +1,675 @@
+use crate::{config::CuteLightsConfig, utils::net};
+use async_trait::async_trait;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    ops::RangeInclusive,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{net::UdpSocket, sync::oneshot};
+
+use super::{DeviceInfo, Integration, Light};
+
+const SACN_PORT: u16 = 5568;
+const ARTNET_PORT: u16 = 6454;
+const UNIVERSE_SIZE: usize = 512;
+
+// E1.31 reserves the rest, Art-Net port addresses are 15 bits
+const SACN_UNIVERSES: RangeInclusive<u16> = 1..=63999;
+const ARTNET_UNIVERSES: RangeInclusive<u16> = 0..=0x7fff;
+
+type Universes = Arc<Mutex<HashMap<u16, [u8; UNIVERSE_SIZE]>>>;
+
+// ANCHOR - DmxLight
+pub struct DmxLight {
+    output: Arc<Output>,
+    fixture: DmxFixture,
+    channels: Vec<Channel>,
+    is_on: bool,
+    brightness: u8,
+    red: u8,
+    green: u8,
+    blue: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Channel {
+    Red,
+    Green,
+    Blue,
+    White,
+    Dimmer,
+    Unused,
+}
+
+/// Parses a channel layout like `rgb`, `drgb` or `rgbw`, one letter per
+/// channel. `-` skips a channel and `dimmer` is short for `d`.
+fn parse_channels(layout: &str) -> anyhow::Result<Vec<Channel>> {
+    if layout.eq_ignore_ascii_case("dimmer") {
+        return Ok(vec![Channel::Dimmer]);
+    }
+    if layout.is_empty() {
+        return Err(anyhow::anyhow!("No DMX channels given"));
+    }
+    layout
+        .chars()
+        .map(|c| match c.to_ascii_lowercase() {
+            'r' => Ok(Channel::Red),
+            'g' => Ok(Channel::Green),
+            'b' => Ok(Channel::Blue),
+            'w' => Ok(Channel::White),
+            'd' => Ok(Channel::Dimmer),
+            '-' => Ok(Channel::Unused),
+            other => Err(anyhow::anyhow!("Unknown DMX channel '{}'", other)),
+        })
+        .collect()
+}
+
+impl DmxLight {
+    fn has(&self, channel: Channel) -> bool {
+        self.channels.contains(&channel)
+    }
+
+    /// The channel values for the current state.
+    fn values(&self) -> Vec<u8> {
+        let level = |value: u8| (value as u16 * self.brightness as u16 / 100) as u8;
+        let dimmed = self.has(Channel::Dimmer);
+        let color = |value: u8| match (self.is_on, dimmed) {
+            (false, _) => 0,
+            (true, true) => value,
+            (true, false) => level(value),
+        };
+
+        // RGBW fixtures take the shared part of the color on the white LED
+        let (mut red, mut green, mut blue) = (self.red, self.green, self.blue);
+        let mut white = 255;
+        if self.supports_color() && self.has(Channel::White) {
+            white = red.min(green).min(blue);
+            (red, green, blue) = (red - white, green - white, blue - white);
+        }
+
+        self.channels
+            .iter()
+            .map(|channel| match channel {
+                Channel::Red => color(red),
+                Channel::Green => color(green),
+                Channel::Blue => color(blue),
+                Channel::White => color(white),
+                Channel::Dimmer if self.is_on => level(255),
+                Channel::Dimmer | Channel::Unused => 0,
+            })
+            .collect()
+    }
+
+    /// Writes the fixture's channels into its universe, the output task
+    /// sends them with the next frame.
+    fn apply(&self) -> anyhow::Result<()> {
+        let values = self.values();
+        let start = self.fixture.address as usize - 1;
+        let mut universes = self
+            .output
+            .universes
+            .lock()
+            .map_err(|_| anyhow::anyhow!("DMX universes poisoned"))?;
+        let universe = universes
+            .entry(self.fixture.universe)
+            .or_insert([0; UNIVERSE_SIZE]);
+        universe[start..start + values.len()].copy_from_slice(&values);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Light for DmxLight {
+    async fn set_on(&mut self, on: bool) -> anyhow::Result<()> {
+        self.is_on = on;
+        self.apply()
+    }
+
+    async fn set_color(&mut self, red: u8, green: u8, blue: u8) -> anyhow::Result<()> {
+        if !self.supports_color() {
+            return Err(anyhow::anyhow!("{} does not support color", self.name()));
+        }
+        (self.red, self.green, self.blue) = (red, green, blue);
+        self.is_on = true;
+        self.apply()
+    }
+
+    async fn set_brightness(&mut self, brightness: u8) -> anyhow::Result<()> {
+        self.brightness = brightness.min(100);
+        self.apply()
+    }
+
+    fn is_on(&self) -> bool {
+        self.is_on
+    }
+
+    fn name(&self) -> String {
+        if self.fixture.name.is_empty() {
+            format!("DMX {}.{}", self.fixture.universe, self.fixture.address)
+        } else {
+            self.fixture.name.clone()
+        }
+    }
+
+    fn supports_color(&self) -> bool {
+        self.has(Channel::Red) && self.has(Channel::Green) && self.has(Channel::Blue)
+    }
+
+    fn red(&self) -> u8 {
+        self.red
+    }
+
+    fn green(&self) -> u8 {
+        self.green
+    }
+
+    fn blue(&self) -> u8 {
+        self.blue
+    }
+
+    fn brightness(&self) -> u8 {
+        self.brightness
+    }
+
+    fn id(&self) -> String {
+        format!("dmx::{}_{}", self.fixture.universe, self.fixture.address)
+    }
+
+    fn device_info(&self) -> DeviceInfo {
+        DeviceInfo {
+            manufacturer: "DMX".to_string(),
+            model: Some(self.fixture.channels.to_uppercase()),
+            firmware_version: None,
+            hardware_version: None,
+        }
+    }
+}
+
+// ANCHOR - Output
+
+/// The universes fixtures have written to, sent at the refresh rate until
+/// the last light is dropped. DMX receivers fall back to their own program
+/// when frames stop, so untouched universes are never sent.
+///
+/// Frames go out from a thread of their own, the bindings only drive their
+/// runtime while a call is in progress.
+struct Output {
+    universes: Universes,
+    // Dropping this stops the sender thread
+    _stop: oneshot::Sender<()>,
+}
+
+impl Output {
+    async fn start(config: &DmxConfig) -> anyhow::Result<Output> {
+        let socket = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        socket.set_broadcast(true)?;
+        socket.set_nonblocking(true)?;
+
+        let port = match config.protocol {
+            DmxProtocol::Sacn => SACN_PORT,
+            DmxProtocol::Artnet => ARTNET_PORT,
+        };
+        let target = match &config.target {
+            Some(target) => {
+                let address = net::with_default_port(target, port);
+                let mut addrs = tokio::net::lookup_host(&address).await?;
+                Some(
+                    addrs
+                        .next()
+                        .ok_or_else(|| anyhow::anyhow!("Failed to resolve {}", target))?,
+                )
+            }
+            None => None,
+        };
+
+        let universes = Universes::default();
+        let (stop, stopped) = oneshot::channel();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let shared = universes.clone();
+        let config = config.clone();
+        std::thread::Builder::new()
+            .name("cute_lights-dmx".to_string())
+            .spawn(move || {
+                runtime.block_on(async move {
+                    // The socket has to be registered with this runtime
+                    let socket = match UdpSocket::from_std(socket) {
+                        Ok(socket) => socket,
+                        Err(e) => {
+                            eprintln!("Failed to start DMX output: {}", e);
+                            return;
+                        }
+                    };
+                    tokio::select! {
+                        _ = send_frames(socket, target, shared, config) => {}
+                        _ = stopped => {}
+                    }
+                })
+            })?;
+        Ok(Output {
+            universes,
+            _stop: stop,
+        })
+    }
+}
+
+async fn send_frames(
+    socket: UdpSocket,
+    target: Option<SocketAddr>,
+    universes: Universes,
+    config: DmxConfig,
+) {
+    let period = Duration::from_secs_f64(1.0 / config.refresh_rate.clamp(1, 44) as f64);
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    let cid = component_id(&config.source_name);
+    let mut sequences: HashMap<u16, u8> = HashMap::new();
+    loop {
+        interval.tick().await;
+        let frames = match universes.lock() {
+            Ok(universes) => universes.clone(),
+            Err(_) => return,
+        };
+
+        for (universe, data) in frames {
+            let sequence = sequences.entry(universe).or_insert(0);
+            *sequence = sequence.wrapping_add(1);
+            let (packet, default_target) = match config.protocol {
+                DmxProtocol::Sacn => (
+                    sacn_packet(&cid, &config, universe, *sequence, &data),
+                    sacn_multicast(universe),
+                ),
+                DmxProtocol::Artnet => (
+                    artnet_packet(universe, (*sequence).max(1), &data),
+                    SocketAddr::new(IpAddr::V4(Ipv4Addr::BROADCAST), ARTNET_PORT),
+                ),
+            };
+            if let Err(e) = socket
+                .send_to(&packet, target.unwrap_or(default_target))
+                .await
+            {
+                eprintln!("Failed to send DMX universe {}: {}", universe, e);
+            }
+        }
+    }
+}
+
+// ANCHOR - Packets
+
+/// Receivers track sources by component id, so it is derived from the
+/// source name to stay the same across restarts.
+fn component_id(source_name: &str) -> [u8; 16] {
+    let digest = Sha256::digest(source_name.as_bytes());
+    let mut cid = [0; 16];
+    cid.copy_from_slice(&digest[..16]);
+    cid
+}
+
+fn sacn_multicast(universe: u16) -> SocketAddr {
+    let [high, low] = universe.to_be_bytes();
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(239, 255, high, low)), SACN_PORT)
+}
+
+/// An E1.31 data packet: root, framing and DMP layers followed by the
+/// start code and 512 slots.
+fn sacn_packet(
+    cid: &[u8; 16],
+    config: &DmxConfig,
+    universe: u16,
+    sequence: u8,
+    data: &[u8; UNIVERSE_SIZE],
+) -> Vec<u8> {
+    const LENGTH: usize = 126 + UNIVERSE_SIZE;
+    let flags_length = |offset: usize| (0x7000 | (LENGTH - offset) as u16).to_be_bytes();
+
+    let mut packet = Vec::with_capacity(LENGTH);
+    // Root layer
+    packet.extend_from_slice(&0x0010u16.to_be_bytes());
+    packet.extend_from_slice(&0u16.to_be_bytes());
+    packet.extend_from_slice(b"ASC-E1.17\0\0\0");
+    packet.extend_from_slice(&flags_length(16));
+    packet.extend_from_slice(&4u32.to_be_bytes());
+    packet.extend_from_slice(cid);
+
+    // Framing layer
+    packet.extend_from_slice(&flags_length(38));
+    packet.extend_from_slice(&2u32.to_be_bytes());
+    let mut source_name = [0; 64];
+    let name = config.source_name.as_bytes();
+    let len = name.len().min(63);
+    source_name[..len].copy_from_slice(&name[..len]);
+    packet.extend_from_slice(&source_name);
+    packet.push(config.priority.min(200));
+    packet.extend_from_slice(&0u16.to_be_bytes());
+    packet.push(sequence);
+    packet.push(0);
+    packet.extend_from_slice(&universe.to_be_bytes());
+
+    // DMP layer
+    packet.extend_from_slice(&flags_length(115));
+    packet.push(0x02);
+    packet.push(0xa1);
+    packet.extend_from_slice(&0u16.to_be_bytes());
+    packet.extend_from_slice(&1u16.to_be_bytes());
+    packet.extend_from_slice(&(UNIVERSE_SIZE as u16 + 1).to_be_bytes());
+    packet.push(0);
+    packet.extend_from_slice(data);
+    packet
+}
+
+/// An ArtDmx packet, `universe` being the 15 bit port address.
+fn artnet_packet(universe: u16, sequence: u8, data: &[u8; UNIVERSE_SIZE]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(18 + UNIVERSE_SIZE);
+    packet.extend_from_slice(b"Art-Net\0");
+    packet.extend_from_slice(&0x5000u16.to_le_bytes());
+    packet.extend_from_slice(&14u16.to_be_bytes());
+    packet.push(sequence);
+    packet.push(0);
+    packet.extend_from_slice(&(universe & 0x7fff).to_le_bytes());
+    packet.extend_from_slice(&(UNIVERSE_SIZE as u16).to_be_bytes());
+    packet.extend_from_slice(data);
+    packet
+}
+
+// ANCHOR - DmxConfig
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DmxProtocol {
+    /// E1.31, multicast per universe unless a target is set
+    Sacn,
+    /// Art-Net, broadcast unless a target is set
+    Artnet,
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct DmxConfig {
+    pub enabled: bool,
+    #[serde(default = "default_protocol")]
+    pub protocol: DmxProtocol,
+    /// Unicast receiver as `host` or `host:port`
+    #[serde(default)]
+    pub target: Option<String>,
+    /// Frames per second sent for every universe in use
+    #[serde(default = "default_refresh_rate")]
+    pub refresh_rate: u32,
+    /// Shown by sACN receivers, also seeds the component id
+    #[serde(default = "default_source_name")]
+    pub source_name: String,
+    /// sACN priority from 0 to 200, the highest source wins
+    #[serde(default = "default_priority")]
+    pub priority: u8,
+    #[serde(default)]
+    pub fixtures: Vec<DmxFixture>,
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct DmxFixture {
+    #[serde(default)]
+    pub name: String,
+    /// 1 to 63999 for sACN, 0 to 32767 for Art-Net
+    pub universe: u16,
+    /// The first channel, from 1 to 512
+    pub address: u16,
+    /// One letter per channel: `r`, `g`, `b`, `w` for white, `d` for a
+    /// dimmer and `-` for channels left at 0
+    #[serde(default = "default_channels")]
+    pub channels: String,
+}
+
+impl Default for DmxConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            protocol: default_protocol(),
+            target: None,
+            refresh_rate: default_refresh_rate(),
+            source_name: default_source_name(),
+            priority: default_priority(),
+            fixtures: Vec::new(),
+        }
+    }
+}
+
+fn default_protocol() -> DmxProtocol {
+    DmxProtocol::Sacn
+}
+
+fn default_refresh_rate() -> u32 {
+    30
+}
+
+fn default_source_name() -> String {
+    "cute_lights".to_string()
+}
+
+fn default_priority() -> u8 {
+    100
+}
+
+fn default_channels() -> String {
+    "rgb".to_string()
+}
+
+// ANCHOR - DmxIntegration
+
+pub struct DmxIntegration;
+
+#[async_trait]
+impl Integration for DmxIntegration {
+    fn name(&self) -> String {
+        "dmx".to_string()
+    }
+
+    fn preflight(&self, config: &CuteLightsConfig) -> bool {
+        config.dmx.enabled && !config.dmx.fixtures.is_empty()
+    }
+
+    async fn discover(&self, config: &CuteLightsConfig) -> anyhow::Result<Vec<Box<dyn Light>>> {
+        let config = &config.dmx;
+        let output = Arc::new(Output::start(config).await?);
+
+        let mut lights: Vec<Box<dyn Light>> = Vec::new();
+        for fixture in &config.fixtures {
+            let channels = match parse_channels(&fixture.channels) {
+                Ok(channels) => channels,
+                Err(e) => {
+                    eprintln!("Skipping DMX fixture {}: {}", fixture.name, e);
+                    continue;
+                }
+            };
+            let universes = match config.protocol {
+                DmxProtocol::Sacn => SACN_UNIVERSES,
+                DmxProtocol::Artnet => ARTNET_UNIVERSES,
+            };
+            if !universes.contains(&fixture.universe) {
+                eprintln!(
+                    "Skipping DMX fixture {}: universe {} is outside {} to {}",
+                    fixture.name,
+                    fixture.universe,
+                    universes.start(),
+                    universes.end()
+                );
+                continue;
+            }
+            let address = fixture.address as usize;
+            if address == 0 || address - 1 + channels.len() > UNIVERSE_SIZE {
+                eprintln!(
+                    "Skipping DMX fixture {}: channels {} to {} are outside the universe",
+                    fixture.name,
+                    address,
+                    address + channels.len() - 1
+                );
+                continue;
+            }
+
+            lights.push(Box::new(DmxLight {
+                output: output.clone(),
+                fixture: fixture.clone(),
+                channels,
+                is_on: false,
+                brightness: 100,
+                red: 255,
+                green: 255,
+                blue: 255,
+            }));
+        }
+        Ok(lights)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ramp() -> [u8; UNIVERSE_SIZE] {
+        std::array::from_fn(|index| index as u8)
+    }
+
+    #[test]
+    fn lays_out_sacn_packets() {
+        let config = DmxConfig {
+            source_name: "x".repeat(70),
+            priority: 250,
+            ..Default::default()
+        };
+        let cid = component_id("cute_lights");
+        let packet = sacn_packet(&cid, &config, 0x0102, 7, &ramp());
+
+        assert_eq!(packet.len(), 638);
+        // Root layer, flags and length counting from their own offset
+        assert_eq!(packet[..4], [0x00, 0x10, 0x00, 0x00]);
+        assert_eq!(&packet[4..16], b"ASC-E1.17\0\0\0");
+        assert_eq!(packet[16..18], [0x72, 0x6e]);
+        assert_eq!(packet[18..22], [0, 0, 0, 4]);
+        assert_eq!(packet[22..38], cid);
+        // Framing layer
+        assert_eq!(packet[38..40], [0x72, 0x58]);
+        assert_eq!(packet[40..44], [0, 0, 0, 2]);
+        assert_eq!(packet[44..107], [b'x'; 63]);
+        assert_eq!(packet[107], 0, "The source name ends in a NUL");
+        assert_eq!(packet[108], 200, "Priority is capped");
+        assert_eq!(packet[109..113], [0, 0, 7, 0]);
+        assert_eq!(packet[113..115], [0x01, 0x02]);
+        // DMP layer, then the start code and slots
+        assert_eq!(packet[115..117], [0x72, 0x0b]);
+        assert_eq!(packet[117..125], [0x02, 0xa1, 0, 0, 0, 1, 0x02, 0x01]);
+        assert_eq!(packet[125], 0);
+        assert_eq!(packet[126..], ramp());
+
+        assert_eq!(
+            sacn_multicast(0x0102),
+            "239.255.1.2:5568".parse::<SocketAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn lays_out_artnet_packets() {
+        let packet = artnet_packet(0x1234, 9, &ramp());
+
+        assert_eq!(packet.len(), 530);
+        assert_eq!(&packet[..8], b"Art-Net\0");
+        // OpDmx is little endian, the protocol version big endian
+        assert_eq!(packet[8..12], [0x00, 0x50, 0x00, 14]);
+        assert_eq!(packet[12..14], [9, 0]);
+        // SubUni then Net
+        assert_eq!(packet[14..16], [0x34, 0x12]);
+        assert_eq!(packet[16..18], [0x02, 0x00]);
+        assert_eq!(packet[18..], ramp());
+    }
+
+    fn fixture(universe: u16, address: u16) -> DmxFixture {
+        DmxFixture {
+            name: format!("{}.{}", universe, address),
+            universe,
+            address,
+            channels: default_channels(),
+        }
+    }
+
+    fn config(
+        protocol: DmxProtocol,
+        target: SocketAddr,
+        fixtures: Vec<DmxFixture>,
+    ) -> CuteLightsConfig {
+        CuteLightsConfig {
+            dmx: DmxConfig {
+                enabled: true,
+                protocol,
+                target: Some(target.to_string()),
+                refresh_rate: 44,
+                fixtures,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn skips_fixtures_outside_the_universes() {
+        let target = "127.0.0.1:9".parse().unwrap();
+        let fixtures = vec![
+            fixture(0, 1),
+            fixture(1, 1),
+            fixture(63999, 510),
+            fixture(64000, 1),
+            fixture(1, 511),
+        ];
+
+        let lights = DmxIntegration
+            .discover(&config(DmxProtocol::Sacn, target, fixtures.clone()))
+            .await
+            .unwrap();
+        let ids: Vec<String> = lights.iter().map(|light| light.id()).collect();
+        assert_eq!(ids, ["dmx::1_1", "dmx::63999_510"]);
+
+        let lights = DmxIntegration
+            .discover(&config(DmxProtocol::Artnet, target, fixtures))
+            .await
+            .unwrap();
+        let ids: Vec<String> = lights.iter().map(|light| light.id()).collect();
+        assert_eq!(ids, ["dmx::0_1", "dmx::1_1"]);
+    }
+
+    #[test]
+    fn keeps_sending_while_the_runtime_is_idle() {
+        let receiver = std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        let config = config(
+            DmxProtocol::Artnet,
+            receiver.local_addr().unwrap(),
+            vec![fixture(3, 2)],
+        );
+
+        // Like the bindings, a current-thread runtime that only runs while
+        // a call blocks on it
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let lights = runtime.block_on(async {
+            let mut lights = DmxIntegration.discover(&config).await.unwrap();
+            lights[0].set_color(255, 0, 0).await.unwrap();
+            lights
+        });
+
+        let mut buf = [0; 1024];
+        for _ in 0..5 {
+            let len = receiver.recv(&mut buf).unwrap();
+            assert_eq!(len, 530);
+            assert_eq!(buf[14..16], [3, 0]);
+            assert_eq!(buf[18..22], [0, 255, 0, 0]);
+        }
+
+        drop(lights);
+        let frames = (0..10).take_while(|_| receiver.recv(&mut buf).is_ok());
+        assert!(frames.count() < 10, "Frames kept coming after the drop");
+    }
+}
@@ -0,0 +1,508 @@
+use crate::{
+    config::CuteLightsConfig,
+    utils::{color, future::FutureBatch, mdns},
+};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::{net::IpAddr, time::Duration};
+
+use super::{DeviceInfo, Integration, Light};
+
+const ELGATO_SERVICE: &str = "_elg._tcp.local.";
+const ELGATO_PORT: u16 = 9123;
+
+// The Key Lights accept 143 to 344 mireds, roughly 7000K down to 2900K
+const MIN_MIREDS: u16 = 143;
+const MAX_MIREDS: u16 = 344;
+
+// ANCHOR - ElgatoLight
+pub struct ElgatoLight {
+    client: reqwest::Client,
+    base_url: String,
+    accessory: AccessoryInfo,
+    supports_color: bool,
+    is_on: bool,
+    brightness: u8,
+    red: u8,
+    green: u8,
+    blue: u8,
+}
+
+impl ElgatoLight {
+    pub async fn new(
+        host: IpAddr,
+        port: u16,
+        config: &ElgatoConfig,
+    ) -> anyhow::Result<ElgatoLight> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_millis(config.request_timeout))
+            .build()?;
+        let base_url = match host {
+            IpAddr::V4(addr) => format!("http://{}:{}/elgato", addr, port),
+            IpAddr::V6(addr) => format!("http://[{}]:{}/elgato", addr, port),
+        };
+
+        let body = client
+            .get(format!("{}/accessory-info", base_url))
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        let accessory: AccessoryInfo = serde_json::from_str(&body)?;
+
+        let mut light = ElgatoLight {
+            client,
+            base_url,
+            accessory,
+            supports_color: false,
+            is_on: false,
+            brightness: 0,
+            red: 0,
+            green: 0,
+            blue: 0,
+        };
+
+        let state = light.read_state().await?;
+        // Only the Light Strips report a hue
+        light.supports_color = state.hue.is_some();
+        light.update_from(&state);
+
+        Ok(light)
+    }
+
+    fn update_from(&mut self, state: &LightState) {
+        self.is_on = state.on == 1;
+        if let Some(brightness) = state.brightness {
+            self.brightness = brightness;
+        }
+        if let (Some(hue), Some(saturation)) = (state.hue, state.saturation) {
+            let (r, g, b) = color::hsb_to_rgb(hue as u16, saturation as u8, 100);
+            self.red = r;
+            self.green = g;
+            self.blue = b;
+        }
+    }
+
+    async fn read_state(&self) -> anyhow::Result<LightState> {
+        let body = self
+            .client
+            .get(format!("{}/lights", self.base_url))
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        let lights: Lights = serde_json::from_str(&body)?;
+        lights
+            .lights
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("{} reported no lights", self.name()))
+    }
+
+    async fn write_state(&self, state: LightState) -> anyhow::Result<()> {
+        let body = Lights {
+            number_of_lights: 1,
+            lights: vec![state],
+        };
+        self.client
+            .put(format!("{}/lights", self.base_url))
+            .body(serde_json::to_string(&body)?)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Light for ElgatoLight {
+    async fn set_on(&mut self, on: bool) -> anyhow::Result<()> {
+        self.write_state(LightState {
+            on: on as u8,
+            ..Default::default()
+        })
+        .await?;
+        self.is_on = on;
+        Ok(())
+    }
+
+    async fn set_color(&mut self, red: u8, green: u8, blue: u8) -> anyhow::Result<()> {
+        if !self.supports_color {
+            return Err(anyhow::anyhow!("{} does not support color", self.name()));
+        }
+
+        let (hue, saturation, _) = color::rgb_to_hsb(red, green, blue);
+        self.write_state(LightState {
+            on: 1,
+            hue: Some(hue as f32),
+            saturation: Some(saturation as f32),
+            ..Default::default()
+        })
+        .await?;
+        self.is_on = true;
+        self.red = red;
+        self.green = green;
+        self.blue = blue;
+        Ok(())
+    }
+
+    async fn set_brightness(&mut self, brightness: u8) -> anyhow::Result<()> {
+        let brightness = brightness.min(100);
+        self.write_state(LightState {
+            on: self.is_on as u8,
+            brightness: Some(brightness),
+            ..Default::default()
+        })
+        .await?;
+        self.brightness = brightness;
+        Ok(())
+    }
+
+    fn id(&self) -> String {
+        format!("elgato::{}", self.accessory.serial_number)
+    }
+
+    fn is_on(&self) -> bool {
+        self.is_on
+    }
+
+    fn name(&self) -> String {
+        if self.accessory.display_name.is_empty() {
+            self.accessory.product_name.clone()
+        } else {
+            self.accessory.display_name.clone()
+        }
+    }
+
+    fn supports_color(&self) -> bool {
+        self.supports_color
+    }
+
+    fn red(&self) -> u8 {
+        self.red
+    }
+
+    fn green(&self) -> u8 {
+        self.green
+    }
+
+    fn blue(&self) -> u8 {
+        self.blue
+    }
+
+    fn brightness(&self) -> u8 {
+        self.brightness
+    }
+
+    fn device_info(&self) -> DeviceInfo {
+        DeviceInfo {
+            manufacturer: "Elgato".to_string(),
+            model: Some(self.accessory.product_name.clone()),
+            firmware_version: Some(self.accessory.firmware_version.clone()),
+            hardware_version: self
+                .accessory
+                .hardware_board_type
+                .map(|board| board.to_string()),
+        }
+    }
+
+    fn supports_color_temperature(&self) -> bool {
+        true
+    }
+
+    async fn set_color_temperature(&mut self, kelvin: u16) -> anyhow::Result<()> {
+        let mireds = (1_000_000 / kelvin.max(1) as u32) as u16;
+        self.write_state(LightState {
+            on: 1,
+            temperature: Some(mireds.clamp(MIN_MIREDS, MAX_MIREDS)),
+            ..Default::default()
+        })
+        .await?;
+        self.is_on = true;
+        Ok(())
+    }
+}
+
+// ANCHOR - ElgatoConfig
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct ElgatoConfig {
+    pub enabled: bool,
+    /// Lights to query directly, as `host` or `host:port`
+    #[serde(default)]
+    pub addresses: Vec<String>,
+    /// Browse for `_elg._tcp` services over mDNS
+    #[serde(default = "default_mdns")]
+    pub mdns: bool,
+    #[serde(default = "default_scan_timeout")]
+    pub scan_timeout: u64,
+    #[serde(default = "default_request_timeout")]
+    pub request_timeout: u64,
+}
+
+impl Default for ElgatoConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            addresses: Vec::new(),
+            mdns: default_mdns(),
+            scan_timeout: default_scan_timeout(),
+            request_timeout: default_request_timeout(),
+        }
+    }
+}
+
+fn default_mdns() -> bool {
+    true
+}
+
+fn default_scan_timeout() -> u64 {
+    2000
+}
+
+fn default_request_timeout() -> u64 {
+    1000
+}
+
+// ANCHOR - ElgatoIntegration
+
+pub struct ElgatoIntegration;
+
+#[async_trait]
+impl Integration for ElgatoIntegration {
+    fn name(&self) -> String {
+        "elgato".to_string()
+    }
+
+    fn preflight(&self, config: &CuteLightsConfig) -> bool {
+        config.elgato.enabled
+    }
+
+    async fn discover(&self, config: &CuteLightsConfig) -> anyhow::Result<Vec<Box<dyn Light>>> {
+        let mut lights = FutureBatch::new();
+
+        let hosts = mdns::resolve_hosts(
+            &config.elgato.addresses,
+            ELGATO_PORT,
+            config.elgato.mdns.then_some(ELGATO_SERVICE),
+            Duration::from_millis(config.elgato.scan_timeout),
+        )
+        .await;
+        for (host, port) in hosts {
+            let config = config.elgato.clone();
+            lights.push(async move {
+                match ElgatoLight::new(host, port, &config).await {
+                    Ok(light) => Some(Box::new(light) as Box<dyn Light>),
+                    Err(e) => {
+                        eprintln!(
+                            "Failed to connect to Elgato light at {}:{}: {}",
+                            host, port, e
+                        );
+                        None
+                    }
+                }
+            });
+        }
+
+        let mut found: Vec<Box<dyn Light>> = Vec::new();
+        for light in lights.run().await.into_iter().flatten() {
+            if !found.iter().any(|l| l.id() == light.id()) {
+                found.push(light);
+            }
+        }
+        Ok(found)
+    }
+}
+
+// ANCHOR - Messages
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Lights {
+    number_of_lights: u8,
+    lights: Vec<LightState>,
+}
+
+// Fields left as None are not sent, the light keeps their current value
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct LightState {
+    on: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    brightness: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hue: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    saturation: Option<f32>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AccessoryInfo {
+    product_name: String,
+    hardware_board_type: Option<u32>,
+    firmware_version: String,
+    serial_number: String,
+    #[serde(default)]
+    display_name: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::http::{FakeHttpServer, HttpRequest};
+    use serde_json::{json, Value};
+    use std::sync::{Arc, Mutex};
+
+    fn key_light() -> Value {
+        json!({
+            "productName": "Elgato Key Light",
+            "hardwareBoardType": 53,
+            "firmwareBuildNumber": 218,
+            "firmwareVersion": "1.0.3",
+            "serialNumber": "CW23K1A01234",
+            "displayName": ""
+        })
+    }
+
+    fn light_strip() -> Value {
+        json!({
+            "productName": "Elgato Light Strip",
+            "hardwareBoardType": 70,
+            "firmwareVersion": "1.0.4",
+            "serialNumber": "EW52J1A05678",
+            "displayName": "Desk Strip"
+        })
+    }
+
+    /// Answers like a light, applying PUTs to the one light in `lights`.
+    fn answer(accessory: &Value, lights: &mut Value, request: &HttpRequest) -> (u16, Value) {
+        match (request.method.as_str(), request.route()) {
+            ("GET", "/elgato/accessory-info") => (200, accessory.clone()),
+            ("GET", "/elgato/lights") => (200, lights.clone()),
+            ("PUT", "/elgato/lights") => {
+                let body = request.body.as_ref().unwrap();
+                for (key, value) in body["lights"][0].as_object().unwrap() {
+                    lights["lights"][0][key] = value.clone();
+                }
+                (200, lights.clone())
+            }
+            _ => (404, Value::Null),
+        }
+    }
+
+    async fn device(accessory: Value, state: Value) -> (FakeHttpServer, ElgatoLight) {
+        let lights = Arc::new(Mutex::new(
+            json!({ "numberOfLights": 1, "lights": [state] }),
+        ));
+        let server = FakeHttpServer::start(move |request| {
+            answer(&accessory, &mut lights.lock().unwrap(), request)
+        })
+        .await
+        .unwrap();
+        let address = server.address();
+        let light = ElgatoLight::new(address.ip(), address.port(), &ElgatoConfig::default())
+            .await
+            .unwrap();
+        (server, light)
+    }
+
+    /// The light state of every PUT so far.
+    fn puts(server: &FakeHttpServer) -> Vec<Value> {
+        server
+            .requests()
+            .into_iter()
+            .filter(|request| request.method == "PUT")
+            .map(|request| {
+                let body = request.body.unwrap();
+                assert_eq!(body["numberOfLights"], 1);
+                body["lights"][0].clone()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn parses_a_key_light() {
+        let (_server, light) = device(
+            key_light(),
+            json!({ "on": 1, "brightness": 40, "temperature": 213 }),
+        )
+        .await;
+
+        assert_eq!(light.id(), "elgato::CW23K1A01234");
+        // Falls back to the product name without a display name
+        assert_eq!(light.name(), "Elgato Key Light");
+        assert!(light.is_on());
+        assert_eq!(light.brightness(), 40);
+        assert!(!light.supports_color());
+        let info = light.device_info();
+        assert_eq!(info.model.as_deref(), Some("Elgato Key Light"));
+        assert_eq!(info.firmware_version.as_deref(), Some("1.0.3"));
+        assert_eq!(info.hardware_version.as_deref(), Some("53"));
+    }
+
+    #[tokio::test]
+    async fn parses_a_light_strip() {
+        let (_server, light) = device(
+            light_strip(),
+            json!({ "on": 0, "brightness": 100, "hue": 120.0, "saturation": 100.0 }),
+        )
+        .await;
+
+        assert_eq!(light.name(), "Desk Strip");
+        assert!(!light.is_on());
+        assert!(light.supports_color());
+        assert_eq!((light.red(), light.green(), light.blue()), (0, 255, 0));
+    }
+
+    #[tokio::test]
+    async fn sends_state() {
+        let (server, mut light) = device(
+            light_strip(),
+            json!({ "on": 0, "brightness": 20, "hue": 0.0, "saturation": 0.0 }),
+        )
+        .await;
+
+        light.set_on(true).await.unwrap();
+        light.set_brightness(150).await.unwrap();
+        light.set_color(0, 0, 255).await.unwrap();
+
+        assert_eq!(
+            puts(&server),
+            [
+                json!({ "on": 1 }),
+                json!({ "on": 1, "brightness": 100 }),
+                json!({ "on": 1, "hue": 240.0, "saturation": 100.0 }),
+            ]
+        );
+        assert_eq!(light.brightness(), 100);
+        assert_eq!((light.red(), light.green(), light.blue()), (0, 0, 255));
+    }
+
+    #[tokio::test]
+    async fn clamps_color_temperature_to_the_mired_range() {
+        let (server, mut light) = device(
+            key_light(),
+            json!({ "on": 0, "brightness": 40, "temperature": 213 }),
+        )
+        .await;
+
+        for kelvin in [4000, 10000, 2000] {
+            light.set_color_temperature(kelvin).await.unwrap();
+        }
+        assert!(light.set_color(255, 0, 0).await.is_err());
+
+        assert_eq!(
+            puts(&server),
+            [
+                json!({ "on": 1, "temperature": 250 }),
+                json!({ "on": 1, "temperature": MIN_MIREDS }),
+                json!({ "on": 1, "temperature": MAX_MIREDS }),
+            ]
+        );
+        assert!(light.is_on());
+    }
+}
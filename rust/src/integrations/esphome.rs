@@ -0,0 +1,1378 @@
+use crate::{
+    config::CuteLightsConfig,
+    utils::{future::FutureBatch, mdns},
+};
+use async_trait::async_trait;
+use base64::Engine;
+use prost::Message;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::{tcp::OwnedWriteHalf, TcpStream},
+    task::JoinHandle,
+};
+
+use super::{DeviceInfo, Integration, Light};
+
+const ESPHOME_SERVICE: &str = "_esphomelib._tcp.local.";
+const ESPHOME_PORT: u16 = 6053;
+
+const NOISE_PATTERN: &str = "Noise_NNpsk0_25519_ChaChaPoly_SHA256";
+const NOISE_PROLOGUE: &[u8] = b"NoiseAPIInit\x00\x00";
+const MAX_FRAME: usize = 64 * 1024;
+
+// The API version this speaks, devices accept older clients
+const API_VERSION_MAJOR: u32 = 1;
+const API_VERSION_MINOR: u32 = 9;
+
+// ANCHOR - EsphomeLight
+pub struct EsphomeLight {
+    device: Arc<Device>,
+    entity: ListEntitiesLightResponse,
+}
+
+impl EsphomeLight {
+    fn state(&self) -> LightStateResponse {
+        self.device
+            .states
+            .lock()
+            .ok()
+            .and_then(|states| states.get(&self.entity.key).cloned())
+            .unwrap_or_default()
+    }
+
+    /// The simplest supported color mode that has `capability`.
+    fn mode_with(&self, capability: i32) -> Option<i32> {
+        self.entity
+            .supported_color_modes
+            .iter()
+            .copied()
+            .filter(|mode| mode & capability != 0)
+            .min_by_key(|mode| mode.count_ones())
+    }
+
+    async fn command(&mut self, mut command: LightCommandRequest) -> anyhow::Result<()> {
+        command.key = self.entity.key;
+        self.device
+            .sender
+            .send(LIGHT_COMMAND_REQUEST, &command)
+            .await?;
+
+        // The device answers with a state update, apply the change now for
+        // callers that read it right away
+        if let Ok(mut states) = self.device.states.lock() {
+            let state = states.entry(self.entity.key).or_default();
+            if command.has_state {
+                state.state = command.state;
+            }
+            if command.has_brightness {
+                state.brightness = command.brightness;
+            }
+            if command.has_rgb {
+                (state.red, state.green, state.blue) = (command.red, command.green, command.blue);
+            }
+            if command.has_color_mode {
+                state.color_mode = command.color_mode;
+            }
+            if command.has_effect {
+                state.effect = command.effect;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Light for EsphomeLight {
+    async fn set_on(&mut self, on: bool) -> anyhow::Result<()> {
+        self.command(LightCommandRequest {
+            has_state: true,
+            state: on,
+            ..Default::default()
+        })
+        .await
+    }
+
+    async fn set_color(&mut self, red: u8, green: u8, blue: u8) -> anyhow::Result<()> {
+        let Some(color_mode) = self.mode_with(CAPABILITY_RGB) else {
+            return Err(anyhow::anyhow!("{} does not support color", self.name()));
+        };
+        self.command(LightCommandRequest {
+            has_state: true,
+            state: true,
+            has_color_mode: true,
+            color_mode,
+            has_rgb: true,
+            red: red as f32 / 255.0,
+            green: green as f32 / 255.0,
+            blue: blue as f32 / 255.0,
+            has_color_brightness: true,
+            color_brightness: 1.0,
+            ..Default::default()
+        })
+        .await
+    }
+
+    async fn set_brightness(&mut self, brightness: u8) -> anyhow::Result<()> {
+        if self.mode_with(CAPABILITY_BRIGHTNESS).is_none() {
+            return Err(anyhow::anyhow!(
+                "{} does not support brightness",
+                self.name()
+            ));
+        }
+        self.command(LightCommandRequest {
+            has_brightness: true,
+            brightness: brightness.min(100) as f32 / 100.0,
+            ..Default::default()
+        })
+        .await
+    }
+
+    fn id(&self) -> String {
+        format!(
+            "esphome::{}_{}",
+            self.device.info.mac_address, self.entity.object_id
+        )
+    }
+
+    fn is_on(&self) -> bool {
+        self.state().state
+    }
+
+    fn name(&self) -> String {
+        // Lights named after their device leave the entity name empty
+        if !self.entity.name.is_empty() {
+            self.entity.name.clone()
+        } else if !self.device.info.friendly_name.is_empty() {
+            self.device.info.friendly_name.clone()
+        } else {
+            self.device.info.name.clone()
+        }
+    }
+
+    fn supports_color(&self) -> bool {
+        self.mode_with(CAPABILITY_RGB).is_some()
+    }
+
+    fn red(&self) -> u8 {
+        (self.state().red.clamp(0.0, 1.0) * 255.0).round() as u8
+    }
+
+    fn green(&self) -> u8 {
+        (self.state().green.clamp(0.0, 1.0) * 255.0).round() as u8
+    }
+
+    fn blue(&self) -> u8 {
+        (self.state().blue.clamp(0.0, 1.0) * 255.0).round() as u8
+    }
+
+    fn brightness(&self) -> u8 {
+        let state = self.state();
+        if self.mode_with(CAPABILITY_BRIGHTNESS).is_none() {
+            return state.state as u8 * 100;
+        }
+        (state.brightness.clamp(0.0, 1.0) * 100.0).round() as u8
+    }
+
+    fn device_info(&self) -> DeviceInfo {
+        let info = &self.device.info;
+        DeviceInfo {
+            manufacturer: if info.manufacturer.is_empty() {
+                "ESPHome".to_string()
+            } else {
+                info.manufacturer.clone()
+            },
+            model: Some(info.model.clone()).filter(|model| !model.is_empty()),
+            firmware_version: Some(info.esphome_version.clone()),
+            hardware_version: None,
+        }
+    }
+
+    fn supports_color_temperature(&self) -> bool {
+        self.mode_with(CAPABILITY_COLOR_TEMPERATURE).is_some()
+    }
+
+    async fn set_color_temperature(&mut self, kelvin: u16) -> anyhow::Result<()> {
+        let Some(color_mode) = self.mode_with(CAPABILITY_COLOR_TEMPERATURE) else {
+            return Err(anyhow::anyhow!(
+                "{} does not support color temperature",
+                self.name()
+            ));
+        };
+        let mireds = 1_000_000.0 / kelvin.max(1) as f32;
+        let (min, max) = (self.entity.min_mireds, self.entity.max_mireds);
+        self.command(LightCommandRequest {
+            has_state: true,
+            state: true,
+            has_color_mode: true,
+            color_mode,
+            has_color_temperature: true,
+            color_temperature: if min < max {
+                mireds.clamp(min, max)
+            } else {
+                mireds
+            },
+            ..Default::default()
+        })
+        .await
+    }
+
+    fn effects(&self) -> Vec<String> {
+        self.entity.effects.clone()
+    }
+
+    async fn set_effect(&mut self, effect: &str) -> anyhow::Result<()> {
+        if !self.entity.effects.iter().any(|e| e == effect) {
+            return Err(anyhow::anyhow!(
+                "{} has no effect named {}",
+                self.name(),
+                effect
+            ));
+        }
+        self.command(LightCommandRequest {
+            has_state: true,
+            state: true,
+            has_effect: true,
+            effect: effect.to_string(),
+            ..Default::default()
+        })
+        .await
+    }
+}
+
+// ANCHOR - Device
+
+/// A connection to one device, shared by its light entities. A background
+/// task applies state updates and answers the device's pings.
+struct Device {
+    sender: Arc<Sender>,
+    info: DeviceInfoResponse,
+    states: Arc<Mutex<HashMap<u32, LightStateResponse>>>,
+    reader: JoinHandle<()>,
+}
+
+impl Device {
+    async fn connect(
+        host: IpAddr,
+        port: u16,
+        encryption_key: Option<&str>,
+        password: Option<&str>,
+        config: &EsphomeConfig,
+    ) -> anyhow::Result<(Arc<Device>, Vec<ListEntitiesLightResponse>)> {
+        let timeout = Duration::from_millis(config.request_timeout);
+        tokio::time::timeout(
+            timeout,
+            Self::handshake(host, port, encryption_key, password),
+        )
+        .await
+        .map_err(|_| anyhow::anyhow!("Timed out connecting to {}:{}", host, port))?
+    }
+
+    async fn handshake(
+        host: IpAddr,
+        port: u16,
+        encryption_key: Option<&str>,
+        password: Option<&str>,
+    ) -> anyhow::Result<(Arc<Device>, Vec<ListEntitiesLightResponse>)> {
+        let stream = TcpStream::connect((host, port)).await?;
+        stream.set_nodelay(true)?;
+        let (mut reader, writer) = stream.into_split();
+        let mut sender = Sender {
+            writer: tokio::sync::Mutex::new(writer),
+            codec: Codec::Plain,
+        };
+
+        if let Some(key) = encryption_key {
+            let transport = noise_handshake(&mut reader, &sender.writer, key).await?;
+            sender.codec = Codec::Noise(Mutex::new(transport));
+        }
+        let sender = Arc::new(sender);
+
+        sender
+            .send(
+                HELLO_REQUEST,
+                &HelloRequest {
+                    client_info: "cute_lights".to_string(),
+                    api_version_major: API_VERSION_MAJOR,
+                    api_version_minor: API_VERSION_MINOR,
+                },
+            )
+            .await?;
+        expect::<HelloResponse>(&mut reader, &sender, HELLO_RESPONSE).await?;
+
+        sender
+            .send(
+                CONNECT_REQUEST,
+                &ConnectRequest {
+                    password: password.unwrap_or_default().to_string(),
+                },
+            )
+            .await?;
+        let connect: ConnectResponse = expect(&mut reader, &sender, CONNECT_RESPONSE).await?;
+        if connect.invalid_password {
+            return Err(anyhow::anyhow!("Wrong API password for {}", host));
+        }
+
+        sender
+            .send(DEVICE_INFO_REQUEST, &DeviceInfoRequest {})
+            .await?;
+        let info: DeviceInfoResponse = expect(&mut reader, &sender, DEVICE_INFO_RESPONSE).await?;
+
+        sender
+            .send(LIST_ENTITIES_REQUEST, &ListEntitiesRequest {})
+            .await?;
+        let mut entities = Vec::new();
+        loop {
+            let (kind, data) = next_message(&mut reader, &sender).await?;
+            match kind {
+                LIST_ENTITIES_LIGHT_RESPONSE => {
+                    entities.push(ListEntitiesLightResponse::decode(data.as_slice())?)
+                }
+                LIST_ENTITIES_DONE_RESPONSE => break,
+                // Sensors, switches and the rest
+                _ => {}
+            }
+        }
+
+        sender
+            .send(SUBSCRIBE_STATES_REQUEST, &SubscribeStatesRequest {})
+            .await?;
+
+        let states = Arc::new(Mutex::new(HashMap::new()));
+        let reader = tokio::spawn(read_states(reader, sender.clone(), states.clone()));
+        let device = Arc::new(Device {
+            sender,
+            info,
+            states,
+            reader,
+        });
+        Ok((device, entities))
+    }
+}
+
+impl Drop for Device {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+async fn read_states<R: AsyncRead + Unpin>(
+    mut reader: R,
+    sender: Arc<Sender>,
+    states: Arc<Mutex<HashMap<u32, LightStateResponse>>>,
+) {
+    loop {
+        let (kind, data) = match next_message(&mut reader, &sender).await {
+            Ok(message) => message,
+            Err(e) => {
+                eprintln!("ESPHome connection closed: {}", e);
+                return;
+            }
+        };
+        if kind == LIGHT_STATE_RESPONSE {
+            let Ok(state) = LightStateResponse::decode(data.as_slice()) else {
+                continue;
+            };
+            if let Ok(mut states) = states.lock() {
+                states.insert(state.key, state);
+            }
+        }
+    }
+}
+
+/// Reads the next message, answering the requests the device may send at
+/// any time along the way.
+async fn next_message<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    sender: &Sender,
+) -> anyhow::Result<(u16, Vec<u8>)> {
+    loop {
+        let (kind, data) = sender.codec.read(reader).await?;
+        match kind {
+            PING_REQUEST => sender.send(PING_RESPONSE, &PingResponse {}).await?,
+            GET_TIME_REQUEST => {
+                let epoch_seconds = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs() as u32)
+                    .unwrap_or(0);
+                sender
+                    .send(GET_TIME_RESPONSE, &GetTimeResponse { epoch_seconds })
+                    .await?
+            }
+            DISCONNECT_REQUEST => {
+                let _ = sender
+                    .send(DISCONNECT_RESPONSE, &DisconnectResponse {})
+                    .await;
+                return Err(anyhow::anyhow!("Device disconnected"));
+            }
+            _ => return Ok((kind, data)),
+        }
+    }
+}
+
+async fn expect<M: Message + Default>(
+    reader: &mut (impl AsyncRead + Unpin),
+    sender: &Sender,
+    expected: u16,
+) -> anyhow::Result<M> {
+    loop {
+        let (kind, data) = next_message(reader, sender).await?;
+        if kind == expected {
+            return Ok(M::decode(data.as_slice())?);
+        }
+    }
+}
+
+// ANCHOR - Framing
+
+struct Sender {
+    writer: tokio::sync::Mutex<OwnedWriteHalf>,
+    codec: Codec,
+}
+
+impl Sender {
+    async fn send<M: Message>(&self, kind: u16, message: &M) -> anyhow::Result<()> {
+        let frame = self.codec.encode(kind, &message.encode_to_vec())?;
+        self.writer.lock().await.write_all(&frame).await?;
+        Ok(())
+    }
+}
+
+/// Plaintext frames are `0x00, varint length, varint type, data`. Noise
+/// frames are `0x01, u16 length, ciphertext` where the plaintext is
+/// `u16 type, u16 length, data`.
+enum Codec {
+    Plain,
+    Noise(Mutex<snow::TransportState>),
+}
+
+impl Codec {
+    fn encode(&self, kind: u16, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut frame = Vec::with_capacity(data.len() + 8);
+        match self {
+            Codec::Plain => {
+                frame.push(0x00);
+                prost::encoding::encode_varint(data.len() as u64, &mut frame);
+                prost::encoding::encode_varint(kind as u64, &mut frame);
+                frame.extend_from_slice(data);
+            }
+            Codec::Noise(transport) => {
+                let mut plain = Vec::with_capacity(data.len() + 4);
+                plain.extend_from_slice(&kind.to_be_bytes());
+                plain.extend_from_slice(&(data.len() as u16).to_be_bytes());
+                plain.extend_from_slice(data);
+
+                let mut sealed = vec![0; plain.len() + 16];
+                let len = lock_transport(transport)?.write_message(&plain, &mut sealed)?;
+                frame.push(0x01);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+                frame.extend_from_slice(&sealed[..len]);
+            }
+        }
+        Ok(frame)
+    }
+
+    async fn read<R: AsyncRead + Unpin>(&self, reader: &mut R) -> anyhow::Result<(u16, Vec<u8>)> {
+        match self {
+            Codec::Plain => {
+                match reader.read_u8().await? {
+                    0x00 => {}
+                    0x01 => return Err(anyhow::anyhow!("Device requires an encryption key")),
+                    other => return Err(anyhow::anyhow!("Unexpected frame indicator {}", other)),
+                }
+                let len = read_varint(reader).await? as usize;
+                let kind = read_varint(reader).await? as u16;
+                if len > MAX_FRAME {
+                    return Err(anyhow::anyhow!(
+                        "ESPHome frame of {} bytes is too long",
+                        len
+                    ));
+                }
+                let mut data = vec![0; len];
+                reader.read_exact(&mut data).await?;
+                Ok((kind, data))
+            }
+            Codec::Noise(transport) => {
+                let sealed = read_noise_frame(reader).await?;
+                let mut plain = vec![0; sealed.len()];
+                let len = lock_transport(transport)?.read_message(&sealed, &mut plain)?;
+                if len < 4 {
+                    return Err(anyhow::anyhow!("ESPHome message is truncated"));
+                }
+                let kind = u16::from_be_bytes([plain[0], plain[1]]);
+                let size = u16::from_be_bytes([plain[2], plain[3]]) as usize;
+                let data = plain
+                    .get(4..4 + size)
+                    .ok_or_else(|| anyhow::anyhow!("ESPHome message is truncated"))?;
+                Ok((kind, data.to_vec()))
+            }
+        }
+    }
+}
+
+fn lock_transport(
+    transport: &Mutex<snow::TransportState>,
+) -> anyhow::Result<std::sync::MutexGuard<'_, snow::TransportState>> {
+    transport
+        .lock()
+        .map_err(|_| anyhow::anyhow!("ESPHome cipher state poisoned"))
+}
+
+async fn read_varint<R: AsyncRead + Unpin>(reader: &mut R) -> anyhow::Result<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let byte = reader.read_u8().await?;
+        value |= ((byte & 0x7F) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(anyhow::anyhow!("Varint is too long"))
+}
+
+async fn read_noise_frame<R: AsyncRead + Unpin>(reader: &mut R) -> anyhow::Result<Vec<u8>> {
+    match reader.read_u8().await? {
+        0x01 => {}
+        0x00 => {
+            return Err(anyhow::anyhow!(
+                "Device does not use encryption, remove its key"
+            ))
+        }
+        other => return Err(anyhow::anyhow!("Unexpected frame indicator {}", other)),
+    }
+    let len = reader.read_u16().await? as usize;
+    let mut data = vec![0; len];
+    reader.read_exact(&mut data).await?;
+    Ok(data)
+}
+
+/// Runs the Noise NNpsk0 handshake with the device's base64 encryption key
+/// as the pre-shared key.
+async fn noise_handshake<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    writer: &tokio::sync::Mutex<OwnedWriteHalf>,
+    key: &str,
+) -> anyhow::Result<snow::TransportState> {
+    let psk = base64::engine::general_purpose::STANDARD
+        .decode(key)
+        .map_err(|e| anyhow::anyhow!("ESPHome encryption key is not base64: {}", e))?;
+    if psk.len() != 32 {
+        return Err(anyhow::anyhow!("ESPHome encryption key must be 32 bytes"));
+    }
+
+    let mut handshake = snow::Builder::new(NOISE_PATTERN.parse()?)
+        .prologue(NOISE_PROLOGUE)
+        .psk(0, &psk)
+        .build_initiator()?;
+    let mut message = vec![0; 128];
+    let len = handshake.write_message(&[], &mut message)?;
+
+    // An empty hello frame, then the first handshake message behind a zero
+    // status byte
+    let mut frames = vec![0x01, 0x00, 0x00, 0x01];
+    frames.extend_from_slice(&(len as u16 + 1).to_be_bytes());
+    frames.push(0x00);
+    frames.extend_from_slice(&message[..len]);
+    writer.lock().await.write_all(&frames).await?;
+
+    let server_hello = read_noise_frame(reader).await?;
+    if server_hello.first() != Some(&0x01) {
+        return Err(anyhow::anyhow!(
+            "Device chose an unknown encryption protocol"
+        ));
+    }
+
+    let reply = read_noise_frame(reader).await?;
+    match reply.split_first() {
+        Some((0x00, message)) => {
+            let mut payload = vec![0; message.len()];
+            handshake.read_message(message, &mut payload)?;
+        }
+        Some((_, error)) => {
+            return Err(anyhow::anyhow!(
+                "Encryption handshake failed, check the key: {}",
+                String::from_utf8_lossy(error)
+            ))
+        }
+        None => return Err(anyhow::anyhow!("Empty handshake reply")),
+    }
+    Ok(handshake.into_transport_mode()?)
+}
+
+// ANCHOR - EsphomeConfig
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct EsphomeConfig {
+    pub enabled: bool,
+    /// Devices to connect to directly, each with its own key
+    #[serde(default)]
+    pub devices: Vec<EsphomeDevice>,
+    /// Browse for `_esphomelib._tcp` services over mDNS, the devices found
+    /// use the key and password below
+    #[serde(default = "default_mdns")]
+    pub mdns: bool,
+    /// The base64 `api: encryption: key` of the device YAML
+    #[serde(default)]
+    pub encryption_key: Option<String>,
+    /// The deprecated `api: password`
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default = "default_scan_timeout")]
+    pub scan_timeout: u64,
+    #[serde(default = "default_request_timeout")]
+    pub request_timeout: u64,
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct EsphomeDevice {
+    /// `host` or `host:port`
+    pub address: String,
+    #[serde(default)]
+    pub encryption_key: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+impl Default for EsphomeConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            devices: Vec::new(),
+            mdns: default_mdns(),
+            encryption_key: None,
+            password: None,
+            scan_timeout: default_scan_timeout(),
+            request_timeout: default_request_timeout(),
+        }
+    }
+}
+
+fn default_mdns() -> bool {
+    true
+}
+
+fn default_scan_timeout() -> u64 {
+    2000
+}
+
+fn default_request_timeout() -> u64 {
+    3000
+}
+
+// ANCHOR - EsphomeIntegration
+
+pub struct EsphomeIntegration;
+
+#[async_trait]
+impl Integration for EsphomeIntegration {
+    fn name(&self) -> String {
+        "esphome".to_string()
+    }
+
+    fn preflight(&self, config: &CuteLightsConfig) -> bool {
+        config.esphome.enabled
+    }
+
+    async fn discover(&self, config: &CuteLightsConfig) -> anyhow::Result<Vec<Box<dyn Light>>> {
+        let config = &config.esphome;
+        let mut targets: Vec<(IpAddr, u16, Option<String>, Option<String>)> = Vec::new();
+
+        for device in &config.devices {
+            let hosts = mdns::resolve_hosts(
+                std::slice::from_ref(&device.address),
+                ESPHOME_PORT,
+                None,
+                Duration::ZERO,
+            )
+            .await;
+            for (host, port) in hosts {
+                targets.push((
+                    host,
+                    port,
+                    device.encryption_key.clone(),
+                    device.password.clone(),
+                ));
+            }
+        }
+        if config.mdns {
+            let hosts = mdns::browse_hosts(
+                ESPHOME_SERVICE,
+                "",
+                Duration::from_millis(config.scan_timeout),
+            )
+            .await;
+            for (host, port) in hosts {
+                if !targets.iter().any(|t| t.0 == host && t.1 == port) {
+                    targets.push((
+                        host,
+                        port,
+                        config.encryption_key.clone(),
+                        config.password.clone(),
+                    ));
+                }
+            }
+        }
+
+        let mut devices = FutureBatch::new();
+        for (host, port, encryption_key, password) in targets {
+            let config = config.clone();
+            devices.push(async move {
+                match Device::connect(
+                    host,
+                    port,
+                    encryption_key.as_deref(),
+                    password.as_deref(),
+                    &config,
+                )
+                .await
+                {
+                    Ok(device) => Some(device),
+                    Err(e) => {
+                        eprintln!(
+                            "Failed to connect to ESPHome device at {}:{}: {}",
+                            host, port, e
+                        );
+                        None
+                    }
+                }
+            });
+        }
+
+        let mut found: Vec<Box<dyn Light>> = Vec::new();
+        for (device, entities) in devices.run().await.into_iter().flatten() {
+            for entity in entities {
+                let light = EsphomeLight {
+                    device: device.clone(),
+                    entity,
+                };
+                if !found.iter().any(|l| l.id() == light.id()) {
+                    found.push(Box::new(light));
+                }
+            }
+        }
+        Ok(found)
+    }
+}
+
+// ANCHOR - Messages
+
+// Message types and fields from ESPHome's api.proto
+const HELLO_REQUEST: u16 = 1;
+const HELLO_RESPONSE: u16 = 2;
+const CONNECT_REQUEST: u16 = 3;
+const CONNECT_RESPONSE: u16 = 4;
+const DISCONNECT_REQUEST: u16 = 5;
+const DISCONNECT_RESPONSE: u16 = 6;
+const PING_REQUEST: u16 = 7;
+const PING_RESPONSE: u16 = 8;
+const DEVICE_INFO_REQUEST: u16 = 9;
+const DEVICE_INFO_RESPONSE: u16 = 10;
+const LIST_ENTITIES_REQUEST: u16 = 11;
+const LIST_ENTITIES_LIGHT_RESPONSE: u16 = 15;
+const LIST_ENTITIES_DONE_RESPONSE: u16 = 19;
+const SUBSCRIBE_STATES_REQUEST: u16 = 20;
+const LIGHT_STATE_RESPONSE: u16 = 24;
+const LIGHT_COMMAND_REQUEST: u16 = 32;
+const GET_TIME_REQUEST: u16 = 36;
+const GET_TIME_RESPONSE: u16 = 37;
+
+// Color modes are bit sets of these
+const CAPABILITY_BRIGHTNESS: i32 = 1 << 1;
+const CAPABILITY_COLOR_TEMPERATURE: i32 = 1 << 3;
+const CAPABILITY_RGB: i32 = 1 << 5;
+
+#[derive(Clone, PartialEq, Message)]
+struct HelloRequest {
+    #[prost(string, tag = "1")]
+    client_info: String,
+    #[prost(uint32, tag = "2")]
+    api_version_major: u32,
+    #[prost(uint32, tag = "3")]
+    api_version_minor: u32,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct HelloResponse {
+    #[prost(uint32, tag = "1")]
+    api_version_major: u32,
+    #[prost(uint32, tag = "2")]
+    api_version_minor: u32,
+    #[prost(string, tag = "3")]
+    server_info: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ConnectRequest {
+    #[prost(string, tag = "1")]
+    password: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ConnectResponse {
+    #[prost(bool, tag = "1")]
+    invalid_password: bool,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct DisconnectResponse {}
+
+#[derive(Clone, PartialEq, Message)]
+struct PingResponse {}
+
+#[derive(Clone, PartialEq, Message)]
+struct DeviceInfoRequest {}
+
+#[derive(Clone, PartialEq, Message)]
+struct DeviceInfoResponse {
+    #[prost(string, tag = "2")]
+    name: String,
+    #[prost(string, tag = "3")]
+    mac_address: String,
+    #[prost(string, tag = "4")]
+    esphome_version: String,
+    #[prost(string, tag = "6")]
+    model: String,
+    #[prost(string, tag = "12")]
+    manufacturer: String,
+    #[prost(string, tag = "13")]
+    friendly_name: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ListEntitiesRequest {}
+
+#[derive(Clone, PartialEq, Message)]
+struct ListEntitiesLightResponse {
+    #[prost(string, tag = "1")]
+    object_id: String,
+    #[prost(fixed32, tag = "2")]
+    key: u32,
+    #[prost(string, tag = "3")]
+    name: String,
+    #[prost(float, tag = "9")]
+    min_mireds: f32,
+    #[prost(float, tag = "10")]
+    max_mireds: f32,
+    #[prost(string, repeated, tag = "11")]
+    effects: Vec<String>,
+    #[prost(int32, repeated, tag = "12")]
+    supported_color_modes: Vec<i32>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct SubscribeStatesRequest {}
+
+#[derive(Clone, PartialEq, Message)]
+struct LightStateResponse {
+    #[prost(fixed32, tag = "1")]
+    key: u32,
+    #[prost(bool, tag = "2")]
+    state: bool,
+    #[prost(float, tag = "3")]
+    brightness: f32,
+    #[prost(float, tag = "4")]
+    red: f32,
+    #[prost(float, tag = "5")]
+    green: f32,
+    #[prost(float, tag = "6")]
+    blue: f32,
+    #[prost(string, tag = "9")]
+    effect: String,
+    #[prost(int32, tag = "11")]
+    color_mode: i32,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct LightCommandRequest {
+    #[prost(fixed32, tag = "1")]
+    key: u32,
+    #[prost(bool, tag = "2")]
+    has_state: bool,
+    #[prost(bool, tag = "3")]
+    state: bool,
+    #[prost(bool, tag = "4")]
+    has_brightness: bool,
+    #[prost(float, tag = "5")]
+    brightness: f32,
+    #[prost(bool, tag = "6")]
+    has_rgb: bool,
+    #[prost(float, tag = "7")]
+    red: f32,
+    #[prost(float, tag = "8")]
+    green: f32,
+    #[prost(float, tag = "9")]
+    blue: f32,
+    #[prost(bool, tag = "12")]
+    has_color_temperature: bool,
+    #[prost(float, tag = "13")]
+    color_temperature: f32,
+    #[prost(bool, tag = "18")]
+    has_effect: bool,
+    #[prost(string, tag = "19")]
+    effect: String,
+    #[prost(bool, tag = "20")]
+    has_color_brightness: bool,
+    #[prost(float, tag = "21")]
+    color_brightness: f32,
+    #[prost(bool, tag = "22")]
+    has_color_mode: bool,
+    #[prost(int32, tag = "23")]
+    color_mode: i32,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct GetTimeResponse {
+    #[prost(fixed32, tag = "1")]
+    epoch_seconds: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    const KEY: u32 = 0x11223344;
+
+    // ESPHome's ColorMode values, capability bits plus the on/off bit
+    const COLOR_MODE_ON_OFF: i32 = 1;
+    const COLOR_MODE_BRIGHTNESS: i32 = 3;
+    const COLOR_MODE_COLOR_TEMPERATURE: i32 = 11;
+    const COLOR_MODE_RGB: i32 = 35;
+
+    #[tokio::test]
+    async fn frames_plaintext_messages() {
+        let data = vec![0xAB; 200];
+        let frame = Codec::Plain.encode(LIGHT_COMMAND_REQUEST, &data).unwrap();
+        // Both varints, the 200 byte length needing two bytes
+        assert_eq!(frame[..4], [0x00, 0xC8, 0x01, 0x20]);
+        assert_eq!(frame[4..], data);
+        assert_eq!(
+            Codec::Plain.read(&mut frame.as_slice()).await.unwrap(),
+            (LIGHT_COMMAND_REQUEST, data)
+        );
+
+        let error = Codec::Plain
+            .read(&mut [0x01, 0x00, 0x03].as_slice())
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("encryption key"), "{}", error);
+        let error = Codec::Plain
+            .read(&mut [0x00, 0xFF, 0xFF, 0x7F, 0x01].as_slice())
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("too long"), "{}", error);
+    }
+
+    #[test]
+    fn encodes_light_commands() {
+        let command = LightCommandRequest {
+            key: KEY,
+            has_state: true,
+            state: true,
+            has_rgb: true,
+            red: 1.0,
+            has_effect: true,
+            effect: "Rainbow".to_string(),
+            has_color_mode: true,
+            color_mode: COLOR_MODE_RGB,
+            ..Default::default()
+        };
+        let mut expected = vec![
+            0x0D, 0x44, 0x33, 0x22, 0x11, // key, fixed32
+            0x10, 0x01, // has_state
+            0x18, 0x01, // state
+            0x30, 0x01, // has_rgb
+            0x3D, 0x00, 0x00, 0x80, 0x3F, // red, float
+            0x90, 0x01, 0x01, // has_effect
+            0x9A, 0x01, 0x07, // effect, 7 bytes
+        ];
+        expected.extend_from_slice(b"Rainbow");
+        expected.extend_from_slice(&[
+            0xB0, 0x01, 0x01, // has_color_mode
+            0xB8, 0x01, 0x23, // color_mode
+        ]);
+        assert_eq!(command.encode_to_vec(), expected);
+    }
+
+    // ANCHOR - Fake device
+
+    /// The device side of both codecs, written out separately so the tests
+    /// do not check `Codec` against itself.
+    enum FakeCodec {
+        Plain,
+        Noise(snow::TransportState),
+    }
+
+    impl FakeCodec {
+        async fn read(&mut self, stream: &mut TcpStream) -> Option<(u16, Vec<u8>)> {
+            match self {
+                FakeCodec::Plain => {
+                    assert_eq!(stream.read_u8().await.ok()?, 0x00);
+                    let len = read_varint(stream).await.ok()? as usize;
+                    let kind = read_varint(stream).await.ok()? as u16;
+                    let mut data = vec![0; len];
+                    stream.read_exact(&mut data).await.ok()?;
+                    Some((kind, data))
+                }
+                FakeCodec::Noise(transport) => {
+                    let sealed = read_frame(stream).await?;
+                    let mut plain = vec![0; sealed.len()];
+                    let len = transport.read_message(&sealed, &mut plain).unwrap();
+                    let kind = u16::from_be_bytes([plain[0], plain[1]]);
+                    let size = u16::from_be_bytes([plain[2], plain[3]]) as usize;
+                    assert_eq!(size + 4, len);
+                    Some((kind, plain[4..len].to_vec()))
+                }
+            }
+        }
+
+        fn frame(&mut self, kind: u16, message: &impl Message) -> Vec<u8> {
+            let data = message.encode_to_vec();
+            match self {
+                FakeCodec::Plain => {
+                    let mut frame = vec![0x00];
+                    prost::encoding::encode_varint(data.len() as u64, &mut frame);
+                    prost::encoding::encode_varint(kind as u64, &mut frame);
+                    frame.extend_from_slice(&data);
+                    frame
+                }
+                FakeCodec::Noise(transport) => {
+                    let mut plain = kind.to_be_bytes().to_vec();
+                    plain.extend_from_slice(&(data.len() as u16).to_be_bytes());
+                    plain.extend_from_slice(&data);
+                    let mut sealed = vec![0; plain.len() + 16];
+                    let len = transport.write_message(&plain, &mut sealed).unwrap();
+                    noise_frame(&sealed[..len])
+                }
+            }
+        }
+    }
+
+    fn noise_frame(data: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0x01];
+        frame.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        frame.extend_from_slice(data);
+        frame
+    }
+
+    async fn read_frame(stream: &mut TcpStream) -> Option<Vec<u8>> {
+        assert_eq!(stream.read_u8().await.ok()?, 0x01);
+        let len = stream.read_u16().await.ok()? as usize;
+        let mut data = vec![0; len];
+        stream.read_exact(&mut data).await.ok()?;
+        Some(data)
+    }
+
+    /// Answers the client's handshake as the responder, or reports a failure
+    /// the way devices do when the key is wrong.
+    async fn accept_noise(stream: &mut TcpStream, psk: &[u8]) -> Option<snow::TransportState> {
+        assert!(
+            read_frame(stream).await?.is_empty(),
+            "The client hello is empty"
+        );
+        // The chosen protocol, then the node name and MAC
+        stream
+            .write_all(&noise_frame(b"\x01desk-lamp\x00AABBCCDDEEFF\x00"))
+            .await
+            .ok()?;
+
+        let message = read_frame(stream).await?;
+        assert_eq!(message[0], 0x00);
+
+        let mut handshake = snow::Builder::new(NOISE_PATTERN.parse().unwrap())
+            .prologue(NOISE_PROLOGUE)
+            .psk(0, psk)
+            .build_responder()
+            .unwrap();
+        let mut payload = vec![0; message.len()];
+        if handshake.read_message(&message[1..], &mut payload).is_err() {
+            let mut reply = vec![0x01];
+            reply.extend_from_slice(b"Handshake MAC failure");
+            stream.write_all(&noise_frame(&reply)).await.ok()?;
+            return None;
+        }
+
+        let mut reply = [0; 128];
+        let len = handshake.write_message(&[], &mut reply[1..]).unwrap();
+        stream
+            .write_all(&noise_frame(&reply[..len + 1]))
+            .await
+            .ok()?;
+        Some(handshake.into_transport_mode().unwrap())
+    }
+
+    /// Message type and data
+    type Received = (u16, Vec<u8>);
+
+    struct FakeDevice {
+        port: u16,
+        received: Arc<Mutex<Vec<Received>>>,
+    }
+
+    impl FakeDevice {
+        /// A device with one light that takes `password`, encrypting with
+        /// `psk` if given.
+        async fn start(psk: Option<[u8; 32]>, password: &'static str) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let received = Arc::new(Mutex::new(Vec::new()));
+
+            let log = received.clone();
+            tokio::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut codec = match psk {
+                    None => FakeCodec::Plain,
+                    Some(psk) => match accept_noise(&mut stream, &psk).await {
+                        Some(transport) => FakeCodec::Noise(transport),
+                        None => return,
+                    },
+                };
+
+                while let Some((kind, data)) = codec.read(&mut stream).await {
+                    log.lock().unwrap().push((kind, data.clone()));
+                    let replies = match kind {
+                        HELLO_REQUEST => vec![codec.frame(
+                            HELLO_RESPONSE,
+                            &HelloResponse {
+                                api_version_major: 1,
+                                api_version_minor: 10,
+                                server_info: "desk-lamp (esphome v2024.6.0)".to_string(),
+                            },
+                        )],
+                        CONNECT_REQUEST => {
+                            let request = ConnectRequest::decode(data.as_slice()).unwrap();
+                            vec![codec.frame(
+                                CONNECT_RESPONSE,
+                                &ConnectResponse {
+                                    invalid_password: request.password != password,
+                                },
+                            )]
+                        }
+                        DEVICE_INFO_REQUEST => vec![codec.frame(
+                            DEVICE_INFO_RESPONSE,
+                            &DeviceInfoResponse {
+                                name: "desk-lamp".to_string(),
+                                mac_address: "AA:BB:CC:DD:EE:FF".to_string(),
+                                esphome_version: "2024.6.0".to_string(),
+                                model: "esp32dev".to_string(),
+                                manufacturer: String::new(),
+                                friendly_name: "Desk Lamp".to_string(),
+                            },
+                        )],
+                        LIST_ENTITIES_REQUEST => vec![
+                            // Pings have no fields, like a ping response
+                            codec.frame(PING_REQUEST, &PingResponse {}),
+                            codec.frame(
+                                LIST_ENTITIES_LIGHT_RESPONSE,
+                                &ListEntitiesLightResponse {
+                                    object_id: "desk".to_string(),
+                                    key: KEY,
+                                    name: String::new(),
+                                    min_mireds: 153.0,
+                                    max_mireds: 500.0,
+                                    effects: vec!["Rainbow".to_string()],
+                                    supported_color_modes: vec![
+                                        COLOR_MODE_ON_OFF,
+                                        COLOR_MODE_BRIGHTNESS,
+                                        COLOR_MODE_COLOR_TEMPERATURE,
+                                        COLOR_MODE_RGB,
+                                    ],
+                                },
+                            ),
+                            // A sensor
+                            codec.frame(16, &ListEntitiesRequest {}),
+                            codec.frame(LIST_ENTITIES_DONE_RESPONSE, &ListEntitiesRequest {}),
+                        ],
+                        SUBSCRIBE_STATES_REQUEST => vec![
+                            codec.frame(
+                                LIGHT_STATE_RESPONSE,
+                                &LightStateResponse {
+                                    key: KEY,
+                                    state: true,
+                                    brightness: 0.5,
+                                    red: 0.0,
+                                    green: 0.5,
+                                    blue: 1.0,
+                                    effect: String::new(),
+                                    color_mode: COLOR_MODE_RGB,
+                                },
+                            ),
+                            codec.frame(GET_TIME_REQUEST, &DeviceInfoRequest {}),
+                        ],
+                        _ => Vec::new(),
+                    };
+                    for reply in replies {
+                        stream.write_all(&reply).await.unwrap();
+                    }
+                }
+            });
+
+            Self { port, received }
+        }
+
+        /// Waits for the next message of `kind`, dropping those before it.
+        async fn expect(&self, kind: u16) -> Vec<u8> {
+            for _ in 0..100 {
+                {
+                    let mut received = self.received.lock().unwrap();
+                    if let Some(index) = received.iter().position(|m| m.0 == kind) {
+                        return received.drain(..=index).next_back().unwrap().1;
+                    }
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            panic!("The device received no message of type {}", kind);
+        }
+    }
+
+    async fn discover(
+        device: &FakeDevice,
+        key: Option<&str>,
+        password: Option<&str>,
+    ) -> Box<dyn Light> {
+        let config = CuteLightsConfig {
+            esphome: EsphomeConfig {
+                enabled: true,
+                devices: vec![EsphomeDevice {
+                    address: format!("127.0.0.1:{}", device.port),
+                    encryption_key: key.map(str::to_string),
+                    password: password.map(str::to_string),
+                }],
+                mdns: false,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut lights = EsphomeIntegration.discover(&config).await.unwrap();
+        assert_eq!(lights.len(), 1);
+        let light = lights.remove(0);
+
+        // The state arrives after subscribing
+        for _ in 0..100 {
+            if light.is_on() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        light
+    }
+
+    #[tokio::test]
+    async fn connects_in_plaintext() {
+        let device = FakeDevice::start(None, "hunter2").await;
+        let mut light = discover(&device, None, Some("hunter2")).await;
+
+        let hello = HelloRequest::decode(device.expect(HELLO_REQUEST).await.as_slice()).unwrap();
+        assert_eq!(
+            (hello.api_version_major, hello.api_version_minor),
+            (API_VERSION_MAJOR, API_VERSION_MINOR)
+        );
+        device.expect(PING_RESPONSE).await;
+        let time = GetTimeResponse::decode(device.expect(GET_TIME_RESPONSE).await.as_slice());
+        assert!(time.unwrap().epoch_seconds > 0);
+
+        assert_eq!(light.id(), "esphome::AA:BB:CC:DD:EE:FF_desk");
+        assert_eq!(light.name(), "Desk Lamp");
+        assert_eq!(light.device_info().manufacturer, "ESPHome");
+        assert!(light.is_on());
+        assert_eq!(light.brightness(), 50);
+        assert_eq!((light.red(), light.green(), light.blue()), (0, 128, 255));
+        assert!(light.supports_color() && light.supports_color_temperature());
+
+        light.set_color(255, 0, 0).await.unwrap();
+        let command = device.expect(LIGHT_COMMAND_REQUEST).await;
+        assert_eq!(
+            LightCommandRequest::decode(command.as_slice()).unwrap(),
+            LightCommandRequest {
+                key: KEY,
+                has_state: true,
+                state: true,
+                has_color_mode: true,
+                color_mode: COLOR_MODE_RGB,
+                has_rgb: true,
+                red: 1.0,
+                has_color_brightness: true,
+                color_brightness: 1.0,
+                ..Default::default()
+            }
+        );
+        assert_eq!((light.red(), light.green(), light.blue()), (255, 0, 0));
+
+        light.set_color_temperature(2000).await.unwrap();
+        let command = device.expect(LIGHT_COMMAND_REQUEST).await;
+        let command = LightCommandRequest::decode(command.as_slice()).unwrap();
+        assert_eq!(command.color_mode, COLOR_MODE_COLOR_TEMPERATURE);
+        // 500 mireds is as warm as the light goes
+        assert_eq!(command.color_temperature, 500.0);
+    }
+
+    #[tokio::test]
+    async fn rejects_wrong_passwords() {
+        let device = FakeDevice::start(None, "hunter2").await;
+        let Err(error) = Device::connect(
+            [127, 0, 0, 1].into(),
+            device.port,
+            None,
+            Some("letmein"),
+            &EsphomeConfig::default(),
+        )
+        .await
+        else {
+            panic!("Connected with the wrong password");
+        };
+        assert!(
+            error.to_string().contains("Wrong API password"),
+            "{}",
+            error
+        );
+    }
+
+    fn encode_key(psk: &[u8; 32]) -> String {
+        base64::engine::general_purpose::STANDARD.encode(psk)
+    }
+
+    #[tokio::test]
+    async fn connects_with_noise() {
+        let psk = [7; 32];
+        let device = FakeDevice::start(Some(psk), "").await;
+        let mut light = discover(&device, Some(&encode_key(&psk)), None).await;
+
+        device.expect(HELLO_REQUEST).await;
+        assert_eq!(light.brightness(), 50);
+
+        light.set_brightness(30).await.unwrap();
+        let command = device.expect(LIGHT_COMMAND_REQUEST).await;
+        assert_eq!(
+            LightCommandRequest::decode(command.as_slice()).unwrap(),
+            LightCommandRequest {
+                key: KEY,
+                has_brightness: true,
+                brightness: 0.3,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_wrong_encryption_keys() {
+        let device = FakeDevice::start(Some([7; 32]), "").await;
+        let Err(error) = Device::connect(
+            [127, 0, 0, 1].into(),
+            device.port,
+            Some(&encode_key(&[8; 32])),
+            None,
+            &EsphomeConfig::default(),
+        )
+        .await
+        else {
+            panic!("Connected with the wrong key");
+        };
+        assert!(error.to_string().contains("check the key"), "{}", error);
+        assert!(error.to_string().contains("MAC failure"), "{}", error);
+    }
+}
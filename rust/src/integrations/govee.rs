@@ -1,51 +1,150 @@
 use super::Integration;
 use crate::utils::json::boolean_int;
 use crate::{config::CuteLightsConfig, utils::future::FutureBatch};
+use base64::Engine;
 use serde::{Deserialize, Serialize};
+use socket2::SockRef;
 use std::{
+    collections::{HashMap, VecDeque},
     net::{IpAddr, Ipv4Addr, SocketAddr},
-    str::FromStr,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, OnceLock, Weak,
+    },
+    time::Duration,
+};
+use tokio::{
+    net::UdpSocket,
+    sync::{broadcast, oneshot},
+    task::JoinHandle,
+    time::Instant,
 };
-use tokio::net::UdpSocket;
 
-use super::Light;
+use super::{DeviceInfo, Light, LightSink};
 
 // ANCHOR - GoveeLight
 pub struct GoveeLight {
-    udp_socket: Arc<UdpSocket>,
+    socket: Arc<GoveeSocket>,
     device_addr: SocketAddr,
+    request_timeout: Duration,
+    verify: Option<VerifyPolicy>,
+    segment_count: usize,
+    realtime: bool,
     is_on: bool,
     brightness: u8,
     red: u8,
     green: u8,
     blue: u8,
-    id: String,
+    device: LanDevice,
+    name: Option<String>,
 }
 
 impl GoveeLight {
-    pub async fn new(udp_socket: Arc<UdpSocket>, ip: &str, mac: &str) -> anyhow::Result<GoveeLight> {
-        let device_addr = SocketAddr::new(IpAddr::V4(ip.parse()?), 4003);
+    pub async fn new(
+        socket: Arc<GoveeSocket>,
+        device: LanDevice,
+        config: &GoveeConfig,
+    ) -> anyhow::Result<GoveeLight> {
+        let device_addr = SocketAddr::new(device.ip, config.device_port);
+
+        let mut light = GoveeLight {
+            socket,
+            device_addr,
+            request_timeout: Duration::from_millis(config.request_timeout),
+            verify: config.verify.then(|| VerifyPolicy {
+                retries: config.verify_retries,
+                backoff: Duration::from_millis(config.verify_backoff),
+            }),
+            segment_count: config.segments.get(&device.device).copied().unwrap_or(0),
+            realtime: false,
+            is_on: false,
+            brightness: 0,
+            red: 0,
+            green: 0,
+            blue: 0,
+            name: config.names.get(&device.device).cloned(),
+            device,
+        };
+        light.refresh().await?;
+
+        Ok(light)
+    }
+
+    /// Re-reads the device status and updates the cached state.
+    pub async fn refresh(&mut self) -> anyhow::Result<()> {
+        let status = self.read_status().await?;
+        self.update_from(&status);
+        Ok(())
+    }
+
+    async fn read_status(&self) -> anyhow::Result<DeviceStatus> {
+        let response = self
+            .socket
+            .request(
+                &self.device_addr,
+                Request::DevStatus {},
+                self.request_timeout,
+            )
+            .await?;
 
-        let msg = Request::DevStatus {};
+        match response {
+            Response::DevStatus(status) => Ok(status),
+            _ => Err(anyhow::anyhow!("Unexpected response")),
+        }
+    }
+
+    fn update_from(&mut self, status: &DeviceStatus) {
+        self.is_on = status.on;
+        self.brightness = status.brightness;
+        self.red = status.color.r;
+        self.green = status.color.g;
+        self.blue = status.color.b;
+    }
 
-        let response = send_message(&udp_socket, &device_addr, msg, true).await?;
+    /// Sends a write and, in verify mode, reads the status back until `applied`
+    /// holds, resending with exponential backoff until the retries run out.
+    async fn write(
+        &mut self,
+        msg: Request,
+        applied: impl Fn(&DeviceStatus) -> bool + Send,
+    ) -> anyhow::Result<()> {
+        self.socket.send(&self.device_addr, msg.clone()).await?;
 
-        let response = match response {
-            Response::DevStatus(status) => status,
-            _ => return Err(anyhow::anyhow!("Unexpected response")),
+        let Some(policy) = self.verify.clone() else {
+            return Ok(());
         };
 
-        Ok(GoveeLight {
-            udp_socket,
-            device_addr,
-            is_on: response.on,
-            brightness: response.brightness as u8,
-            red: response.color.r,
-            green: response.color.g,
-            blue: response.color.b,
-            id: mac.to_string(),
-        })
+        let mut backoff = policy.backoff;
+        for attempt in 0..=policy.retries {
+            if attempt > 0 {
+                self.socket.send(&self.device_addr, msg.clone()).await?;
+            }
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+
+            match self.read_status().await {
+                Ok(status) if applied(&status) => {
+                    self.update_from(&status);
+                    return Ok(());
+                }
+                Ok(_) => {}
+                Err(e) => eprintln!("Failed to verify {}: {}", self.name(), e),
+            }
+        }
+
+        Err(anyhow::anyhow!(
+            "{} did not apply {:?} after {} attempts",
+            self.name(),
+            msg,
+            policy.retries + 1
+        ))
+    }
+
+    async fn stream_pixels(&mut self, colors: &[(u8, u8, u8)]) -> anyhow::Result<()> {
+        let msg = Request::Razer {
+            pt: encode_packet(&razer_pixels_packet(colors, false)?),
+        };
+        self.socket.send(&self.device_addr, msg).await
     }
 }
 
@@ -53,21 +152,22 @@ impl GoveeLight {
 impl Light for GoveeLight {
     async fn set_on(&mut self, on: bool) -> anyhow::Result<()> {
         let msg = Request::Turn { value: on as u8 };
-        send_message(&self.udp_socket, &self.device_addr, msg, false).await?;
+        self.write(msg, |status| status.on == on).await?;
         self.is_on = on;
         Ok(())
     }
 
     async fn set_color(&mut self, red: u8, green: u8, blue: u8) -> anyhow::Result<()> {
+        let color = DeviceColor {
+            r: red,
+            g: green,
+            b: blue,
+        };
         let msg = Request::Color {
-            color: DeviceColor {
-                r: red,
-                g: green,
-                b: blue,
-            },
+            color: color.clone(),
             color_temperature_kelvin: 7200,
         };
-        send_message(&self.udp_socket, &self.device_addr, msg, false).await?;
+        self.write(msg, |status| status.color == color).await?;
         self.red = red;
         self.green = green;
         self.blue = blue;
@@ -75,23 +175,25 @@ impl Light for GoveeLight {
     }
 
     async fn set_brightness(&mut self, brightness: u8) -> anyhow::Result<()> {
-        let msg = Request::Brightness {
-            value: brightness as u8,
-        };
-        send_message(&self.udp_socket, &self.device_addr, msg, false).await?;
+        let msg = Request::Brightness { value: brightness };
+        self.write(msg, |status| status.brightness == brightness)
+            .await?;
         self.brightness = brightness;
         Ok(())
     }
 
     fn id(&self) -> String {
-        format!("govee::{}", self.id)
+        format!("govee::{}", self.device.device)
     }
 
     fn is_on(&self) -> bool {
         self.is_on
     }
     fn name(&self) -> String {
-        format!("Govee Light ({})", self.id)
+        match &self.name {
+            Some(name) => name.clone(),
+            None => format!("Govee {} ({})", self.device.sku, self.device.device),
+        }
     }
     fn supports_color(&self) -> bool {
         true
@@ -112,195 +214,591 @@ impl Light for GoveeLight {
     fn brightness(&self) -> u8 {
         self.brightness
     }
+
+    fn device_info(&self) -> DeviceInfo {
+        DeviceInfo {
+            manufacturer: "Govee".to_string(),
+            model: Some(self.device.sku.clone()),
+            firmware_version: Some(self.device.wifi_version_soft.clone()),
+            hardware_version: Some(self.device.wifi_version_hard.clone()),
+        }
+    }
+
+    fn segment_count(&self) -> usize {
+        self.segment_count
+    }
+
+    async fn set_segment_colors(&mut self, colors: &[(u8, u8, u8)]) -> anyhow::Result<()> {
+        if self.realtime {
+            return self.stream_pixels(colors).await;
+        }
+
+        if colors.len() > self.segment_count.min(MAX_SEGMENTS) {
+            return Err(anyhow::anyhow!(
+                "{} has {} segments, got {} colors",
+                self.name(),
+                self.segment_count,
+                colors.len()
+            ));
+        }
+
+        // Segments sharing a color go out as one packet
+        let mut masks: Vec<((u8, u8, u8), u32)> = Vec::new();
+        for (i, color) in colors.iter().enumerate() {
+            match masks.iter_mut().find(|(c, _)| c == color) {
+                Some((_, mask)) => *mask |= 1 << i,
+                None => masks.push((*color, 1 << i)),
+            }
+        }
+
+        let msg = Request::PtReal {
+            command: masks
+                .into_iter()
+                .map(|((r, g, b), mask)| encode_packet(&segment_color_packet(mask, r, g, b)))
+                .collect(),
+        };
+        self.socket.send(&self.device_addr, msg).await
+    }
+
+    async fn set_realtime(&mut self, enabled: bool) -> anyhow::Result<()> {
+        let msg = Request::Razer {
+            pt: encode_packet(&razer_mode_packet(enabled)),
+        };
+        self.socket.send(&self.device_addr, msg).await?;
+        self.realtime = enabled;
+        Ok(())
+    }
 }
 
 // ANCHOR - GoveeConfig
 
-#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, Default)]
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 pub struct GoveeConfig {
     pub enabled: bool,
     pub addresses: Vec<String>,
     #[serde(default = "default_scan_timeout")]
     pub scan_timeout: u64,
+    #[serde(default = "default_listen_port")]
+    pub listen_port: u16,
+    /// Port devices take scans on
+    #[serde(default = "default_scan_port")]
+    pub scan_port: u16,
+    /// Port devices take commands on
+    #[serde(default = "default_device_port")]
+    pub device_port: u16,
+    #[serde(default = "default_multicast_interface")]
+    pub multicast_interface: Ipv4Addr,
+    #[serde(default = "default_multicast_ttl")]
+    pub multicast_ttl: u32,
+    #[serde(default = "default_request_timeout")]
+    pub request_timeout: u64,
+    /// Segment count per device id, devices not listed are single color
+    #[serde(default)]
+    pub segments: HashMap<String, usize>,
+    /// Display name per device id
+    #[serde(default)]
+    pub names: HashMap<String, String>,
+    /// Read the status back after every write and resend until it matches
+    #[serde(default)]
+    pub verify: bool,
+    #[serde(default = "default_verify_retries")]
+    pub verify_retries: u32,
+    #[serde(default = "default_verify_backoff")]
+    pub verify_backoff: u64,
+}
+
+#[derive(Debug, Clone)]
+struct VerifyPolicy {
+    retries: u32,
+    backoff: Duration,
+}
+
+impl Default for GoveeConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            addresses: Vec::new(),
+            scan_timeout: default_scan_timeout(),
+            listen_port: default_listen_port(),
+            scan_port: default_scan_port(),
+            device_port: default_device_port(),
+            multicast_interface: default_multicast_interface(),
+            multicast_ttl: default_multicast_ttl(),
+            request_timeout: default_request_timeout(),
+            segments: HashMap::new(),
+            names: HashMap::new(),
+            verify: false,
+            verify_retries: default_verify_retries(),
+            verify_backoff: default_verify_backoff(),
+        }
+    }
 }
 
 fn default_scan_timeout() -> u64 {
     5000
 }
 
+fn default_listen_port() -> u16 {
+    4002
+}
+
+fn default_scan_port() -> u16 {
+    4001
+}
+
+fn default_device_port() -> u16 {
+    4003
+}
+
+fn default_multicast_interface() -> Ipv4Addr {
+    Ipv4Addr::UNSPECIFIED
+}
+
+fn default_multicast_ttl() -> u32 {
+    2
+}
+
+fn default_request_timeout() -> u64 {
+    1000
+}
+
+fn default_verify_retries() -> u32 {
+    3
+}
+
+fn default_verify_backoff() -> u64 {
+    200
+}
+
 // ANCHOR - GoveeIntegration
 
 pub struct GoveeIntegration;
 
 #[async_trait::async_trait]
 impl Integration for GoveeIntegration {
-    fn name() -> String {
+    fn name(&self) -> String {
         "govee".to_string()
     }
-    async fn discover(config: &'static CuteLightsConfig) -> anyhow::Result<Vec<Box<dyn Light>>> {
-        let mut batch = FutureBatch::new();
-        let client_sock = Arc::new(UdpSocket::bind("0.0.0.0:4002").await?);
 
-        let discovered = tokio::time::timeout(
-            std::time::Duration::from_millis(config.govee.scan_timeout),
-            discover_ids(
-                client_sock.clone(),
-                config.govee.addresses.clone(),
-            ),
-        )
-        .await??;
-
-        for (ip, mac) in discovered {
-            let client_sock = client_sock.clone();
-            batch.push(async move {
-                match GoveeLight::new(client_sock, &ip, &mac).await {
-                    Ok(light) => Some(Box::new(light) as Box<dyn Light>),
-                    Err(e) => {
-                        eprintln!(
-                            "Failed to connect to Govee light at {} {:?}: {}",
-                            ip, mac, e
-                        );
-                        None
+    async fn discover(&self, config: &CuteLightsConfig) -> anyhow::Result<Vec<Box<dyn Light>>> {
+        let lights = Mutex::new(Vec::new());
+        self.discover_into(config, &|light| {
+            if let Ok(mut lights) = lights.lock() {
+                lights.push(light);
+            }
+        })
+        .await?;
+        lights
+            .into_inner()
+            .map_err(|_| anyhow::anyhow!("Govee discovery poisoned"))
+    }
+
+    /// Connects to every device as its scan response comes in, so the first
+    /// lights are ready long before the scan times out.
+    async fn discover_into(
+        &self,
+        config: &CuteLightsConfig,
+        sink: &LightSink<'_>,
+    ) -> anyhow::Result<()> {
+        let socket = GoveeSocket::shared(&config.govee)?;
+        let mut scan = Scan::start(&socket, &config.govee).await?;
+        let mut connecting = FutureBatch::new();
+
+        let mut scanning = true;
+        while scanning || !connecting.is_empty() {
+            tokio::select! {
+                device = scan.next(), if scanning => match device {
+                    Some(device) => {
+                        let config = config.govee.clone();
+                        connecting.push(connect(socket.clone(), device, config));
+                    }
+                    None => scanning = false,
+                },
+                Some(light) = connecting.next() => {
+                    if let Some(light) = light {
+                        sink(light);
                     }
                 }
-            });
+                // The last connection panicked
+                else => break,
+            }
         }
-
-        Ok(batch.run().await.into_iter().flatten().collect())
+        Ok(())
     }
 
-    fn preflight(config: &CuteLightsConfig) -> bool {
+    fn preflight(&self, config: &CuteLightsConfig) -> bool {
         config.govee.enabled
     }
 }
 
-// ANCHOR - Multicast Discovery
+async fn connect(
+    socket: Arc<GoveeSocket>,
+    device: LanDevice,
+    config: GoveeConfig,
+) -> Option<Box<dyn Light>> {
+    let (ip, mac) = (device.ip, device.device.clone());
+    match GoveeLight::new(socket, device, &config).await {
+        Ok(light) => Some(Box::new(light)),
+        Err(e) => {
+            eprintln!(
+                "Failed to connect to Govee light at {} {:?}: {}",
+                ip, mac, e
+            );
+            None
+        }
+    }
+}
 
-const MULTICAST_GROUP: &str = "239.255.255.250";
-const MULTICAST_PORT: u16 = 4001;
-const MULTICAST_TTL: u32 = 2;
-
-pub async fn discover_ids(
-    client_sock: Arc<UdpSocket>,
-    ips: Vec<String>,
-) -> anyhow::Result<Vec<(String, String)>> {
-    let (tx, mut rx) = tokio::sync::mpsc::channel(32);
-
-    tokio::spawn(async move {
-        client_sock
-            .join_multicast_v4(
-                Ipv4Addr::from_str(MULTICAST_GROUP).unwrap(),
-                Ipv4Addr::UNSPECIFIED,
-            )
-            .expect("Failed to join multicast group");
-
-        let mut buf = [0; 10240];
-        loop {
-            if let Ok((size, _)) = client_sock.recv_from(&mut buf).await {
-                let res = tx
-                    .send(String::from_utf8_lossy(&buf[..size]).to_string())
-                    .await;
-                if let Err(_) = res {
-                    break;
-                }
+// ANCHOR - GoveeSocket
+
+type Pending = HashMap<IpAddr, VecDeque<(u64, oneshot::Sender<Response>)>>;
+
+static SOCKETS: OnceLock<Mutex<HashMap<u16, Weak<GoveeSocket>>>> = OnceLock::new();
+
+/// The listener every Govee device replies to. A background task reads all
+/// incoming datagrams and hands each reply to the oldest request waiting on
+/// the address it came from, while scan responses go to every scan in progress.
+pub struct GoveeSocket {
+    socket: Arc<UdpSocket>,
+    pending: Arc<Mutex<Pending>>,
+    scans: broadcast::Sender<LanDevice>,
+    next_id: AtomicU64,
+    reader: JoinHandle<()>,
+}
+
+impl GoveeSocket {
+    /// Returns the socket listening on `config.listen_port`, binding it and
+    /// joining the multicast group on first use. Every caller in the process
+    /// shares it for as long as one of them holds on to it.
+    pub fn shared(config: &GoveeConfig) -> anyhow::Result<Arc<GoveeSocket>> {
+        let mut sockets = SOCKETS
+            .get_or_init(Default::default)
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Govee socket registry poisoned"))?;
+
+        if let Some(socket) = sockets.get(&config.listen_port).and_then(Weak::upgrade) {
+            // A socket whose reader gave up is replaced by a fresh one
+            if !socket.reader.is_finished() {
+                return Ok(socket);
             }
         }
 
-        client_sock
-            .leave_multicast_v4(
-                Ipv4Addr::from_str(MULTICAST_GROUP).unwrap(),
-                Ipv4Addr::UNSPECIFIED,
+        let std_sock = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, config.listen_port))
+            .map_err(|e| anyhow::anyhow!("Failed to bind port {}: {}", config.listen_port, e))?;
+        std_sock.set_nonblocking(true)?;
+        let udp = UdpSocket::from_std(std_sock)?;
+        udp.join_multicast_v4(MULTICAST_GROUP, config.multicast_interface)
+            .map_err(|e| anyhow::anyhow!("Failed to join multicast group: {}", e))?;
+
+        let socket = Arc::new(GoveeSocket::new(udp));
+        sockets.insert(config.listen_port, Arc::downgrade(&socket));
+        Ok(socket)
+    }
+
+    fn new(udp: UdpSocket) -> GoveeSocket {
+        let socket = Arc::new(udp);
+        let pending = Arc::new(Mutex::new(Pending::new()));
+        let (scans, _) = broadcast::channel(32);
+
+        let reader = tokio::spawn(read_datagrams(
+            socket.clone(),
+            pending.clone(),
+            scans.clone(),
+        ));
+
+        GoveeSocket {
+            socket,
+            pending,
+            scans,
+            next_id: AtomicU64::new(0),
+            reader,
+        }
+    }
+
+    /// Sends a request without waiting for a reply.
+    pub async fn send(&self, addr: &SocketAddr, data: Request) -> anyhow::Result<()> {
+        self.socket
+            .send_to(
+                serde_json::to_string(&RequestMessage { msg: data })?.as_bytes(),
+                addr,
             )
-            .expect("Failed to leave multicast group");
-    });
+            .await?;
+        Ok(())
+    }
 
-    tokio::spawn(async move {
-        let message = r#"
-        {
-            "msg": {
-                "cmd": "scan",
-                "data": {
-                    "account_topic": "reserve"
-                }
+    /// Sends a request and waits up to `timeout` for the device to answer.
+    pub async fn request(
+        &self,
+        addr: &SocketAddr,
+        data: Request,
+        timeout: Duration,
+    ) -> anyhow::Result<Response> {
+        if self.reader.is_finished() {
+            return Err(anyhow::anyhow!("Govee socket closed"));
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        self.lock_pending()?
+            .entry(addr.ip())
+            .or_default()
+            .push_back((id, tx));
+
+        let result = match self.send(addr, data).await {
+            Ok(()) => tokio::time::timeout(timeout, rx).await,
+            Err(e) => {
+                self.forget(addr.ip(), id)?;
+                return Err(e);
+            }
+        };
+
+        match result {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(_)) => Err(anyhow::anyhow!("Govee socket closed")),
+            Err(_) => {
+                self.forget(addr.ip(), id)?;
+                Err(anyhow::anyhow!("Timed out waiting for {}", addr))
             }
         }
-    "#;
+    }
 
-        let socket = UdpSocket::bind("0.0.0.0:0")
-            .await
-            .expect("Failed to bind socket");
-        socket
-            .set_multicast_ttl_v4(MULTICAST_TTL)
-            .expect("Failed to set TTL");
+    /// Receives every scan response arriving from now on.
+    pub fn subscribe_scans(&self) -> broadcast::Receiver<LanDevice> {
+        self.scans.subscribe()
+    }
 
-        let json_result = message.trim().to_string();
+    fn forget(&self, ip: IpAddr, id: u64) -> anyhow::Result<()> {
+        let mut pending = self.lock_pending()?;
+        if let Some(waiting) = pending.get_mut(&ip) {
+            waiting.retain(|(waiting_id, _)| *waiting_id != id);
+            if waiting.is_empty() {
+                pending.remove(&ip);
+            }
+        }
+        Ok(())
+    }
 
-        socket
-            .send_to(
-                json_result.as_bytes(),
-                format!("{}:{}", MULTICAST_GROUP, MULTICAST_PORT),
-            )
-            .await
-            .expect("Failed to send message");
-    });
+    fn lock_pending(&self) -> anyhow::Result<std::sync::MutexGuard<'_, Pending>> {
+        self.pending
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Govee request table poisoned"))
+    }
+}
+
+impl Drop for GoveeSocket {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
 
-    let mut results = Vec::new();
+/// Consecutive receive errors after which the reader stops.
+const MAX_RECEIVE_ERRORS: u32 = 10;
+
+async fn read_datagrams(
+    socket: Arc<UdpSocket>,
+    pending: Arc<Mutex<Pending>>,
+    scans: broadcast::Sender<LanDevice>,
+) {
+    let mut buf = [0; 10240];
+    let mut errors = 0;
+    loop {
+        let (size, from) = match socket.recv_from(&mut buf).await {
+            Ok(res) => {
+                errors = 0;
+                res
+            }
+            Err(e) => {
+                errors += 1;
+                if errors >= MAX_RECEIVE_ERRORS {
+                    eprintln!("Giving up on Govee socket after {} errors: {}", errors, e);
+                    // Dropping the senders fails every waiting request right away
+                    if let Ok(mut pending) = pending.lock() {
+                        pending.clear();
+                    }
+                    return;
+                }
+                eprintln!("Failed to receive Govee datagram: {}", e);
+                tokio::time::sleep(Duration::from_millis(50 << errors)).await;
+                continue;
+            }
+        };
 
-    while let Some(message) = rx.recv().await {
-        let response: ResponseMessage = serde_json::from_str(&message).unwrap();
+        let response: ResponseMessage = match serde_json::from_slice(&buf[..size]) {
+            Ok(response) => response,
+            Err(e) => {
+                eprintln!("Ignoring unexpected Govee datagram from {}: {}", from, e);
+                continue;
+            }
+        };
 
-        if let Response::Scan(device) = response.msg {
-            if ips.contains(&device.ip.to_string()) {
-                results.push((device.ip.to_string(), device.device));
+        match response.msg {
+            Response::Scan(device) => {
+                // Nobody scanning is not an error
+                let _ = scans.send(device);
+            }
+            response => {
+                let Ok(mut pending) = pending.lock() else {
+                    return;
+                };
+                let Some(waiting) = pending.get_mut(&from.ip()) else {
+                    continue;
+                };
+                if let Some((_, tx)) = waiting.pop_front() {
+                    let _ = tx.send(response);
+                }
+                if waiting.is_empty() {
+                    pending.remove(&from.ip());
+                }
             }
         }
+    }
+}
+
+// ANCHOR - Multicast Discovery
+
+const MULTICAST_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
 
-        if results.len() == ips.len() {
-            break;
+async fn send_scan(config: &GoveeConfig) -> anyhow::Result<()> {
+    let msg = RequestMessage {
+        msg: Request::Scan {
+            account_topic: AccountTopic::Reserve,
+        },
+    };
+
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket
+        .set_multicast_ttl_v4(config.multicast_ttl)
+        .map_err(|e| anyhow::anyhow!("Failed to set TTL: {}", e))?;
+    SockRef::from(&socket)
+        .set_multicast_if_v4(&config.multicast_interface)
+        .map_err(|e| anyhow::anyhow!("Failed to set multicast interface: {}", e))?;
+
+    let msg = serde_json::to_string(&msg)?;
+
+    // Devices answer scans sent straight to them too, which gets through
+    // networks that drop multicast
+    for address in &config.addresses {
+        let Ok(ip) = address.parse::<IpAddr>() else {
+            continue;
+        };
+        if let Err(e) = socket.send_to(msg.as_bytes(), (ip, config.scan_port)).await {
+            eprintln!("Failed to send scan to {}: {}", address, e);
         }
     }
 
-    rx.close();
+    socket
+        .send_to(msg.as_bytes(), (MULTICAST_GROUP, config.scan_port))
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to send scan: {}", e))?;
 
-    Ok(results)
+    Ok(())
 }
-// ANCHOR - Messages
 
-async fn send_message(
-    sock: &UdpSocket,
-    addr: &SocketAddr,
-    data: Request,
-    expect_response: bool,
-) -> anyhow::Result<Response> {
-    sock.send_to(
-        serde_json::to_string(&RequestMessage { msg: data })?.as_bytes(),
-        addr,
-    )
-    .await?;
+/// A scan for the configured addresses, running until all of them answered
+/// or `scan_timeout` elapsed.
+pub struct Scan {
+    scans: broadcast::Receiver<LanDevice>,
+    deadline: Instant,
+    addresses: Vec<String>,
+    found: Vec<IpAddr>,
+}
 
-    if !expect_response {
-        return Ok(Response::Void);
+impl Scan {
+    pub async fn start(socket: &GoveeSocket, config: &GoveeConfig) -> anyhow::Result<Scan> {
+        let scans = socket.subscribe_scans();
+        send_scan(config).await?;
+        Ok(Scan {
+            scans,
+            deadline: Instant::now() + Duration::from_millis(config.scan_timeout),
+            addresses: config.addresses.clone(),
+            found: Vec::new(),
+        })
     }
-    let mut buf = [0; 1024];
 
-    let (amt, _) = sock.recv_from(&mut buf).await?;
+    /// The next configured device to answer, `None` once the scan is over.
+    pub async fn next(&mut self) -> Option<LanDevice> {
+        while self.found.len() < self.addresses.len() {
+            let device = match tokio::time::timeout_at(self.deadline, self.scans.recv()).await {
+                Ok(Ok(device)) => device,
+                Ok(Err(broadcast::error::RecvError::Lagged(_))) => continue,
+                Ok(Err(broadcast::error::RecvError::Closed)) | Err(_) => break,
+            };
+
+            let ip = device.ip.to_string();
+            if self.addresses.contains(&ip) && !self.found.contains(&device.ip) {
+                self.found.push(device.ip);
+                return Some(device);
+            }
+        }
+        None
+    }
+}
 
-    let response: ResponseMessage = serde_json::from_str(&String::from_utf8_lossy(&buf[..amt]))?;
+// ANCHOR - Realtime Packets
 
-    Ok(response.msg)
+// Both ptReal and razer carry raw BLE packets, base64 encoded and closed by
+// an XOR checksum of every byte before it.
+
+const MAX_SEGMENTS: usize = 24;
+const MAX_RAZER_PIXELS: usize = u8::MAX as usize;
+
+fn encode_packet(packet: &[u8]) -> String {
+    base64::engine::general_purpose::STANDARD.encode(packet)
+}
+
+fn checksum(packet: &[u8]) -> u8 {
+    packet.iter().fold(0, |acc, b| acc ^ b)
+}
+
+/// `33 05 15 01 RR GG BB 00 00 00 00 00 <mask, 3 bytes LE> 00 00 00 XOR`
+fn segment_color_packet(mask: u32, red: u8, green: u8, blue: u8) -> [u8; 20] {
+    let mut packet = [0; 20];
+    packet[..7].copy_from_slice(&[0x33, 0x05, 0x15, 0x01, red, green, blue]);
+    packet[12..15].copy_from_slice(&mask.to_le_bytes()[..3]);
+    packet[19] = checksum(&packet[..19]);
+    packet
+}
+
+/// `BB 00 01 B1 <on/off> XOR`
+fn razer_mode_packet(enabled: bool) -> Vec<u8> {
+    let mut packet = vec![0xBB, 0x00, 0x01, 0xB1, enabled as u8];
+    packet.push(checksum(&packet));
+    packet
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+/// `BB <len, 2 bytes BE> B0 <gradient> <count> [RR GG BB]... XOR`
+fn razer_pixels_packet(colors: &[(u8, u8, u8)], gradient: bool) -> anyhow::Result<Vec<u8>> {
+    if colors.len() > MAX_RAZER_PIXELS {
+        return Err(anyhow::anyhow!(
+            "Razer frames hold at most {} pixels, got {}",
+            MAX_RAZER_PIXELS,
+            colors.len()
+        ));
+    }
+
+    let len = (2 + colors.len() * 3) as u16;
+    let mut packet = vec![0xBB];
+    packet.extend_from_slice(&len.to_be_bytes());
+    packet.extend_from_slice(&[0xB0, gradient as u8, colors.len() as u8]);
+    for (r, g, b) in colors {
+        packet.extend_from_slice(&[*r, *g, *b]);
+    }
+    packet.push(checksum(&packet));
+    Ok(packet)
+}
+
+// ANCHOR - Messages
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub enum AccountTopic {
     #[serde(rename = "reserve")]
     Reserve,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 #[serde(tag = "cmd", content = "data")]
 pub enum Request {
     #[serde(rename = "scan")]
-    Scan { topic: AccountTopic },
+    Scan { account_topic: AccountTopic },
     #[serde(rename = "devStatus")]
     DevStatus {},
     #[serde(rename = "turn")]
@@ -313,6 +811,10 @@ pub enum Request {
         #[serde(rename = "colorTemInKelvin")]
         color_temperature_kelvin: u32,
     },
+    #[serde(rename = "ptReal")]
+    PtReal { command: Vec<String> },
+    #[serde(rename = "razer")]
+    Razer { pt: String },
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -335,7 +837,7 @@ pub struct DeviceStatus {
     pub color_temperature_kelvin: u32,
 }
 
-#[derive(Deserialize, Serialize, Debug, Default, Clone)]
+#[derive(Deserialize, Serialize, Debug, Default, Clone, PartialEq, Eq)]
 pub struct DeviceColor {
     pub r: u8,
     pub g: u8,
@@ -347,13 +849,13 @@ pub struct LanDevice {
     pub ip: IpAddr,
     pub device: String,
     pub sku: String,
-    #[serde(rename = "bleVersionHard")]
+    #[serde(rename = "bleVersionHard", default)]
     pub ble_version_hard: String,
-    #[serde(rename = "bleVersionSoft")]
+    #[serde(rename = "bleVersionSoft", default)]
     pub ble_version_soft: String,
-    #[serde(rename = "wifiVersionHard")]
+    #[serde(rename = "wifiVersionHard", default)]
     pub wifi_version_hard: String,
-    #[serde(rename = "wifiVersionSoft")]
+    #[serde(rename = "wifiVersionSoft", default)]
     pub wifi_version_soft: String,
 }
 
@@ -366,3 +868,400 @@ pub struct RequestMessage {
 pub struct ResponseMessage {
     msg: Response,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{
+        govee::{FakeGoveeDevice, GoveeDeviceState, GoveePorts},
+        Fault,
+    };
+    use serde_json::{json, Value};
+
+    const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    fn config(ports: &GoveePorts) -> GoveeConfig {
+        GoveeConfig {
+            enabled: true,
+            addresses: vec![LOCALHOST.to_string()],
+            scan_timeout: 500,
+            listen_port: ports.listen,
+            scan_port: ports.scan,
+            device_port: ports.device,
+            request_timeout: 500,
+            ..Default::default()
+        }
+    }
+
+    async fn discover(govee: GoveeConfig) -> Vec<Box<dyn Light>> {
+        let config = CuteLightsConfig {
+            govee,
+            ..Default::default()
+        };
+        GoveeIntegration.discover(&config).await.unwrap()
+    }
+
+    async fn listen(ports: &GoveePorts) -> GoveeSocket {
+        GoveeSocket::new(
+            UdpSocket::bind((Ipv4Addr::UNSPECIFIED, ports.listen))
+                .await
+                .unwrap(),
+        )
+    }
+
+    async fn brightness(socket: &GoveeSocket, addr: SocketAddr) -> anyhow::Result<u8> {
+        let timeout = Duration::from_millis(100);
+        match socket
+            .request(&addr, Request::DevStatus {}, timeout)
+            .await?
+        {
+            Response::DevStatus(status) => Ok(status.brightness),
+            other => Err(anyhow::anyhow!("Unexpected reply {:?}", other)),
+        }
+    }
+
+    fn commands(device: &FakeGoveeDevice) -> Vec<Value> {
+        device
+            .requests()
+            .into_iter()
+            .filter(|request| request["cmd"] != "scan" && request["cmd"] != "devStatus")
+            .collect()
+    }
+
+    /// Decodes the base64 packets of a ptReal or razer command.
+    fn packets(encoded: &Value) -> Vec<Vec<u8>> {
+        encoded
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|packet| {
+                base64::engine::general_purpose::STANDARD
+                    .decode(packet.as_str().unwrap())
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn discovers_and_controls_devices() {
+        let ports = GoveePorts::unused().unwrap();
+        let device = FakeGoveeDevice::start(
+            LOCALHOST,
+            &ports,
+            GoveeDeviceState {
+                brightness: 40,
+                color: (255, 0, 0),
+                ..GoveeDeviceState::new("AA:BB:CC:DD:EE:FF:00:11", "H6199")
+            },
+        )
+        .await
+        .unwrap();
+
+        let mut lights = discover(config(&ports)).await;
+        assert_eq!(lights.len(), 1);
+        let light = &mut lights[0];
+        assert_eq!(light.id(), "govee::AA:BB:CC:DD:EE:FF:00:11");
+        assert_eq!(light.name(), "Govee H6199 (AA:BB:CC:DD:EE:FF:00:11)");
+        assert!(light.is_on());
+        assert_eq!(light.brightness(), 40);
+        assert_eq!((light.red(), light.green(), light.blue()), (255, 0, 0));
+        assert_eq!(light.device_info().model.as_deref(), Some("H6199"));
+
+        light.set_on(false).await.unwrap();
+        light.set_color(0, 128, 255).await.unwrap();
+        light.set_brightness(75).await.unwrap();
+
+        // Writes are not acknowledged, wait for the datagrams to land
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(
+            commands(&device),
+            [
+                json!({ "cmd": "turn", "data": { "value": 0 } }),
+                json!({
+                    "cmd": "colorwc",
+                    "data": {
+                        "color": { "r": 0, "g": 128, "b": 255 },
+                        "colorTemInKelvin": 7200
+                    }
+                }),
+                json!({ "cmd": "brightness", "data": { "value": 75 } }),
+            ]
+        );
+        let state = device.state();
+        assert!(!state.on);
+        assert_eq!(state.color, (0, 128, 255));
+        assert_eq!(state.brightness, 75);
+    }
+
+    #[tokio::test]
+    async fn hands_over_lights_before_the_scan_ends() {
+        let ports = GoveePorts::unused().unwrap();
+        let _device = FakeGoveeDevice::start(
+            LOCALHOST,
+            &ports,
+            GoveeDeviceState::new("AA:BB:CC:DD:EE:FF:00:66", "H6199"),
+        )
+        .await
+        .unwrap();
+        // The second address never answers, so the scan runs to its timeout
+        let config = CuteLightsConfig {
+            govee: GoveeConfig {
+                addresses: vec![LOCALHOST.to_string(), "127.0.0.2".to_string()],
+                scan_timeout: 1000,
+                ..config(&ports)
+            },
+            ..Default::default()
+        };
+
+        let started = Instant::now();
+        let arrivals = Mutex::new(Vec::new());
+        GoveeIntegration
+            .discover_into(&config, &|light| {
+                arrivals
+                    .lock()
+                    .unwrap()
+                    .push((light.id(), started.elapsed()))
+            })
+            .await
+            .unwrap();
+
+        assert!(started.elapsed() >= Duration::from_millis(1000));
+        let arrivals = arrivals.into_inner().unwrap();
+        assert_eq!(arrivals.len(), 1);
+        assert_eq!(arrivals[0].0, "govee::AA:BB:CC:DD:EE:FF:00:66");
+        assert!(arrivals[0].1 < Duration::from_millis(500), "{:?}", arrivals);
+    }
+
+    #[tokio::test]
+    async fn routes_replies_by_address() {
+        let ports = GoveePorts::unused().unwrap();
+        let other = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2));
+        let _dim = FakeGoveeDevice::start(
+            LOCALHOST,
+            &ports,
+            GoveeDeviceState {
+                brightness: 10,
+                ..GoveeDeviceState::new("AA:BB:CC:DD:EE:FF:00:44", "H6008")
+            },
+        )
+        .await
+        .unwrap();
+        let _bright = FakeGoveeDevice::start(
+            other,
+            &ports,
+            GoveeDeviceState {
+                brightness: 90,
+                ..GoveeDeviceState::new("AA:BB:CC:DD:EE:FF:00:55", "H6008")
+            },
+        )
+        .await
+        .unwrap();
+
+        let socket = listen(&ports).await;
+        let dim = SocketAddr::new(LOCALHOST, ports.device);
+        let bright = SocketAddr::new(other, ports.device);
+        let replies = tokio::join!(
+            brightness(&socket, dim),
+            brightness(&socket, bright),
+            brightness(&socket, dim),
+            brightness(&socket, bright),
+        );
+        assert_eq!(
+            (
+                replies.0.unwrap(),
+                replies.1.unwrap(),
+                replies.2.unwrap(),
+                replies.3.unwrap()
+            ),
+            (10, 90, 10, 90)
+        );
+    }
+
+    #[tokio::test]
+    async fn drops_replies_arriving_after_a_timeout() {
+        let ports = GoveePorts::unused().unwrap();
+        let ip = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 3));
+        let device = UdpSocket::bind((ip, ports.device)).await.unwrap();
+        let client = SocketAddr::new(LOCALHOST, ports.listen);
+        let (late_sent, late_arrived) = oneshot::channel();
+
+        let reply = |brightness: u8| {
+            json!({
+                "msg": {
+                    "cmd": "devStatus",
+                    "data": {
+                        "onOff": 1,
+                        "brightness": brightness,
+                        "color": { "r": 0, "g": 0, "b": 0 },
+                        "colorTemInKelvin": 0
+                    }
+                }
+            })
+            .to_string()
+        };
+        let fake = tokio::spawn(async move {
+            let mut buf = [0; 1024];
+            device.recv_from(&mut buf).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            device.send_to(reply(1).as_bytes(), client).await.unwrap();
+            late_sent.send(()).unwrap();
+
+            device.recv_from(&mut buf).await.unwrap();
+            device.send_to(reply(2).as_bytes(), client).await.unwrap();
+        });
+
+        let socket = listen(&ports).await;
+        let addr = SocketAddr::new(ip, ports.device);
+        assert!(brightness(&socket, addr).await.is_err());
+
+        late_arrived.await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // The late answer to the first request must not answer the second
+        assert_eq!(brightness(&socket, addr).await.unwrap(), 2);
+        fake.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn encodes_segment_packets() {
+        let ports = GoveePorts::unused().unwrap();
+        let id = "AA:BB:CC:DD:EE:FF:00:22";
+        let device = FakeGoveeDevice::start(LOCALHOST, &ports, GoveeDeviceState::new(id, "H619A"))
+            .await
+            .unwrap();
+
+        let mut govee = config(&ports);
+        govee.segments.insert(id.to_string(), 3);
+        let mut lights = discover(govee).await;
+        let light = &mut lights[0];
+        assert_eq!(light.segment_count(), 3);
+
+        let (red, blue) = ((255, 0, 0), (0, 0, 255));
+        light.set_segment_colors(&[red, blue, red]).await.unwrap();
+        assert!(light.set_segment_colors(&[red; 4]).await.is_err());
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let commands = commands(&device);
+        assert_eq!(commands.len(), 1);
+        assert_eq!(commands[0]["cmd"], "ptReal");
+        // Red on segments 0 and 2, blue on segment 1
+        assert_eq!(
+            packets(&commands[0]["data"]["command"]),
+            [
+                [
+                    0x33, 0x05, 0x15, 0x01, 0xFF, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x05,
+                    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xD8,
+                ],
+                [
+                    0x33, 0x05, 0x15, 0x01, 0x00, 0x00, 0xFF, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02,
+                    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xDF,
+                ],
+            ]
+        );
+    }
+
+    #[test]
+    fn builds_segment_color_packets() {
+        assert_eq!(
+            segment_color_packet(0x800001, 0x12, 0x34, 0x56),
+            [
+                0x33, 0x05, 0x15, 0x01, 0x12, 0x34, 0x56, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00,
+                0x80, 0x00, 0x00, 0x00, 0x00, 0xD3,
+            ]
+        );
+    }
+
+    #[test]
+    fn builds_razer_packets() {
+        assert_eq!(
+            razer_mode_packet(true),
+            [0xBB, 0x00, 0x01, 0xB1, 0x01, 0x0A]
+        );
+        assert_eq!(
+            razer_mode_packet(false),
+            [0xBB, 0x00, 0x01, 0xB1, 0x00, 0x0B]
+        );
+
+        assert_eq!(
+            razer_pixels_packet(&[(255, 0, 0), (0, 0, 255)], false).unwrap(),
+            [
+                0xBB, 0x00, 0x08, // header, length of what follows B0 and the pixels
+                0xB0, 0x00, 0x02, // pixel frame, no gradient, 2 pixels
+                0xFF, 0x00, 0x00, 0x00, 0x00, 0xFF, //
+                0x01, // XOR of everything before
+            ]
+        );
+        assert_eq!(
+            razer_pixels_packet(&[(0x10, 0x20, 0x30)], true).unwrap(),
+            [0xBB, 0x00, 0x05, 0xB0, 0x01, 0x01, 0x10, 0x20, 0x30, 0x0E]
+        );
+        assert!(razer_pixels_packet(&[(0, 0, 0); MAX_RAZER_PIXELS], false).is_ok());
+        assert!(razer_pixels_packet(&[(0, 0, 0); MAX_RAZER_PIXELS + 1], false).is_err());
+    }
+
+    #[tokio::test]
+    async fn streams_segments_in_realtime() {
+        let ports = GoveePorts::unused().unwrap();
+        let id = "AA:BB:CC:DD:EE:FF:00:44";
+        let device = FakeGoveeDevice::start(LOCALHOST, &ports, GoveeDeviceState::new(id, "H619A"))
+            .await
+            .unwrap();
+
+        let mut govee = config(&ports);
+        govee.segments.insert(id.to_string(), 3);
+        let mut lights = discover(govee).await;
+        let light = &mut lights[0];
+
+        light.set_realtime(true).await.unwrap();
+        // Realtime frames are not held to the segment count
+        light
+            .set_segment_colors(&[(255, 0, 0), (0, 0, 255)])
+            .await
+            .unwrap();
+        light.set_realtime(false).await.unwrap();
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let commands = commands(&device);
+        assert!(commands.iter().all(|command| command["cmd"] == "razer"));
+        let pts: Vec<Value> = commands
+            .iter()
+            .map(|command| command["data"]["pt"].clone())
+            .collect();
+        assert_eq!(
+            packets(&Value::Array(pts)),
+            [
+                vec![0xBB, 0x00, 0x01, 0xB1, 0x01, 0x0A],
+                vec![0xBB, 0x00, 0x08, 0xB0, 0x00, 0x02, 0xFF, 0x00, 0x00, 0x00, 0x00, 0xFF, 0x01],
+                vec![0xBB, 0x00, 0x01, 0xB1, 0x00, 0x0B],
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_unresponsive_devices() {
+        let ports = GoveePorts::unused().unwrap();
+        let device = FakeGoveeDevice::start(
+            LOCALHOST,
+            &ports,
+            GoveeDeviceState::new("AA:BB:CC:DD:EE:FF:00:33", "H6008"),
+        )
+        .await
+        .unwrap();
+
+        let mut govee = config(&ports);
+        govee.verify = true;
+        govee.verify_retries = 1;
+        govee.verify_backoff = 10;
+        let mut lights = discover(govee.clone()).await;
+        let light = &mut lights[0];
+
+        light.set_brightness(20).await.unwrap();
+        assert_eq!(device.state().brightness, 20);
+
+        for fault in [Fault::Silent, Fault::Garbage] {
+            device.set_fault(fault);
+            assert!(light.set_on(false).await.is_err(), "{:?}", fault);
+            assert!(light.is_on(), "{:?}", fault);
+            assert!(discover(govee.clone()).await.is_empty(), "{:?}", fault);
+        }
+    }
+}
@@ -0,0 +1,800 @@
+use crate::config::CuteLightsConfig;
+use async_trait::async_trait;
+use futures_util::{SinkExt, StreamExt};
+use serde::Deserialize;
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::Message;
+
+use super::{DeviceInfo, Integration, Light};
+
+// ANCHOR - HomeAssistantLight
+pub struct HomeAssistantLight {
+    instance: Arc<Instance>,
+    entity_id: String,
+    capabilities: Capabilities,
+}
+
+impl HomeAssistantLight {
+    async fn turn_on(&mut self, data: serde_json::Value) -> anyhow::Result<()> {
+        self.instance.call("turn_on", &self.entity_id, data).await
+    }
+
+    fn state(&self) -> EntityState {
+        self.instance
+            .lock_states()
+            .ok()
+            .and_then(|states| states.get(&self.entity_id).cloned())
+            .unwrap_or_default()
+    }
+}
+
+#[async_trait]
+impl Light for HomeAssistantLight {
+    async fn set_on(&mut self, on: bool) -> anyhow::Result<()> {
+        if on {
+            self.turn_on(json!({})).await
+        } else {
+            self.instance
+                .call("turn_off", &self.entity_id, json!({}))
+                .await
+        }
+    }
+
+    async fn set_color(&mut self, red: u8, green: u8, blue: u8) -> anyhow::Result<()> {
+        if !self.capabilities.color {
+            return Err(anyhow::anyhow!("{} does not support color", self.name()));
+        }
+        self.turn_on(json!({ "rgb_color": [red, green, blue] }))
+            .await
+    }
+
+    async fn set_brightness(&mut self, brightness: u8) -> anyhow::Result<()> {
+        if !self.capabilities.brightness {
+            return Err(anyhow::anyhow!(
+                "{} does not support brightness",
+                self.name()
+            ));
+        }
+        self.turn_on(json!({ "brightness_pct": brightness.min(100) }))
+            .await
+    }
+
+    fn id(&self) -> String {
+        format!("home_assistant::{}", self.entity_id)
+    }
+
+    fn is_on(&self) -> bool {
+        self.state().on
+    }
+
+    fn name(&self) -> String {
+        self.state().name.unwrap_or_else(|| self.entity_id.clone())
+    }
+
+    fn supports_color(&self) -> bool {
+        self.capabilities.color
+    }
+
+    fn red(&self) -> u8 {
+        self.state().rgb.0
+    }
+
+    fn green(&self) -> u8 {
+        self.state().rgb.1
+    }
+
+    fn blue(&self) -> u8 {
+        self.state().rgb.2
+    }
+
+    fn brightness(&self) -> u8 {
+        let state = self.state();
+        if self.capabilities.brightness {
+            state.brightness
+        } else {
+            state.on as u8 * 100
+        }
+    }
+
+    fn device_info(&self) -> DeviceInfo {
+        DeviceInfo {
+            manufacturer: "Home Assistant".to_string(),
+            model: None,
+            firmware_version: None,
+            hardware_version: None,
+        }
+    }
+
+    fn supports_color_temperature(&self) -> bool {
+        self.capabilities.color_temp
+    }
+
+    async fn set_color_temperature(&mut self, kelvin: u16) -> anyhow::Result<()> {
+        if !self.capabilities.color_temp {
+            return Err(anyhow::anyhow!(
+                "{} does not support color temperature",
+                self.name()
+            ));
+        }
+        self.turn_on(json!({ "color_temp_kelvin": kelvin })).await
+    }
+
+    fn effects(&self) -> Vec<String> {
+        self.state().effects
+    }
+
+    async fn set_effect(&mut self, effect: &str) -> anyhow::Result<()> {
+        if !self.state().effects.iter().any(|e| e == effect) {
+            return Err(anyhow::anyhow!(
+                "{} has no effect named {}",
+                self.name(),
+                effect
+            ));
+        }
+        self.turn_on(json!({ "effect": effect })).await
+    }
+}
+
+/// What a light can do, from its `supported_color_modes`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Capabilities {
+    brightness: bool,
+    color: bool,
+    color_temp: bool,
+}
+
+impl Capabilities {
+    fn from_modes(modes: &[String]) -> Capabilities {
+        let has = |names: &[&str]| modes.iter().any(|mode| names.contains(&mode.as_str()));
+        Capabilities {
+            // Every mode but onoff includes brightness
+            brightness: modes.iter().any(|mode| mode != "onoff"),
+            color: has(&["hs", "xy", "rgb", "rgbw", "rgbww"]),
+            color_temp: has(&["color_temp"]),
+        }
+    }
+}
+
+/// The last known state of an entity. Home Assistant leaves out brightness
+/// and color while a light is off, those keep their previous values.
+#[derive(Debug, Clone, PartialEq)]
+struct EntityState {
+    on: bool,
+    name: Option<String>,
+    brightness: u8,
+    rgb: (u8, u8, u8),
+    effects: Vec<String>,
+}
+
+impl Default for EntityState {
+    fn default() -> Self {
+        Self {
+            on: false,
+            name: None,
+            brightness: 0,
+            rgb: (255, 255, 255),
+            effects: Vec::new(),
+        }
+    }
+}
+
+impl EntityState {
+    fn update(&mut self, entity: &Entity) {
+        self.on = entity.state == "on";
+        let attributes = &entity.attributes;
+        if let Some(name) = &attributes.friendly_name {
+            self.name = Some(name.clone());
+        }
+        if let Some(brightness) = attributes.brightness {
+            self.brightness = (brightness.min(255.0) / 255.0 * 100.0).round() as u8;
+        }
+        if let Some([red, green, blue]) = attributes.rgb_color {
+            self.rgb = (red, green, blue);
+        }
+        if let Some(effects) = &attributes.effect_list {
+            self.effects = effects.clone();
+        }
+    }
+}
+
+// ANCHOR - Instance
+
+/// The REST client and WebSocket subscription shared by every light.
+struct Instance {
+    client: reqwest::Client,
+    url: String,
+    token: String,
+    states: Arc<Mutex<HashMap<String, EntityState>>>,
+    events: Option<JoinHandle<()>>,
+}
+
+impl Instance {
+    async fn entities(&self) -> anyhow::Result<Vec<serde_json::Value>> {
+        let body = self
+            .client
+            .get(format!("{}/api/states", self.url))
+            .bearer_auth(&self.token)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        Ok(serde_json::from_str(&body)?)
+    }
+
+    /// Calls a `light` service. The response lists the states that changed
+    /// while it ran, which are applied before the WebSocket event arrives.
+    async fn call(
+        &self,
+        service: &str,
+        entity_id: &str,
+        mut data: serde_json::Value,
+    ) -> anyhow::Result<()> {
+        data["entity_id"] = json!(entity_id);
+        let body = self
+            .client
+            .post(format!("{}/api/services/light/{}", self.url, service))
+            .bearer_auth(&self.token)
+            .body(data.to_string())
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        if let Ok(changed) = serde_json::from_str::<Vec<serde_json::Value>>(&body) {
+            apply(&self.states, &changed);
+        }
+        Ok(())
+    }
+
+    fn lock_states(
+        &self,
+    ) -> anyhow::Result<std::sync::MutexGuard<'_, HashMap<String, EntityState>>> {
+        self.states
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Home Assistant state poisoned"))
+    }
+}
+
+impl Drop for Instance {
+    fn drop(&mut self) {
+        if let Some(events) = &self.events {
+            events.abort();
+        }
+    }
+}
+
+fn apply(states: &Mutex<HashMap<String, EntityState>>, entities: &[serde_json::Value]) {
+    let Ok(mut states) = states.lock() else {
+        return;
+    };
+    for entity in entities {
+        // Only the lights found at discovery are tracked, other domains are
+        // not parsed at all
+        let Some(state) = entity["entity_id"]
+            .as_str()
+            .and_then(|entity_id| states.get_mut(entity_id))
+        else {
+            continue;
+        };
+        if let Ok(entity) = Entity::deserialize(entity) {
+            state.update(&entity);
+        }
+    }
+}
+
+/// Follows `state_changed` events over the WebSocket API, reconnecting when
+/// Home Assistant restarts.
+async fn read_events(url: String, token: String, states: Arc<Mutex<HashMap<String, EntityState>>>) {
+    loop {
+        if let Err(e) = subscribe(&url, &token, &states).await {
+            eprintln!("Home Assistant event feed failed: {}", e);
+        }
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    }
+}
+
+async fn subscribe(
+    url: &str,
+    token: &str,
+    states: &Mutex<HashMap<String, EntityState>>,
+) -> anyhow::Result<()> {
+    let (mut socket, _) = tokio_tungstenite::connect_async(url).await?;
+    while let Some(message) = socket.next().await {
+        let Message::Text(text) = message? else {
+            continue;
+        };
+        let message: WsMessage = serde_json::from_str(&text)?;
+        match message.kind.as_str() {
+            "auth_required" => {
+                let auth = json!({ "type": "auth", "access_token": token });
+                socket.send(Message::Text(auth.to_string())).await?;
+            }
+            "auth_ok" => {
+                let subscribe = json!({
+                    "id": 1,
+                    "type": "subscribe_events",
+                    "event_type": "state_changed",
+                });
+                socket.send(Message::Text(subscribe.to_string())).await?;
+            }
+            "auth_invalid" => {
+                return Err(anyhow::anyhow!(
+                    "Home Assistant rejected the token: {}",
+                    message.message.unwrap_or_default()
+                ));
+            }
+            "event" => {
+                if let Some(new_state) = message.event.and_then(|event| event.data.new_state) {
+                    apply(states, &[new_state]);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+// ANCHOR - HomeAssistantConfig
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct HomeAssistantConfig {
+    pub enabled: bool,
+    /// Where Home Assistant is served, like `http://homeassistant.local:8123`
+    #[serde(default)]
+    pub url: Option<String>,
+    /// A long-lived access token, created on the Home Assistant profile page
+    #[serde(default)]
+    pub token: Option<String>,
+    /// Keep state current from the WebSocket API
+    #[serde(default = "default_events")]
+    pub events: bool,
+    #[serde(default = "default_request_timeout")]
+    pub request_timeout: u64,
+}
+
+impl Default for HomeAssistantConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            url: None,
+            token: None,
+            events: default_events(),
+            request_timeout: default_request_timeout(),
+        }
+    }
+}
+
+fn default_events() -> bool {
+    true
+}
+
+fn default_request_timeout() -> u64 {
+    5000
+}
+
+// ANCHOR - HomeAssistantIntegration
+
+pub struct HomeAssistantIntegration;
+
+#[async_trait]
+impl Integration for HomeAssistantIntegration {
+    fn name(&self) -> String {
+        "home_assistant".to_string()
+    }
+
+    fn preflight(&self, config: &CuteLightsConfig) -> bool {
+        if !config.home_assistant.enabled {
+            return false;
+        }
+
+        if config.home_assistant.url.is_none() {
+            eprintln!("Home Assistant url not configured");
+            return false;
+        }
+
+        if config.home_assistant.token.is_none() {
+            eprintln!("Home Assistant token not configured");
+            return false;
+        }
+
+        true
+    }
+
+    async fn discover(&self, config: &CuteLightsConfig) -> anyhow::Result<Vec<Box<dyn Light>>> {
+        let config = &config.home_assistant;
+        let (Some(url), Some(token)) = (&config.url, &config.token) else {
+            return Err(anyhow::anyhow!("Home Assistant not configured"));
+        };
+        let url = url.trim_end_matches('/').to_string();
+
+        let mut instance = Instance {
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_millis(config.request_timeout))
+                .build()?,
+            url: url.clone(),
+            token: token.clone(),
+            states: Arc::new(Mutex::new(HashMap::new())),
+            events: None,
+        };
+
+        let mut lights = Vec::new();
+        for entity in instance.entities().await? {
+            let is_light = entity["entity_id"]
+                .as_str()
+                .is_some_and(|entity_id| entity_id.starts_with("light."));
+            if !is_light {
+                continue;
+            }
+            let entity = match Entity::deserialize(&entity) {
+                Ok(entity) => entity,
+                Err(e) => {
+                    eprintln!(
+                        "Skipping Home Assistant entity {}: {}",
+                        entity["entity_id"], e
+                    );
+                    continue;
+                }
+            };
+            if entity.state == "unavailable" {
+                continue;
+            }
+            let capabilities = Capabilities::from_modes(
+                entity
+                    .attributes
+                    .supported_color_modes
+                    .as_deref()
+                    .unwrap_or_default(),
+            );
+            let mut state = EntityState::default();
+            state.update(&entity);
+            instance
+                .lock_states()?
+                .insert(entity.entity_id.clone(), state);
+            lights.push((entity.entity_id, capabilities));
+        }
+
+        if config.events {
+            let ws_url = match url.split_once("://") {
+                Some(("https", rest)) => format!("wss://{}/api/websocket", rest),
+                Some((_, rest)) => format!("ws://{}/api/websocket", rest),
+                None => format!("ws://{}/api/websocket", url),
+            };
+            instance.events = Some(tokio::spawn(read_events(
+                ws_url,
+                token.clone(),
+                instance.states.clone(),
+            )));
+        }
+
+        let instance = Arc::new(instance);
+        Ok(lights
+            .into_iter()
+            .map(|(entity_id, capabilities)| {
+                Box::new(HomeAssistantLight {
+                    instance: instance.clone(),
+                    entity_id,
+                    capabilities,
+                }) as Box<dyn Light>
+            })
+            .collect())
+    }
+}
+
+// ANCHOR - Messages
+
+#[derive(Debug, Clone, Deserialize)]
+struct Entity {
+    entity_id: String,
+    state: String,
+    #[serde(default)]
+    attributes: Attributes,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct Attributes {
+    friendly_name: Option<String>,
+    supported_color_modes: Option<Vec<String>>,
+    brightness: Option<f64>,
+    rgb_color: Option<[u8; 3]>,
+    effect_list: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct WsMessage {
+    #[serde(rename = "type")]
+    kind: String,
+    message: Option<String>,
+    event: Option<WsEvent>,
+}
+
+#[derive(Debug, Deserialize)]
+struct WsEvent {
+    data: WsEventData,
+}
+
+#[derive(Debug, Deserialize)]
+struct WsEventData {
+    new_state: Option<serde_json::Value>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::http::FakeHttpServer;
+    use serde_json::Value;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_tungstenite::WebSocketStream;
+
+    fn modes(modes: &[&str]) -> Capabilities {
+        let modes: Vec<String> = modes.iter().map(|mode| mode.to_string()).collect();
+        Capabilities::from_modes(&modes)
+    }
+
+    #[test]
+    fn reads_capabilities_from_color_modes() {
+        let none = Capabilities {
+            brightness: false,
+            color: false,
+            color_temp: false,
+        };
+        assert_eq!(modes(&[]), none);
+        assert_eq!(modes(&["onoff"]), none);
+        assert_eq!(
+            modes(&["brightness"]),
+            Capabilities {
+                brightness: true,
+                ..none
+            }
+        );
+        assert_eq!(
+            modes(&["color_temp"]),
+            Capabilities {
+                brightness: true,
+                color_temp: true,
+                ..none
+            }
+        );
+        for color in ["hs", "xy", "rgb", "rgbw", "rgbww"] {
+            assert_eq!(
+                modes(&["color_temp", color]),
+                Capabilities {
+                    brightness: true,
+                    color: true,
+                    color_temp: true,
+                },
+                "{}",
+                color
+            );
+        }
+    }
+
+    fn entity(value: Value) -> Entity {
+        Entity::deserialize(&value).unwrap()
+    }
+
+    #[test]
+    fn keeps_color_and_brightness_while_off() {
+        let mut state = EntityState::default();
+        state.update(&entity(json!({
+            "entity_id": "light.desk",
+            "state": "on",
+            "attributes": {
+                "friendly_name": "Desk",
+                "brightness": 128,
+                "rgb_color": [255, 100, 0],
+                "effect_list": ["colorloop"]
+            }
+        })));
+        state.update(&entity(json!({
+            "entity_id": "light.desk",
+            "state": "off",
+            "attributes": { "friendly_name": "Desk" }
+        })));
+
+        assert_eq!(
+            state,
+            EntityState {
+                on: false,
+                name: Some("Desk".to_string()),
+                brightness: 50,
+                rgb: (255, 100, 0),
+                effects: vec!["colorloop".to_string()],
+            }
+        );
+    }
+
+    // ANCHOR - Fake WebSocket
+
+    async fn send(socket: &mut WebSocketStream<TcpStream>, message: Value) {
+        socket
+            .send(Message::Text(message.to_string()))
+            .await
+            .unwrap();
+    }
+
+    async fn receive(socket: &mut WebSocketStream<TcpStream>) -> Value {
+        loop {
+            if let Message::Text(text) = socket.next().await.unwrap().unwrap() {
+                return serde_json::from_str(&text).unwrap();
+            }
+        }
+    }
+
+    /// Accepts one WebSocket connection and authenticates it like Home
+    /// Assistant does, returning the socket after `auth_ok`.
+    async fn authenticate(listener: TcpListener, token: &str) -> WebSocketStream<TcpStream> {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut socket = tokio_tungstenite::accept_async(stream).await.unwrap();
+        send(
+            &mut socket,
+            json!({ "type": "auth_required", "ha_version": "2024.6.0" }),
+        )
+        .await;
+        let auth = receive(&mut socket).await;
+        assert_eq!(auth["type"], "auth");
+        if auth["access_token"] != token {
+            send(
+                &mut socket,
+                json!({ "type": "auth_invalid", "message": "Invalid access token" }),
+            )
+            .await;
+        } else {
+            send(
+                &mut socket,
+                json!({ "type": "auth_ok", "ha_version": "2024.6.0" }),
+            )
+            .await;
+        }
+        socket
+    }
+
+    fn tracking(entity_id: &str) -> Mutex<HashMap<String, EntityState>> {
+        Mutex::new(HashMap::from([(
+            entity_id.to_string(),
+            EntityState::default(),
+        )]))
+    }
+
+    #[tokio::test]
+    async fn follows_state_changed_events() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/api/websocket", listener.local_addr().unwrap());
+
+        let server = tokio::spawn(async move {
+            let mut socket = authenticate(listener, "secret").await;
+            assert_eq!(
+                receive(&mut socket).await,
+                json!({ "id": 1, "type": "subscribe_events", "event_type": "state_changed" })
+            );
+            send(
+                &mut socket,
+                json!({ "id": 1, "type": "result", "success": true, "result": null }),
+            )
+            .await;
+            for entity_id in ["sensor.outside", "light.desk"] {
+                send(
+                    &mut socket,
+                    json!({
+                        "id": 1,
+                        "type": "event",
+                        "event": {
+                            "event_type": "state_changed",
+                            "data": {
+                                "entity_id": entity_id,
+                                "old_state": null,
+                                "new_state": {
+                                    "entity_id": entity_id,
+                                    "state": "on",
+                                    "attributes": { "brightness": 255, "rgb_color": [0, 0, 255] }
+                                }
+                            }
+                        }
+                    }),
+                )
+                .await;
+            }
+            socket.close(None).await.unwrap();
+        });
+
+        let states = tracking("light.desk");
+        subscribe(&url, "secret", &states).await.unwrap();
+        server.await.unwrap();
+
+        let states = states.lock().unwrap();
+        assert_eq!(states.len(), 1);
+        let desk = &states["light.desk"];
+        assert!(desk.on);
+        assert_eq!(desk.brightness, 100);
+        assert_eq!(desk.rgb, (0, 0, 255));
+    }
+
+    #[tokio::test]
+    async fn reports_rejected_tokens() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/api/websocket", listener.local_addr().unwrap());
+        let server = tokio::spawn(authenticate(listener, "secret"));
+
+        let error = subscribe(&url, "wrong", &tracking("light.desk"))
+            .await
+            .unwrap_err();
+        assert!(
+            error.to_string().contains("Invalid access token"),
+            "{}",
+            error
+        );
+        drop(server.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn discovers_and_controls_lights_over_rest() {
+        let server = FakeHttpServer::start(|request| match request.route() {
+            "/api/states" => (
+                200,
+                json!([
+                    {
+                        "entity_id": "light.desk",
+                        "state": "off",
+                        "attributes": {
+                            "friendly_name": "Desk",
+                            "supported_color_modes": ["color_temp", "hs"],
+                            "effect_list": ["colorloop"]
+                        }
+                    },
+                    {
+                        "entity_id": "light.porch",
+                        "state": "unavailable",
+                        "attributes": { "supported_color_modes": ["onoff"] }
+                    },
+                    { "entity_id": "switch.fan", "state": "on", "attributes": {} }
+                ]),
+            ),
+            // The service response lists the states it changed
+            "/api/services/light/turn_on" => (
+                200,
+                json!([{
+                    "entity_id": "light.desk",
+                    "state": "on",
+                    "attributes": { "brightness": 255, "rgb_color": [255, 0, 0] }
+                }]),
+            ),
+            _ => (404, json!({ "message": "Not found" })),
+        })
+        .await
+        .unwrap();
+        let config = CuteLightsConfig {
+            home_assistant: HomeAssistantConfig {
+                enabled: true,
+                url: Some(format!("http://{}/", server.address())),
+                token: Some("secret".to_string()),
+                events: false,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let mut lights = HomeAssistantIntegration.discover(&config).await.unwrap();
+
+        assert_eq!(lights.len(), 1);
+        let light = &mut lights[0];
+        assert_eq!(light.id(), "home_assistant::light.desk");
+        assert_eq!(light.name(), "Desk");
+        assert!(!light.is_on());
+        assert!(light.supports_color() && light.supports_color_temperature());
+        assert!(light.set_effect("rainbow").await.is_err());
+
+        light.set_color(255, 0, 0).await.unwrap();
+        assert!(light.is_on());
+        assert_eq!((light.red(), light.green(), light.blue()), (255, 0, 0));
+        assert_eq!(
+            server.requests().last().unwrap().body,
+            Some(json!({ "rgb_color": [255, 0, 0], "entity_id": "light.desk" }))
+        );
+    }
+}
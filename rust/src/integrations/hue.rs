@@ -1,6 +1,6 @@
 use crate::utils::json;
 
-use super::Light;
+use super::{DeviceInfo, Light};
 
 // ANCHOR - HueLight
 
@@ -16,49 +16,60 @@ pub struct HueLight {
     name: String,
     supports_color: bool,
     is_on: bool,
+    manufacturer: Option<String>,
+    model: Option<String>,
+    sw_version: Option<String>,
 }
 
-#[async_trait::async_trait]
-impl Light for HueLight {
-    async fn set_on(&mut self, on: bool) -> anyhow::Result<()> {
+impl HueLight {
+    /// The bridge answers failed changes with `[{"error": {...}}]` and a
+    /// success status, so the reply is checked too.
+    async fn put_state(&self, body: serde_json::Value) -> anyhow::Result<()> {
         let url = format!(
             "http://{}/api/{}/lights/{}/state",
             self.bridge, self.username, self.id
         );
-        let body = serde_json::json!({"on": on});
         let client = reqwest::Client::new();
-        client.put(&url).body(body.to_string()).send().await?;
+        let response = client.put(&url).body(body.to_string()).send().await?;
+        check_errors(
+            &serde_json::from_str(&response.text().await?)?,
+            "Hue bridge",
+        )
+    }
+}
+
+#[async_trait::async_trait]
+impl Light for HueLight {
+    async fn set_on(&mut self, on: bool) -> anyhow::Result<()> {
+        self.put_state(serde_json::json!({"on": on})).await?;
         self.is_on = on;
         Ok(())
     }
 
     async fn set_brightness(&mut self, brightness: u8) -> anyhow::Result<()> {
-        let url = format!(
-            "http://{}/api/{}/lights/{}/state",
-            self.bridge, self.username, self.id
-        );
-        let body = serde_json::json!({"bri": (brightness as f64 / 100.0 * 254.0).round() as i64});
-        let client = reqwest::Client::new();
-        client.put(&url).body(body.to_string()).send().await?;
+        // Lights that are off refuse brightness and color changes
+        let body = serde_json::json!({
+            "on": true,
+            "bri": (brightness as f64 / 100.0 * 254.0).round() as i64
+        });
+        self.put_state(body).await?;
+        self.is_on = true;
         self.brightness = brightness;
         Ok(())
     }
 
     async fn set_color(&mut self, red: u8, green: u8, blue: u8) -> anyhow::Result<()> {
-        let (hue, saturation, brightness) = crate::utils::color::rgb_to_hsv(red, green, blue);
+        let (hue, saturation, brightness) = crate::utils::color::rgb_to_hsb(red, green, blue);
 
-        let url = format!(
-            "http://{}/api/{}/lights/{}/state",
-            self.bridge, self.username, self.id
-        );
         let body = serde_json::json!({
+            "on": true,
             "hue": (hue as f64 / 360.0 * 65535.0).round() as i64,
             "sat": (saturation as f64 / 100.0 * 254.0).round() as i64,
             "bri": (brightness as f64 / 100.0 * 254.0).round() as i64
         });
-        let client = reqwest::Client::new();
-        client.put(&url).body(body.to_string()).send().await?;
+        self.put_state(body).await?;
 
+        self.is_on = true;
         self.red = red;
         self.green = green;
         self.blue = blue;
@@ -95,6 +106,18 @@ impl Light for HueLight {
     fn supports_color(&self) -> bool {
         self.supports_color
     }
+
+    fn device_info(&self) -> DeviceInfo {
+        DeviceInfo {
+            manufacturer: self
+                .manufacturer
+                .clone()
+                .unwrap_or_else(|| "Philips".to_string()),
+            model: self.model.clone(),
+            firmware_version: self.sw_version.clone(),
+            hardware_version: None,
+        }
+    }
 }
 
 // ANCHOR - HueConfig
@@ -111,11 +134,12 @@ pub struct HueIntegration;
 
 #[async_trait::async_trait]
 impl super::Integration for HueIntegration {
-    fn name() -> String {
+    fn name(&self) -> String {
         "hue".to_string()
     }
     async fn discover(
-        config: &'static crate::config::CuteLightsConfig,
+        &self,
+        config: &crate::config::CuteLightsConfig,
     ) -> anyhow::Result<Vec<Box<dyn Light>>> {
         let bridge = &config.hue.bridge_ip.as_ref().unwrap();
         let user = &config.hue.username.as_ref().unwrap();
@@ -125,32 +149,34 @@ impl super::Integration for HueIntegration {
         let response = reqwest::get(&url).await?;
         let body = response.text().await?;
         let js: serde_json::Value = serde_json::from_str(&body)?;
+        check_errors(&js, "Hue bridge")?;
 
         for (light_id, value) in json::object(&js)? {
-            let is_reachable = json::bool(&value["state"]["reachable"])?;
-            if !is_reachable {
+            let light = parse_light(value)?;
+            if !light.state.reachable {
                 continue;
             }
-            let is_on = json::bool(&value["state"]["on"])?;
-            let saturation = (json::float(&value["state"]["sat"])? / 254.0 * 100.0).round() as i64;
-            let hue = (json::float(&value["state"]["hue"])? / 65535.0 * 360.0).round() as i64;
-            let brightness = (json::float(&value["state"]["bri"])? / 254.0 * 100.0).round() as i64;
-            let name = json::object(&value)?["name"].as_str().unwrap();
-            let supports_color = !&value["capabilities"]["control"]["colorgamut"].is_null();
 
-            let (red, green, blue) = crate::utils::color::hsv_to_rgb(hue, saturation, brightness);
+            let (red, green, blue) = crate::utils::color::hsb_to_rgb(
+                light.state.hue.clamp(0, 360) as u16,
+                light.state.saturation.clamp(0, 100) as u8,
+                light.state.brightness,
+            );
 
             lights.push(HueLight {
                 id: light_id.to_string(),
                 username: user.to_string(),
                 bridge: bridge.to_string(),
-                red: red,
-                green: green,
-                blue: blue,
-                brightness: brightness as u8,
-                name: name.to_string(),
-                supports_color,
-                is_on,
+                red,
+                green,
+                blue,
+                brightness: light.state.brightness,
+                name: light.name,
+                supports_color: light.supports_color,
+                is_on: light.state.on,
+                manufacturer: light.manufacturer,
+                model: light.model,
+                sw_version: light.sw_version,
             });
         }
 
@@ -160,7 +186,7 @@ impl super::Integration for HueIntegration {
             .collect())
     }
 
-    fn preflight(config: &crate::config::CuteLightsConfig) -> bool {
+    fn preflight(&self, config: &crate::config::CuteLightsConfig) -> bool {
         if !config.hue.enabled {
             return false;
         }
@@ -178,3 +204,233 @@ impl super::Integration for HueIntegration {
         true
     }
 }
+
+// ANCHOR - Parsing
+
+/// A light from the v1 `lights` endpoint. deCONZ serves the same JSON, so
+/// its integration parses lights with this too.
+#[derive(Debug, Clone)]
+pub(crate) struct HueLightInfo {
+    pub name: String,
+    pub state: HueState,
+    pub supports_color: bool,
+    pub supports_color_temperature: bool,
+    pub manufacturer: Option<String>,
+    pub model: Option<String>,
+    pub sw_version: Option<String>,
+    pub unique_id: Option<String>,
+}
+
+/// Light state with brightness and saturation in percent and hue in degrees.
+#[derive(Debug, Clone)]
+pub(crate) struct HueState {
+    pub on: bool,
+    pub reachable: bool,
+    pub brightness: u8,
+    pub hue: i64,
+    pub saturation: i64,
+    pub xy: Option<(f64, f64)>,
+    pub color_mode: Option<String>,
+}
+
+impl HueState {
+    /// Applies the fields present in a v1 `state` object, the deCONZ events
+    /// only carry the ones that changed.
+    pub(crate) fn update(&mut self, state: &serde_json::Value) {
+        if let Ok(on) = json::bool(&state["on"]) {
+            self.on = on;
+        }
+        if let Ok(reachable) = json::bool(&state["reachable"]) {
+            self.reachable = reachable;
+        }
+        if let Ok(bri) = json::float(&state["bri"]) {
+            self.brightness = (bri / 254.0 * 100.0).round() as u8;
+        }
+        if let Ok(hue) = json::float(&state["hue"]) {
+            self.hue = (hue / 65535.0 * 360.0).round() as i64;
+        }
+        if let Ok(sat) = json::float(&state["sat"]) {
+            self.saturation = (sat / 254.0 * 100.0).round() as i64;
+        }
+        if let (Ok(x), Ok(y)) = (json::float(&state["xy"][0]), json::float(&state["xy"][1])) {
+            self.xy = Some((x, y));
+        }
+        if let Some(color_mode) = state["colormode"].as_str() {
+            self.color_mode = Some(color_mode.to_string());
+        }
+    }
+}
+
+/// Finds the first `{"error": {...}}` in a reply, which the v1 API and
+/// deCONZ send with a success status. `bridge` names the sender in the
+/// error.
+pub(crate) fn check_errors(value: &serde_json::Value, bridge: &str) -> anyhow::Result<()> {
+    let Some(results) = value.as_array() else {
+        return Ok(());
+    };
+    for result in results {
+        if let Some(description) = result["error"]["description"].as_str() {
+            return Err(anyhow::anyhow!("{} error: {}", bridge, description));
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn parse_light(value: &serde_json::Value) -> anyhow::Result<HueLightInfo> {
+    let light = json::object(value)?;
+    let name = light
+        .get("name")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| anyhow::anyhow!("Light has no name"))?;
+    let text = |key: &str| light.get(key).and_then(|v| v.as_str()).map(str::to_string);
+
+    let mut state = HueState {
+        on: json::bool(&value["state"]["on"])?,
+        reachable: true,
+        brightness: 100,
+        hue: 0,
+        saturation: 0,
+        xy: None,
+        color_mode: None,
+    };
+    state.update(&value["state"]);
+
+    Ok(HueLightInfo {
+        name: name.to_string(),
+        state,
+        // deCONZ has no capabilities object but says `hascolor`
+        supports_color: !value["capabilities"]["control"]["colorgamut"].is_null()
+            || (value["hascolor"].as_bool().unwrap_or(false)
+                && (!value["state"]["xy"].is_null() || !value["state"]["hue"].is_null())),
+        supports_color_temperature: !value["capabilities"]["control"]["ct"].is_null()
+            || !value["state"]["ct"].is_null(),
+        manufacturer: text("manufacturername"),
+        model: text("modelid"),
+        sw_version: text("swversion"),
+        unique_id: text("uniqueid"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::CuteLightsConfig;
+    use crate::integrations::Integration;
+    use crate::test_support::{
+        hue::{color_light, dimmable_light, FakeHueBridge},
+        Fault,
+    };
+    use serde_json::json;
+
+    async fn discover(
+        bridge: &FakeHueBridge,
+        username: &str,
+    ) -> anyhow::Result<Vec<Box<dyn Light>>> {
+        let config = CuteLightsConfig {
+            hue: HueConfig {
+                enabled: true,
+                bridge_ip: Some(bridge.address()),
+                username: Some(username.to_string()),
+            },
+            ..Default::default()
+        };
+        HueIntegration.discover(&config).await
+    }
+
+    #[tokio::test]
+    async fn discovers_reachable_lights() {
+        let bridge = FakeHueBridge::start("user").await.unwrap();
+        bridge.add_light("1", color_light("Desk"));
+        bridge.add_light("2", dimmable_light("Hall"));
+        let mut unreachable = color_light("Porch");
+        unreachable["state"]["reachable"] = json!(false);
+        bridge.add_light("3", unreachable);
+
+        let mut lights = discover(&bridge, "user").await.unwrap();
+        lights.sort_by_key(|light| light.id());
+        assert_eq!(lights.len(), 2);
+
+        let desk = &lights[0];
+        assert_eq!(desk.id(), "hue::1");
+        assert_eq!(desk.name(), "Desk");
+        assert!(desk.is_on());
+        assert!(desk.supports_color());
+        assert_eq!(desk.brightness(), 100);
+        assert_eq!(desk.device_info().model.as_deref(), Some("LCA001"));
+
+        let hall = &lights[1];
+        assert_eq!(hall.id(), "hue::2");
+        assert!(!hall.is_on());
+        assert!(!hall.supports_color());
+        assert_eq!(hall.brightness(), 50);
+
+        let requests = bridge.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(
+            (requests[0].method.as_str(), requests[0].path.as_str()),
+            ("GET", "/api/user/lights/")
+        );
+    }
+
+    #[tokio::test]
+    async fn encodes_state_changes() {
+        let bridge = FakeHueBridge::start("user").await.unwrap();
+        bridge.add_light("2", dimmable_light("Hall"));
+        let mut lights = discover(&bridge, "user").await.unwrap();
+        let light = &mut lights[0];
+
+        // The light starts off, so changes only work because they turn it on.
+        // A white bulb has no hue, the bridge applies the rest and reports that.
+        light.set_brightness(25).await.unwrap();
+        light.set_color(0, 0, 255).await.unwrap_err();
+        light.set_on(false).await.unwrap();
+
+        let puts: Vec<_> = bridge.requests()[1..]
+            .iter()
+            .map(|request| {
+                assert_eq!(request.method, "PUT");
+                assert_eq!(request.path, "/api/user/lights/2/state");
+                request.body.clone().unwrap()
+            })
+            .collect();
+        assert_eq!(
+            puts,
+            [
+                json!({ "on": true, "bri": 64 }),
+                json!({ "on": true, "hue": 43690, "sat": 254, "bri": 254 }),
+                json!({ "on": false }),
+            ]
+        );
+
+        let state = &bridge.light("2").unwrap()["state"];
+        assert_eq!(state["on"], json!(false));
+        assert_eq!(state["bri"], json!(254));
+        assert!(state.get("hue").is_none());
+        assert!(!light.is_on());
+        assert_eq!(light.brightness(), 25);
+    }
+
+    #[tokio::test]
+    async fn reports_bridge_errors() {
+        let bridge = FakeHueBridge::start("user").await.unwrap();
+        bridge.add_light("1", color_light("Desk"));
+
+        let error = discover(&bridge, "intruder").await.err().unwrap();
+        assert!(error.to_string().contains("unauthorized user"), "{}", error);
+
+        let mut lights = discover(&bridge, "user").await.unwrap();
+        let light = &mut lights[0];
+        bridge.remove_light("1");
+        let error = light.set_on(false).await.unwrap_err();
+        assert!(error.to_string().contains("not available"), "{}", error);
+        assert!(light.is_on());
+
+        bridge.add_light("1", color_light("Desk"));
+        for fault in [Fault::Garbage, Fault::Disconnect] {
+            bridge.set_fault(fault);
+            assert!(light.set_on(false).await.is_err(), "{:?}", fault);
+            assert!(discover(&bridge, "user").await.is_err(), "{:?}", fault);
+        }
+        assert!(light.is_on());
+    }
+}
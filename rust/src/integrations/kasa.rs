@@ -1,21 +1,24 @@
 use crate::utils::json::boolean_int;
 use crate::{
     config::CuteLightsConfig,
-    utils::{future::FutureBatch, json},
+    utils::{future::FutureBatch, json, net},
 };
 use async_trait::async_trait;
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::{fmt::Debug, io::Cursor};
+use std::{fmt::Debug, io::Cursor, time::Duration};
 use tokio::net::TcpStream;
 
-use super::{Integration, Light};
+use super::{DeviceInfo, Integration, Light};
+
+const KASA_PORT: u16 = 9999;
 
 // ANCHOR - KasaLight
 #[derive(Debug)]
 pub struct KasaLight {
     ip: String,
+    timeout: Duration,
     is_on: bool,
     brightness: u8,
     red: u8,
@@ -24,24 +27,28 @@ pub struct KasaLight {
     supports_color: bool,
     id: String,
     name: String,
+    model: Option<String>,
+    sw_ver: Option<String>,
+    hw_ver: Option<String>,
 }
 
 impl KasaLight {
-    pub async fn new(ip: String) -> anyhow::Result<KasaLight> {
+    pub async fn new(ip: String, timeout: Duration) -> anyhow::Result<KasaLight> {
         let data = get_sysinfo_message();
-        let response = KasaLight::send(ip.clone(), data.to_string()).await?;
+        let response = KasaLight::send(&ip, timeout, data.to_string()).await?;
 
-        let json: serde_json::Value = serde_json::from_str(&response).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&response)?;
 
         let state: SysInfo = serde_json::from_value(json["system"]["get_sysinfo"].clone())?;
-        let (red, green, blue) = crate::utils::color::hsv_to_rgb(
-            state.light_state.hue.unwrap_or(0),
-            state.light_state.saturation.unwrap_or(0),
-            state.light_state.brightness.unwrap_or(0),
+        let (red, green, blue) = crate::utils::color::hsb_to_rgb(
+            state.light_state.hue.unwrap_or(0).clamp(0, 360) as u16,
+            state.light_state.saturation.unwrap_or(0).clamp(0, 100) as u8,
+            state.light_state.brightness.unwrap_or(0).clamp(0, 100) as u8,
         );
 
         Ok(KasaLight {
             ip,
+            timeout,
             is_on: state.light_state.on_off,
             brightness: state.light_state.brightness.unwrap_or(0) as u8,
             red,
@@ -50,31 +57,36 @@ impl KasaLight {
             supports_color: state.is_color,
             name: state.alias,
             id: state.mic_mac,
+            model: state.model,
+            sw_ver: state.sw_ver,
+            hw_ver: state.hw_ver,
         })
     }
 
-    async fn send(ip: String, data: String) -> anyhow::Result<String> {
+    /// Sends one request to `ip`, given as `host` or `host:port`.
+    async fn send(ip: &str, timeout: Duration, data: String) -> anyhow::Result<String> {
         use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
-        let mut stream = TcpStream::connect(format!("{}:9999", ip)).await?;
-        stream
-            .write(
-                &KasaLight::encrypt(&data)
-                    .iter()
-                    .map(|b| *b)
-                    .collect::<Vec<u8>>(),
-            )
-            .await?;
-
-        let mut buffer = Vec::new();
-        loop {
-            stream.read_buf(&mut buffer).await?;
-            let decrypted = KasaLight::decrypt(&buffer);
-            if json::is_valid(&decrypted) {
-                break;
+        let address = net::with_default_port(ip, KASA_PORT);
+
+        let exchange = async {
+            let mut stream = TcpStream::connect(&address).await?;
+            stream.write_all(&KasaLight::encrypt(&data)).await?;
+
+            let mut buffer = Vec::new();
+            loop {
+                if stream.read_buf(&mut buffer).await? == 0 {
+                    return Err(anyhow::anyhow!("{} closed the connection", address));
+                }
+                let decrypted = KasaLight::decrypt(&buffer);
+                if json::is_valid(&decrypted) {
+                    return Ok(decrypted);
+                }
             }
-        }
-        Ok(KasaLight::decrypt(&buffer))
+        };
+        tokio::time::timeout(timeout, exchange)
+            .await
+            .map_err(|_| anyhow::anyhow!("Timed out waiting for {}", address))?
     }
 
     fn encrypt(input: &str) -> Vec<u8> {
@@ -94,6 +106,9 @@ impl KasaLight {
     }
 
     fn decrypt(encrypted_bytes: &[u8]) -> String {
+        if encrypted_bytes.len() < 4 {
+            return String::new();
+        }
         let mut key: u32 = 171;
         let mut result = String::new();
 
@@ -114,7 +129,7 @@ impl KasaLight {
 impl Light for KasaLight {
     async fn set_on(&mut self, on: bool) -> anyhow::Result<()> {
         let msg = on_off_message(on);
-        match KasaLight::send(self.ip.clone(), msg.to_string()).await {
+        match KasaLight::send(&self.ip, self.timeout, msg.to_string()).await {
             Ok(_) => {
                 self.is_on = on;
                 Ok(())
@@ -124,9 +139,9 @@ impl Light for KasaLight {
     }
 
     async fn set_color(&mut self, red: u8, green: u8, blue: u8) -> anyhow::Result<()> {
-        let (h, s, b) = crate::utils::color::rgb_to_hsv(red, green, blue);
-        let msg = color_message(h, s, b);
-        match KasaLight::send(self.ip.clone(), msg.to_string()).await {
+        let (h, s, b) = crate::utils::color::rgb_to_hsb(red, green, blue);
+        let msg = color_message(h as i64, s as i64, b as i64);
+        match KasaLight::send(&self.ip, self.timeout, msg.to_string()).await {
             Ok(_) => {
                 self.red = red;
                 self.green = green;
@@ -139,7 +154,7 @@ impl Light for KasaLight {
 
     async fn set_brightness(&mut self, brightness: u8) -> anyhow::Result<()> {
         let msg = brightness_message(brightness as i64);
-        match KasaLight::send(self.ip.clone(), msg.to_string()).await {
+        match KasaLight::send(&self.ip, self.timeout, msg.to_string()).await {
             Ok(_) => {
                 self.brightness = brightness;
                 Ok(())
@@ -179,14 +194,40 @@ impl Light for KasaLight {
     fn supports_color(&self) -> bool {
         self.supports_color
     }
+
+    fn device_info(&self) -> DeviceInfo {
+        DeviceInfo {
+            manufacturer: "TP-Link".to_string(),
+            model: self.model.clone(),
+            firmware_version: self.sw_ver.clone(),
+            hardware_version: self.hw_ver.clone(),
+        }
+    }
 }
 
 // ANCHOR - KasaConfig
 
-#[derive(Debug, serde::Deserialize, serde::Serialize, Default, Clone)]
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 pub struct KasaConfig {
     pub enabled: bool,
+    /// Bulbs as `host` or `host:port`
     pub addresses: Vec<String>,
+    #[serde(default = "default_request_timeout")]
+    pub request_timeout: u64,
+}
+
+impl Default for KasaConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            addresses: Vec::new(),
+            request_timeout: default_request_timeout(),
+        }
+    }
+}
+
+fn default_request_timeout() -> u64 {
+    2000
 }
 
 // ANCHOR - KasaIntegration
@@ -195,20 +236,21 @@ pub struct KasaIntegration;
 
 #[async_trait]
 impl Integration for KasaIntegration {
-    fn name() -> String {
+    fn name(&self) -> String {
         "kasa".to_string()
     }
 
-    fn preflight(config: &CuteLightsConfig) -> bool {
+    fn preflight(&self, config: &CuteLightsConfig) -> bool {
         config.kasa.enabled
     }
-    async fn discover(config: &'static CuteLightsConfig) -> anyhow::Result<Vec<Box<dyn Light>>> {
+    async fn discover(&self, config: &CuteLightsConfig) -> anyhow::Result<Vec<Box<dyn Light>>> {
         let mut lights = FutureBatch::new();
 
+        let timeout = Duration::from_millis(config.kasa.request_timeout);
         for address in &config.kasa.addresses {
             let address = address.clone();
             lights.push(async move {
-                match KasaLight::new(address).await {
+                match KasaLight::new(address, timeout).await {
                     Ok(light) => Some(Box::new(light) as Box<dyn Light>),
                     Err(e) => {
                         eprintln!("Error: {}", e);
@@ -231,6 +273,9 @@ struct SysInfo {
     #[serde(deserialize_with = "boolean_int")]
     is_color: bool,
     light_state: LightState,
+    model: Option<String>,
+    sw_ver: Option<String>,
+    hw_ver: Option<String>,
 }
 #[derive(Debug, Serialize, Deserialize, Default, Clone)]
 
@@ -286,3 +331,138 @@ fn get_sysinfo_message() -> serde_json::Value {
         }
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{
+        kasa::{FakeKasaBulb, KasaBulbState},
+        Fault,
+    };
+    use serde_json::Value;
+
+    async fn discover(addresses: Vec<String>, request_timeout: u64) -> Vec<Box<dyn Light>> {
+        let config = CuteLightsConfig {
+            kasa: KasaConfig {
+                enabled: true,
+                addresses,
+                request_timeout,
+            },
+            ..Default::default()
+        };
+        KasaIntegration.discover(&config).await.unwrap()
+    }
+
+    /// A port nothing listens on.
+    fn closed_address() -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn discovers_bulbs() {
+        let desk = FakeKasaBulb::start(KasaBulbState {
+            hue: 120,
+            saturation: 100,
+            brightness: 80,
+            ..KasaBulbState::new("Desk", "50:C7:BF:00:00:01")
+        })
+        .await
+        .unwrap();
+        let hall = FakeKasaBulb::start(KasaBulbState {
+            on: false,
+            is_color: false,
+            ..KasaBulbState::new("Hall", "50:C7:BF:00:00:02")
+        })
+        .await
+        .unwrap();
+
+        let mut lights = discover(
+            vec![desk.address(), closed_address(), hall.address()],
+            1000,
+        )
+        .await;
+        lights.sort_by_key(|light| light.name());
+        assert_eq!(lights.len(), 2);
+
+        let desk_light = &lights[0];
+        assert_eq!(desk_light.id(), "kasa::50:C7:BF:00:00:01");
+        assert_eq!(desk_light.name(), "Desk");
+        assert!(desk_light.is_on());
+        assert!(desk_light.supports_color());
+        assert_eq!(desk_light.brightness(), 80);
+        assert_eq!(
+            (desk_light.red(), desk_light.green(), desk_light.blue()),
+            (0, 204, 0)
+        );
+        assert_eq!(desk_light.device_info().model.as_deref(), Some("KL130(US)"));
+
+        let hall_light = &lights[1];
+        assert!(!hall_light.is_on());
+        assert!(!hall_light.supports_color());
+        assert_eq!(
+            desk.requests(),
+            [json!({ "system": { "get_sysinfo": {} } })]
+        );
+    }
+
+    #[tokio::test]
+    async fn encodes_commands() {
+        let bulb = FakeKasaBulb::start(KasaBulbState::new("Desk", "50:C7:BF:00:00:03"))
+            .await
+            .unwrap();
+        let mut lights = discover(vec![bulb.address()], 1000).await;
+        let light = &mut lights[0];
+
+        light.set_on(false).await.unwrap();
+        light.set_color(0, 0, 255).await.unwrap();
+        light.set_brightness(25).await.unwrap();
+
+        let changes: Vec<Value> = bulb.requests()[1..]
+            .iter()
+            .map(|request| {
+                request["smartlife.iot.smartbulb.lightingservice"]["transition_light_state"]
+                    .clone()
+            })
+            .collect();
+        assert_eq!(
+            changes,
+            [
+                json!({ "on_off": 0, "transition_period": 0 }),
+                json!({
+                    "on_off": 1,
+                    "hue": 240,
+                    "saturation": 100,
+                    "brightness": 100,
+                    "transition_period": 0
+                }),
+                json!({ "on_off": 1, "brightness": 25, "transition_period": 0 }),
+            ]
+        );
+
+        let state = bulb.state();
+        assert!(state.on);
+        assert_eq!((state.hue, state.saturation, state.brightness), (240, 100, 25));
+        assert_eq!((light.red(), light.green(), light.blue()), (0, 0, 255));
+        assert_eq!(light.brightness(), 25);
+    }
+
+    #[tokio::test]
+    async fn reports_faults() {
+        let bulb = FakeKasaBulb::start(KasaBulbState::new("Desk", "50:C7:BF:00:00:04"))
+            .await
+            .unwrap();
+        let mut lights = discover(vec![bulb.address()], 200).await;
+        let light = &mut lights[0];
+
+        for fault in [Fault::Disconnect, Fault::Garbage, Fault::Silent] {
+            bulb.set_fault(fault);
+            assert!(light.set_on(false).await.is_err(), "{:?}", fault);
+            assert!(light.is_on(), "{:?}", fault);
+        }
+        assert!(bulb.state().on);
+
+        bulb.set_fault(Fault::Silent);
+        assert!(discover(vec![bulb.address()], 200).await.is_empty());
+    }
+}
@@ -0,0 +1,1305 @@
+use crate::utils::color::{hsb_to_rgb, rgb_to_hsb};
+use crate::{
+    config::CuteLightsConfig,
+    utils::{future::FutureBatch, net},
+};
+use async_trait::async_trait;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::{
+    io::{Cursor, Read},
+    net::SocketAddr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{net::UdpSocket, time::Instant};
+
+use super::{DeviceInfo, Integration, Light};
+
+const LIFX_PORT: u16 = 56700;
+
+// ANCHOR - LifxLight
+pub struct LifxLight {
+    socket: UdpSocket,
+    target: u64,
+    source: u32,
+    sequence: u8,
+    request_timeout: Duration,
+    transition: u32,
+    label: String,
+    product: Option<u32>,
+    firmware: Option<(u16, u16)>,
+    is_on: bool,
+    color: Hsbk,
+    zones: Vec<Hsbk>,
+}
+
+impl LifxLight {
+    pub async fn new(
+        addr: SocketAddr,
+        target: u64,
+        config: &LifxConfig,
+    ) -> anyhow::Result<LifxLight> {
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        socket.connect(addr).await?;
+
+        let mut light = LifxLight {
+            socket,
+            target,
+            source: client_source(),
+            sequence: 0,
+            request_timeout: Duration::from_millis(config.request_timeout),
+            transition: config.transition,
+            label: String::new(),
+            product: None,
+            firmware: None,
+            is_on: false,
+            color: Hsbk::default(),
+            zones: Vec::new(),
+        };
+
+        match light.request(Message::LightGet).await? {
+            Message::LightState {
+                color,
+                power,
+                label,
+            } => {
+                light.color = color;
+                light.is_on = power > 0;
+                light.label = label;
+            }
+            other => return Err(anyhow::anyhow!("Unexpected response {:?}", other)),
+        }
+
+        if let Ok(Message::StateVersion { product, .. }) = light.request(Message::GetVersion).await
+        {
+            light.product = Some(product);
+        }
+
+        if let Ok(Message::StateHostFirmware { major, minor }) =
+            light.request(Message::GetHostFirmware).await
+        {
+            light.firmware = Some((major, minor));
+        }
+
+        light.zones = light.read_zones().await.unwrap_or_default();
+
+        Ok(light)
+    }
+
+    /// Reads every zone of a multizone light, non multizone lights answer
+    /// with `StateUnhandled` and end up with no zones.
+    async fn read_zones(&mut self) -> anyhow::Result<Vec<Hsbk>> {
+        let sequence = self
+            .send(
+                Message::GetColorZones {
+                    start: 0,
+                    end: u8::MAX,
+                },
+                false,
+                true,
+            )
+            .await?;
+
+        let deadline = Instant::now() + self.request_timeout;
+        let mut zones: Vec<Option<Hsbk>> = Vec::new();
+
+        loop {
+            let (index, count, colors) = match self.recv(sequence, deadline).await? {
+                Message::StateZone {
+                    count,
+                    index,
+                    color,
+                } => (index, count, vec![color]),
+                Message::StateMultiZone {
+                    count,
+                    index,
+                    colors,
+                } => (index, count, colors),
+                _ => return Ok(Vec::new()),
+            };
+
+            zones.resize(count as usize, None);
+            for (offset, color) in colors.into_iter().enumerate() {
+                if let Some(zone) = zones.get_mut(index as usize + offset) {
+                    *zone = Some(color);
+                }
+            }
+
+            if zones.iter().all(Option::is_some) {
+                return Ok(zones.into_iter().flatten().collect());
+            }
+        }
+    }
+
+    async fn set_color_hsbk(&mut self, color: Hsbk) -> anyhow::Result<()> {
+        let msg = Message::LightSetColor {
+            color,
+            duration: self.transition,
+        };
+        let sequence = self.send(msg, true, false).await?;
+        self.await_ack(sequence).await?;
+        self.color = color;
+        for zone in self.zones.iter_mut() {
+            *zone = color;
+        }
+        Ok(())
+    }
+
+    /// Sends a message and returns the sequence number it went out with.
+    async fn send(
+        &mut self,
+        message: Message,
+        ack_required: bool,
+        res_required: bool,
+    ) -> anyhow::Result<u8> {
+        self.sequence = self.sequence.wrapping_add(1);
+        let packet = Packet {
+            header: Header {
+                source: self.source,
+                target: self.target,
+                tagged: false,
+                ack_required,
+                res_required,
+                sequence: self.sequence,
+            },
+            message,
+        };
+        self.socket.send(&packet.encode()).await?;
+        Ok(self.sequence)
+    }
+
+    async fn request(&mut self, message: Message) -> anyhow::Result<Message> {
+        let sequence = self.send(message, false, true).await?;
+        let deadline = Instant::now() + self.request_timeout;
+        self.recv(sequence, deadline).await
+    }
+
+    async fn await_ack(&mut self, sequence: u8) -> anyhow::Result<()> {
+        let deadline = Instant::now() + self.request_timeout;
+        loop {
+            if let Message::Acknowledgement = self.recv(sequence, deadline).await? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Waits for the next reply to `sequence`, skipping anything else.
+    async fn recv(&mut self, sequence: u8, deadline: Instant) -> anyhow::Result<Message> {
+        let mut buf = [0; 1024];
+        loop {
+            let size = tokio::time::timeout_at(deadline, self.socket.recv(&mut buf))
+                .await
+                .map_err(|_| anyhow::anyhow!("Timed out waiting for {}", self.label))??;
+
+            match Packet::decode(&buf[..size]) {
+                Ok(packet)
+                    if packet.header.source == self.source
+                        && packet.header.sequence == sequence =>
+                {
+                    return Ok(packet.message)
+                }
+                Ok(_) => continue,
+                Err(e) => eprintln!("Ignoring malformed LIFX packet: {}", e),
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Light for LifxLight {
+    async fn set_on(&mut self, on: bool) -> anyhow::Result<()> {
+        let msg = Message::LightSetPower {
+            level: if on { u16::MAX } else { 0 },
+            duration: self.transition,
+        };
+        let sequence = self.send(msg, true, false).await?;
+        self.await_ack(sequence).await?;
+        self.is_on = on;
+        Ok(())
+    }
+
+    async fn set_color(&mut self, red: u8, green: u8, blue: u8) -> anyhow::Result<()> {
+        let (hue, saturation, _) = rgb_to_hsb(red, green, blue);
+        let color = Hsbk {
+            hue: scale_to_u16(hue as i64, 360),
+            saturation: scale_to_u16(saturation as i64, 100),
+            ..self.color
+        };
+        self.set_color_hsbk(color).await
+    }
+
+    async fn set_brightness(&mut self, brightness: u8) -> anyhow::Result<()> {
+        let color = Hsbk {
+            brightness: scale_to_u16(brightness as i64, 100),
+            ..self.color
+        };
+        self.set_color_hsbk(color).await
+    }
+
+    fn id(&self) -> String {
+        format!("lifx::{}", format_mac(self.target))
+    }
+
+    fn is_on(&self) -> bool {
+        self.is_on
+    }
+
+    fn name(&self) -> String {
+        self.label.clone()
+    }
+
+    fn supports_color(&self) -> bool {
+        true
+    }
+
+    fn red(&self) -> u8 {
+        self.color.to_rgb().0
+    }
+
+    fn green(&self) -> u8 {
+        self.color.to_rgb().1
+    }
+
+    fn blue(&self) -> u8 {
+        self.color.to_rgb().2
+    }
+
+    fn brightness(&self) -> u8 {
+        scale_from_u16(self.color.brightness, 100) as u8
+    }
+
+    fn device_info(&self) -> DeviceInfo {
+        DeviceInfo {
+            manufacturer: "LIFX".to_string(),
+            model: self.product.map(|product| format!("Product {}", product)),
+            firmware_version: self
+                .firmware
+                .map(|(major, minor)| format!("{}.{}", major, minor)),
+            hardware_version: None,
+        }
+    }
+
+    fn segment_count(&self) -> usize {
+        self.zones.len()
+    }
+
+    async fn set_segment_colors(&mut self, colors: &[(u8, u8, u8)]) -> anyhow::Result<()> {
+        if colors.len() > self.zones.len() {
+            return Err(anyhow::anyhow!(
+                "{} has {} zones, got {} colors",
+                self.name(),
+                self.zones.len(),
+                colors.len()
+            ));
+        }
+
+        // Zones are buffered and only applied with the last one
+        let mut last = None;
+        for (index, (red, green, blue)) in colors.iter().enumerate() {
+            let (hue, saturation, _) = rgb_to_hsb(*red, *green, *blue);
+            let color = Hsbk {
+                hue: scale_to_u16(hue as i64, 360),
+                saturation: scale_to_u16(saturation as i64, 100),
+                ..self.color
+            };
+            let msg = Message::SetColorZones {
+                start: index as u8,
+                end: index as u8,
+                color,
+                duration: self.transition,
+                apply: index == colors.len() - 1,
+            };
+            last = Some(self.send(msg, true, false).await?);
+            self.zones[index] = color;
+        }
+
+        if let Some(sequence) = last {
+            self.await_ack(sequence).await?;
+        }
+        Ok(())
+    }
+}
+
+// ANCHOR - LifxConfig
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct LifxConfig {
+    pub enabled: bool,
+    /// Lights to query directly, in addition to the broadcast scan
+    #[serde(default)]
+    pub addresses: Vec<String>,
+    #[serde(default = "default_broadcast")]
+    pub broadcast: String,
+    #[serde(default = "default_scan_timeout")]
+    pub scan_timeout: u64,
+    #[serde(default = "default_request_timeout")]
+    pub request_timeout: u64,
+    /// Fade time in milliseconds applied to every change
+    #[serde(default)]
+    pub transition: u32,
+}
+
+impl Default for LifxConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            addresses: Vec::new(),
+            broadcast: default_broadcast(),
+            scan_timeout: default_scan_timeout(),
+            request_timeout: default_request_timeout(),
+            transition: 0,
+        }
+    }
+}
+
+fn default_broadcast() -> String {
+    format!("255.255.255.255:{}", LIFX_PORT)
+}
+
+fn default_scan_timeout() -> u64 {
+    2000
+}
+
+fn default_request_timeout() -> u64 {
+    1000
+}
+
+// ANCHOR - LifxIntegration
+
+pub struct LifxIntegration;
+
+#[async_trait]
+impl Integration for LifxIntegration {
+    fn name(&self) -> String {
+        "lifx".to_string()
+    }
+
+    fn preflight(&self, config: &CuteLightsConfig) -> bool {
+        config.lifx.enabled
+    }
+
+    async fn discover(&self, config: &CuteLightsConfig) -> anyhow::Result<Vec<Box<dyn Light>>> {
+        let mut lights = FutureBatch::new();
+
+        for (addr, target) in discover_services(&config.lifx).await? {
+            let config = config.lifx.clone();
+            lights.push(async move {
+                match LifxLight::new(addr, target, &config).await {
+                    Ok(light) => Some(Box::new(light) as Box<dyn Light>),
+                    Err(e) => {
+                        eprintln!("Failed to connect to LIFX light at {}: {}", addr, e);
+                        None
+                    }
+                }
+            });
+        }
+
+        Ok(lights.run().await.into_iter().flatten().collect())
+    }
+}
+
+// ANCHOR - Discovery
+
+/// Broadcasts `GetService` and returns the UDP service address and target of
+/// every device that answered within `scan_timeout`.
+pub async fn discover_services(config: &LifxConfig) -> anyhow::Result<Vec<(SocketAddr, u64)>> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    socket.set_broadcast(true)?;
+
+    let source = client_source();
+    let packet = Packet {
+        header: Header {
+            source,
+            target: 0,
+            tagged: true,
+            ack_required: false,
+            res_required: true,
+            sequence: 0,
+        },
+        message: Message::GetService,
+    }
+    .encode();
+
+    socket.send_to(&packet, &config.broadcast).await?;
+    for address in &config.addresses {
+        let target = net::with_default_port(address, LIFX_PORT);
+        let result = socket.send_to(&packet, target.as_str()).await;
+        if let Err(e) = result {
+            eprintln!("Failed to query LIFX light at {}: {}", address, e);
+        }
+    }
+
+    let deadline = Instant::now() + Duration::from_millis(config.scan_timeout);
+    let mut found: Vec<(SocketAddr, u64)> = Vec::new();
+    let mut buf = [0; 1024];
+
+    while let Ok(res) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let (size, from) = match res {
+            Ok(res) => res,
+            Err(e) => {
+                eprintln!("Failed to receive LIFX datagram: {}", e);
+                continue;
+            }
+        };
+
+        let packet = match Packet::decode(&buf[..size]) {
+            Ok(packet) if packet.header.source == source => packet,
+            _ => continue,
+        };
+
+        if let Message::StateService { service: 1, port } = packet.message {
+            let addr = SocketAddr::new(from.ip(), port as u16);
+            if !found
+                .iter()
+                .any(|(_, target)| *target == packet.header.target)
+            {
+                found.push((addr, packet.header.target));
+            }
+        }
+    }
+
+    Ok(found)
+}
+
+fn client_source() -> u32 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or(0);
+    // 0 and 1 make devices broadcast their replies
+    (std::process::id() ^ nanos).max(2)
+}
+
+fn format_mac(target: u64) -> String {
+    target.to_le_bytes()[..6]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+fn scale_to_u16(value: i64, max: i64) -> u16 {
+    (value.clamp(0, max) as f64 / max as f64 * u16::MAX as f64).round() as u16
+}
+
+fn scale_from_u16(value: u16, max: i64) -> i64 {
+    (value as f64 / u16::MAX as f64 * max as f64).round() as i64
+}
+
+// ANCHOR - Messages
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hsbk {
+    pub hue: u16,
+    pub saturation: u16,
+    pub brightness: u16,
+    pub kelvin: u16,
+}
+
+impl Default for Hsbk {
+    fn default() -> Self {
+        Hsbk {
+            hue: 0,
+            saturation: 0,
+            brightness: u16::MAX,
+            kelvin: 3500,
+        }
+    }
+}
+
+impl Hsbk {
+    fn to_rgb(self) -> (u8, u8, u8) {
+        // Brightness is reported separately, keep the color itself at full brightness
+        hsb_to_rgb(
+            scale_from_u16(self.hue, 360) as u16,
+            scale_from_u16(self.saturation, 100) as u8,
+            100,
+        )
+    }
+
+    fn write(&self, buf: &mut Vec<u8>) -> std::io::Result<()> {
+        buf.write_u16::<LittleEndian>(self.hue)?;
+        buf.write_u16::<LittleEndian>(self.saturation)?;
+        buf.write_u16::<LittleEndian>(self.brightness)?;
+        buf.write_u16::<LittleEndian>(self.kelvin)
+    }
+
+    fn read(cursor: &mut Cursor<&[u8]>) -> std::io::Result<Hsbk> {
+        Ok(Hsbk {
+            hue: cursor.read_u16::<LittleEndian>()?,
+            saturation: cursor.read_u16::<LittleEndian>()?,
+            brightness: cursor.read_u16::<LittleEndian>()?,
+            kelvin: cursor.read_u16::<LittleEndian>()?,
+        })
+    }
+}
+
+const HEADER_SIZE: usize = 36;
+const PROTOCOL: u16 = 1024;
+const ADDRESSABLE: u16 = 1 << 12;
+const TAGGED: u16 = 1 << 13;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub source: u32,
+    pub target: u64,
+    pub tagged: bool,
+    pub ack_required: bool,
+    pub res_required: bool,
+    pub sequence: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    GetService,
+    StateService {
+        service: u8,
+        port: u32,
+    },
+    GetHostFirmware,
+    StateHostFirmware {
+        major: u16,
+        minor: u16,
+    },
+    GetVersion,
+    StateVersion {
+        vendor: u32,
+        product: u32,
+    },
+    Acknowledgement,
+    LightGet,
+    LightSetColor {
+        color: Hsbk,
+        duration: u32,
+    },
+    LightState {
+        color: Hsbk,
+        power: u16,
+        label: String,
+    },
+    LightSetPower {
+        level: u16,
+        duration: u32,
+    },
+    LightStatePower {
+        level: u16,
+    },
+    StateUnhandled {
+        message_type: u16,
+    },
+    SetColorZones {
+        start: u8,
+        end: u8,
+        color: Hsbk,
+        duration: u32,
+        apply: bool,
+    },
+    GetColorZones {
+        start: u8,
+        end: u8,
+    },
+    StateZone {
+        count: u8,
+        index: u8,
+        color: Hsbk,
+    },
+    StateMultiZone {
+        count: u8,
+        index: u8,
+        colors: Vec<Hsbk>,
+    },
+}
+
+impl Message {
+    pub fn message_type(&self) -> u16 {
+        match self {
+            Message::GetService => 2,
+            Message::StateService { .. } => 3,
+            Message::GetHostFirmware => 14,
+            Message::StateHostFirmware { .. } => 15,
+            Message::GetVersion => 32,
+            Message::StateVersion { .. } => 33,
+            Message::Acknowledgement => 45,
+            Message::LightGet => 101,
+            Message::LightSetColor { .. } => 102,
+            Message::LightState { .. } => 107,
+            Message::LightSetPower { .. } => 117,
+            Message::LightStatePower { .. } => 118,
+            Message::StateUnhandled { .. } => 223,
+            Message::SetColorZones { .. } => 501,
+            Message::GetColorZones { .. } => 502,
+            Message::StateZone { .. } => 503,
+            Message::StateMultiZone { .. } => 506,
+        }
+    }
+
+    fn write_payload(&self, buf: &mut Vec<u8>) -> std::io::Result<()> {
+        match self {
+            Message::GetService
+            | Message::GetHostFirmware
+            | Message::GetVersion
+            | Message::Acknowledgement
+            | Message::LightGet => {}
+            Message::StateService { service, port } => {
+                buf.write_u8(*service)?;
+                buf.write_u32::<LittleEndian>(*port)?;
+            }
+            Message::StateHostFirmware { major, minor } => {
+                buf.write_u64::<LittleEndian>(0)?;
+                buf.write_u64::<LittleEndian>(0)?;
+                buf.write_u16::<LittleEndian>(*minor)?;
+                buf.write_u16::<LittleEndian>(*major)?;
+            }
+            Message::StateVersion { vendor, product } => {
+                buf.write_u32::<LittleEndian>(*vendor)?;
+                buf.write_u32::<LittleEndian>(*product)?;
+                buf.write_u32::<LittleEndian>(0)?;
+            }
+            Message::LightSetColor { color, duration } => {
+                buf.write_u8(0)?;
+                color.write(buf)?;
+                buf.write_u32::<LittleEndian>(*duration)?;
+            }
+            Message::LightState {
+                color,
+                power,
+                label,
+            } => {
+                color.write(buf)?;
+                buf.write_i16::<LittleEndian>(0)?;
+                buf.write_u16::<LittleEndian>(*power)?;
+                let mut raw = [0; 32];
+                let len = label.len().min(32);
+                raw[..len].copy_from_slice(&label.as_bytes()[..len]);
+                buf.extend_from_slice(&raw);
+                buf.write_u64::<LittleEndian>(0)?;
+            }
+            Message::LightSetPower { level, duration } => {
+                buf.write_u16::<LittleEndian>(*level)?;
+                buf.write_u32::<LittleEndian>(*duration)?;
+            }
+            Message::LightStatePower { level } => {
+                buf.write_u16::<LittleEndian>(*level)?;
+            }
+            Message::StateUnhandled { message_type } => {
+                buf.write_u16::<LittleEndian>(*message_type)?;
+            }
+            Message::SetColorZones {
+                start,
+                end,
+                color,
+                duration,
+                apply,
+            } => {
+                buf.write_u8(*start)?;
+                buf.write_u8(*end)?;
+                color.write(buf)?;
+                buf.write_u32::<LittleEndian>(*duration)?;
+                buf.write_u8(*apply as u8)?;
+            }
+            Message::GetColorZones { start, end } => {
+                buf.write_u8(*start)?;
+                buf.write_u8(*end)?;
+            }
+            Message::StateZone {
+                count,
+                index,
+                color,
+            } => {
+                buf.write_u8(*count)?;
+                buf.write_u8(*index)?;
+                color.write(buf)?;
+            }
+            Message::StateMultiZone {
+                count,
+                index,
+                colors,
+            } => {
+                buf.write_u8(*count)?;
+                buf.write_u8(*index)?;
+                for i in 0..8 {
+                    colors.get(i).copied().unwrap_or_default().write(buf)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn read_payload(message_type: u16, payload: &[u8]) -> anyhow::Result<Message> {
+        let mut cursor = Cursor::new(payload);
+        let cursor = &mut cursor;
+        let message = match message_type {
+            2 => Message::GetService,
+            3 => Message::StateService {
+                service: cursor.read_u8()?,
+                port: cursor.read_u32::<LittleEndian>()?,
+            },
+            14 => Message::GetHostFirmware,
+            15 => {
+                cursor.read_u64::<LittleEndian>()?;
+                cursor.read_u64::<LittleEndian>()?;
+                let minor = cursor.read_u16::<LittleEndian>()?;
+                let major = cursor.read_u16::<LittleEndian>()?;
+                Message::StateHostFirmware { major, minor }
+            }
+            32 => Message::GetVersion,
+            33 => Message::StateVersion {
+                vendor: cursor.read_u32::<LittleEndian>()?,
+                product: cursor.read_u32::<LittleEndian>()?,
+            },
+            45 => Message::Acknowledgement,
+            101 => Message::LightGet,
+            102 => {
+                cursor.read_u8()?;
+                Message::LightSetColor {
+                    color: Hsbk::read(cursor)?,
+                    duration: cursor.read_u32::<LittleEndian>()?,
+                }
+            }
+            107 => {
+                let color = Hsbk::read(cursor)?;
+                cursor.read_i16::<LittleEndian>()?;
+                let power = cursor.read_u16::<LittleEndian>()?;
+                let mut raw = [0; 32];
+                cursor.read_exact(&mut raw)?;
+                let len = raw.iter().position(|b| *b == 0).unwrap_or(raw.len());
+                Message::LightState {
+                    color,
+                    power,
+                    label: String::from_utf8_lossy(&raw[..len]).to_string(),
+                }
+            }
+            117 => Message::LightSetPower {
+                level: cursor.read_u16::<LittleEndian>()?,
+                duration: cursor.read_u32::<LittleEndian>()?,
+            },
+            118 => Message::LightStatePower {
+                level: cursor.read_u16::<LittleEndian>()?,
+            },
+            223 => Message::StateUnhandled {
+                message_type: cursor.read_u16::<LittleEndian>()?,
+            },
+            501 => Message::SetColorZones {
+                start: cursor.read_u8()?,
+                end: cursor.read_u8()?,
+                color: Hsbk::read(cursor)?,
+                duration: cursor.read_u32::<LittleEndian>()?,
+                apply: cursor.read_u8()? != 0,
+            },
+            502 => Message::GetColorZones {
+                start: cursor.read_u8()?,
+                end: cursor.read_u8()?,
+            },
+            503 => Message::StateZone {
+                count: cursor.read_u8()?,
+                index: cursor.read_u8()?,
+                color: Hsbk::read(cursor)?,
+            },
+            506 => {
+                let count = cursor.read_u8()?;
+                let index = cursor.read_u8()?;
+                let mut colors = Vec::new();
+                for _ in 0..8 {
+                    colors.push(Hsbk::read(cursor)?);
+                }
+                // The last packet is padded past the zone count
+                colors.truncate(count.saturating_sub(index) as usize);
+                Message::StateMultiZone {
+                    count,
+                    index,
+                    colors,
+                }
+            }
+            other => return Err(anyhow::anyhow!("Unsupported message type {}", other)),
+        };
+        Ok(message)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    pub header: Header,
+    pub message: Message,
+}
+
+impl Packet {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        self.message
+            .write_payload(&mut payload)
+            .expect("writing to a Vec cannot fail");
+
+        let mut buf = Vec::with_capacity(HEADER_SIZE + payload.len());
+        let flags = PROTOCOL | ADDRESSABLE | if self.header.tagged { TAGGED } else { 0 };
+        let response_flags = self.header.res_required as u8 | (self.header.ack_required as u8) << 1;
+
+        // Writes into a Vec never fail
+        let _ = buf.write_u16::<LittleEndian>((HEADER_SIZE + payload.len()) as u16);
+        let _ = buf.write_u16::<LittleEndian>(flags);
+        let _ = buf.write_u32::<LittleEndian>(self.header.source);
+        let _ = buf.write_u64::<LittleEndian>(self.header.target);
+        buf.extend_from_slice(&[0; 6]);
+        buf.push(response_flags);
+        buf.push(self.header.sequence);
+        buf.extend_from_slice(&[0; 8]);
+        let _ = buf.write_u16::<LittleEndian>(self.message.message_type());
+        buf.extend_from_slice(&[0; 2]);
+        buf.extend_from_slice(&payload);
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> anyhow::Result<Packet> {
+        if buf.len() < HEADER_SIZE {
+            return Err(anyhow::anyhow!("Packet too short ({} bytes)", buf.len()));
+        }
+
+        let mut cursor = Cursor::new(buf);
+        let size = cursor.read_u16::<LittleEndian>()? as usize;
+        if size != buf.len() {
+            return Err(anyhow::anyhow!(
+                "Packet size {} does not match {} received bytes",
+                size,
+                buf.len()
+            ));
+        }
+
+        let flags = cursor.read_u16::<LittleEndian>()?;
+        if flags & 0x0fff != PROTOCOL {
+            return Err(anyhow::anyhow!("Unknown protocol {}", flags & 0x0fff));
+        }
+        let source = cursor.read_u32::<LittleEndian>()?;
+        let target = cursor.read_u64::<LittleEndian>()?;
+        cursor.set_position(22);
+        let response_flags = cursor.read_u8()?;
+        let sequence = cursor.read_u8()?;
+        cursor.set_position(32);
+        let message_type = cursor.read_u16::<LittleEndian>()?;
+
+        Ok(Packet {
+            header: Header {
+                source,
+                target,
+                tagged: flags & TAGGED != 0,
+                ack_required: response_flags & 0b10 != 0,
+                res_required: response_flags & 0b01 != 0,
+                sequence,
+            },
+            message: Message::read_payload(message_type, &buf[HEADER_SIZE..])?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn encodes_documented_set_color_packet() {
+        // The SetColor example from the LIFX LAN protocol documentation
+        let expected: [u8; 49] = [
+            0x31, 0x00, 0x00, 0x34, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x66, 0x00, 0x00, 0x00, 0x00, 0x55, 0x55, 0xFF, 0xFF, 0xFF,
+            0xFF, 0xAC, 0x0D, 0x00, 0x04, 0x00, 0x00,
+        ];
+        let packet = Packet {
+            header: Header {
+                source: 0,
+                target: 0,
+                tagged: true,
+                ack_required: false,
+                res_required: false,
+                sequence: 0,
+            },
+            message: Message::LightSetColor {
+                color: Hsbk {
+                    hue: 21845,
+                    saturation: u16::MAX,
+                    brightness: u16::MAX,
+                    kelvin: 3500,
+                },
+                duration: 1024,
+            },
+        };
+
+        assert_eq!(packet.encode(), expected);
+        assert_eq!(Packet::decode(&expected).unwrap(), packet);
+    }
+
+    #[test]
+    fn round_trips_every_message() {
+        let color = Hsbk {
+            hue: 1,
+            saturation: 2,
+            brightness: 3,
+            kelvin: 4,
+        };
+        let messages = vec![
+            Message::GetService,
+            Message::StateService {
+                service: 1,
+                port: 56700,
+            },
+            Message::GetHostFirmware,
+            Message::StateHostFirmware {
+                major: 3,
+                minor: 70,
+            },
+            Message::GetVersion,
+            Message::StateVersion {
+                vendor: 1,
+                product: 32,
+            },
+            Message::Acknowledgement,
+            Message::LightGet,
+            Message::LightSetColor {
+                color,
+                duration: 500,
+            },
+            Message::LightState {
+                color,
+                power: u16::MAX,
+                label: "Kitchen".to_string(),
+            },
+            Message::LightSetPower {
+                level: u16::MAX,
+                duration: 10,
+            },
+            Message::LightStatePower { level: 0 },
+            Message::StateUnhandled { message_type: 502 },
+            Message::SetColorZones {
+                start: 2,
+                end: 5,
+                color,
+                duration: 0,
+                apply: true,
+            },
+            Message::GetColorZones { start: 0, end: 255 },
+            Message::StateZone {
+                count: 16,
+                index: 3,
+                color,
+            },
+            Message::StateMultiZone {
+                count: 16,
+                index: 8,
+                colors: vec![color; 8],
+            },
+        ];
+
+        for message in messages {
+            let packet = Packet {
+                header: Header {
+                    source: 0xdead_beef,
+                    target: 0x0000_5544_3322_11d0,
+                    tagged: false,
+                    ack_required: true,
+                    res_required: true,
+                    sequence: 42,
+                },
+                message,
+            };
+            assert_eq!(Packet::decode(&packet.encode()).unwrap(), packet);
+        }
+    }
+
+    #[test]
+    fn trims_padding_from_last_multizone_packet() {
+        let packet = Packet {
+            header: Header {
+                source: 2,
+                target: 0,
+                tagged: false,
+                ack_required: false,
+                res_required: false,
+                sequence: 1,
+            },
+            message: Message::StateMultiZone {
+                count: 10,
+                index: 8,
+                colors: vec![Hsbk::default(); 8],
+            },
+        };
+
+        match Packet::decode(&packet.encode()).unwrap().message {
+            Message::StateMultiZone { colors, .. } => assert_eq!(colors.len(), 2),
+            other => panic!("Unexpected message {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_packets() {
+        assert!(Packet::decode(&[0; 10]).is_err());
+
+        let mut truncated = Packet {
+            header: Header {
+                source: 2,
+                target: 0,
+                tagged: false,
+                ack_required: false,
+                res_required: false,
+                sequence: 1,
+            },
+            message: Message::LightGet,
+        }
+        .encode();
+        truncated[0] = 40;
+        assert!(Packet::decode(&truncated).is_err());
+    }
+
+    #[test]
+    fn formats_target_as_mac() {
+        assert_eq!(format_mac(0x0000_5544_3322_11d0), "d0:11:22:33:44:55");
+    }
+
+    // ANCHOR - Fake Device
+
+    struct FakeState {
+        label: String,
+        power: u16,
+        color: Hsbk,
+        zones: Vec<Hsbk>,
+        received: Vec<Message>,
+    }
+
+    /// A LIFX device answering on a loopback port.
+    struct FakeDevice {
+        addr: SocketAddr,
+        target: u64,
+        state: Arc<Mutex<FakeState>>,
+    }
+
+    impl FakeDevice {
+        async fn spawn(label: &str, zones: usize) -> FakeDevice {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let addr = socket.local_addr().unwrap();
+            let target = 0x0000_0605_0403_02d0;
+            let state = Arc::new(Mutex::new(FakeState {
+                label: label.to_string(),
+                power: 0,
+                color: Hsbk::default(),
+                zones: vec![Hsbk::default(); zones],
+                received: Vec::new(),
+            }));
+
+            let device_state = state.clone();
+            tokio::spawn(async move {
+                let mut buf = [0; 1024];
+                while let Ok((size, from)) = socket.recv_from(&mut buf).await {
+                    let packet = Packet::decode(&buf[..size]).unwrap();
+                    let replies = device_state.lock().unwrap().handle(&packet, addr);
+                    for message in replies {
+                        let reply = Packet {
+                            header: Header {
+                                target,
+                                tagged: false,
+                                ack_required: false,
+                                res_required: false,
+                                ..packet.header.clone()
+                            },
+                            message,
+                        };
+                        socket.send_to(&reply.encode(), from).await.unwrap();
+                    }
+                }
+            });
+
+            FakeDevice {
+                addr,
+                target,
+                state,
+            }
+        }
+
+        fn config(&self) -> LifxConfig {
+            LifxConfig {
+                enabled: true,
+                broadcast: self.addr.to_string(),
+                scan_timeout: 200,
+                request_timeout: 200,
+                ..Default::default()
+            }
+        }
+    }
+
+    impl FakeState {
+        fn handle(&mut self, packet: &Packet, addr: SocketAddr) -> Vec<Message> {
+            self.received.push(packet.message.clone());
+
+            let mut replies = Vec::new();
+            if packet.header.ack_required {
+                replies.push(Message::Acknowledgement);
+            }
+
+            match &packet.message {
+                Message::GetService => replies.push(Message::StateService {
+                    service: 1,
+                    port: addr.port() as u32,
+                }),
+                Message::GetVersion => replies.push(Message::StateVersion {
+                    vendor: 1,
+                    product: 32,
+                }),
+                Message::GetHostFirmware => replies.push(Message::StateHostFirmware {
+                    major: 3,
+                    minor: 70,
+                }),
+                Message::LightGet => replies.push(self.light_state()),
+                Message::LightSetColor { color, .. } => {
+                    self.color = *color;
+                    self.zones.iter_mut().for_each(|zone| *zone = *color);
+                }
+                Message::LightSetPower { level, .. } => self.power = *level,
+                Message::SetColorZones {
+                    start, end, color, ..
+                } => {
+                    for zone in *start..=*end {
+                        self.zones[zone as usize] = *color;
+                    }
+                }
+                Message::GetColorZones { .. } if self.zones.is_empty() => {
+                    replies.push(Message::StateUnhandled { message_type: 502 })
+                }
+                Message::GetColorZones { .. } => {
+                    for (chunk, colors) in self.zones.chunks(8).enumerate() {
+                        replies.push(Message::StateMultiZone {
+                            count: self.zones.len() as u8,
+                            index: (chunk * 8) as u8,
+                            colors: colors.to_vec(),
+                        });
+                    }
+                }
+                _ => {}
+            }
+
+            replies
+        }
+
+        fn light_state(&self) -> Message {
+            Message::LightState {
+                color: self.color,
+                power: self.power,
+                label: self.label.clone(),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn discovers_fake_device() {
+        let device = FakeDevice::spawn("Bedroom", 0).await;
+
+        let found = discover_services(&device.config()).await.unwrap();
+
+        assert_eq!(found, vec![(device.addr, device.target)]);
+    }
+
+    #[tokio::test]
+    async fn reads_initial_state() {
+        let device = FakeDevice::spawn("Bedroom", 0).await;
+        device.state.lock().unwrap().power = u16::MAX;
+
+        let light = LifxLight::new(device.addr, device.target, &device.config())
+            .await
+            .unwrap();
+
+        assert_eq!(light.name(), "Bedroom");
+        assert_eq!(light.id(), "lifx::d0:02:03:04:05:06");
+        assert!(light.is_on());
+        assert_eq!(light.brightness(), 100);
+        assert_eq!(light.segment_count(), 0);
+        assert_eq!(
+            light.device_info().firmware_version.as_deref(),
+            Some("3.70")
+        );
+    }
+
+    #[tokio::test]
+    async fn sets_power_and_color() {
+        let device = FakeDevice::spawn("Bedroom", 0).await;
+        let mut light = LifxLight::new(device.addr, device.target, &device.config())
+            .await
+            .unwrap();
+
+        light.set_on(true).await.unwrap();
+        light.set_color(0, 0, 255).await.unwrap();
+        light.set_brightness(50).await.unwrap();
+
+        let state = device.state.lock().unwrap();
+        assert_eq!(state.power, u16::MAX);
+        assert_eq!(scale_from_u16(state.color.hue, 360), 240);
+        assert_eq!(state.color.saturation, u16::MAX);
+        assert_eq!(scale_from_u16(state.color.brightness, 100), 50);
+        assert!(light.is_on());
+        assert_eq!(light.brightness(), 50);
+    }
+
+    #[tokio::test]
+    async fn keeps_pastels_pale() {
+        let device = FakeDevice::spawn("Bedroom", 0).await;
+        let mut light = LifxLight::new(device.addr, device.target, &device.config())
+            .await
+            .unwrap();
+
+        light.set_color(255, 128, 128).await.unwrap();
+
+        let color = device.state.lock().unwrap().color;
+        assert_eq!(scale_from_u16(color.hue, 360), 0);
+        assert_eq!(scale_from_u16(color.saturation, 100), 50);
+        assert_eq!((light.red(), light.green(), light.blue()), (255, 128, 128));
+    }
+
+    #[tokio::test]
+    async fn colors_multizone_strip() {
+        let device = FakeDevice::spawn("Strip", 10).await;
+        let mut light = LifxLight::new(device.addr, device.target, &device.config())
+            .await
+            .unwrap();
+        assert_eq!(light.segment_count(), 10);
+
+        light
+            .set_segment_colors(&[(255, 0, 0), (0, 255, 0), (0, 0, 255)])
+            .await
+            .unwrap();
+
+        let state = device.state.lock().unwrap();
+        let hues: Vec<i64> = state
+            .zones
+            .iter()
+            .take(3)
+            .map(|zone| scale_from_u16(zone.hue, 360))
+            .collect();
+        assert_eq!(hues, vec![0, 120, 240]);
+        let applied: Vec<bool> = state
+            .received
+            .iter()
+            .filter_map(|message| match message {
+                Message::SetColorZones { apply, .. } => Some(*apply),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(applied, vec![false, false, true]);
+    }
+
+    #[tokio::test]
+    async fn rejects_more_colors_than_zones() {
+        let device = FakeDevice::spawn("Bulb", 0).await;
+        let mut light = LifxLight::new(device.addr, device.target, &device.config())
+            .await
+            .unwrap();
+
+        assert!(light.set_segment_colors(&[(255, 0, 0)]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn times_out_without_device() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let config = LifxConfig {
+            request_timeout: 100,
+            ..Default::default()
+        };
+
+        let result = LifxLight::new(socket.local_addr().unwrap(), 0, &config).await;
+
+        assert!(result.is_err());
+    }
+}
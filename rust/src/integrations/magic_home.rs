@@ -0,0 +1,830 @@
+use crate::{config::CuteLightsConfig, utils::future::FutureBatch};
+use async_trait::async_trait;
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+    time::Instant,
+};
+
+use super::{DeviceInfo, Integration, Light};
+
+const DISCOVERY_PORT: u16 = 48899;
+const CONTROL_PORT: u16 = 5577;
+const DISCOVERY_MESSAGE: &[u8] = b"HF-A11ASSISTHREAD";
+
+// Built in patterns, the names follow the Magic Home app
+const PATTERNS: [(u8, &str); 20] = [
+    (0x25, "Seven Color Cross Fade"),
+    (0x26, "Red Gradual Change"),
+    (0x27, "Green Gradual Change"),
+    (0x28, "Blue Gradual Change"),
+    (0x29, "Yellow Gradual Change"),
+    (0x2A, "Cyan Gradual Change"),
+    (0x2B, "Purple Gradual Change"),
+    (0x2C, "White Gradual Change"),
+    (0x2D, "Red Green Cross Fade"),
+    (0x2E, "Red Blue Cross Fade"),
+    (0x2F, "Green Blue Cross Fade"),
+    (0x30, "Seven Color Strobe Flash"),
+    (0x31, "Red Strobe Flash"),
+    (0x32, "Green Strobe Flash"),
+    (0x33, "Blue Strobe Flash"),
+    (0x34, "Yellow Strobe Flash"),
+    (0x35, "Cyan Strobe Flash"),
+    (0x36, "Purple Strobe Flash"),
+    (0x37, "White Strobe Flash"),
+    (0x38, "Seven Color Jumping"),
+];
+
+// The white LEDs of RGBWW controllers are mixed between these
+const WARM_KELVIN: u16 = 2700;
+const COOL_KELVIN: u16 = 6500;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    Color,
+    White { kelvin: u16 },
+}
+
+// ANCHOR - MagicHomeLight
+pub struct MagicHomeLight {
+    stream: TcpStream,
+    request_timeout: Duration,
+    pattern_speed: u8,
+    advertisement: Advertisement,
+    state: DeviceState,
+    mode: Mode,
+    brightness: u8,
+    // Full brightness color, the controller only knows scaled levels
+    red: u8,
+    green: u8,
+    blue: u8,
+}
+
+impl MagicHomeLight {
+    pub async fn new(
+        advertisement: Advertisement,
+        config: &MagicHomeConfig,
+    ) -> anyhow::Result<MagicHomeLight> {
+        let request_timeout = Duration::from_millis(config.request_timeout);
+        let addr = SocketAddr::from((advertisement.ip, config.port));
+        let stream = tokio::time::timeout(request_timeout, TcpStream::connect(addr))
+            .await
+            .map_err(|_| anyhow::anyhow!("Timed out connecting to {}", addr))??;
+
+        let mut light = MagicHomeLight {
+            stream,
+            request_timeout,
+            pattern_speed: config.pattern_speed,
+            advertisement,
+            state: DeviceState::default(),
+            mode: Mode::Color,
+            brightness: 0,
+            red: 0,
+            green: 0,
+            blue: 0,
+        };
+
+        light.refresh().await?;
+        Ok(light)
+    }
+
+    /// Queries the controller and recomputes color and brightness from the
+    /// levels it reports.
+    pub async fn refresh(&mut self) -> anyhow::Result<()> {
+        self.send(Command::QueryState).await?;
+        let state = self.read_state().await?;
+
+        let channels = Channels::of(state.model);
+        let white = state.warm_white.max(if channels.cool_white {
+            state.cool_white
+        } else {
+            0
+        });
+        let color = state.red.max(state.green).max(state.blue);
+
+        if channels.warm_white && white > color {
+            let kelvin = if channels.cool_white {
+                let total = state.warm_white as u32 + state.cool_white as u32;
+                let cool = state.cool_white as u32 * (COOL_KELVIN - WARM_KELVIN) as u32;
+                WARM_KELVIN + cool.checked_div(total).unwrap_or(0) as u16
+            } else {
+                WARM_KELVIN
+            };
+            self.mode = Mode::White { kelvin };
+            self.brightness = scale(white, 255, 100);
+            (self.red, self.green, self.blue) = (255, 255, 255);
+        } else {
+            self.mode = Mode::Color;
+            self.brightness = scale(color, 255, 100);
+            if color > 0 {
+                self.red = scale(state.red, color, 255);
+                self.green = scale(state.green, color, 255);
+                self.blue = scale(state.blue, color, 255);
+            }
+        }
+
+        self.state = state;
+        Ok(())
+    }
+
+    async fn send(&mut self, command: Command) -> anyhow::Result<()> {
+        tokio::time::timeout(
+            self.request_timeout,
+            self.stream.write_all(&command.encode()),
+        )
+        .await
+        .map_err(|_| anyhow::anyhow!("Timed out writing to {}", self.advertisement.ip))??;
+        Ok(())
+    }
+
+    /// Reads until a state response with a valid checksum turns up. Some
+    /// firmware acknowledges commands, those replies are skipped here.
+    async fn read_state(&mut self) -> anyhow::Result<DeviceState> {
+        let deadline = Instant::now() + self.request_timeout;
+        let mut buf: Vec<u8> = Vec::new();
+        let mut chunk = [0; 64];
+
+        loop {
+            while let Some(start) = buf.iter().position(|b| *b == STATE_HEADER) {
+                buf.drain(..start);
+                if buf.len() < STATE_LEN {
+                    break;
+                }
+                match DeviceState::decode(&buf[..STATE_LEN]) {
+                    Ok(state) => return Ok(state),
+                    Err(_) => {
+                        buf.remove(0);
+                    }
+                }
+            }
+            if !buf.contains(&STATE_HEADER) {
+                buf.clear();
+            }
+
+            let size = tokio::time::timeout_at(deadline, self.stream.read(&mut chunk))
+                .await
+                .map_err(|_| anyhow::anyhow!("Timed out waiting for state"))??;
+            if size == 0 {
+                return Err(anyhow::anyhow!("{} closed the connection", self.name()));
+            }
+            buf.extend_from_slice(&chunk[..size]);
+        }
+    }
+
+    async fn write_color(&mut self, brightness: u8) -> anyhow::Result<()> {
+        let channels = Channels::of(self.state.model);
+        self.send(Command::SetLevels {
+            red: dim(self.red, brightness),
+            green: dim(self.green, brightness),
+            blue: dim(self.blue, brightness),
+            warm_white: 0,
+            cool_white: channels.cool_white.then_some(0),
+            mask: MASK_COLOR,
+        })
+        .await
+    }
+
+    async fn write_white(&mut self, kelvin: u16, brightness: u8) -> anyhow::Result<()> {
+        let channels = Channels::of(self.state.model);
+        let level = dim(255, brightness);
+        let (warm_white, cool_white) = if channels.cool_white {
+            let kelvin = kelvin.clamp(WARM_KELVIN, COOL_KELVIN);
+            let cool = ((kelvin - WARM_KELVIN) as u32 * level as u32
+                / (COOL_KELVIN - WARM_KELVIN) as u32) as u8;
+            (level - cool, Some(cool))
+        } else {
+            (level, None)
+        };
+
+        self.send(Command::SetLevels {
+            red: 0,
+            green: 0,
+            blue: 0,
+            warm_white,
+            cool_white,
+            mask: MASK_WHITE,
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl Light for MagicHomeLight {
+    async fn set_on(&mut self, on: bool) -> anyhow::Result<()> {
+        self.send(Command::SetPower(on)).await?;
+        self.state.is_on = on;
+        Ok(())
+    }
+
+    async fn set_color(&mut self, red: u8, green: u8, blue: u8) -> anyhow::Result<()> {
+        if !self.supports_color() {
+            return Err(anyhow::anyhow!("{} does not support color", self.name()));
+        }
+        self.red = red;
+        self.green = green;
+        self.blue = blue;
+        self.mode = Mode::Color;
+        self.write_color(self.brightness).await
+    }
+
+    async fn set_brightness(&mut self, brightness: u8) -> anyhow::Result<()> {
+        let brightness = brightness.min(100);
+        match self.mode {
+            Mode::Color => self.write_color(brightness).await?,
+            Mode::White { kelvin } => self.write_white(kelvin, brightness).await?,
+        }
+        self.brightness = brightness;
+        Ok(())
+    }
+
+    fn id(&self) -> String {
+        format!("magic_home::{}", self.advertisement.mac)
+    }
+
+    fn is_on(&self) -> bool {
+        self.state.is_on
+    }
+
+    fn name(&self) -> String {
+        format!("Magic Home ({})", self.advertisement.mac)
+    }
+
+    fn supports_color(&self) -> bool {
+        Channels::of(self.state.model).rgb
+    }
+
+    fn red(&self) -> u8 {
+        self.red
+    }
+
+    fn green(&self) -> u8 {
+        self.green
+    }
+
+    fn blue(&self) -> u8 {
+        self.blue
+    }
+
+    fn brightness(&self) -> u8 {
+        self.brightness
+    }
+
+    fn device_info(&self) -> DeviceInfo {
+        DeviceInfo {
+            manufacturer: "Magic Home".to_string(),
+            model: Some(self.advertisement.model.clone()),
+            firmware_version: Some(self.state.version.to_string()),
+            hardware_version: Some(format!("0x{:02X}", self.state.model)),
+        }
+    }
+
+    fn supports_color_temperature(&self) -> bool {
+        Channels::of(self.state.model).warm_white
+    }
+
+    async fn set_color_temperature(&mut self, kelvin: u16) -> anyhow::Result<()> {
+        if !self.supports_color_temperature() {
+            return Err(anyhow::anyhow!(
+                "{} does not support color temperature",
+                self.name()
+            ));
+        }
+        self.write_white(kelvin, self.brightness).await?;
+        self.mode = Mode::White { kelvin };
+        Ok(())
+    }
+
+    fn effects(&self) -> Vec<String> {
+        if !self.supports_color() {
+            return Vec::new();
+        }
+        PATTERNS.iter().map(|(_, name)| name.to_string()).collect()
+    }
+
+    async fn set_effect(&mut self, effect: &str) -> anyhow::Result<()> {
+        let (pattern, _) = PATTERNS
+            .iter()
+            .find(|(_, name)| name.eq_ignore_ascii_case(effect))
+            .ok_or_else(|| anyhow::anyhow!("Unknown Magic Home pattern {}", effect))?;
+        self.send(Command::SetPattern {
+            pattern: *pattern,
+            delay: speed_to_delay(self.pattern_speed),
+        })
+        .await
+    }
+}
+
+/// Rescales `value` from `0..=from` to `0..=to`.
+fn scale(value: u8, from: u8, to: u8) -> u8 {
+    if from == 0 {
+        return 0;
+    }
+    ((value.min(from) as u32 * to as u32 + from as u32 / 2) / from as u32) as u8
+}
+
+/// Scales a full brightness level down to `brightness` percent.
+fn dim(level: u8, brightness: u8) -> u8 {
+    ((level as u32 * brightness.min(100) as u32 + 50) / 100) as u8
+}
+
+/// Patterns take a delay from 1 (fastest) to 31 (slowest) instead of a speed.
+fn speed_to_delay(speed: u8) -> u8 {
+    let speed = speed.min(100) as u16;
+    (1 + (100 - speed) * 30 / 100) as u8
+}
+
+// ANCHOR - MagicHomeConfig
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct MagicHomeConfig {
+    pub enabled: bool,
+    /// Controllers to query directly, in addition to the broadcast scan
+    #[serde(default)]
+    pub addresses: Vec<String>,
+    #[serde(default = "default_broadcast")]
+    pub broadcast: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    #[serde(default = "default_scan_timeout")]
+    pub scan_timeout: u64,
+    #[serde(default = "default_request_timeout")]
+    pub request_timeout: u64,
+    /// Speed of the built in patterns, 0 to 100
+    #[serde(default = "default_pattern_speed")]
+    pub pattern_speed: u8,
+}
+
+impl Default for MagicHomeConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            addresses: Vec::new(),
+            broadcast: default_broadcast(),
+            port: default_port(),
+            scan_timeout: default_scan_timeout(),
+            request_timeout: default_request_timeout(),
+            pattern_speed: default_pattern_speed(),
+        }
+    }
+}
+
+fn default_broadcast() -> String {
+    format!("255.255.255.255:{}", DISCOVERY_PORT)
+}
+
+fn default_port() -> u16 {
+    CONTROL_PORT
+}
+
+fn default_scan_timeout() -> u64 {
+    2000
+}
+
+fn default_request_timeout() -> u64 {
+    1000
+}
+
+fn default_pattern_speed() -> u8 {
+    50
+}
+
+// ANCHOR - MagicHomeIntegration
+
+pub struct MagicHomeIntegration;
+
+#[async_trait]
+impl Integration for MagicHomeIntegration {
+    fn name(&self) -> String {
+        "magic_home".to_string()
+    }
+
+    fn preflight(&self, config: &CuteLightsConfig) -> bool {
+        config.magic_home.enabled
+    }
+
+    async fn discover(&self, config: &CuteLightsConfig) -> anyhow::Result<Vec<Box<dyn Light>>> {
+        let mut lights = FutureBatch::new();
+
+        for advertisement in discover_controllers(&config.magic_home).await? {
+            let config = config.magic_home.clone();
+            lights.push(async move {
+                let ip = advertisement.ip;
+                match MagicHomeLight::new(advertisement, &config).await {
+                    Ok(light) => Some(Box::new(light) as Box<dyn Light>),
+                    Err(e) => {
+                        eprintln!(
+                            "Failed to connect to Magic Home controller at {}: {}",
+                            ip, e
+                        );
+                        None
+                    }
+                }
+            });
+        }
+
+        Ok(lights.run().await.into_iter().flatten().collect())
+    }
+}
+
+// ANCHOR - Discovery
+
+/// Broadcasts the discovery message and returns every controller that
+/// answered within `scan_timeout`.
+pub async fn discover_controllers(config: &MagicHomeConfig) -> anyhow::Result<Vec<Advertisement>> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    socket.set_broadcast(true)?;
+
+    socket.send_to(DISCOVERY_MESSAGE, &config.broadcast).await?;
+    for address in &config.addresses {
+        let result = if address.contains(':') {
+            socket.send_to(DISCOVERY_MESSAGE, address.as_str()).await
+        } else {
+            socket
+                .send_to(DISCOVERY_MESSAGE, (address.as_str(), DISCOVERY_PORT))
+                .await
+        };
+        if let Err(e) = result {
+            eprintln!(
+                "Failed to query Magic Home controller at {}: {}",
+                address, e
+            );
+        }
+    }
+
+    let deadline = Instant::now() + Duration::from_millis(config.scan_timeout);
+    let mut found: Vec<Advertisement> = Vec::new();
+    let mut buf = [0; 256];
+
+    while let Ok(res) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let size = match res {
+            Ok((size, _)) => size,
+            Err(e) => {
+                eprintln!("Failed to receive Magic Home datagram: {}", e);
+                continue;
+            }
+        };
+
+        // Our own broadcast can loop back, it does not parse
+        if let Some(advertisement) = Advertisement::parse(&String::from_utf8_lossy(&buf[..size])) {
+            if !found.iter().any(|a| a.mac == advertisement.mac) {
+                found.push(advertisement);
+            }
+        }
+    }
+
+    Ok(found)
+}
+
+// ANCHOR - Messages
+
+const STATE_HEADER: u8 = 0x81;
+const STATE_LEN: usize = 14;
+const POWER_ON: u8 = 0x23;
+const POWER_OFF: u8 = 0x24;
+// Marks a command as sent from a local (LAN) client
+const LOCAL: u8 = 0x0F;
+// Which levels of a SetLevels command the controller applies
+const MASK_COLOR: u8 = 0xF0;
+const MASK_WHITE: u8 = 0x0F;
+
+/// A discovery reply, `<ip>,<mac>,<model>`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Advertisement {
+    pub ip: Ipv4Addr,
+    pub mac: String,
+    pub model: String,
+}
+
+impl Advertisement {
+    pub fn parse(reply: &str) -> Option<Advertisement> {
+        let mut parts = reply.trim().splitn(3, ',');
+        let ip = parts.next()?.parse().ok()?;
+        let mac = parts.next()?;
+        if mac.len() != 12 || !mac.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        Some(Advertisement {
+            ip,
+            mac: mac.to_string(),
+            model: parts.next().unwrap_or_default().to_string(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    QueryState,
+    SetPower(bool),
+    /// Sent as the 9 byte form when `cool_white` is set, 8 bytes otherwise
+    SetLevels {
+        red: u8,
+        green: u8,
+        blue: u8,
+        warm_white: u8,
+        cool_white: Option<u8>,
+        mask: u8,
+    },
+    SetPattern {
+        pattern: u8,
+        delay: u8,
+    },
+}
+
+impl Command {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = match self {
+            Command::QueryState => vec![0x81, 0x8A, 0x8B],
+            Command::SetPower(on) => vec![0x71, if *on { POWER_ON } else { POWER_OFF }, LOCAL],
+            Command::SetLevels {
+                red,
+                green,
+                blue,
+                warm_white,
+                cool_white,
+                mask,
+            } => {
+                let mut buf = vec![0x31, *red, *green, *blue, *warm_white];
+                if let Some(cool_white) = cool_white {
+                    buf.push(*cool_white);
+                }
+                buf.extend_from_slice(&[*mask, LOCAL]);
+                buf
+            }
+            Command::SetPattern { pattern, delay } => vec![0x61, *pattern, *delay, LOCAL],
+        };
+        buf.push(checksum(&buf));
+        buf
+    }
+}
+
+/// The low byte of the sum of every byte.
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DeviceState {
+    pub model: u8,
+    pub is_on: bool,
+    pub pattern: u8,
+    pub delay: u8,
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+    pub warm_white: u8,
+    pub version: u8,
+    pub cool_white: u8,
+}
+
+impl DeviceState {
+    pub fn decode(buf: &[u8]) -> anyhow::Result<DeviceState> {
+        if buf.len() != STATE_LEN || buf[0] != STATE_HEADER {
+            return Err(anyhow::anyhow!("Not a Magic Home state response"));
+        }
+        if checksum(&buf[..STATE_LEN - 1]) != buf[STATE_LEN - 1] {
+            return Err(anyhow::anyhow!("Magic Home state has a bad checksum"));
+        }
+        Ok(DeviceState {
+            model: buf[1],
+            is_on: buf[2] == POWER_ON,
+            pattern: buf[3],
+            delay: buf[5],
+            red: buf[6],
+            green: buf[7],
+            blue: buf[8],
+            warm_white: buf[9],
+            version: buf[10],
+            cool_white: buf[11],
+        })
+    }
+
+    #[cfg(test)]
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![
+            STATE_HEADER,
+            self.model,
+            if self.is_on { POWER_ON } else { POWER_OFF },
+            self.pattern,
+            0x21,
+            self.delay,
+            self.red,
+            self.green,
+            self.blue,
+            self.warm_white,
+            self.version,
+            self.cool_white,
+            MASK_COLOR,
+        ];
+        buf.push(checksum(&buf));
+        buf
+    }
+}
+
+/// Which LED channels a controller drives, by the model byte of its state.
+struct Channels {
+    rgb: bool,
+    warm_white: bool,
+    cool_white: bool,
+}
+
+impl Channels {
+    fn of(model: u8) -> Channels {
+        let (rgb, warm_white, cool_white) = match model {
+            // Single color dimmers
+            0x21 | 0x41 => (false, true, false),
+            // RGBW strip controllers and bulbs
+            0x04 | 0x06 | 0x44 => (true, true, false),
+            // RGBWW strip controllers and RGBCW bulbs
+            0x07 | 0x25 | 0x35 => (true, true, true),
+            _ => (true, false, false),
+        };
+        Channels {
+            rgb,
+            warm_white,
+            cool_white,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_commands_with_checksum() {
+        assert_eq!(Command::QueryState.encode(), [0x81, 0x8A, 0x8B, 0x96]);
+        assert_eq!(Command::SetPower(true).encode(), [0x71, 0x23, 0x0F, 0xA3]);
+        assert_eq!(Command::SetPower(false).encode(), [0x71, 0x24, 0x0F, 0xA4]);
+        assert_eq!(
+            Command::SetPattern {
+                pattern: 0x25,
+                delay: 0x10
+            }
+            .encode(),
+            [0x61, 0x25, 0x10, 0x0F, 0xA5]
+        );
+    }
+
+    #[test]
+    fn encodes_eight_and_nine_byte_levels() {
+        let rgb = Command::SetLevels {
+            red: 0xFF,
+            green: 0x00,
+            blue: 0x00,
+            warm_white: 0x00,
+            cool_white: None,
+            mask: MASK_COLOR,
+        };
+        assert_eq!(
+            rgb.encode(),
+            [0x31, 0xFF, 0x00, 0x00, 0x00, 0xF0, 0x0F, 0x2F]
+        );
+
+        let white = Command::SetLevels {
+            red: 0x00,
+            green: 0x00,
+            blue: 0x00,
+            warm_white: 0x80,
+            cool_white: Some(0x40),
+            mask: MASK_WHITE,
+        };
+        assert_eq!(
+            white.encode(),
+            [0x31, 0x00, 0x00, 0x00, 0x80, 0x40, 0x0F, 0x0F, 0x0F]
+        );
+    }
+
+    #[test]
+    fn checksum_wraps() {
+        assert_eq!(checksum(&[]), 0);
+        assert_eq!(checksum(&[0xFF, 0x02]), 0x01);
+    }
+
+    #[test]
+    fn decodes_state_response() {
+        let buf = [
+            0x81, 0x25, 0x23, 0x61, 0x21, 0x10, 0xFF, 0x00, 0x00, 0x00, 0x08, 0x00, 0xF0, 0x52,
+        ];
+        let state = DeviceState::decode(&buf).unwrap();
+
+        assert_eq!(
+            state,
+            DeviceState {
+                model: 0x25,
+                is_on: true,
+                pattern: 0x61,
+                delay: 0x10,
+                red: 0xFF,
+                green: 0x00,
+                blue: 0x00,
+                warm_white: 0x00,
+                version: 0x08,
+                cool_white: 0x00,
+            }
+        );
+        assert_eq!(state.encode(), buf);
+    }
+
+    #[test]
+    fn rejects_bad_state_responses() {
+        let mut buf = DeviceState::default().encode();
+        buf[13] = buf[13].wrapping_add(1);
+        assert!(DeviceState::decode(&buf).is_err());
+
+        assert!(DeviceState::decode(&[0x81, 0x25]).is_err());
+
+        let mut buf = DeviceState::default().encode();
+        buf[0] = 0xF0;
+        assert!(DeviceState::decode(&buf).is_err());
+    }
+
+    #[test]
+    fn parses_discovery_replies() {
+        assert_eq!(
+            Advertisement::parse("192.168.1.50,ACCF23A1B2C3,HF-LPB100-ZJ200"),
+            Some(Advertisement {
+                ip: Ipv4Addr::new(192, 168, 1, 50),
+                mac: "ACCF23A1B2C3".to_string(),
+                model: "HF-LPB100-ZJ200".to_string(),
+            })
+        );
+        assert_eq!(
+            Advertisement::parse("10.0.0.2,600194000001,"),
+            Some(Advertisement {
+                ip: Ipv4Addr::new(10, 0, 0, 2),
+                mac: "600194000001".to_string(),
+                model: String::new(),
+            })
+        );
+        assert_eq!(Advertisement::parse("HF-A11ASSISTHREAD"), None);
+        assert_eq!(Advertisement::parse("10.0.0.2,not-a-mac,x"), None);
+    }
+
+    #[test]
+    fn converts_speed_and_levels() {
+        assert_eq!(speed_to_delay(100), 1);
+        assert_eq!(speed_to_delay(0), 31);
+        assert_eq!(scale(255, 255, 100), 100);
+        assert_eq!(scale(128, 255, 100), 50);
+        assert_eq!(scale(200, 100, 50), 50);
+        assert_eq!(dim(255, 50), 128);
+        assert_eq!(dim(255, 100), 255);
+        assert_eq!(scale(10, 0, 50), 0);
+    }
+
+    #[tokio::test]
+    async fn skips_acknowledgements_before_state() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let state = DeviceState {
+            model: 0x33,
+            is_on: true,
+            red: 0x80,
+            version: 5,
+            ..Default::default()
+        };
+
+        let reply = state.encode();
+        let device = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 4];
+            socket.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, [0x81, 0x8A, 0x8B, 0x96]);
+            // A stray power acknowledgement ahead of the state
+            socket.write_all(&[0xF0, 0x71, 0x23, 0x84]).await.unwrap();
+            socket.write_all(&reply).await.unwrap();
+            let mut buf = [0; 16];
+            socket.read_exact(&mut buf).await.unwrap();
+            assert_eq!(
+                buf,
+                [
+                    0x31, 0xFF, 0x00, 0x00, 0x00, 0xF0, 0x0F, 0x2F, 0x31, 0x00, 0x00, 0xFF, 0x00,
+                    0xF0, 0x0F, 0x2F
+                ]
+            );
+        });
+
+        let config = MagicHomeConfig {
+            enabled: true,
+            port,
+            request_timeout: 500,
+            ..Default::default()
+        };
+        let advertisement = Advertisement::parse("127.0.0.1,ACCF23000001,AK001-ZJ2149").unwrap();
+        let mut light = MagicHomeLight::new(advertisement, &config).await.unwrap();
+
+        assert!(light.is_on());
+        assert_eq!(light.brightness(), 50);
+        assert_eq!((light.red(), light.green(), light.blue()), (255, 0, 0));
+        assert_eq!(light.device_info().firmware_version.as_deref(), Some("5"));
+
+        light.set_brightness(100).await.unwrap();
+        light.set_color(0, 0, 255).await.unwrap();
+        device.await.unwrap();
+    }
+}
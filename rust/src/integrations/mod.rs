@@ -1,20 +1,68 @@
 use crate::config::CuteLightsConfig;
 
+pub mod deconz;
+pub mod dmx;
+pub mod elgato;
+pub mod esphome;
 pub mod govee;
+pub mod home_assistant;
 pub mod hue;
 pub mod kasa;
+pub mod lifx;
+pub mod magic_home;
+pub mod mqtt;
+pub mod nanoleaf;
+pub mod openrgb;
+pub mod shelly;
+pub mod tasmota;
+pub mod tuya;
+pub mod virtual_lights;
+pub mod wiz;
+pub mod wled;
+pub mod yeelight;
 
 // ANCHOR - ImplementationDiscoverer
+
+/// A kind of device that lights are discovered through. Applications add
+/// their own with [`crate::LightRegistry::register`], reading their settings
+/// with [`CuteLightsConfig::section`].
 #[async_trait::async_trait]
 pub trait Integration
 where
     Self: std::marker::Send + std::marker::Sync,
-    Self: Sized,
 {
-    fn name() -> String;
-    async fn discover(config: &'static CuteLightsConfig) -> anyhow::Result<Vec<Box<dyn Light>>>;
+    /// Unique name, registering another integration with it replaces this one.
+    fn name(&self) -> String;
+    async fn discover(&self, config: &CuteLightsConfig) -> anyhow::Result<Vec<Box<dyn Light>>>;
+
+    /// Discovers like [`Integration::discover`], handing every light to
+    /// `sink` as soon as it is ready. Integrations that find lights one at a
+    /// time override it, the default reports them once `discover` returns.
+    async fn discover_into(
+        &self,
+        config: &CuteLightsConfig,
+        sink: &LightSink<'_>,
+    ) -> anyhow::Result<()> {
+        for light in self.discover(config).await? {
+            sink(light);
+        }
+        Ok(())
+    }
+
+    /// Whether discovery should run at all, usually whether it is enabled.
+    fn preflight(&self, config: &CuteLightsConfig) -> bool;
+}
 
-    fn preflight(config: &CuteLightsConfig) -> bool;
+/// Takes the lights an integration finds, see [`Integration::discover_into`].
+pub type LightSink<'a> = dyn Fn(Box<dyn Light>) + Send + Sync + 'a;
+
+// ANCHOR - DeviceInfo
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeviceInfo {
+    pub manufacturer: String,
+    pub model: Option<String>,
+    pub firmware_version: Option<String>,
+    pub hardware_version: Option<String>,
 }
 
 // ANCHOR - ImplementationLight
@@ -36,6 +84,55 @@ where
 
     fn brightness(&self) -> u8;
     fn id(&self) -> String;
+
+    /// Manufacturer and model details, for integrations that know them.
+    fn device_info(&self) -> DeviceInfo {
+        DeviceInfo {
+            manufacturer: "Unknown".to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn supports_color_temperature(&self) -> bool {
+        false
+    }
+
+    /// Switches to white light of the given color temperature.
+    async fn set_color_temperature(&mut self, _kelvin: u16) -> anyhow::Result<()> {
+        Err(anyhow::anyhow!(
+            "{} does not support color temperature",
+            self.name()
+        ))
+    }
+
+    /// Names of the built in scenes or effects `set_effect` accepts.
+    fn effects(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    async fn set_effect(&mut self, _effect: &str) -> anyhow::Result<()> {
+        Err(anyhow::anyhow!("{} does not support effects", self.name()))
+    }
+
+    /// Number of individually colorable segments, 0 for single color lights.
+    fn segment_count(&self) -> usize {
+        0
+    }
+
+    /// Colors the light segment by segment, `colors[0]` being the first segment.
+    async fn set_segment_colors(&mut self, _colors: &[(u8, u8, u8)]) -> anyhow::Result<()> {
+        Err(anyhow::anyhow!("{} does not support segments", self.name()))
+    }
+
+    /// Switches realtime streaming on or off. While it is on `set_segment_colors`
+    /// pushes frames as fast as possible and may address more pixels than
+    /// `segment_count`.
+    async fn set_realtime(&mut self, _enabled: bool) -> anyhow::Result<()> {
+        Err(anyhow::anyhow!(
+            "{} does not support realtime mode",
+            self.name()
+        ))
+    }
 }
 
 impl std::fmt::Debug for dyn Light {
@@ -0,0 +1,890 @@
+use crate::{config::CuteLightsConfig, utils::color};
+use async_trait::async_trait;
+use rumqttc::{AsyncClient, Event, EventLoop, MqttOptions, Packet, QoS};
+use serde::Deserialize;
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::{sync::mpsc, task::JoinHandle};
+
+use super::{DeviceInfo, Integration, Light};
+
+// Zigbee2MQTT publishes every device in one message, which easily runs past
+// the 10kB rumqttc allows by default
+const MAX_INCOMING_PACKET: usize = 4 * 1024 * 1024;
+const MAX_OUTGOING_PACKET: usize = 64 * 1024;
+
+static CLIENT_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+// ANCHOR - MqttLight
+pub struct MqttLight {
+    bridge: Arc<Bridge>,
+    device: Device,
+    features: LightFeatures,
+}
+
+impl MqttLight {
+    fn new(bridge: Arc<Bridge>, device: Device, features: LightFeatures) -> MqttLight {
+        MqttLight {
+            bridge,
+            device,
+            features,
+        }
+    }
+
+    /// Asks Zigbee2MQTT to read the device, the answer arrives on its state
+    /// topic.
+    async fn request_state(&self) -> anyhow::Result<()> {
+        let mut properties = serde_json::Map::new();
+        for feature in [&self.features.state, &self.features.brightness] {
+            if let Some(property) = feature.as_ref().map(|f| f.property()) {
+                properties.insert(property.to_string(), json!(""));
+            }
+        }
+        self.bridge
+            .publish(
+                &format!("{}/get", self.device.friendly_name),
+                serde_json::Value::Object(properties),
+            )
+            .await
+    }
+
+    async fn set(&mut self, payload: serde_json::Value) -> anyhow::Result<()> {
+        self.bridge
+            .publish(
+                &format!("{}/set", self.device.friendly_name),
+                payload.clone(),
+            )
+            .await?;
+
+        // Zigbee2MQTT publishes the new state once the device confirms it,
+        // keep the optimistic one until then
+        let mut states = self.bridge.lock_states()?;
+        let state = states
+            .entry(self.device.friendly_name.clone())
+            .or_insert_with(|| json!({}));
+        if let (Some(state), serde_json::Value::Object(payload)) = (state.as_object_mut(), payload)
+        {
+            state.extend(payload);
+        }
+        Ok(())
+    }
+
+    fn state(&self, property: &str) -> Option<serde_json::Value> {
+        let states = self.bridge.lock_states().ok()?;
+        states
+            .get(&self.device.friendly_name)
+            .map(|state| state[property].clone())
+            .filter(|value| !value.is_null())
+    }
+
+    fn rgb(&self) -> (u8, u8, u8) {
+        let color = self
+            .features
+            .color_xy
+            .as_ref()
+            .and_then(|property| self.state(property));
+        match color {
+            Some(color) => match (color["x"].as_f64(), color["y"].as_f64()) {
+                (Some(x), Some(y)) => color::xy_to_rgb(x, y),
+                _ => (255, 255, 255),
+            },
+            None => (255, 255, 255),
+        }
+    }
+}
+
+#[async_trait]
+impl Light for MqttLight {
+    async fn set_on(&mut self, on: bool) -> anyhow::Result<()> {
+        let Some(Feature::Binary {
+            property,
+            value_on,
+            value_off,
+        }) = self.features.state.clone()
+        else {
+            return Err(anyhow::anyhow!("{} can not be switched", self.name()));
+        };
+        let value = if on { value_on } else { value_off };
+        self.set(json!({ property: value })).await
+    }
+
+    async fn set_color(&mut self, red: u8, green: u8, blue: u8) -> anyhow::Result<()> {
+        let Some(property) = self.features.color_xy.clone() else {
+            return Err(anyhow::anyhow!("{} does not support color", self.name()));
+        };
+        let (x, y) = color::rgb_to_xy(red, green, blue);
+        self.set(json!({ property: { "x": x, "y": y } })).await
+    }
+
+    async fn set_brightness(&mut self, brightness: u8) -> anyhow::Result<()> {
+        let Some(Feature::Numeric {
+            property, min, max, ..
+        }) = self.features.brightness.clone()
+        else {
+            return Err(anyhow::anyhow!(
+                "{} does not support brightness",
+                self.name()
+            ));
+        };
+        let value = min + (max - min) * brightness.min(100) as f64 / 100.0;
+        self.set(json!({ property: value.round() })).await
+    }
+
+    fn id(&self) -> String {
+        match &self.features.endpoint {
+            Some(endpoint) => format!("mqtt::{}_{}", self.device.ieee_address, endpoint),
+            None => format!("mqtt::{}", self.device.ieee_address),
+        }
+    }
+
+    fn is_on(&self) -> bool {
+        match &self.features.state {
+            Some(Feature::Binary {
+                property, value_on, ..
+            }) => self.state(property).as_ref() == Some(value_on),
+            _ => false,
+        }
+    }
+
+    fn name(&self) -> String {
+        match &self.features.endpoint {
+            Some(endpoint) => format!("{} {}", self.device.friendly_name, endpoint),
+            None => self.device.friendly_name.clone(),
+        }
+    }
+
+    fn supports_color(&self) -> bool {
+        self.features.color_xy.is_some()
+    }
+
+    fn red(&self) -> u8 {
+        self.rgb().0
+    }
+
+    fn green(&self) -> u8 {
+        self.rgb().1
+    }
+
+    fn blue(&self) -> u8 {
+        self.rgb().2
+    }
+
+    fn brightness(&self) -> u8 {
+        match &self.features.brightness {
+            Some(Feature::Numeric {
+                property, min, max, ..
+            }) => self
+                .state(property)
+                .and_then(|value| value.as_f64())
+                .map(|value| {
+                    ((value - min) / (max - min) * 100.0)
+                        .round()
+                        .clamp(0.0, 100.0) as u8
+                })
+                .unwrap_or(0),
+            _ => 100,
+        }
+    }
+
+    fn device_info(&self) -> DeviceInfo {
+        let definition = self.device.definition.as_ref();
+        DeviceInfo {
+            manufacturer: definition
+                .map(|definition| definition.vendor.clone())
+                .or_else(|| self.device.manufacturer.clone())
+                .unwrap_or_else(|| "Zigbee".to_string()),
+            model: definition.map(|definition| definition.model.clone()),
+            firmware_version: self.device.software_build_id.clone(),
+            hardware_version: self.device.date_code.clone(),
+        }
+    }
+
+    fn supports_color_temperature(&self) -> bool {
+        self.features.color_temp.is_some()
+    }
+
+    async fn set_color_temperature(&mut self, kelvin: u16) -> anyhow::Result<()> {
+        let Some(Feature::Numeric {
+            property, min, max, ..
+        }) = self.features.color_temp.clone()
+        else {
+            return Err(anyhow::anyhow!(
+                "{} does not support color temperature",
+                self.name()
+            ));
+        };
+        let mireds = (1_000_000.0 / kelvin.max(1) as f64).clamp(min, max);
+        self.set(json!({ property: mireds.round() })).await
+    }
+}
+
+// ANCHOR - Bridge
+
+/// The broker connection shared by every light, it keeps the last state
+/// Zigbee2MQTT published for each device.
+struct Bridge {
+    client: AsyncClient,
+    base_topic: String,
+    states: Arc<Mutex<HashMap<String, serde_json::Value>>>,
+    event_loop: JoinHandle<()>,
+}
+
+impl Bridge {
+    /// Connects and returns the bridge with the devices Zigbee2MQTT
+    /// reported, waiting at most `scan_timeout`.
+    async fn connect(config: &MqttConfig) -> anyhow::Result<(Arc<Bridge>, Vec<Device>)> {
+        let client_id = format!(
+            "cute-lights-{}-{}",
+            std::process::id(),
+            CLIENT_COUNT.fetch_add(1, Ordering::Relaxed)
+        );
+        let mut options = MqttOptions::new(client_id, config.host.clone(), config.port);
+        options.set_keep_alive(Duration::from_secs(30));
+        options.set_max_packet_size(MAX_INCOMING_PACKET, MAX_OUTGOING_PACKET);
+        if let Some(username) = &config.username {
+            options.set_credentials(username, config.password.clone().unwrap_or_default());
+        }
+
+        let (client, event_loop) = AsyncClient::new(options, 64);
+        let states = Arc::new(Mutex::new(HashMap::new()));
+        let (devices_tx, mut devices_rx) = mpsc::unbounded_channel();
+        let event_loop = tokio::spawn(poll_events(
+            event_loop,
+            client.clone(),
+            config.base_topic.clone(),
+            states.clone(),
+            devices_tx,
+        ));
+        let bridge = Arc::new(Bridge {
+            client,
+            base_topic: config.base_topic.clone(),
+            states,
+            event_loop,
+        });
+
+        let devices = tokio::time::timeout(
+            Duration::from_millis(config.scan_timeout),
+            devices_rx.recv(),
+        )
+        .await
+        .map_err(|_| {
+            anyhow::anyhow!(
+                "No device list on {}/bridge/devices, is Zigbee2MQTT running?",
+                config.base_topic
+            )
+        })?
+        .ok_or_else(|| anyhow::anyhow!("MQTT connection closed"))??;
+
+        Ok((bridge, devices))
+    }
+
+    async fn publish(&self, topic: &str, payload: serde_json::Value) -> anyhow::Result<()> {
+        self.client
+            .publish(
+                format!("{}/{}", self.base_topic, topic),
+                QoS::AtLeastOnce,
+                false,
+                payload.to_string(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    fn lock_states(
+        &self,
+    ) -> anyhow::Result<std::sync::MutexGuard<'_, HashMap<String, serde_json::Value>>> {
+        self.states
+            .lock()
+            .map_err(|_| anyhow::anyhow!("MQTT state poisoned"))
+    }
+}
+
+impl Drop for Bridge {
+    fn drop(&mut self) {
+        self.event_loop.abort();
+    }
+}
+
+/// Drives the connection, subscribing again after every reconnect since the
+/// session is not kept. Connection errors before the first device list are
+/// passed on so discovery can report them.
+async fn poll_events(
+    mut event_loop: EventLoop,
+    client: AsyncClient,
+    base_topic: String,
+    states: Arc<Mutex<HashMap<String, serde_json::Value>>>,
+    devices: mpsc::UnboundedSender<anyhow::Result<Vec<Device>>>,
+) {
+    let devices_topic = format!("{}/bridge/devices", base_topic);
+    let prefix = format!("{}/", base_topic);
+    loop {
+        match event_loop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                if let Err(e) = client.try_subscribe(format!("{}/#", base_topic), QoS::AtMostOnce) {
+                    eprintln!("Failed to subscribe to {}: {}", base_topic, e);
+                }
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                if publish.topic == devices_topic {
+                    let _ = devices.send(
+                        serde_json::from_slice(&publish.payload).map_err(anyhow::Error::from),
+                    );
+                    continue;
+                }
+                let Some(name) = publish.topic.strip_prefix(&prefix) else {
+                    continue;
+                };
+                if name.starts_with("bridge/") {
+                    continue;
+                }
+                // Device states are objects, this skips /set, /get and
+                // /availability echoes
+                let Ok(serde_json::Value::Object(update)) =
+                    serde_json::from_slice::<serde_json::Value>(&publish.payload)
+                else {
+                    continue;
+                };
+                let Ok(mut states) = states.lock() else {
+                    return;
+                };
+                let state = states.entry(name.to_string()).or_insert_with(|| json!({}));
+                if let Some(state) = state.as_object_mut() {
+                    state.extend(update);
+                }
+            }
+            Ok(_) => {}
+            Err(e) => {
+                if devices
+                    .send(Err(anyhow::anyhow!("MQTT connection failed: {}", e)))
+                    .is_err()
+                {
+                    // Discovery is over, keep reconnecting
+                    eprintln!("MQTT connection failed, retrying: {}", e);
+                }
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+// ANCHOR - MqttConfig
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct MqttConfig {
+    pub enabled: bool,
+    #[serde(default = "default_host")]
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    /// The `base_topic` set in the Zigbee2MQTT configuration
+    #[serde(default = "default_base_topic")]
+    pub base_topic: String,
+    /// How long to wait for the device list
+    #[serde(default = "default_scan_timeout")]
+    pub scan_timeout: u64,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            host: default_host(),
+            port: default_port(),
+            username: None,
+            password: None,
+            base_topic: default_base_topic(),
+            scan_timeout: default_scan_timeout(),
+        }
+    }
+}
+
+fn default_host() -> String {
+    "localhost".to_string()
+}
+
+fn default_port() -> u16 {
+    1883
+}
+
+fn default_base_topic() -> String {
+    "zigbee2mqtt".to_string()
+}
+
+fn default_scan_timeout() -> u64 {
+    3000
+}
+
+// ANCHOR - MqttIntegration
+
+pub struct MqttIntegration;
+
+#[async_trait]
+impl Integration for MqttIntegration {
+    fn name(&self) -> String {
+        "mqtt".to_string()
+    }
+
+    fn preflight(&self, config: &CuteLightsConfig) -> bool {
+        config.mqtt.enabled
+    }
+
+    async fn discover(&self, config: &CuteLightsConfig) -> anyhow::Result<Vec<Box<dyn Light>>> {
+        let (bridge, devices) = Bridge::connect(&config.mqtt).await?;
+
+        let mut lights: Vec<Box<dyn Light>> = Vec::new();
+        for device in devices {
+            if device.disabled {
+                continue;
+            }
+            let Some(definition) = &device.definition else {
+                continue;
+            };
+            // Devices with several lights expose each on its own endpoint
+            let exposes: Vec<LightFeatures> = definition
+                .exposes
+                .iter()
+                .filter(|expose| expose.kind == "light")
+                .map(LightFeatures::from)
+                .collect();
+            for features in exposes {
+                let light = MqttLight::new(bridge.clone(), device.clone(), features);
+                if let Err(e) = light.request_state().await {
+                    eprintln!("Failed to read {}: {}", light.name(), e);
+                }
+                lights.push(Box::new(light));
+            }
+        }
+        Ok(lights)
+    }
+}
+
+// ANCHOR - Messages
+
+#[derive(Debug, Clone, Deserialize)]
+struct Device {
+    ieee_address: String,
+    friendly_name: String,
+    #[serde(default)]
+    disabled: bool,
+    manufacturer: Option<String>,
+    software_build_id: Option<String>,
+    date_code: Option<String>,
+    /// Missing for the coordinator and devices Zigbee2MQTT does not support
+    definition: Option<Definition>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Definition {
+    model: String,
+    vendor: String,
+    #[serde(default)]
+    exposes: Vec<Expose>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Expose {
+    #[serde(rename = "type")]
+    kind: String,
+    endpoint: Option<String>,
+    #[serde(default)]
+    features: Vec<ExposeFeature>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct ExposeFeature {
+    name: String,
+    #[serde(default)]
+    property: String,
+    value_on: Option<serde_json::Value>,
+    value_off: Option<serde_json::Value>,
+    value_min: Option<f64>,
+    value_max: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Feature {
+    Binary {
+        property: String,
+        value_on: serde_json::Value,
+        value_off: serde_json::Value,
+    },
+    Numeric {
+        property: String,
+        min: f64,
+        max: f64,
+    },
+}
+
+impl Feature {
+    fn property(&self) -> &str {
+        match self {
+            Feature::Binary { property, .. } | Feature::Numeric { property, .. } => property,
+        }
+    }
+}
+
+/// The parts of a `light` expose this library drives.
+#[derive(Debug, Clone, PartialEq)]
+struct LightFeatures {
+    endpoint: Option<String>,
+    state: Option<Feature>,
+    brightness: Option<Feature>,
+    color_temp: Option<Feature>,
+    /// Property of the `color_xy` composite, usually `color`
+    color_xy: Option<String>,
+}
+
+impl From<&Expose> for LightFeatures {
+    fn from(expose: &Expose) -> Self {
+        let mut features = LightFeatures {
+            endpoint: expose.endpoint.clone(),
+            state: None,
+            brightness: None,
+            color_temp: None,
+            color_xy: None,
+        };
+        for feature in &expose.features {
+            let numeric = |default_max: f64| Feature::Numeric {
+                property: feature.property.clone(),
+                min: feature.value_min.unwrap_or(0.0),
+                max: feature.value_max.unwrap_or(default_max),
+            };
+            match feature.name.as_str() {
+                "state" => {
+                    features.state = Some(Feature::Binary {
+                        property: feature.property.clone(),
+                        value_on: feature.value_on.clone().unwrap_or_else(|| json!("ON")),
+                        value_off: feature.value_off.clone().unwrap_or_else(|| json!("OFF")),
+                    })
+                }
+                "brightness" => features.brightness = Some(numeric(254.0)),
+                "color_temp" => features.color_temp = Some(numeric(500.0)),
+                "color_xy" => features.color_xy = Some(feature.property.clone()),
+                _ => {}
+            }
+        }
+        features
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+    use rumqttc::{
+        ConnAck, ConnectReturnCode, PingResp, PubAck, Publish, SubAck, SubscribeReasonCode,
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    const DEVICES: &str = r#"[
+        {
+            "ieee_address": "0x00124b0001",
+            "friendly_name": "Coordinator",
+            "type": "Coordinator",
+            "definition": null
+        },
+        {
+            "ieee_address": "0x0017880102",
+            "friendly_name": "Desk Bulb",
+            "type": "Router",
+            "manufacturer": "Signify Netherlands B.V.",
+            "software_build_id": "1.104.2",
+            "date_code": "20230127",
+            "definition": {
+                "model": "9290022166",
+                "vendor": "Philips",
+                "exposes": [
+                    {
+                        "type": "light",
+                        "features": [
+                            { "name": "state", "property": "state", "value_on": "ON", "value_off": "OFF" },
+                            { "name": "brightness", "property": "brightness", "value_min": 0, "value_max": 254 },
+                            { "name": "color_temp", "property": "color_temp", "value_min": 153, "value_max": 500 },
+                            { "name": "color_xy", "property": "color", "features": [{ "name": "x" }, { "name": "y" }] },
+                            { "name": "color_hs", "property": "color", "features": [{ "name": "hue" }, { "name": "saturation" }] }
+                        ]
+                    },
+                    { "type": "numeric", "name": "linkquality", "property": "linkquality" }
+                ]
+            }
+        },
+        {
+            "ieee_address": "0xa4c1380103",
+            "friendly_name": "Kitchen/Dimmer",
+            "type": "Router",
+            "definition": {
+                "model": "TS110E_2gang",
+                "vendor": "TuYa",
+                "exposes": [
+                    {
+                        "type": "light",
+                        "endpoint": "l1",
+                        "features": [
+                            { "name": "state", "property": "state_l1", "value_on": "ON", "value_off": "OFF" },
+                            { "name": "brightness", "property": "brightness_l1", "value_min": 0, "value_max": 1000 }
+                        ]
+                    },
+                    {
+                        "type": "light",
+                        "endpoint": "l2",
+                        "features": [
+                            { "name": "state", "property": "state_l2", "value_on": "ON", "value_off": "OFF" },
+                            { "name": "brightness", "property": "brightness_l2", "value_min": 0, "value_max": 1000 }
+                        ]
+                    }
+                ]
+            }
+        },
+        {
+            "ieee_address": "0x00158d0104",
+            "friendly_name": "Hallway Sensor",
+            "type": "EndDevice",
+            "definition": {
+                "model": "RTCGQ11LM",
+                "vendor": "Aqara",
+                "exposes": [{ "type": "binary", "name": "occupancy", "property": "occupancy" }]
+            }
+        }
+    ]"#;
+
+    /// Just enough of an MQTT 3.1.1 broker for one client. It sends the
+    /// retained messages on subscribe, echoes the client's publishes back to
+    /// it like a `#` subscription would and hands them to the test.
+    struct Broker {
+        port: u16,
+        inject: mpsc::UnboundedSender<Publish>,
+        received: mpsc::UnboundedReceiver<Publish>,
+    }
+
+    impl Broker {
+        async fn start(retained: Vec<Publish>) -> Broker {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let (inject, inject_rx) = mpsc::unbounded_channel();
+            let (received_tx, received) = mpsc::unbounded_channel();
+            tokio::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                serve(stream, retained, inject_rx, received_tx).await;
+            });
+            Broker {
+                port,
+                inject,
+                received,
+            }
+        }
+
+        fn publish(&self, topic: &str, payload: serde_json::Value) {
+            let publish = Publish::new(topic, QoS::AtMostOnce, payload.to_string());
+            self.inject.send(publish).unwrap();
+        }
+
+        /// The next publish from the client on `topic`, skipping others.
+        async fn expect(&mut self, topic: &str) -> serde_json::Value {
+            loop {
+                let publish = tokio::time::timeout(Duration::from_secs(2), self.received.recv())
+                    .await
+                    .expect("timed out waiting for a publish")
+                    .unwrap();
+                if publish.topic == topic {
+                    return serde_json::from_slice(&publish.payload).unwrap();
+                }
+            }
+        }
+    }
+
+    async fn serve(
+        mut stream: TcpStream,
+        retained: Vec<Publish>,
+        mut inject: mpsc::UnboundedReceiver<Publish>,
+        received: mpsc::UnboundedSender<Publish>,
+    ) {
+        let mut incoming = BytesMut::new();
+        loop {
+            let mut out = BytesMut::new();
+            tokio::select! {
+                read = stream.read_buf(&mut incoming) => {
+                    if read.unwrap_or(0) == 0 {
+                        return;
+                    }
+                    while let Ok(packet) = rumqttc::mqttbytes::v4::read(&mut incoming, 1 << 20) {
+                        match packet {
+                            Packet::Connect(_) => {
+                                ConnAck::new(ConnectReturnCode::Success, false).write(&mut out).unwrap();
+                            }
+                            Packet::Subscribe(subscribe) => {
+                                let codes = vec![
+                                    SubscribeReasonCode::Success(QoS::AtMostOnce);
+                                    subscribe.filters.len()
+                                ];
+                                SubAck::new(subscribe.pkid, codes).write(&mut out).unwrap();
+                                for publish in &retained {
+                                    publish.write(&mut out).unwrap();
+                                }
+                            }
+                            Packet::Publish(publish) => {
+                                if publish.qos == QoS::AtLeastOnce {
+                                    PubAck::new(publish.pkid).write(&mut out).unwrap();
+                                }
+                                let echo = Publish::from_bytes(
+                                    publish.topic.clone(),
+                                    QoS::AtMostOnce,
+                                    publish.payload.clone(),
+                                );
+                                echo.write(&mut out).unwrap();
+                                let _ = received.send(publish);
+                            }
+                            Packet::PingReq => {
+                                PingResp.write(&mut out).unwrap();
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                Some(publish) = inject.recv() => {
+                    publish.write(&mut out).unwrap();
+                }
+            }
+            if stream.write_all(&out).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    async fn discover(broker: &Broker) -> Vec<Box<dyn Light>> {
+        let config = CuteLightsConfig {
+            mqtt: MqttConfig {
+                enabled: true,
+                host: "127.0.0.1".to_string(),
+                port: broker.port,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        MqttIntegration.discover(&config).await.unwrap()
+    }
+
+    async fn wait_for(light: &dyn Light, done: impl Fn(&dyn Light) -> bool) {
+        for _ in 0..100 {
+            if done(light) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("{} never reached the expected state", light.name());
+    }
+
+    #[test]
+    fn reads_light_exposes() {
+        let devices: Vec<Device> = serde_json::from_str(DEVICES).unwrap();
+        let exposes = |index: usize| -> Vec<LightFeatures> {
+            devices[index]
+                .definition
+                .iter()
+                .flat_map(|definition| &definition.exposes)
+                .filter(|expose| expose.kind == "light")
+                .map(LightFeatures::from)
+                .collect()
+        };
+
+        let bulb = exposes(1);
+        assert_eq!(bulb.len(), 1);
+        assert_eq!(bulb[0].color_xy.as_deref(), Some("color"));
+        assert_eq!(
+            bulb[0].color_temp,
+            Some(Feature::Numeric {
+                property: "color_temp".to_string(),
+                min: 153.0,
+                max: 500.0
+            })
+        );
+
+        let dimmer = exposes(2);
+        assert_eq!(dimmer.len(), 2);
+        assert_eq!(dimmer[1].endpoint.as_deref(), Some("l2"));
+        assert_eq!(dimmer[1].state.as_ref().unwrap().property(), "state_l2");
+        assert!(dimmer[1].color_xy.is_none());
+
+        assert!(exposes(0).is_empty());
+        assert!(exposes(3).is_empty());
+    }
+
+    #[tokio::test]
+    async fn controls_lights_through_broker() {
+        let mut devices = Publish::new(
+            "zigbee2mqtt/bridge/devices",
+            QoS::AtMostOnce,
+            DEVICES.as_bytes(),
+        );
+        devices.retain = true;
+        let mut broker = Broker::start(vec![devices]).await;
+
+        let mut lights = discover(&broker).await;
+        let ids: Vec<String> = lights.iter().map(|light| light.id()).collect();
+        assert_eq!(
+            ids,
+            [
+                "mqtt::0x0017880102",
+                "mqtt::0xa4c1380103_l1",
+                "mqtt::0xa4c1380103_l2"
+            ]
+        );
+        assert_eq!(
+            broker.expect("zigbee2mqtt/Desk Bulb/get").await,
+            json!({ "state": "", "brightness": "" })
+        );
+
+        let bulb = &mut lights[0];
+        assert!(bulb.supports_color());
+        assert!(bulb.supports_color_temperature());
+        assert_eq!(bulb.device_info().manufacturer, "Philips");
+
+        bulb.set_on(true).await.unwrap();
+        assert_eq!(
+            broker.expect("zigbee2mqtt/Desk Bulb/set").await,
+            json!({ "state": "ON" })
+        );
+        assert!(bulb.is_on());
+
+        bulb.set_color(255, 0, 0).await.unwrap();
+        let set = broker.expect("zigbee2mqtt/Desk Bulb/set").await;
+        assert!((set["color"]["x"].as_f64().unwrap() - 0.64).abs() < 0.001);
+        assert!((set["color"]["y"].as_f64().unwrap() - 0.33).abs() < 0.001);
+
+        bulb.set_color_temperature(4000).await.unwrap();
+        assert_eq!(
+            broker.expect("zigbee2mqtt/Desk Bulb/set").await,
+            json!({ "color_temp": 250.0 })
+        );
+
+        // The echoed /set must not be taken for state, the device topic is
+        broker.publish(
+            "zigbee2mqtt/Desk Bulb",
+            json!({ "state": "OFF", "brightness": 127, "color": { "x": 0.3, "y": 0.6 } }),
+        );
+        wait_for(bulb.as_ref(), |light| !light.is_on()).await;
+        assert_eq!(bulb.brightness(), 50);
+        assert!(bulb.green() > bulb.red() && bulb.green() > bulb.blue());
+
+        let dimmer = &mut lights[2];
+        dimmer.set_brightness(25).await.unwrap();
+        assert_eq!(
+            broker.expect("zigbee2mqtt/Kitchen/Dimmer/set").await,
+            json!({ "brightness_l2": 250.0 })
+        );
+        assert!(dimmer.set_color(0, 0, 255).await.is_err());
+    }
+}
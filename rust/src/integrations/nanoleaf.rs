@@ -0,0 +1,714 @@
+use crate::{
+    config::CuteLightsConfig,
+    utils::{color, future::FutureBatch},
+};
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde_json::json;
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+use tokio::net::UdpSocket;
+
+use super::{DeviceInfo, Integration, Light};
+
+const NANOLEAF_PORT: u16 = 16021;
+const EXT_CONTROL_PORT: u16 = 60222;
+
+// Shape types that are controllers or connectors rather than lit panels
+const UNLIT_SHAPES: [u8; 5] = [1, 12, 16, 19, 20];
+
+// ANCHOR - NanoleafLight
+pub struct NanoleafLight {
+    client: reqwest::Client,
+    base_url: String,
+    host: IpAddr,
+    info: Info,
+    panels: Vec<u16>,
+    stream: Option<UdpSocket>,
+    // Effect that was running before external control took over
+    resume_effect: Option<String>,
+    is_on: bool,
+    brightness: u8,
+    red: u8,
+    green: u8,
+    blue: u8,
+}
+
+impl NanoleafLight {
+    pub async fn new(
+        host: IpAddr,
+        port: u16,
+        token: &str,
+        config: &NanoleafConfig,
+    ) -> anyhow::Result<NanoleafLight> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_millis(config.request_timeout))
+            .build()?;
+        let base_url = format!("{}/api/v1/{}", host_url(host, port), token);
+
+        let body = client
+            .get(format!("{}/", base_url))
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        let info: Info = serde_json::from_str(&body)?;
+
+        // Left to right, then bottom to top, so segment 0 is the left most panel
+        let mut positions: Vec<&Position> = info
+            .panel_layout
+            .layout
+            .position_data
+            .iter()
+            .filter(|panel| !UNLIT_SHAPES.contains(&panel.shape_type))
+            .collect();
+        positions.sort_by_key(|panel| (panel.x, panel.y));
+        let panels = positions.iter().map(|panel| panel.panel_id).collect();
+
+        let state = &info.state;
+        // Brightness is reported separately, keep the color itself at full brightness
+        let (red, green, blue) = color::hsb_to_rgb(state.hue.value, state.sat.value, 100);
+
+        Ok(NanoleafLight {
+            client,
+            base_url,
+            host,
+            panels,
+            stream: None,
+            resume_effect: None,
+            is_on: state.on.value,
+            brightness: state.brightness.value,
+            red,
+            green,
+            blue,
+            info,
+        })
+    }
+
+    async fn put(&self, path: &str, body: serde_json::Value) -> anyhow::Result<()> {
+        self.client
+            .put(format!("{}/{}", self.base_url, path))
+            .body(body.to_string())
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    async fn start_stream(&mut self) -> anyhow::Result<()> {
+        if self.stream.is_some() {
+            return Ok(());
+        }
+
+        self.put(
+            "effects",
+            json!({
+                "write": {
+                    "command": "display",
+                    "animType": "extControl",
+                    "extControlVersion": "v2"
+                }
+            }),
+        )
+        .await?;
+
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        socket.connect((self.host, EXT_CONTROL_PORT)).await?;
+        self.resume_effect = Some(self.info.effects.select.clone());
+        self.stream = Some(socket);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Light for NanoleafLight {
+    async fn set_on(&mut self, on: bool) -> anyhow::Result<()> {
+        self.put("state", json!({ "on": { "value": on } })).await?;
+        self.is_on = on;
+        Ok(())
+    }
+
+    async fn set_color(&mut self, red: u8, green: u8, blue: u8) -> anyhow::Result<()> {
+        let (hue, sat, _) = color::rgb_to_hsb(red, green, blue);
+        self.put(
+            "state",
+            json!({ "hue": { "value": hue }, "sat": { "value": sat } }),
+        )
+        .await?;
+        self.red = red;
+        self.green = green;
+        self.blue = blue;
+        Ok(())
+    }
+
+    async fn set_brightness(&mut self, brightness: u8) -> anyhow::Result<()> {
+        let brightness = brightness.min(100);
+        self.put("state", json!({ "brightness": { "value": brightness } }))
+            .await?;
+        self.brightness = brightness;
+        Ok(())
+    }
+
+    fn id(&self) -> String {
+        format!("nanoleaf::{}", self.info.serial_no)
+    }
+
+    fn is_on(&self) -> bool {
+        self.is_on
+    }
+
+    fn name(&self) -> String {
+        self.info.name.clone()
+    }
+
+    fn supports_color(&self) -> bool {
+        true
+    }
+
+    fn red(&self) -> u8 {
+        self.red
+    }
+
+    fn green(&self) -> u8 {
+        self.green
+    }
+
+    fn blue(&self) -> u8 {
+        self.blue
+    }
+
+    fn brightness(&self) -> u8 {
+        self.brightness
+    }
+
+    fn device_info(&self) -> DeviceInfo {
+        DeviceInfo {
+            manufacturer: self.info.manufacturer.clone(),
+            model: Some(self.info.model.clone()),
+            firmware_version: Some(self.info.firmware_version.clone()),
+            hardware_version: self.info.hardware_version.clone(),
+        }
+    }
+
+    fn supports_color_temperature(&self) -> bool {
+        true
+    }
+
+    async fn set_color_temperature(&mut self, kelvin: u16) -> anyhow::Result<()> {
+        self.put(
+            "state",
+            json!({ "ct": { "value": kelvin.clamp(1200, 6500) } }),
+        )
+        .await
+    }
+
+    fn effects(&self) -> Vec<String> {
+        self.info.effects.effects_list.clone()
+    }
+
+    async fn set_effect(&mut self, effect: &str) -> anyhow::Result<()> {
+        let name = self
+            .info
+            .effects
+            .effects_list
+            .iter()
+            .find(|name| name.eq_ignore_ascii_case(effect))
+            .ok_or_else(|| anyhow::anyhow!("Unknown Nanoleaf effect {}", effect))?
+            .clone();
+        self.put("effects", json!({ "select": name })).await?;
+        self.info.effects.select = name;
+        // Selecting an effect ends external control on the panels
+        self.stream = None;
+        Ok(())
+    }
+
+    fn segment_count(&self) -> usize {
+        self.panels.len()
+    }
+
+    async fn set_segment_colors(&mut self, colors: &[(u8, u8, u8)]) -> anyhow::Result<()> {
+        if colors.len() > self.panels.len() {
+            return Err(anyhow::anyhow!(
+                "{} has {} panels, got {} colors",
+                self.name(),
+                self.panels.len(),
+                colors.len()
+            ));
+        }
+
+        self.start_stream().await?;
+        let frame = ext_control_frame(&self.panels, colors, 1);
+        if let Some(socket) = &self.stream {
+            socket.send(&frame).await?;
+        }
+        Ok(())
+    }
+
+    async fn set_realtime(&mut self, enabled: bool) -> anyhow::Result<()> {
+        if enabled {
+            return self.start_stream().await;
+        }
+        if self.stream.take().is_some() {
+            if let Some(effect) = self.resume_effect.take() {
+                self.put("effects", json!({ "select": effect })).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// ANCHOR - External control
+
+/// Builds an external control v2 frame. Every panel gets its color with a
+/// transition time in units of 100ms.
+fn ext_control_frame(panels: &[u16], colors: &[(u8, u8, u8)], transition: u16) -> Vec<u8> {
+    let count = panels.len().min(colors.len());
+    let mut frame = Vec::with_capacity(2 + count * 8);
+    frame.extend_from_slice(&(count as u16).to_be_bytes());
+    for (panel, (r, g, b)) in panels.iter().zip(colors) {
+        frame.extend_from_slice(&panel.to_be_bytes());
+        frame.extend_from_slice(&[*r, *g, *b, 0]);
+        frame.extend_from_slice(&transition.to_be_bytes());
+    }
+    frame
+}
+
+// ANCHOR - Pairing
+
+/// Requests a new auth token. The controller only hands one out in the 30
+/// seconds after its power button was held for 5 to 7 seconds.
+pub async fn pair(host: IpAddr, port: u16) -> anyhow::Result<String> {
+    let client = reqwest::Client::new();
+    let response = client
+        .post(format!("{}/api/v1/new", host_url(host, port)))
+        .send()
+        .await?;
+    if response.status() == reqwest::StatusCode::FORBIDDEN {
+        return Err(anyhow::anyhow!(
+            "Nanoleaf at {} is not in pairing mode, hold its power button for 5 seconds",
+            host
+        ));
+    }
+    let body = response.error_for_status()?.text().await?;
+    let token: NewToken = serde_json::from_str(&body)?;
+    Ok(token.auth_token)
+}
+
+fn host_url(host: IpAddr, port: u16) -> String {
+    format!("http://{}", SocketAddr::new(host, port))
+}
+
+// ANCHOR - NanoleafConfig
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct NanoleafConfig {
+    pub enabled: bool,
+    #[serde(default)]
+    pub devices: Vec<NanoleafDevice>,
+    #[serde(default = "default_request_timeout")]
+    pub request_timeout: u64,
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct NanoleafDevice {
+    pub address: IpAddr,
+    /// Left out to pair on the next discovery, the new token is printed so it
+    /// can be added here
+    pub token: Option<String>,
+}
+
+impl Default for NanoleafConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            devices: Vec::new(),
+            request_timeout: default_request_timeout(),
+        }
+    }
+}
+
+fn default_request_timeout() -> u64 {
+    1000
+}
+
+// ANCHOR - NanoleafIntegration
+
+pub struct NanoleafIntegration;
+
+#[async_trait]
+impl Integration for NanoleafIntegration {
+    fn name(&self) -> String {
+        "nanoleaf".to_string()
+    }
+
+    fn preflight(&self, config: &CuteLightsConfig) -> bool {
+        if !config.nanoleaf.enabled {
+            return false;
+        }
+
+        if config.nanoleaf.devices.is_empty() {
+            eprintln!("No Nanoleaf devices configured");
+            return false;
+        }
+
+        true
+    }
+
+    async fn discover(&self, config: &CuteLightsConfig) -> anyhow::Result<Vec<Box<dyn Light>>> {
+        let mut lights = FutureBatch::new();
+
+        for device in config.nanoleaf.devices.clone() {
+            let config = config.nanoleaf.clone();
+            lights.push(async move {
+                let token = match &device.token {
+                    Some(token) => token.clone(),
+                    None => match pair(device.address, NANOLEAF_PORT).await {
+                        Ok(token) => {
+                            eprintln!(
+                                "Paired with Nanoleaf at {}, add token = \"{}\" to its config",
+                                device.address, token
+                            );
+                            token
+                        }
+                        Err(e) => {
+                            eprintln!("Failed to pair with Nanoleaf: {}", e);
+                            return None;
+                        }
+                    },
+                };
+
+                match NanoleafLight::new(device.address, NANOLEAF_PORT, &token, &config).await {
+                    Ok(light) => Some(Box::new(light) as Box<dyn Light>),
+                    Err(e) => {
+                        eprintln!("Failed to connect to Nanoleaf at {}: {}", device.address, e);
+                        None
+                    }
+                }
+            });
+        }
+
+        Ok(lights.run().await.into_iter().flatten().collect())
+    }
+}
+
+// ANCHOR - Messages
+
+#[derive(Debug, Deserialize)]
+struct NewToken {
+    auth_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Info {
+    name: String,
+    serial_no: String,
+    manufacturer: String,
+    model: String,
+    firmware_version: String,
+    hardware_version: Option<String>,
+    state: State,
+    effects: Effects,
+    panel_layout: PanelLayout,
+}
+
+#[derive(Debug, Deserialize)]
+struct State {
+    on: Value<bool>,
+    brightness: Value<u8>,
+    hue: Value<u16>,
+    sat: Value<u8>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Value<T> {
+    value: T,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Effects {
+    #[serde(default)]
+    select: String,
+    #[serde(default)]
+    effects_list: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PanelLayout {
+    layout: Layout,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Layout {
+    #[serde(default)]
+    position_data: Vec<Position>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Position {
+    panel_id: u16,
+    x: i32,
+    y: i32,
+    #[serde(default)]
+    shape_type: u8,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::http::{FakeHttpServer, HttpRequest};
+    use std::net::Ipv4Addr;
+    use std::sync::{Arc, Mutex};
+
+    const TOKEN: &str = "A1b2C3d4E5f6G7h8J9k0L1m2N3p4Q5r6";
+
+    fn info() -> serde_json::Value {
+        json!({
+            "name": "Shapes 4A2B",
+            "serialNo": "S19124C8036",
+            "manufacturer": "Nanoleaf",
+            "model": "NL42",
+            "firmwareVersion": "9.2.4",
+            "hardwareVersion": "1.4-0",
+            "state": {
+                "on": { "value": true },
+                "brightness": { "value": 80 },
+                "hue": { "value": 240 },
+                "sat": { "value": 100 },
+                "ct": { "value": 4000 }
+            },
+            "effects": {
+                "select": "Northern Lights",
+                "effectsList": ["Northern Lights", "Fireplace"]
+            },
+            "panelLayout": {
+                "layout": {
+                    "numPanels": 5,
+                    "positionData": [
+                        { "panelId": 300, "x": 100, "y": 0, "o": 0, "shapeType": 8 },
+                        { "panelId": 1, "x": 0, "y": 0, "o": 0, "shapeType": 12 },
+                        { "panelId": 200, "x": 0, "y": 50, "o": 0, "shapeType": 8 },
+                        { "panelId": 100, "x": 0, "y": 0, "o": 0, "shapeType": 8 },
+                        { "panelId": 2, "x": 50, "y": 0, "o": 0, "shapeType": 19 }
+                    ]
+                }
+            }
+        })
+    }
+
+    /// Answers the OpenAPI like a controller, applying state PUTs to `info`.
+    fn answer(info: &mut serde_json::Value, request: &HttpRequest) -> (u16, serde_json::Value) {
+        let prefix = format!("/api/v1/{}/", TOKEN);
+        let Some(path) = request.route().strip_prefix(&prefix) else {
+            return (401, serde_json::Value::Null);
+        };
+        match (request.method.as_str(), path) {
+            ("GET", "") => (200, info.clone()),
+            ("PUT", "state") => {
+                for (key, value) in request.body.as_ref().unwrap().as_object().unwrap() {
+                    info["state"][key] = value.clone();
+                }
+                (204, serde_json::Value::Null)
+            }
+            ("PUT", "effects") => {
+                let body = request.body.as_ref().unwrap();
+                if let Some(select) = body.get("select") {
+                    info["effects"]["select"] = select.clone();
+                }
+                (204, serde_json::Value::Null)
+            }
+            _ => (404, serde_json::Value::Null),
+        }
+    }
+
+    async fn controller() -> (FakeHttpServer, NanoleafLight) {
+        let info = Arc::new(Mutex::new(info()));
+        let server =
+            FakeHttpServer::start(move |request| answer(&mut info.lock().unwrap(), request))
+                .await
+                .unwrap();
+        let address = server.address();
+        let light = NanoleafLight::new(
+            address.ip(),
+            address.port(),
+            TOKEN,
+            &NanoleafConfig::default(),
+        )
+        .await
+        .unwrap();
+        (server, light)
+    }
+
+    /// The path after the token and the body of every PUT so far.
+    fn puts(server: &FakeHttpServer) -> Vec<(String, serde_json::Value)> {
+        let prefix = format!("/api/v1/{}/", TOKEN);
+        server
+            .requests()
+            .into_iter()
+            .filter(|request| request.method == "PUT")
+            .map(|request| {
+                let path = request.route().strip_prefix(&prefix).unwrap().to_string();
+                (path, request.body.unwrap())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn builds_ext_control_frames() {
+        let frame = ext_control_frame(&[100, 300], &[(255, 128, 0), (1, 2, 3)], 1);
+        assert_eq!(
+            frame,
+            [
+                0, 2, // panel count
+                0, 100, 255, 128, 0, 0, 0, 1, // panel id, rgbw, transition
+                1, 44, 1, 2, 3, 0, 0, 1,
+            ]
+        );
+
+        // Panels without a color are left out of the count
+        let frame = ext_control_frame(&[100, 300, 200], &[(9, 9, 9)], 5);
+        assert_eq!(frame, [0, 1, 0, 100, 9, 9, 9, 0, 0, 5]);
+    }
+
+    #[tokio::test]
+    async fn parses_info() {
+        let (_server, light) = controller().await;
+        assert_eq!(light.id(), "nanoleaf::S19124C8036");
+        assert_eq!(light.name(), "Shapes 4A2B");
+        assert!(light.is_on());
+        assert_eq!(light.brightness(), 80);
+        assert_eq!((light.red(), light.green(), light.blue()), (0, 0, 255));
+        let info = light.device_info();
+        assert_eq!(info.model.as_deref(), Some("NL42"));
+        assert_eq!(info.firmware_version.as_deref(), Some("9.2.4"));
+        assert_eq!(info.hardware_version.as_deref(), Some("1.4-0"));
+    }
+
+    #[tokio::test]
+    async fn orders_lit_panels_left_to_right() {
+        let (_server, light) = controller().await;
+        // The controller (12) and connector (19) are not segments
+        assert_eq!(light.panels, [100, 200, 300]);
+        assert_eq!(light.segment_count(), 3);
+    }
+
+    #[tokio::test]
+    async fn sends_state() {
+        let (server, mut light) = controller().await;
+        light.set_on(false).await.unwrap();
+        light.set_brightness(150).await.unwrap();
+        light.set_color(255, 0, 0).await.unwrap();
+        light.set_color_temperature(10000).await.unwrap();
+
+        assert_eq!(
+            puts(&server),
+            [
+                ("state".to_string(), json!({ "on": { "value": false } })),
+                (
+                    "state".to_string(),
+                    json!({ "brightness": { "value": 100 } })
+                ),
+                (
+                    "state".to_string(),
+                    json!({ "hue": { "value": 0 }, "sat": { "value": 100 } })
+                ),
+                ("state".to_string(), json!({ "ct": { "value": 6500 } })),
+            ]
+        );
+        assert!(!light.is_on());
+        assert_eq!(light.brightness(), 100);
+        assert_eq!((light.red(), light.green(), light.blue()), (255, 0, 0));
+    }
+
+    #[tokio::test]
+    async fn selects_effects_from_the_list() {
+        let (server, mut light) = controller().await;
+        assert_eq!(light.effects(), ["Northern Lights", "Fireplace"]);
+
+        light.set_effect("fireplace").await.unwrap();
+        assert!(light.set_effect("Disco").await.is_err());
+        assert_eq!(
+            puts(&server),
+            [("effects".to_string(), json!({ "select": "Fireplace" }))]
+        );
+    }
+
+    #[tokio::test]
+    async fn streams_segments_and_resumes_the_effect() {
+        // External control always goes to the fixed port on the controller
+        let panels = UdpSocket::bind((Ipv4Addr::LOCALHOST, EXT_CONTROL_PORT))
+            .await
+            .unwrap();
+        let (server, mut light) = controller().await;
+
+        light
+            .set_segment_colors(&[(255, 0, 0), (0, 255, 0)])
+            .await
+            .unwrap();
+        let mut frame = [0; 64];
+        let length = tokio::time::timeout(Duration::from_secs(1), panels.recv(&mut frame))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            frame[..length],
+            [0, 2, 0, 100, 255, 0, 0, 0, 0, 1, 0, 200, 0, 255, 0, 0, 0, 1]
+        );
+        assert!(light.set_segment_colors(&[(0, 0, 0); 4]).await.is_err());
+
+        light.set_realtime(false).await.unwrap();
+        assert_eq!(
+            puts(&server),
+            [
+                (
+                    "effects".to_string(),
+                    json!({
+                        "write": {
+                            "command": "display",
+                            "animType": "extControl",
+                            "extControlVersion": "v2"
+                        }
+                    })
+                ),
+                (
+                    "effects".to_string(),
+                    json!({ "select": "Northern Lights" })
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn pairing_reports_a_controller_not_in_pairing_mode() {
+        let server = FakeHttpServer::start(|_| (403, serde_json::Value::Null))
+            .await
+            .unwrap();
+        let address = server.address();
+
+        let error = pair(address.ip(), address.port()).await.unwrap_err();
+        assert!(
+            error.to_string().contains("not in pairing mode"),
+            "{}",
+            error
+        );
+    }
+
+    #[tokio::test]
+    async fn pairing_returns_the_token() {
+        let server = FakeHttpServer::start(|_| (200, json!({ "auth_token": TOKEN })))
+            .await
+            .unwrap();
+        let address = server.address();
+
+        assert_eq!(pair(address.ip(), address.port()).await.unwrap(), TOKEN);
+        let requests = server.requests();
+        assert_eq!(requests[0].method, "POST");
+        assert_eq!(requests[0].route(), "/api/v1/new");
+    }
+}
@@ -0,0 +1,801 @@
+use crate::{config::CuteLightsConfig, utils::mdns};
+use async_trait::async_trait;
+use std::{
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use super::{DeviceInfo, Integration, Light};
+
+const OPENRGB_PORT: u16 = 6742;
+const MAGIC: &[u8; 4] = b"ORGB";
+
+// Newer servers answer with the lowest version both sides speak
+const PROTOCOL_VERSION: u32 = 3;
+
+const REQUEST_CONTROLLER_COUNT: u32 = 0;
+const REQUEST_CONTROLLER_DATA: u32 = 1;
+const REQUEST_PROTOCOL_VERSION: u32 = 40;
+const SET_CLIENT_NAME: u32 = 50;
+const UPDATE_LEDS: u32 = 1050;
+const UPDATE_ZONE_LEDS: u32 = 1051;
+const SET_CUSTOM_MODE: u32 = 1100;
+
+// ANCHOR - OpenRgbLight
+
+/// A whole controller, or one zone of it when `zone` is set.
+pub struct OpenRgbLight {
+    server: Arc<Server>,
+    controller: Arc<Controller>,
+    zone: Option<u32>,
+    colors: Vec<(u8, u8, u8)>,
+    brightness: u8,
+    is_on: bool,
+}
+
+impl OpenRgbLight {
+    fn new(server: Arc<Server>, controller: Arc<Controller>, zone: Option<u32>) -> Self {
+        let colors = match zone {
+            Some(zone) => {
+                let start = controller.zones[..zone as usize]
+                    .iter()
+                    .map(|z| z.leds_count as usize)
+                    .sum::<usize>();
+                let count = controller.zones[zone as usize].leds_count as usize;
+                controller
+                    .colors
+                    .iter()
+                    .skip(start)
+                    .take(count)
+                    .copied()
+                    .collect()
+            }
+            None => controller.colors.clone(),
+        };
+        let is_on = colors.iter().any(|&color| color != (0, 0, 0));
+        Self {
+            server,
+            controller,
+            zone,
+            colors,
+            brightness: 100,
+            is_on,
+        }
+    }
+
+    /// The first lit LED stands in for the color of the light.
+    fn color(&self) -> (u8, u8, u8) {
+        self.colors
+            .iter()
+            .find(|&&color| color != (0, 0, 0))
+            .or(self.colors.first())
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// OpenRGB has no notion of power or brightness, both are applied to the
+    /// colors sent.
+    async fn update(&self) -> anyhow::Result<()> {
+        // Controllers only take colors in their direct or custom mode
+        if !self.controller.custom_mode.swap(true, Ordering::SeqCst) {
+            self.server
+                .send(self.controller.index, SET_CUSTOM_MODE, &[])
+                .await?;
+        }
+
+        let scale = |value: u8| (value as u16 * self.brightness as u16 / 100) as u8;
+        let mut data = Vec::with_capacity(self.colors.len() * 4 + 10);
+        let size = 4 + self.zone.map_or(0, |_| 4) + 2 + self.colors.len() as u32 * 4;
+        data.extend_from_slice(&size.to_le_bytes());
+        if let Some(zone) = self.zone {
+            data.extend_from_slice(&zone.to_le_bytes());
+        }
+        data.extend_from_slice(&(self.colors.len() as u16).to_le_bytes());
+        for &(red, green, blue) in &self.colors {
+            if self.is_on {
+                data.extend_from_slice(&[scale(red), scale(green), scale(blue), 0]);
+            } else {
+                data.extend_from_slice(&[0; 4]);
+            }
+        }
+
+        let packet = match self.zone {
+            Some(_) => UPDATE_ZONE_LEDS,
+            None => UPDATE_LEDS,
+        };
+        self.server.send(self.controller.index, packet, &data).await
+    }
+}
+
+#[async_trait]
+impl Light for OpenRgbLight {
+    async fn set_on(&mut self, on: bool) -> anyhow::Result<()> {
+        self.is_on = on;
+        self.update().await
+    }
+
+    async fn set_color(&mut self, red: u8, green: u8, blue: u8) -> anyhow::Result<()> {
+        self.colors.fill((red, green, blue));
+        self.is_on = true;
+        self.update().await
+    }
+
+    async fn set_brightness(&mut self, brightness: u8) -> anyhow::Result<()> {
+        self.brightness = brightness.min(100);
+        self.update().await
+    }
+
+    fn is_on(&self) -> bool {
+        self.is_on
+    }
+
+    fn name(&self) -> String {
+        match self.zone {
+            Some(zone) => format!(
+                "{} {}",
+                self.controller.name, self.controller.zones[zone as usize].name
+            ),
+            None => self.controller.name.clone(),
+        }
+    }
+
+    fn supports_color(&self) -> bool {
+        true
+    }
+
+    fn red(&self) -> u8 {
+        self.color().0
+    }
+
+    fn green(&self) -> u8 {
+        self.color().1
+    }
+
+    fn blue(&self) -> u8 {
+        self.color().2
+    }
+
+    fn brightness(&self) -> u8 {
+        self.brightness
+    }
+
+    fn id(&self) -> String {
+        // Controller indexes shift as devices come and go, the serial or the
+        // bus location does not
+        let device = if self.controller.serial.is_empty() {
+            &self.controller.location
+        } else {
+            &self.controller.serial
+        };
+        match self.zone {
+            Some(zone) => format!("openrgb::{}_{}", device, zone),
+            None => format!("openrgb::{}", device),
+        }
+    }
+
+    fn device_info(&self) -> DeviceInfo {
+        DeviceInfo {
+            manufacturer: if self.controller.vendor.is_empty() {
+                "OpenRGB".to_string()
+            } else {
+                self.controller.vendor.clone()
+            },
+            model: Some(self.controller.description.clone())
+                .filter(|description| !description.is_empty()),
+            firmware_version: Some(self.controller.version.clone())
+                .filter(|version| !version.is_empty()),
+            hardware_version: None,
+        }
+    }
+
+    fn segment_count(&self) -> usize {
+        self.colors.len()
+    }
+
+    async fn set_segment_colors(&mut self, colors: &[(u8, u8, u8)]) -> anyhow::Result<()> {
+        if colors.len() > self.colors.len() {
+            return Err(anyhow::anyhow!(
+                "{} has {} LEDs, got {} colors",
+                self.name(),
+                self.colors.len(),
+                colors.len()
+            ));
+        }
+        self.colors[..colors.len()].copy_from_slice(colors);
+        self.is_on = true;
+        self.update().await
+    }
+}
+
+// ANCHOR - Server
+
+/// One SDK connection, shared by the lights of every controller on it.
+struct Server {
+    stream: tokio::sync::Mutex<TcpStream>,
+    timeout: Duration,
+}
+
+impl Server {
+    async fn connect(host: IpAddr, port: u16, timeout: Duration) -> anyhow::Result<Self> {
+        let stream = tokio::time::timeout(timeout, TcpStream::connect((host, port)))
+            .await
+            .map_err(|_| anyhow::anyhow!("Timed out connecting to {}:{}", host, port))??;
+        stream.set_nodelay(true)?;
+        Ok(Self {
+            stream: tokio::sync::Mutex::new(stream),
+            timeout,
+        })
+    }
+
+    async fn send(&self, device: u32, packet: u32, data: &[u8]) -> anyhow::Result<()> {
+        let mut stream = self.stream.lock().await;
+        stream
+            .write_all(&header(device, packet, data.len()))
+            .await?;
+        stream.write_all(data).await?;
+        Ok(())
+    }
+
+    /// Sends a request and waits for the reply of the same packet id,
+    /// skipping the notifications the server sends in between.
+    async fn request(&self, device: u32, packet: u32, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut stream = self.stream.lock().await;
+        stream
+            .write_all(&header(device, packet, data.len()))
+            .await?;
+        stream.write_all(data).await?;
+
+        tokio::time::timeout(self.timeout, async {
+            loop {
+                let mut header = [0; 16];
+                stream.read_exact(&mut header).await?;
+                if &header[..4] != MAGIC {
+                    return Err(anyhow::anyhow!("Not an OpenRGB SDK server"));
+                }
+                let id = u32::from_le_bytes([header[8], header[9], header[10], header[11]]);
+                let size = u32::from_le_bytes([header[12], header[13], header[14], header[15]]);
+                let mut reply = vec![0; size as usize];
+                stream.read_exact(&mut reply).await?;
+                if id == packet {
+                    return Ok(reply);
+                }
+            }
+        })
+        .await
+        .map_err(|_| anyhow::anyhow!("OpenRGB server did not answer packet {}", packet))?
+    }
+}
+
+fn header(device: u32, packet: u32, size: usize) -> [u8; 16] {
+    let mut header = [0; 16];
+    header[..4].copy_from_slice(MAGIC);
+    header[4..8].copy_from_slice(&device.to_le_bytes());
+    header[8..12].copy_from_slice(&packet.to_le_bytes());
+    header[12..].copy_from_slice(&(size as u32).to_le_bytes());
+    header
+}
+
+// ANCHOR - Parsing
+
+struct Controller {
+    index: u32,
+    name: String,
+    vendor: String,
+    description: String,
+    version: String,
+    serial: String,
+    location: String,
+    zones: Vec<Zone>,
+    colors: Vec<(u8, u8, u8)>,
+    custom_mode: AtomicBool,
+}
+
+struct Zone {
+    name: String,
+    leds_count: u32,
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> anyhow::Result<&'a [u8]> {
+        if self.data.len() < len {
+            return Err(anyhow::anyhow!("Controller data is truncated"));
+        }
+        let (taken, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    fn u16(&mut self) -> anyhow::Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into()?))
+    }
+
+    fn u32(&mut self) -> anyhow::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    /// Strings carry their length, NUL included.
+    fn string(&mut self) -> anyhow::Result<String> {
+        let len = self.u16()? as usize;
+        let bytes = self.take(len)?;
+        let bytes = bytes.strip_suffix(&[0]).unwrap_or(bytes);
+        Ok(String::from_utf8_lossy(bytes).to_string())
+    }
+
+    fn colors(&mut self) -> anyhow::Result<Vec<(u8, u8, u8)>> {
+        let count = self.u16()? as usize;
+        let bytes = self.take(count * 4)?;
+        Ok(bytes.chunks(4).map(|c| (c[0], c[1], c[2])).collect())
+    }
+}
+
+fn parse_controller(index: u32, data: &[u8], protocol: u32) -> anyhow::Result<Controller> {
+    let mut reader = Reader { data };
+    reader.u32()?; // data size
+    reader.u32()?; // device type
+    let name = reader.string()?;
+    let vendor = if protocol >= 1 {
+        reader.string()?
+    } else {
+        String::new()
+    };
+    let description = reader.string()?;
+    let version = reader.string()?;
+    let serial = reader.string()?;
+    let location = reader.string()?;
+
+    let modes = reader.u16()?;
+    reader.u32()?; // active mode
+    for _ in 0..modes {
+        reader.string()?;
+        // value, flags, speed min and max
+        reader.take(16)?;
+        if protocol >= 3 {
+            // brightness min and max
+            reader.take(8)?;
+        }
+        // colors min and max, speed
+        reader.take(12)?;
+        if protocol >= 3 {
+            // brightness
+            reader.take(4)?;
+        }
+        // direction, color mode
+        reader.take(8)?;
+        reader.colors()?;
+    }
+
+    let mut zones = Vec::new();
+    for _ in 0..reader.u16()? {
+        let name = reader.string()?;
+        // type, leds min and max
+        reader.take(12)?;
+        let leds_count = reader.u32()?;
+        let matrix = reader.u16()? as usize;
+        reader.take(matrix)?;
+        zones.push(Zone { name, leds_count });
+    }
+
+    for _ in 0..reader.u16()? {
+        reader.string()?;
+        reader.u32()?; // value
+    }
+    let colors = reader.colors()?;
+
+    Ok(Controller {
+        index,
+        name,
+        vendor,
+        description,
+        version,
+        serial,
+        location,
+        zones,
+        colors,
+        custom_mode: AtomicBool::new(false),
+    })
+}
+
+async fn discover_server(
+    host: IpAddr,
+    port: u16,
+    config: &OpenRgbConfig,
+) -> anyhow::Result<Vec<Box<dyn Light>>> {
+    let server =
+        Arc::new(Server::connect(host, port, Duration::from_millis(config.request_timeout)).await?);
+
+    let reply = server
+        .request(0, REQUEST_PROTOCOL_VERSION, &PROTOCOL_VERSION.to_le_bytes())
+        .await?;
+    let protocol = Reader { data: &reply }.u32()?.min(PROTOCOL_VERSION);
+    server.send(0, SET_CLIENT_NAME, b"cute_lights\0").await?;
+
+    let reply = server.request(0, REQUEST_CONTROLLER_COUNT, &[]).await?;
+    let count = Reader { data: &reply }.u32()?;
+
+    let mut lights: Vec<Box<dyn Light>> = Vec::new();
+    for index in 0..count {
+        let data = server
+            .request(index, REQUEST_CONTROLLER_DATA, &protocol.to_le_bytes())
+            .await?;
+        let controller = Arc::new(parse_controller(index, &data, protocol)?);
+        if controller.colors.is_empty() {
+            continue;
+        }
+
+        lights.push(Box::new(OpenRgbLight::new(
+            server.clone(),
+            controller.clone(),
+            None,
+        )));
+        // A zone light for a single zone controller would only repeat it
+        if controller.zones.len() > 1 {
+            for (zone, info) in controller.zones.iter().enumerate() {
+                if info.leds_count > 0 {
+                    lights.push(Box::new(OpenRgbLight::new(
+                        server.clone(),
+                        controller.clone(),
+                        Some(zone as u32),
+                    )));
+                }
+            }
+        }
+    }
+    Ok(lights)
+}
+
+// ANCHOR - OpenRgbConfig
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct OpenRgbConfig {
+    pub enabled: bool,
+    /// SDK servers, as `host` or `host:port`
+    #[serde(default = "default_addresses")]
+    pub addresses: Vec<String>,
+    #[serde(default = "default_request_timeout")]
+    pub request_timeout: u64,
+}
+
+impl Default for OpenRgbConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            addresses: default_addresses(),
+            request_timeout: default_request_timeout(),
+        }
+    }
+}
+
+fn default_addresses() -> Vec<String> {
+    vec!["127.0.0.1".to_string()]
+}
+
+fn default_request_timeout() -> u64 {
+    2000
+}
+
+// ANCHOR - OpenRgbIntegration
+
+pub struct OpenRgbIntegration;
+
+#[async_trait]
+impl Integration for OpenRgbIntegration {
+    fn name(&self) -> String {
+        "openrgb".to_string()
+    }
+
+    fn preflight(&self, config: &CuteLightsConfig) -> bool {
+        config.openrgb.enabled
+    }
+
+    async fn discover(&self, config: &CuteLightsConfig) -> anyhow::Result<Vec<Box<dyn Light>>> {
+        let config = &config.openrgb;
+        let hosts =
+            mdns::resolve_hosts(&config.addresses, OPENRGB_PORT, None, Duration::ZERO).await;
+
+        let mut lights = Vec::new();
+        for (host, port) in hosts {
+            match discover_server(host, port, config).await {
+                Ok(found) => lights.extend(found),
+                Err(e) => eprintln!("Failed to list OpenRGB devices on {}:{}: {}", host, port, e),
+            }
+        }
+        Ok(lights)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use tokio::net::TcpListener;
+
+    struct FakeZone {
+        name: &'static str,
+        leds: u32,
+    }
+
+    struct FakeController {
+        name: &'static str,
+        serial: &'static str,
+        zones: Vec<FakeZone>,
+        colors: Vec<(u8, u8, u8)>,
+    }
+
+    /// Device index, packet id and data
+    type Packet = (u32, u32, Vec<u8>);
+
+    struct FakeServer {
+        port: u16,
+        received: Arc<Mutex<Vec<Packet>>>,
+    }
+
+    fn put_string(data: &mut Vec<u8>, value: &str) {
+        data.extend_from_slice(&(value.len() as u16 + 1).to_le_bytes());
+        data.extend_from_slice(value.as_bytes());
+        data.push(0);
+    }
+
+    fn put_colors(data: &mut Vec<u8>, colors: &[(u8, u8, u8)]) {
+        data.extend_from_slice(&(colors.len() as u16).to_le_bytes());
+        for &(red, green, blue) in colors {
+            data.extend_from_slice(&[red, green, blue, 0]);
+        }
+    }
+
+    /// Serializes a controller the way the server does at protocol 3.
+    fn controller_data(controller: &FakeController) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&0u32.to_le_bytes());
+        data.extend_from_slice(&5i32.to_le_bytes());
+        put_string(&mut data, controller.name);
+        put_string(&mut data, "Corsair");
+        put_string(&mut data, "Corsair Peripheral Device");
+        put_string(&mut data, "1.2");
+        put_string(&mut data, controller.serial);
+        put_string(&mut data, "HID: /dev/hidraw3");
+
+        data.extend_from_slice(&1u16.to_le_bytes());
+        data.extend_from_slice(&0i32.to_le_bytes());
+        put_string(&mut data, "Direct");
+        data.extend_from_slice(&[0; 48]);
+        put_colors(&mut data, &[(1, 2, 3)]);
+
+        data.extend_from_slice(&(controller.zones.len() as u16).to_le_bytes());
+        for zone in &controller.zones {
+            put_string(&mut data, zone.name);
+            data.extend_from_slice(&1i32.to_le_bytes());
+            data.extend_from_slice(&zone.leds.to_le_bytes());
+            data.extend_from_slice(&zone.leds.to_le_bytes());
+            data.extend_from_slice(&zone.leds.to_le_bytes());
+            // A 1x1 matrix
+            data.extend_from_slice(&12u16.to_le_bytes());
+            data.extend_from_slice(&[1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0]);
+        }
+
+        data.extend_from_slice(&(controller.colors.len() as u16).to_le_bytes());
+        for index in 0..controller.colors.len() {
+            put_string(&mut data, &format!("LED {}", index));
+            data.extend_from_slice(&(index as u32).to_le_bytes());
+        }
+        put_colors(&mut data, &controller.colors);
+
+        let size = data.len() as u32;
+        data[..4].copy_from_slice(&size.to_le_bytes());
+        data
+    }
+
+    impl FakeServer {
+        async fn start(controllers: Vec<FakeController>) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let received = Arc::new(Mutex::new(Vec::new()));
+
+            let log = received.clone();
+            tokio::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                loop {
+                    let mut head = [0; 16];
+                    if stream.read_exact(&mut head).await.is_err() {
+                        return;
+                    }
+                    assert_eq!(&head[..4], MAGIC);
+                    let device = u32::from_le_bytes(head[4..8].try_into().unwrap());
+                    let packet = u32::from_le_bytes(head[8..12].try_into().unwrap());
+                    let size = u32::from_le_bytes(head[12..].try_into().unwrap());
+                    let mut data = vec![0; size as usize];
+                    stream.read_exact(&mut data).await.unwrap();
+
+                    let reply = match packet {
+                        REQUEST_PROTOCOL_VERSION => Some(4u32.to_le_bytes().to_vec()),
+                        REQUEST_CONTROLLER_COUNT => {
+                            // Notifications may arrive before any reply
+                            stream.write_all(&header(0, 100, 0)).await.unwrap();
+                            Some((controllers.len() as u32).to_le_bytes().to_vec())
+                        }
+                        REQUEST_CONTROLLER_DATA => {
+                            assert_eq!(data, 3u32.to_le_bytes());
+                            Some(controller_data(&controllers[device as usize]))
+                        }
+                        _ => {
+                            log.lock().unwrap().push((device, packet, data));
+                            None
+                        }
+                    };
+                    if let Some(reply) = reply {
+                        stream
+                            .write_all(&header(device, packet, reply.len()))
+                            .await
+                            .unwrap();
+                        stream.write_all(&reply).await.unwrap();
+                    }
+                }
+            });
+
+            Self { port, received }
+        }
+
+        /// Waits for the next packet the server does not answer.
+        async fn expect(&self) -> Packet {
+            for _ in 0..100 {
+                {
+                    let mut received = self.received.lock().unwrap();
+                    if !received.is_empty() {
+                        return received.remove(0);
+                    }
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            panic!("The server received nothing");
+        }
+    }
+
+    fn fake_controllers() -> Vec<FakeController> {
+        vec![
+            FakeController {
+                name: "K70 Keyboard",
+                serial: "0B0F1A2C",
+                zones: vec![
+                    FakeZone {
+                        name: "Keys",
+                        leds: 3,
+                    },
+                    FakeZone {
+                        name: "Logo",
+                        leds: 2,
+                    },
+                ],
+                colors: vec![
+                    (0, 0, 0),
+                    (255, 0, 0),
+                    (255, 0, 0),
+                    (0, 0, 255),
+                    (0, 0, 255),
+                ],
+            },
+            FakeController {
+                name: "M65 Mouse",
+                serial: "",
+                zones: vec![FakeZone {
+                    name: "Mouse",
+                    leds: 2,
+                }],
+                colors: vec![(0, 0, 0), (0, 0, 0)],
+            },
+        ]
+    }
+
+    async fn discover(server: &FakeServer) -> Vec<Box<dyn Light>> {
+        let config = CuteLightsConfig {
+            openrgb: OpenRgbConfig {
+                enabled: true,
+                addresses: vec![format!("127.0.0.1:{}", server.port)],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        OpenRgbIntegration.discover(&config).await.unwrap()
+    }
+
+    fn colors(data: &[u8]) -> Vec<[u8; 4]> {
+        data.chunks(4).map(|c| c.try_into().unwrap()).collect()
+    }
+
+    #[tokio::test]
+    async fn lists_controllers_and_zones() {
+        let server = FakeServer::start(fake_controllers()).await;
+        let mut lights = discover(&server).await;
+
+        let ids: Vec<String> = lights.iter().map(|light| light.id()).collect();
+        assert_eq!(
+            ids,
+            [
+                "openrgb::0B0F1A2C",
+                "openrgb::0B0F1A2C_0",
+                "openrgb::0B0F1A2C_1",
+                "openrgb::HID: /dev/hidraw3"
+            ]
+        );
+        let names: Vec<String> = lights.iter().map(|light| light.name()).collect();
+        assert_eq!(
+            names,
+            [
+                "K70 Keyboard",
+                "K70 Keyboard Keys",
+                "K70 Keyboard Logo",
+                "M65 Mouse"
+            ]
+        );
+        let segments: Vec<usize> = lights.iter().map(|light| light.segment_count()).collect();
+        assert_eq!(segments, [5, 3, 2, 2]);
+
+        let keyboard = &lights[0];
+        assert!(keyboard.is_on());
+        assert_eq!(
+            (keyboard.red(), keyboard.green(), keyboard.blue()),
+            (255, 0, 0)
+        );
+        assert_eq!(keyboard.device_info().manufacturer, "Corsair");
+        let logo = &lights[2];
+        assert_eq!((logo.red(), logo.green(), logo.blue()), (0, 0, 255));
+        assert!(!lights[3].is_on());
+
+        assert_eq!(
+            server.expect().await,
+            (0, SET_CLIENT_NAME, b"cute_lights\0".to_vec())
+        );
+        lights[3].set_on(true).await.unwrap();
+        assert_eq!(server.expect().await, (1, SET_CUSTOM_MODE, Vec::new()));
+    }
+
+    #[tokio::test]
+    async fn updates_leds() {
+        let server = FakeServer::start(fake_controllers()).await;
+        let mut lights = discover(&server).await;
+        server.expect().await;
+
+        let keyboard = &mut lights[0];
+        keyboard.set_color(0, 255, 0).await.unwrap();
+        assert_eq!(server.expect().await, (0, SET_CUSTOM_MODE, Vec::new()));
+        let (device, packet, data) = server.expect().await;
+        assert_eq!((device, packet), (0, UPDATE_LEDS));
+        assert_eq!(u32::from_le_bytes(data[..4].try_into().unwrap()), 26);
+        assert_eq!(u16::from_le_bytes(data[4..6].try_into().unwrap()), 5);
+        assert_eq!(colors(&data[6..]), [[0, 255, 0, 0]; 5]);
+
+        keyboard.set_brightness(50).await.unwrap();
+        let (_, _, data) = server.expect().await;
+        assert_eq!(colors(&data[6..]), [[0, 127, 0, 0]; 5]);
+        assert_eq!(keyboard.brightness(), 50);
+
+        keyboard.set_on(false).await.unwrap();
+        let (_, _, data) = server.expect().await;
+        assert_eq!(colors(&data[6..]), [[0; 4]; 5]);
+        assert!(!keyboard.is_on());
+
+        let logo = &mut lights[2];
+        logo.set_segment_colors(&[(255, 255, 0)]).await.unwrap();
+        let (device, packet, data) = server.expect().await;
+        assert_eq!((device, packet), (0, UPDATE_ZONE_LEDS));
+        assert_eq!(u32::from_le_bytes(data[..4].try_into().unwrap()), 18);
+        assert_eq!(u32::from_le_bytes(data[4..8].try_into().unwrap()), 1);
+        assert_eq!(u16::from_le_bytes(data[8..10].try_into().unwrap()), 2);
+        assert_eq!(colors(&data[10..]), [[255, 255, 0, 0], [0, 0, 255, 0]]);
+
+        assert!(logo
+            .set_segment_colors(&[(0, 0, 0), (0, 0, 0), (0, 0, 0)])
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,760 @@
+use crate::{
+    config::CuteLightsConfig,
+    utils::{future::FutureBatch, mdns},
+};
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde_json::json;
+use std::{net::IpAddr, time::Duration};
+
+use super::{DeviceInfo, Integration, Light};
+
+// Both generations announce a web server named after the device id
+const HTTP_SERVICE: &str = "_http._tcp.local.";
+const HOSTNAME_PREFIX: &str = "shelly";
+
+// ANCHOR - Api
+
+enum Api {
+    /// Gen1 REST, either `light/0` or `color/0` on an RGBW2 in color mode
+    Gen1 { path: &'static str },
+    /// Gen2 and later RPC, by component
+    Rpc { component: Component },
+}
+
+#[derive(Clone, Copy)]
+enum Component {
+    Light,
+    Rgb,
+    Rgbw,
+}
+
+impl Component {
+    fn key(self) -> &'static str {
+        match self {
+            Component::Light => "light:0",
+            Component::Rgb => "rgb:0",
+            Component::Rgbw => "rgbw:0",
+        }
+    }
+
+    fn method(self, method: &str) -> String {
+        let prefix = match self {
+            Component::Light => "Light",
+            Component::Rgb => "RGB",
+            Component::Rgbw => "RGBW",
+        };
+        format!("{}.{}", prefix, method)
+    }
+}
+
+// ANCHOR - ShellyLight
+pub struct ShellyLight {
+    client: reqwest::Client,
+    base_url: String,
+    api: Api,
+    device: ShellyDevice,
+    name: Option<String>,
+    supports_color: bool,
+    has_white_channel: bool,
+    supports_temperature: bool,
+    // Gen1 lights dim color with `gain` and white with `brightness`
+    color_mode: bool,
+    is_on: bool,
+    brightness: u8,
+    red: u8,
+    green: u8,
+    blue: u8,
+}
+
+impl ShellyLight {
+    pub async fn new(
+        host: IpAddr,
+        port: u16,
+        config: &ShellyConfig,
+    ) -> anyhow::Result<ShellyLight> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_millis(config.request_timeout))
+            .build()?;
+        let base_url = match host {
+            IpAddr::V4(addr) => format!("http://{}:{}", addr, port),
+            IpAddr::V6(addr) => format!("http://[{}]:{}", addr, port),
+        };
+
+        let body = client
+            .get(format!("{}/shelly", base_url))
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        let device: ShellyDevice = serde_json::from_str(&body)?;
+
+        let mut light = ShellyLight {
+            client,
+            base_url,
+            api: Api::Gen1 { path: "light/0" },
+            name: device.name.clone(),
+            device,
+            supports_color: false,
+            has_white_channel: false,
+            supports_temperature: false,
+            color_mode: false,
+            is_on: false,
+            brightness: 0,
+            red: 0,
+            green: 0,
+            blue: 0,
+        };
+
+        if light.device.gen.unwrap_or(1) >= 2 {
+            light.init_rpc().await?;
+        } else {
+            light.init_gen1().await?;
+        }
+
+        Ok(light)
+    }
+
+    async fn init_gen1(&mut self) -> anyhow::Result<()> {
+        let mut status = self.gen1_get("light/0", &[]).await;
+        if status.is_err() {
+            // The RGBW2 only has color/0 while it is in color mode
+            status = self.gen1_get("color/0", &[]).await;
+            self.api = Api::Gen1 { path: "color/0" };
+        }
+        let status: Gen1Status = serde_json::from_value(status?)?;
+
+        self.supports_color = status.red.is_some();
+        self.has_white_channel = status.white.is_some();
+        self.supports_temperature = status.temp.is_some();
+        self.update_from_gen1(&status);
+
+        if let Ok(settings) = self.gen1_get("settings", &[]).await {
+            self.name = settings["name"].as_str().map(str::to_string);
+        }
+        Ok(())
+    }
+
+    async fn init_rpc(&mut self) -> anyhow::Result<()> {
+        let status = self.rpc("Shelly.GetStatus", json!({})).await?;
+        let component = [Component::Rgbw, Component::Rgb, Component::Light]
+            .into_iter()
+            .find(|component| !status[component.key()].is_null())
+            .ok_or_else(|| anyhow::anyhow!("Shelly {} has no light output", self.device.mac))?;
+
+        self.api = Api::Rpc { component };
+        self.supports_color = !matches!(component, Component::Light);
+        self.has_white_channel = matches!(component, Component::Rgbw);
+        self.color_mode = self.supports_color;
+
+        let status: RpcStatus = serde_json::from_value(status[component.key()].clone())?;
+        self.is_on = status.output;
+        if let Some(brightness) = status.brightness {
+            self.brightness = brightness as u8;
+        }
+        if let Some([r, g, b]) = status.rgb {
+            let white = status.white.unwrap_or(0);
+            self.red = r.saturating_add(white);
+            self.green = g.saturating_add(white);
+            self.blue = b.saturating_add(white);
+        }
+        Ok(())
+    }
+
+    fn update_from_gen1(&mut self, status: &Gen1Status) {
+        self.is_on = status.ison;
+        self.color_mode = match &status.mode {
+            Some(mode) => mode == "color",
+            None => self.supports_color,
+        };
+        let brightness = if self.color_mode {
+            status.gain.or(status.brightness)
+        } else {
+            status.brightness
+        };
+        if let Some(brightness) = brightness {
+            self.brightness = brightness;
+        }
+        if let (Some(r), Some(g), Some(b)) = (status.red, status.green, status.blue) {
+            let white = status.white.unwrap_or(0);
+            self.red = r.saturating_add(white);
+            self.green = g.saturating_add(white);
+            self.blue = b.saturating_add(white);
+        }
+    }
+
+    async fn gen1_get(
+        &self,
+        path: &str,
+        query: &[(&str, String)],
+    ) -> anyhow::Result<serde_json::Value> {
+        let body = self
+            .client
+            .get(format!("{}/{}", self.base_url, path))
+            .query(query)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        Ok(serde_json::from_str(&body)?)
+    }
+
+    async fn rpc(
+        &self,
+        method: &str,
+        params: serde_json::Value,
+    ) -> anyhow::Result<serde_json::Value> {
+        let body = json!({ "id": 1, "method": method, "params": params });
+        let body = self
+            .client
+            .post(format!("{}/rpc", self.base_url))
+            .body(body.to_string())
+            .send()
+            .await?
+            .text()
+            .await?;
+        let response: RpcResponse = serde_json::from_str(&body)?;
+        if let Some(error) = response.error {
+            return Err(anyhow::anyhow!("{} failed: {}", method, error.message));
+        }
+        Ok(response.result.unwrap_or_default())
+    }
+
+    /// Sends a change to the light output, `query` for Gen1 or `params` for RPC.
+    async fn set(
+        &mut self,
+        query: &[(&str, String)],
+        params: serde_json::Value,
+    ) -> anyhow::Result<()> {
+        match &self.api {
+            Api::Gen1 { path } => {
+                let status = self.gen1_get(path, query).await?;
+                if let Ok(status) = serde_json::from_value::<Gen1Status>(status) {
+                    self.update_from_gen1(&status);
+                }
+            }
+            Api::Rpc { component } => {
+                let mut params = params;
+                params["id"] = json!(0);
+                self.rpc(&component.method("Set"), params).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Light for ShellyLight {
+    async fn set_on(&mut self, on: bool) -> anyhow::Result<()> {
+        let turn = if on { "on" } else { "off" };
+        self.set(&[("turn", turn.to_string())], json!({ "on": on }))
+            .await?;
+        self.is_on = on;
+        Ok(())
+    }
+
+    async fn set_color(&mut self, red: u8, green: u8, blue: u8) -> anyhow::Result<()> {
+        if !self.supports_color {
+            return Err(anyhow::anyhow!("{} does not support color", self.name()));
+        }
+
+        // Lights with a white channel get the shared part of the color from
+        // the white LEDs, the rest from RGB
+        let white = if self.has_white_channel {
+            red.min(green).min(blue)
+        } else {
+            0
+        };
+        let (r, g, b) = (red - white, green - white, blue - white);
+
+        let mut query = vec![
+            ("turn", "on".to_string()),
+            ("red", r.to_string()),
+            ("green", g.to_string()),
+            ("blue", b.to_string()),
+        ];
+        let mut params = json!({ "on": true, "rgb": [r, g, b] });
+        if self.has_white_channel {
+            query.push(("white", white.to_string()));
+            params["white"] = json!(white);
+        }
+        if matches!(self.api, Api::Gen1 { path: "light/0" }) {
+            query.push(("mode", "color".to_string()));
+        }
+
+        self.set(&query, params).await?;
+        self.color_mode = true;
+        self.is_on = true;
+        self.red = red;
+        self.green = green;
+        self.blue = blue;
+        Ok(())
+    }
+
+    async fn set_brightness(&mut self, brightness: u8) -> anyhow::Result<()> {
+        let brightness = brightness.min(100);
+        let key = match self.api {
+            Api::Gen1 { .. } if self.color_mode => "gain",
+            _ => "brightness",
+        };
+        self.set(
+            &[(key, brightness.to_string())],
+            json!({ "brightness": brightness }),
+        )
+        .await?;
+        self.brightness = brightness;
+        Ok(())
+    }
+
+    fn id(&self) -> String {
+        format!("shelly::{}", self.device.mac)
+    }
+
+    fn is_on(&self) -> bool {
+        self.is_on
+    }
+
+    fn name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| {
+            format!(
+                "Shelly {} ({})",
+                self.device.model().unwrap_or("Light"),
+                self.device.mac
+            )
+        })
+    }
+
+    fn supports_color(&self) -> bool {
+        self.supports_color
+    }
+
+    fn red(&self) -> u8 {
+        self.red
+    }
+
+    fn green(&self) -> u8 {
+        self.green
+    }
+
+    fn blue(&self) -> u8 {
+        self.blue
+    }
+
+    fn brightness(&self) -> u8 {
+        self.brightness
+    }
+
+    fn device_info(&self) -> DeviceInfo {
+        DeviceInfo {
+            manufacturer: "Shelly".to_string(),
+            model: self.device.model().map(str::to_string),
+            firmware_version: self.device.ver.clone().or(self.device.fw.clone()),
+            hardware_version: None,
+        }
+    }
+
+    fn supports_color_temperature(&self) -> bool {
+        self.supports_temperature
+    }
+
+    async fn set_color_temperature(&mut self, kelvin: u16) -> anyhow::Result<()> {
+        if !self.supports_temperature {
+            return Err(anyhow::anyhow!(
+                "{} does not support color temperature",
+                self.name()
+            ));
+        }
+        let query = [
+            ("turn", "on".to_string()),
+            ("mode", "white".to_string()),
+            ("temp", kelvin.clamp(2700, 6500).to_string()),
+        ];
+        self.set(&query, json!({})).await?;
+        self.color_mode = false;
+        Ok(())
+    }
+}
+
+// ANCHOR - ShellyConfig
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct ShellyConfig {
+    pub enabled: bool,
+    /// Devices to query directly, as `host` or `host:port`
+    #[serde(default)]
+    pub addresses: Vec<String>,
+    /// Browse for `shelly*` web servers over mDNS
+    #[serde(default = "default_mdns")]
+    pub mdns: bool,
+    #[serde(default = "default_scan_timeout")]
+    pub scan_timeout: u64,
+    #[serde(default = "default_request_timeout")]
+    pub request_timeout: u64,
+}
+
+impl Default for ShellyConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            addresses: Vec::new(),
+            mdns: default_mdns(),
+            scan_timeout: default_scan_timeout(),
+            request_timeout: default_request_timeout(),
+        }
+    }
+}
+
+fn default_mdns() -> bool {
+    true
+}
+
+fn default_scan_timeout() -> u64 {
+    2000
+}
+
+fn default_request_timeout() -> u64 {
+    1000
+}
+
+// ANCHOR - ShellyIntegration
+
+pub struct ShellyIntegration;
+
+#[async_trait]
+impl Integration for ShellyIntegration {
+    fn name(&self) -> String {
+        "shelly".to_string()
+    }
+
+    fn preflight(&self, config: &CuteLightsConfig) -> bool {
+        config.shelly.enabled
+    }
+
+    async fn discover(&self, config: &CuteLightsConfig) -> anyhow::Result<Vec<Box<dyn Light>>> {
+        let timeout = Duration::from_millis(config.shelly.scan_timeout);
+        let mut hosts = mdns::resolve_hosts(&config.shelly.addresses, 80, None, timeout).await;
+        if config.shelly.mdns {
+            for host in mdns::browse_hosts(HTTP_SERVICE, HOSTNAME_PREFIX, timeout).await {
+                if !hosts.contains(&host) {
+                    hosts.push(host);
+                }
+            }
+        }
+
+        let mut lights = FutureBatch::new();
+        for (host, port) in hosts {
+            let config = config.shelly.clone();
+            lights.push(async move {
+                match ShellyLight::new(host, port, &config).await {
+                    Ok(light) => Some(Box::new(light) as Box<dyn Light>),
+                    Err(e) => {
+                        eprintln!("Failed to connect to Shelly at {}:{}: {}", host, port, e);
+                        None
+                    }
+                }
+            });
+        }
+
+        let mut found: Vec<Box<dyn Light>> = Vec::new();
+        for light in lights.run().await.into_iter().flatten() {
+            if !found.iter().any(|l| l.id() == light.id()) {
+                found.push(light);
+            }
+        }
+        Ok(found)
+    }
+}
+
+// ANCHOR - Messages
+
+// `/shelly`, Gen1 sets type and fw, Gen2 and later gen, model, ver and name
+#[derive(Debug, Deserialize)]
+struct ShellyDevice {
+    mac: String,
+    gen: Option<u8>,
+    #[serde(rename = "type")]
+    device_type: Option<String>,
+    model: Option<String>,
+    fw: Option<String>,
+    ver: Option<String>,
+    name: Option<String>,
+}
+
+impl ShellyDevice {
+    fn model(&self) -> Option<&str> {
+        self.model.as_deref().or(self.device_type.as_deref())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Gen1Status {
+    ison: bool,
+    mode: Option<String>,
+    brightness: Option<u8>,
+    gain: Option<u8>,
+    temp: Option<u16>,
+    red: Option<u8>,
+    green: Option<u8>,
+    blue: Option<u8>,
+    white: Option<u8>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RpcResponse {
+    result: Option<serde_json::Value>,
+    error: Option<RpcError>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RpcError {
+    message: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct RpcStatus {
+    output: bool,
+    brightness: Option<f32>,
+    rgb: Option<[u8; 3]>,
+    white: Option<u8>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::http::{FakeHttpServer, HttpRequest};
+    use serde_json::Value;
+    use std::sync::{Arc, Mutex};
+
+    /// A Gen1 light serving its status on `path` and applying query changes to it.
+    async fn gen1(path: &'static str, status: Value) -> (FakeHttpServer, ShellyLight) {
+        let status = Arc::new(Mutex::new(status));
+        let server = FakeHttpServer::start(move |request| match request.route() {
+            "/shelly" => (
+                200,
+                json!({ "type": "SHRGBW2", "mac": "A4CF12F45B6E", "fw": "20230913-112003/v1.14.0" }),
+            ),
+            "/settings" => (200, json!({ "name": "Porch" })),
+            route if route.trim_start_matches('/') == path => {
+                let mut status = status.lock().unwrap();
+                for (key, value) in request.query() {
+                    match key.as_str() {
+                        "turn" => status["ison"] = json!(value == "on"),
+                        "mode" => status["mode"] = json!(value),
+                        _ => status[key.as_str()] = json!(value.parse::<u16>().unwrap()),
+                    }
+                }
+                (200, status.clone())
+            }
+            _ => (404, json!("Not Found")),
+        })
+        .await
+        .unwrap();
+
+        let address = server.address();
+        let light = ShellyLight::new(address.ip(), address.port(), &ShellyConfig::default())
+            .await
+            .unwrap();
+        (server, light)
+    }
+
+    /// The queries sent to `path` after connecting, as `key=value` lists.
+    fn changes(server: &FakeHttpServer, path: &str) -> Vec<String> {
+        server
+            .requests()
+            .iter()
+            .filter(|request| request.route() == path)
+            .map(HttpRequest::query)
+            .filter(|query| !query.is_empty())
+            .map(|query| {
+                query
+                    .iter()
+                    .map(|(key, value)| format!("{}={}", key, value))
+                    .collect::<Vec<_>>()
+                    .join("&")
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_color_output_of_rgbw2() {
+        let (server, mut light) = gen1(
+            "color/0",
+            json!({
+                "ison": true,
+                "mode": "color",
+                "red": 255,
+                "green": 0,
+                "blue": 0,
+                "white": 20,
+                "gain": 40,
+                "effect": 0
+            }),
+        )
+        .await;
+
+        assert_eq!(light.id(), "shelly::A4CF12F45B6E");
+        assert_eq!(light.name(), "Porch");
+        assert_eq!(light.device_info().model.as_deref(), Some("SHRGBW2"));
+        assert!(light.supports_color());
+        assert_eq!(light.brightness(), 40);
+        assert_eq!((light.red(), light.green(), light.blue()), (255, 20, 20));
+
+        light.set_color(255, 200, 100).await.unwrap();
+        light.set_brightness(70).await.unwrap();
+
+        // Without light/0 there is no mode to switch
+        assert_eq!(
+            changes(&server, "/color/0"),
+            ["turn=on&red=155&green=100&blue=0&white=100", "gain=70"]
+        );
+        assert_eq!(light.brightness(), 70);
+    }
+
+    #[tokio::test]
+    async fn dims_gen1_bulbs_by_gain_or_brightness() {
+        let (server, mut light) = gen1(
+            "light/0",
+            json!({
+                "ison": false,
+                "mode": "white",
+                "red": 255,
+                "green": 255,
+                "blue": 255,
+                "gain": 30,
+                "brightness": 80,
+                "temp": 4000,
+                "white": 0
+            }),
+        )
+        .await;
+
+        // White mode dims with brightness, color mode with gain
+        assert!(!light.is_on());
+        assert_eq!(light.brightness(), 80);
+        assert!(light.supports_color_temperature());
+
+        light.set_brightness(50).await.unwrap();
+        light.set_color(0, 0, 255).await.unwrap();
+        light.set_brightness(20).await.unwrap();
+        light.set_color_temperature(9000).await.unwrap();
+        light.set_brightness(10).await.unwrap();
+
+        assert_eq!(
+            changes(&server, "/light/0"),
+            [
+                "brightness=50",
+                "turn=on&red=0&green=0&blue=255&white=0&mode=color",
+                "gain=20",
+                "turn=on&mode=white&temp=6500",
+                "brightness=10"
+            ]
+        );
+        assert!(light.is_on());
+    }
+
+    /// A Gen2 device with one `component` output, answering RPC calls.
+    async fn gen2(component: &'static str, status: Value) -> (FakeHttpServer, ShellyLight) {
+        let server = FakeHttpServer::start(move |request| match request.route() {
+            "/shelly" => (
+                200,
+                json!({
+                    "id": "shellyplusrgbwpm-a0a3b3c4d5e6",
+                    "mac": "A0A3B3C4D5E6",
+                    "model": "SNDC-0D4P10WW",
+                    "gen": 2,
+                    "ver": "1.4.4",
+                    "name": "Shelf"
+                }),
+            ),
+            "/rpc" => {
+                let body = request.body.clone().unwrap_or_default();
+                let result = match body["method"].as_str().unwrap_or_default() {
+                    "Shelly.GetStatus" => json!({ component: status.clone(), "sys": {} }),
+                    method if method.ends_with(".Set") => json!(null),
+                    _ => {
+                        return (
+                            200,
+                            json!({ "id": 1, "error": { "code": 404, "message": "No handler" } }),
+                        )
+                    }
+                };
+                (200, json!({ "id": 1, "result": result }))
+            }
+            _ => (404, json!("Not Found")),
+        })
+        .await
+        .unwrap();
+
+        let address = server.address();
+        let light = ShellyLight::new(address.ip(), address.port(), &ShellyConfig::default())
+            .await
+            .unwrap();
+        (server, light)
+    }
+
+    /// The RPC calls made after `Shelly.GetStatus`.
+    fn calls(server: &FakeHttpServer) -> Vec<Value> {
+        server
+            .requests()
+            .into_iter()
+            .filter_map(|request| request.body)
+            .filter(|body| body["method"] != "Shelly.GetStatus")
+            .map(|body| json!({ "method": body["method"], "params": body["params"] }))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn controls_gen2_rgbw_output() {
+        let (server, mut light) = gen2(
+            "rgbw:0",
+            json!({ "id": 0, "output": true, "brightness": 60.0, "rgb": [255, 0, 0], "white": 20 }),
+        )
+        .await;
+
+        assert_eq!(light.name(), "Shelf");
+        assert_eq!(
+            light.device_info().firmware_version.as_deref(),
+            Some("1.4.4")
+        );
+        assert!(light.is_on());
+        assert_eq!(light.brightness(), 60);
+        assert_eq!((light.red(), light.green(), light.blue()), (255, 20, 20));
+
+        light.set_color(255, 200, 100).await.unwrap();
+        light.set_brightness(30).await.unwrap();
+        light.set_on(false).await.unwrap();
+
+        assert_eq!(
+            calls(&server),
+            [
+                json!({
+                    "method": "RGBW.Set",
+                    "params": { "id": 0, "on": true, "rgb": [155, 100, 0], "white": 100 }
+                }),
+                json!({ "method": "RGBW.Set", "params": { "id": 0, "brightness": 30 } }),
+                json!({ "method": "RGBW.Set", "params": { "id": 0, "on": false } }),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn controls_gen2_dimmers() {
+        let (server, mut light) = gen2(
+            "light:0",
+            json!({ "id": 0, "output": false, "brightness": 45.0 }),
+        )
+        .await;
+
+        assert!(!light.supports_color());
+        assert_eq!(light.brightness(), 45);
+        assert!(light.set_color(255, 0, 0).await.is_err());
+        light.set_brightness(80).await.unwrap();
+
+        assert_eq!(
+            calls(&server),
+            [json!({ "method": "Light.Set", "params": { "id": 0, "brightness": 80 } })]
+        );
+    }
+}
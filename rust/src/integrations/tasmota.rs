@@ -0,0 +1,595 @@
+use crate::{
+    config::CuteLightsConfig,
+    utils::{color, future::FutureBatch, mdns},
+};
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::{net::IpAddr, time::Duration};
+
+use super::{DeviceInfo, Integration, Light};
+
+// Tasmota announces itself as a plain web server named after its hostname
+const HTTP_SERVICE: &str = "_http._tcp.local.";
+const HOSTNAME_PREFIX: &str = "tasmota";
+
+// Tasmota takes color temperature as 153 to 500 mireds
+const MIN_MIREDS: u32 = 153;
+const MAX_MIREDS: u32 = 500;
+
+// ANCHOR - TasmotaLight
+pub struct TasmotaLight {
+    client: reqwest::Client,
+    url: String,
+    credentials: Option<(String, String)>,
+    status: Status,
+    firmware: StatusFwr,
+    network: StatusNet,
+    // 0 for relays, 1 dimmer, 2 CCT, 3 RGB, 4 RGBW, 5 RGBCCT
+    channels: usize,
+    is_on: bool,
+    brightness: u8,
+    red: u8,
+    green: u8,
+    blue: u8,
+}
+
+impl TasmotaLight {
+    pub async fn new(
+        host: IpAddr,
+        port: u16,
+        config: &TasmotaConfig,
+    ) -> anyhow::Result<TasmotaLight> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_millis(config.request_timeout))
+            .build()?;
+        let url = match host {
+            IpAddr::V4(addr) => format!("http://{}:{}/cm", addr, port),
+            IpAddr::V6(addr) => format!("http://[{}]:{}/cm", addr, port),
+        };
+        let credentials = config
+            .password
+            .as_ref()
+            .map(|password| (config.username.clone(), password.clone()));
+
+        let mut light = TasmotaLight {
+            client,
+            url,
+            credentials,
+            status: Status::default(),
+            firmware: StatusFwr::default(),
+            network: StatusNet::default(),
+            channels: 0,
+            is_on: false,
+            brightness: 0,
+            red: 0,
+            green: 0,
+            blue: 0,
+        };
+
+        let full: FullStatus = serde_json::from_value(light.command("Status 0").await?)?;
+        light.channels = match (&full.status_sts.channel, full.status_sts.dimmer) {
+            (Some(channel), _) => channel.len(),
+            (None, Some(_)) => 1,
+            (None, None) => 0,
+        };
+        light.update_from(&full.status_sts);
+        light.status = full.status;
+        light.firmware = full.status_fwr;
+        light.network = full.status_net;
+
+        Ok(light)
+    }
+
+    fn update_from(&mut self, state: &StatusSts) {
+        if let Some(power) = state.power.as_ref().or(state.power1.as_ref()) {
+            self.is_on = power == "ON";
+        }
+        if let Some(dimmer) = state.dimmer {
+            self.brightness = dimmer;
+        }
+        if let Some((r, g, b)) = state.color.as_deref().and_then(parse_color) {
+            self.red = r;
+            self.green = g;
+            self.blue = b;
+        }
+    }
+
+    async fn command(&self, cmnd: &str) -> anyhow::Result<serde_json::Value> {
+        let mut query = vec![("cmnd", cmnd)];
+        if let Some((user, password)) = &self.credentials {
+            query.push(("user", user.as_str()));
+            query.push(("password", password.as_str()));
+        }
+
+        let body = self
+            .client
+            .get(&self.url)
+            .query(&query)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        let response: serde_json::Value = serde_json::from_str(&body)?;
+
+        if let Some(warning) = response["WARNING"].as_str() {
+            return Err(anyhow::anyhow!("{} failed: {}", cmnd, warning));
+        }
+        if response["Command"].as_str() == Some("Unknown") {
+            return Err(anyhow::anyhow!("{} is not supported by this device", cmnd));
+        }
+        Ok(response)
+    }
+
+    /// Runs a light command and picks up the state Tasmota reports back.
+    async fn light_command(&mut self, cmnd: &str) -> anyhow::Result<()> {
+        let response = self.command(cmnd).await?;
+        if let Ok(state) = serde_json::from_value::<StatusSts>(response) {
+            self.update_from(&state);
+        }
+        Ok(())
+    }
+
+    fn has_white_channel(&self) -> bool {
+        self.channels >= 4
+    }
+}
+
+#[async_trait]
+impl Light for TasmotaLight {
+    async fn set_on(&mut self, on: bool) -> anyhow::Result<()> {
+        let cmnd = if on { "Power ON" } else { "Power OFF" };
+        self.light_command(cmnd).await?;
+        self.is_on = on;
+        Ok(())
+    }
+
+    async fn set_color(&mut self, red: u8, green: u8, blue: u8) -> anyhow::Result<()> {
+        if !self.supports_color() {
+            return Err(anyhow::anyhow!("{} does not support color", self.name()));
+        }
+
+        let cmnd = if self.has_white_channel() {
+            // Drive the shared part of the color from the white LEDs, they are
+            // brighter and a lot closer to real white than mixed RGB
+            let white = red.min(green).min(blue);
+            let (r, g, b) = (red - white, green - white, blue - white);
+            if self.channels == 5 {
+                format!("Color {},{},{},{},{}", r, g, b, white, white)
+            } else {
+                format!("Color {},{},{},{}", r, g, b, white)
+            }
+        } else {
+            // HSBColor keeps the dimmer where it is, Color would scale it
+            let (hue, saturation, _) = color::rgb_to_hsb(red, green, blue);
+            format!("HSBColor {},{},{}", hue, saturation, self.brightness)
+        };
+
+        self.light_command(&cmnd).await?;
+        self.red = red;
+        self.green = green;
+        self.blue = blue;
+        Ok(())
+    }
+
+    async fn set_brightness(&mut self, brightness: u8) -> anyhow::Result<()> {
+        if self.channels == 0 {
+            return Err(anyhow::anyhow!("{} is a relay and cannot dim", self.name()));
+        }
+        let brightness = brightness.min(100);
+        self.light_command(&format!("Dimmer {}", brightness))
+            .await?;
+        self.brightness = brightness;
+        Ok(())
+    }
+
+    fn id(&self) -> String {
+        format!("tasmota::{}", self.network.mac)
+    }
+
+    fn is_on(&self) -> bool {
+        self.is_on
+    }
+
+    fn name(&self) -> String {
+        self.status
+            .friendly_name
+            .first()
+            .cloned()
+            .or_else(|| self.status.device_name.clone())
+            .unwrap_or_else(|| self.network.hostname.clone())
+    }
+
+    fn supports_color(&self) -> bool {
+        self.channels >= 3
+    }
+
+    fn red(&self) -> u8 {
+        self.red
+    }
+
+    fn green(&self) -> u8 {
+        self.green
+    }
+
+    fn blue(&self) -> u8 {
+        self.blue
+    }
+
+    fn brightness(&self) -> u8 {
+        self.brightness
+    }
+
+    fn device_info(&self) -> DeviceInfo {
+        DeviceInfo {
+            manufacturer: "Tasmota".to_string(),
+            model: self.firmware.hardware.clone(),
+            firmware_version: Some(self.firmware.version.clone()),
+            hardware_version: None,
+        }
+    }
+
+    fn supports_color_temperature(&self) -> bool {
+        self.channels == 2 || self.channels == 5
+    }
+
+    async fn set_color_temperature(&mut self, kelvin: u16) -> anyhow::Result<()> {
+        if !self.supports_color_temperature() {
+            return Err(anyhow::anyhow!(
+                "{} does not support color temperature",
+                self.name()
+            ));
+        }
+        let mireds = (1_000_000 / kelvin.max(1) as u32).clamp(MIN_MIREDS, MAX_MIREDS);
+        self.light_command(&format!("CT {}", mireds)).await
+    }
+}
+
+/// Parses the hex `Color` Tasmota reports, which has one byte per channel.
+/// The white channels of RGBW lights are added back onto the RGB part.
+fn parse_color(color: &str) -> Option<(u8, u8, u8)> {
+    if color.len() < 6 || !color.is_ascii() {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(color.get(i * 2..i * 2 + 2)?, 16).ok();
+    let (r, g, b) = (channel(0)?, channel(1)?, channel(2)?);
+    let white = channel(3).unwrap_or(0);
+    Some((
+        r.saturating_add(white),
+        g.saturating_add(white),
+        b.saturating_add(white),
+    ))
+}
+
+// ANCHOR - TasmotaConfig
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct TasmotaConfig {
+    pub enabled: bool,
+    /// Devices to query directly, as `host` or `host:port`
+    #[serde(default)]
+    pub addresses: Vec<String>,
+    /// Browse for `tasmota-*` web servers over mDNS, needs a build with mDNS
+    #[serde(default = "default_mdns")]
+    pub mdns: bool,
+    #[serde(default = "default_username")]
+    pub username: String,
+    /// Web admin password, if one is set
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default = "default_scan_timeout")]
+    pub scan_timeout: u64,
+    #[serde(default = "default_request_timeout")]
+    pub request_timeout: u64,
+}
+
+impl Default for TasmotaConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            addresses: Vec::new(),
+            mdns: default_mdns(),
+            username: default_username(),
+            password: None,
+            scan_timeout: default_scan_timeout(),
+            request_timeout: default_request_timeout(),
+        }
+    }
+}
+
+fn default_mdns() -> bool {
+    true
+}
+
+fn default_username() -> String {
+    "admin".to_string()
+}
+
+fn default_scan_timeout() -> u64 {
+    2000
+}
+
+fn default_request_timeout() -> u64 {
+    1000
+}
+
+// ANCHOR - TasmotaIntegration
+
+pub struct TasmotaIntegration;
+
+#[async_trait]
+impl Integration for TasmotaIntegration {
+    fn name(&self) -> String {
+        "tasmota".to_string()
+    }
+
+    fn preflight(&self, config: &CuteLightsConfig) -> bool {
+        config.tasmota.enabled
+    }
+
+    async fn discover(&self, config: &CuteLightsConfig) -> anyhow::Result<Vec<Box<dyn Light>>> {
+        let timeout = Duration::from_millis(config.tasmota.scan_timeout);
+        let mut hosts = mdns::resolve_hosts(&config.tasmota.addresses, 80, None, timeout).await;
+        if config.tasmota.mdns {
+            for host in mdns::browse_hosts(HTTP_SERVICE, HOSTNAME_PREFIX, timeout).await {
+                if !hosts.contains(&host) {
+                    hosts.push(host);
+                }
+            }
+        }
+
+        let mut lights = FutureBatch::new();
+        for (host, port) in hosts {
+            let config = config.tasmota.clone();
+            lights.push(async move {
+                match TasmotaLight::new(host, port, &config).await {
+                    Ok(light) => Some(Box::new(light) as Box<dyn Light>),
+                    Err(e) => {
+                        eprintln!("Failed to connect to Tasmota at {}:{}: {}", host, port, e);
+                        None
+                    }
+                }
+            });
+        }
+
+        let mut found: Vec<Box<dyn Light>> = Vec::new();
+        for light in lights.run().await.into_iter().flatten() {
+            if !found.iter().any(|l| l.id() == light.id()) {
+                found.push(light);
+            }
+        }
+        Ok(found)
+    }
+}
+
+// ANCHOR - Messages
+
+#[derive(Debug, Deserialize)]
+struct FullStatus {
+    #[serde(rename = "Status")]
+    status: Status,
+    #[serde(rename = "StatusFWR")]
+    status_fwr: StatusFwr,
+    #[serde(rename = "StatusNET")]
+    status_net: StatusNet,
+    #[serde(rename = "StatusSTS")]
+    status_sts: StatusSts,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Status {
+    #[serde(rename = "DeviceName")]
+    device_name: Option<String>,
+    #[serde(rename = "FriendlyName", default)]
+    friendly_name: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct StatusFwr {
+    #[serde(rename = "Version")]
+    version: String,
+    #[serde(rename = "Hardware")]
+    hardware: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct StatusNet {
+    #[serde(rename = "Hostname")]
+    hostname: String,
+    #[serde(rename = "Mac")]
+    mac: String,
+}
+
+// Also the shape of the reply to Power, Dimmer, Color and CT
+#[derive(Debug, Deserialize)]
+struct StatusSts {
+    #[serde(rename = "POWER")]
+    power: Option<String>,
+    #[serde(rename = "POWER1")]
+    power1: Option<String>,
+    #[serde(rename = "Dimmer")]
+    dimmer: Option<u8>,
+    #[serde(rename = "Color")]
+    color: Option<String>,
+    #[serde(rename = "Channel")]
+    channel: Option<Vec<u8>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::http::{FakeHttpServer, HttpRequest};
+    use serde_json::{json, Value};
+    use std::sync::{Arc, Mutex};
+
+    const PASSWORD: &str = "hunter2";
+
+    fn cmnd(request: &HttpRequest) -> String {
+        request
+            .query()
+            .into_iter()
+            .find(|(key, _)| key == "cmnd")
+            .map(|(_, value)| value)
+            .unwrap_or_default()
+    }
+
+    /// Answers `/cm` like Tasmota does, reporting `sts` back after every light command.
+    fn answer(sts: &mut Value, request: &HttpRequest) -> Value {
+        if !request
+            .query()
+            .contains(&("password".to_string(), PASSWORD.to_string()))
+        {
+            return json!({ "WARNING": "Need user=<username>&password=<password>" });
+        }
+
+        let cmnd = cmnd(request);
+        let (command, argument) = cmnd.split_once(' ').unwrap_or((&cmnd, ""));
+        match command {
+            "Status" => {
+                return json!({
+                    "Status": { "DeviceName": "Desk", "FriendlyName": ["Desk Lamp"] },
+                    "StatusFWR": { "Version": "13.2.0(tasmota)", "Hardware": "ESP8266EX" },
+                    "StatusNET": { "Hostname": "tasmota-1A2B3C", "Mac": "A4:CF:12:1A:2B:3C" },
+                    "StatusSTS": sts
+                })
+            }
+            "Power" => sts["POWER"] = json!(argument),
+            "Dimmer" => sts["Dimmer"] = json!(argument.parse::<u8>().unwrap()),
+            "Color" => {
+                let hex: String = argument
+                    .split(',')
+                    .map(|channel| format!("{:02X}", channel.parse::<u8>().unwrap()))
+                    .collect();
+                sts["Color"] = json!(hex);
+            }
+            "HSBColor" | "CT" => {}
+            _ => return json!({ "Command": "Unknown" }),
+        }
+        sts.clone()
+    }
+
+    async fn device(sts: Value) -> (FakeHttpServer, TasmotaLight) {
+        let sts = Arc::new(Mutex::new(sts));
+        let server =
+            FakeHttpServer::start(move |request| (200, answer(&mut sts.lock().unwrap(), request)))
+                .await
+                .unwrap();
+
+        let config = TasmotaConfig {
+            password: Some(PASSWORD.to_string()),
+            ..Default::default()
+        };
+        let address = server.address();
+        let light = TasmotaLight::new(address.ip(), address.port(), &config)
+            .await
+            .unwrap();
+        (server, light)
+    }
+
+    /// The commands received after `Status 0`.
+    fn commands(server: &FakeHttpServer) -> Vec<String> {
+        server.requests().iter().skip(1).map(cmnd).collect()
+    }
+
+    #[test]
+    fn parses_hex_colors() {
+        assert_eq!(parse_color("FF8000"), Some((255, 128, 0)));
+        // RGBW and RGBCCT add white onto every channel
+        assert_eq!(parse_color("FF000080"), Some((255, 128, 128)));
+        assert_eq!(parse_color("00000040FF"), Some((64, 64, 64)));
+        assert_eq!(parse_color("12345"), None);
+        assert_eq!(parse_color("ZZ0000"), None);
+    }
+
+    #[tokio::test]
+    async fn drives_the_white_channel_of_rgbw_lights() {
+        let (server, mut light) = device(json!({
+            "POWER": "ON",
+            "Dimmer": 60,
+            "Color": "FF000080",
+            "Channel": [100, 0, 0, 50]
+        }))
+        .await;
+
+        assert_eq!(light.id(), "tasmota::A4:CF:12:1A:2B:3C");
+        assert_eq!(light.name(), "Desk Lamp");
+        assert!(light.is_on());
+        assert_eq!(light.brightness(), 60);
+        assert_eq!((light.red(), light.green(), light.blue()), (255, 128, 128));
+        assert!(light.supports_color());
+        assert!(!light.supports_color_temperature());
+
+        light.set_color(255, 200, 100).await.unwrap();
+        light.set_brightness(30).await.unwrap();
+        light.set_on(false).await.unwrap();
+
+        assert_eq!(
+            commands(&server),
+            ["Color 155,100,0,100", "Dimmer 30", "Power OFF"]
+        );
+        assert_eq!((light.red(), light.green(), light.blue()), (255, 200, 100));
+        assert_eq!(light.brightness(), 30);
+        assert!(!light.is_on());
+    }
+
+    #[tokio::test]
+    async fn keeps_the_dimmer_when_coloring_rgb_lights() {
+        let (server, mut light) = device(json!({
+            "POWER": "OFF",
+            "Dimmer": 40,
+            "Color": "00FF00",
+            "Channel": [0, 100, 0]
+        }))
+        .await;
+        assert!(!light.is_on());
+
+        light.set_color(255, 128, 128).await.unwrap();
+
+        assert_eq!(commands(&server), ["HSBColor 0,50,40"]);
+    }
+
+    #[tokio::test]
+    async fn splits_white_across_both_white_channels_of_rgbcct_lights() {
+        let (server, mut light) = device(json!({
+            "POWER": "ON",
+            "Dimmer": 100,
+            "Color": "0000000000",
+            "Channel": [0, 0, 0, 0, 0]
+        }))
+        .await;
+        assert!(light.supports_color_temperature());
+
+        light.set_color(10, 20, 30).await.unwrap();
+        light.set_color_temperature(2700).await.unwrap();
+
+        assert_eq!(commands(&server), ["Color 0,10,20,10,10", "CT 370"]);
+    }
+
+    #[tokio::test]
+    async fn refuses_to_dim_or_color_relays() {
+        let (server, mut light) = device(json!({ "POWER1": "ON" })).await;
+
+        assert!(light.is_on());
+        assert!(!light.supports_color());
+        assert!(light.set_brightness(50).await.is_err());
+        assert!(light.set_color(255, 0, 0).await.is_err());
+        light.set_on(false).await.unwrap();
+
+        assert_eq!(commands(&server), ["Power OFF"]);
+    }
+
+    #[tokio::test]
+    async fn reports_warnings_and_unknown_commands() {
+        let (_server, light) = device(json!({ "POWER": "ON", "Dimmer": 10 })).await;
+        assert!(light.command("Fade 1").await.is_err());
+
+        let server =
+            FakeHttpServer::start(|request| (200, answer(&mut json!({ "POWER": "ON" }), request)))
+                .await
+                .unwrap();
+        let address = server.address();
+        let result =
+            TasmotaLight::new(address.ip(), address.port(), &TasmotaConfig::default()).await;
+        assert!(result.err().unwrap().to_string().contains("Need user"));
+    }
+}
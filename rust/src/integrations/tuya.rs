@@ -0,0 +1,1263 @@
+use crate::{
+    config::CuteLightsConfig,
+    utils::{color, future::FutureBatch},
+};
+use aes::{
+    cipher::{generic_array::GenericArray, BlockDecrypt, BlockEncrypt, KeyInit},
+    Aes128,
+};
+use aes_gcm::{
+    aead::{rand_core::RngCore, Aead, OsRng, Payload},
+    Aes128Gcm, Nonce,
+};
+use async_trait::async_trait;
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use serde_json::json;
+use sha2::Sha256;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+    time::Instant,
+};
+
+use super::{DeviceInfo, Integration, Light};
+
+const TUYA_PORT: u16 = 6668;
+// 3.1 devices broadcast in plain text on 6666, later ones encrypted on 6667
+const BROADCAST_PORTS: [u16; 2] = [6666, 6667];
+
+// md5("yGAdlopoPVldABfn"), every device encrypts its broadcasts with it
+const UDP_KEY: [u8; 16] = [
+    0x6C, 0x1E, 0xC8, 0xE2, 0xBB, 0x9B, 0xB5, 0x9A, 0xB5, 0x0B, 0x0D, 0xAF, 0x64, 0x9B, 0x41, 0x0A,
+];
+
+// Color temperature range the 0-1000 (or 0-255) temp value is spread over
+const WARM_KELVIN: u16 = 2700;
+const COOL_KELVIN: u16 = 6500;
+
+// ANCHOR - TuyaLight
+pub struct TuyaLight {
+    stream: Option<TcpStream>,
+    addr: SocketAddr,
+    device: TuyaDevice,
+    version: Version,
+    local_key: [u8; 16],
+    // Equal to local_key on 3.3, negotiated per connection on 3.4 and later
+    session_key: [u8; 16],
+    seqno: u32,
+    request_timeout: Duration,
+    product_key: Option<String>,
+    scheme: Scheme,
+    dps: HashMap<String, serde_json::Value>,
+}
+
+impl TuyaLight {
+    pub async fn new(
+        device: TuyaDevice,
+        ip: IpAddr,
+        version: Version,
+        product_key: Option<String>,
+        config: &TuyaConfig,
+    ) -> anyhow::Result<TuyaLight> {
+        let local_key: [u8; 16] = device
+            .key
+            .as_bytes()
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("Tuya local key of {} is not 16 bytes", device.id))?;
+
+        let mut light = TuyaLight {
+            stream: None,
+            addr: SocketAddr::new(ip, config.port),
+            device,
+            version,
+            local_key,
+            session_key: local_key,
+            seqno: 0,
+            request_timeout: Duration::from_millis(config.request_timeout),
+            product_key,
+            scheme: Scheme::V2,
+            dps: HashMap::new(),
+        };
+
+        light.refresh().await?;
+        // Lights from before the v2 schema use the low dps numbers
+        if !light.dps.contains_key(Scheme::V2.switch())
+            && light.dps.contains_key(Scheme::V1.switch())
+        {
+            light.scheme = Scheme::V1;
+        }
+        Ok(light)
+    }
+
+    /// Reads every data point from the device.
+    pub async fn refresh(&mut self) -> anyhow::Result<()> {
+        let (cmd, body) = match self.version {
+            Version::V33 => (DP_QUERY, self.legacy_body(None)),
+            _ => (DP_QUERY_NEW, self.legacy_body(None)),
+        };
+        let frame = self.request(cmd, &body).await?;
+        self.update_from(&frame.payload);
+        Ok(())
+    }
+
+    async fn set_dps(&mut self, dps: serde_json::Value) -> anyhow::Result<()> {
+        let (cmd, body) = match self.version {
+            Version::V33 => (CONTROL, self.legacy_body(Some(dps.clone()))),
+            _ => (
+                CONTROL_NEW,
+                json!({ "protocol": 5, "t": timestamp(), "data": { "dps": dps } }).to_string(),
+            ),
+        };
+        self.request(cmd, &body).await?;
+
+        if let serde_json::Value::Object(dps) = dps {
+            self.dps.extend(dps);
+        }
+        Ok(())
+    }
+
+    fn legacy_body(&self, dps: Option<serde_json::Value>) -> String {
+        let mut body = json!({
+            "gwId": self.device.id,
+            "devId": self.device.id,
+            "uid": self.device.id,
+            "t": timestamp().to_string(),
+        });
+        if let Some(dps) = dps {
+            body["dps"] = dps;
+        }
+        body.to_string()
+    }
+
+    fn update_from(&mut self, payload: &[u8]) {
+        let Ok(value) = serde_json::from_slice::<serde_json::Value>(payload) else {
+            return;
+        };
+        let dps = if value["dps"].is_object() {
+            &value["dps"]
+        } else {
+            &value["data"]["dps"]
+        };
+        if let serde_json::Value::Object(dps) = dps {
+            self.dps.extend(dps.clone());
+        }
+    }
+
+    /// Sends a command and waits for the device to answer it, reconnecting
+    /// once if the device dropped an idle connection.
+    async fn request(&mut self, cmd: u32, body: &str) -> anyhow::Result<Frame> {
+        if self.stream.is_some() {
+            match self.exchange(cmd, body).await {
+                Ok(frame) => return Ok(frame),
+                Err(e) => {
+                    if !e.is::<std::io::Error>() {
+                        return Err(e);
+                    }
+                    self.stream = None;
+                }
+            }
+        }
+        self.connect().await?;
+        self.exchange(cmd, body).await
+    }
+
+    async fn exchange(&mut self, cmd: u32, body: &str) -> anyhow::Result<Frame> {
+        self.send(cmd, body.as_bytes()).await?;
+        let deadline = Instant::now() + self.request_timeout;
+        loop {
+            let frame = self.read_frame(deadline).await?;
+            if let Some(code) = frame.retcode.filter(|code| *code != 0) {
+                return Err(anyhow::anyhow!(
+                    "Tuya device rejected command {}: {}",
+                    cmd,
+                    code
+                ));
+            }
+            // Status pushes can arrive in between, keep their data
+            if frame.cmd == STATUS {
+                self.update_from(&frame.payload);
+            }
+            if frame.cmd == cmd || (frame.cmd == STATUS && cmd == CONTROL) {
+                return Ok(frame);
+            }
+        }
+    }
+
+    async fn connect(&mut self) -> anyhow::Result<()> {
+        let stream = tokio::time::timeout(self.request_timeout, TcpStream::connect(self.addr))
+            .await
+            .map_err(|_| anyhow::anyhow!("Timed out connecting to {}", self.addr))??;
+        self.stream = Some(stream);
+        self.session_key = self.local_key;
+
+        if self.version != Version::V33 {
+            if let Err(e) = self.negotiate_session_key().await {
+                self.stream = None;
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// The 3.4 and 3.5 handshake: both sides trade a nonce and the session
+    /// key is derived from the two.
+    async fn negotiate_session_key(&mut self) -> anyhow::Result<()> {
+        let local_nonce: [u8; 16] = random_bytes();
+        self.send(SESS_KEY_NEG_START, &local_nonce).await?;
+
+        let deadline = Instant::now() + self.request_timeout;
+        let frame = loop {
+            let frame = self.read_frame(deadline).await?;
+            if frame.cmd == SESS_KEY_NEG_RESP {
+                break frame;
+            }
+        };
+        if frame.payload.len() < 48 {
+            return Err(anyhow::anyhow!("Tuya session key response is too short"));
+        }
+        let remote_nonce: [u8; 16] = frame.payload[..16].try_into()?;
+        if frame.payload[16..48] != hmac_sha256(&self.local_key, &local_nonce) {
+            return Err(anyhow::anyhow!(
+                "Tuya device did not prove it has the local key, check the key for {}",
+                self.device.id
+            ));
+        }
+
+        self.send(
+            SESS_KEY_NEG_FINISH,
+            &hmac_sha256(&self.local_key, &remote_nonce),
+        )
+        .await?;
+
+        self.session_key =
+            derive_session_key(self.version, &self.local_key, &local_nonce, &remote_nonce);
+        Ok(())
+    }
+
+    async fn send(&mut self, cmd: u32, body: &[u8]) -> anyhow::Result<()> {
+        self.seqno = self.seqno.wrapping_add(1);
+        let frame = encode_frame(self.version, &self.session_key, self.seqno, cmd, body);
+        let stream = self
+            .stream
+            .as_mut()
+            .ok_or_else(|| anyhow::anyhow!("Not connected to {}", self.addr))?;
+        tokio::time::timeout(self.request_timeout, stream.write_all(&frame))
+            .await
+            .map_err(|_| anyhow::anyhow!("Timed out writing to {}", self.addr))??;
+        Ok(())
+    }
+
+    async fn read_frame(&mut self, deadline: Instant) -> anyhow::Result<Frame> {
+        let stream = self
+            .stream
+            .as_mut()
+            .ok_or_else(|| anyhow::anyhow!("Not connected to {}", self.addr))?;
+
+        let mut buf = vec![0; 16];
+        read_exact_until(stream, &mut buf, deadline).await?;
+        let prefix = u32::from_be_bytes(buf[..4].try_into()?);
+        let rest = match prefix {
+            PREFIX_55AA => u32::from_be_bytes(buf[12..16].try_into()?) as usize,
+            PREFIX_6699 => {
+                buf.resize(18, 0);
+                read_exact_until(stream, &mut buf[16..], deadline).await?;
+                u32::from_be_bytes(buf[14..18].try_into()?) as usize + 4
+            }
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "Unexpected Tuya frame prefix {:08x}",
+                    prefix
+                ))
+            }
+        };
+        if rest > MAX_FRAME {
+            return Err(anyhow::anyhow!("Tuya frame of {} bytes is too long", rest));
+        }
+
+        let start = buf.len();
+        buf.resize(start + rest, 0);
+        read_exact_until(stream, &mut buf[start..], deadline).await?;
+        decode_frame(self.version, &self.session_key, &buf)
+    }
+
+    fn dp(&self, key: &str) -> Option<&serde_json::Value> {
+        self.dps.get(key)
+    }
+
+    fn colour(&self) -> Option<(u16, u8, u8)> {
+        let value = self.dp(self.scheme.colour())?.as_str()?;
+        self.scheme.decode_colour(value)
+    }
+
+    fn in_colour_mode(&self) -> bool {
+        self.dp(self.scheme.mode()).and_then(|mode| mode.as_str()) == Some("colour")
+    }
+}
+
+async fn read_exact_until(
+    stream: &mut TcpStream,
+    buf: &mut [u8],
+    deadline: Instant,
+) -> anyhow::Result<()> {
+    tokio::time::timeout_at(deadline, stream.read_exact(buf))
+        .await
+        .map_err(|_| anyhow::anyhow!("Timed out waiting for Tuya device"))??;
+    Ok(())
+}
+
+#[async_trait]
+impl Light for TuyaLight {
+    async fn set_on(&mut self, on: bool) -> anyhow::Result<()> {
+        self.set_dps(json!({ self.scheme.switch(): on })).await
+    }
+
+    async fn set_color(&mut self, red: u8, green: u8, blue: u8) -> anyhow::Result<()> {
+        if !self.supports_color() {
+            return Err(anyhow::anyhow!("{} does not support color", self.name()));
+        }
+        let (hue, saturation, _) = color::rgb_to_hsb(red, green, blue);
+        let colour = self
+            .scheme
+            .encode_colour(hue, saturation, self.brightness().max(1));
+        self.set_dps(json!({
+            self.scheme.mode(): "colour",
+            self.scheme.colour(): colour,
+        }))
+        .await
+    }
+
+    async fn set_brightness(&mut self, brightness: u8) -> anyhow::Result<()> {
+        let brightness = brightness.clamp(1, 100);
+        if self.in_colour_mode() {
+            let (hue, saturation, _) = self.colour().unwrap_or((0, 0, 100));
+            let colour = self.scheme.encode_colour(hue, saturation, brightness);
+            return self.set_dps(json!({ self.scheme.colour(): colour })).await;
+        }
+        self.set_dps(json!({ self.scheme.bright(): self.scheme.encode_bright(brightness) }))
+            .await
+    }
+
+    fn id(&self) -> String {
+        format!("tuya::{}", self.device.id)
+    }
+
+    fn is_on(&self) -> bool {
+        self.dp(self.scheme.switch())
+            .and_then(|on| on.as_bool())
+            .unwrap_or(false)
+    }
+
+    fn name(&self) -> String {
+        self.device
+            .name
+            .clone()
+            .unwrap_or_else(|| format!("Tuya Light ({})", self.device.id))
+    }
+
+    fn supports_color(&self) -> bool {
+        self.dps.contains_key(self.scheme.colour())
+    }
+
+    fn red(&self) -> u8 {
+        self.rgb().0
+    }
+
+    fn green(&self) -> u8 {
+        self.rgb().1
+    }
+
+    fn blue(&self) -> u8 {
+        self.rgb().2
+    }
+
+    fn brightness(&self) -> u8 {
+        if self.in_colour_mode() {
+            if let Some((_, _, value)) = self.colour() {
+                return value;
+            }
+        }
+        self.dp(self.scheme.bright())
+            .and_then(|bright| bright.as_u64())
+            .map(|bright| self.scheme.decode_bright(bright))
+            .unwrap_or(0)
+    }
+
+    fn device_info(&self) -> DeviceInfo {
+        DeviceInfo {
+            manufacturer: "Tuya".to_string(),
+            model: self.product_key.clone(),
+            firmware_version: Some(format!("protocol {}", self.version.as_str())),
+            hardware_version: None,
+        }
+    }
+
+    fn supports_color_temperature(&self) -> bool {
+        self.dps.contains_key(self.scheme.temp())
+    }
+
+    async fn set_color_temperature(&mut self, kelvin: u16) -> anyhow::Result<()> {
+        if !self.supports_color_temperature() {
+            return Err(anyhow::anyhow!(
+                "{} does not support color temperature",
+                self.name()
+            ));
+        }
+        let brightness = self.brightness().max(1);
+        self.set_dps(json!({
+            self.scheme.mode(): "white",
+            self.scheme.bright(): self.scheme.encode_bright(brightness),
+            self.scheme.temp(): self.scheme.encode_temp(kelvin),
+        }))
+        .await
+    }
+}
+
+impl TuyaLight {
+    fn rgb(&self) -> (u8, u8, u8) {
+        match self.colour() {
+            Some((hue, saturation, _)) => color::hsb_to_rgb(hue, saturation, 100),
+            None => (255, 255, 255),
+        }
+    }
+}
+
+// ANCHOR - Data points
+
+/// Which data points a light uses. Most lights follow the v2 schema, older
+/// ones the original v1 numbering and value ranges.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Scheme {
+    V1,
+    V2,
+}
+
+impl Scheme {
+    fn switch(self) -> &'static str {
+        match self {
+            Scheme::V1 => "1",
+            Scheme::V2 => "20",
+        }
+    }
+
+    fn mode(self) -> &'static str {
+        match self {
+            Scheme::V1 => "2",
+            Scheme::V2 => "21",
+        }
+    }
+
+    fn bright(self) -> &'static str {
+        match self {
+            Scheme::V1 => "3",
+            Scheme::V2 => "22",
+        }
+    }
+
+    fn temp(self) -> &'static str {
+        match self {
+            Scheme::V1 => "4",
+            Scheme::V2 => "23",
+        }
+    }
+
+    fn colour(self) -> &'static str {
+        match self {
+            Scheme::V1 => "5",
+            Scheme::V2 => "24",
+        }
+    }
+
+    /// Brightness runs 25-255 on v1 and 10-1000 on v2.
+    fn encode_bright(self, percent: u8) -> u32 {
+        let (min, max) = self.bright_range();
+        min + (max - min) * percent.min(100) as u32 / 100
+    }
+
+    fn decode_bright(self, value: u64) -> u8 {
+        let (min, max) = self.bright_range();
+        let value = (value as u32).clamp(min, max);
+        ((value - min) * 100 / (max - min)) as u8
+    }
+
+    fn bright_range(self) -> (u32, u32) {
+        match self {
+            Scheme::V1 => (25, 255),
+            Scheme::V2 => (10, 1000),
+        }
+    }
+
+    fn encode_temp(self, kelvin: u16) -> u32 {
+        let max = match self {
+            Scheme::V1 => 255,
+            Scheme::V2 => 1000,
+        };
+        let kelvin = kelvin.clamp(WARM_KELVIN, COOL_KELVIN);
+        (kelvin - WARM_KELVIN) as u32 * max / (COOL_KELVIN - WARM_KELVIN) as u32
+    }
+
+    /// v2 colours are `HHHHSSSSVVVV` with saturation and value out of 1000,
+    /// v1 colours `RRGGBB0HHHSSVV` with saturation and value out of 255.
+    fn encode_colour(self, hue: u16, saturation: u8, value: u8) -> String {
+        match self {
+            Scheme::V1 => {
+                let (r, g, b) = color::hsb_to_rgb(hue, saturation, value);
+                format!(
+                    "{:02x}{:02x}{:02x}{:04x}{:02x}{:02x}",
+                    r,
+                    g,
+                    b,
+                    hue,
+                    saturation as u32 * 255 / 100,
+                    value as u32 * 255 / 100
+                )
+            }
+            Scheme::V2 => format!(
+                "{:04x}{:04x}{:04x}",
+                hue,
+                saturation as u32 * 10,
+                value as u32 * 10
+            ),
+        }
+    }
+
+    fn decode_colour(self, colour: &str) -> Option<(u16, u8, u8)> {
+        let field =
+            |range: std::ops::Range<usize>| u32::from_str_radix(colour.get(range)?, 16).ok();
+        match self {
+            Scheme::V1 => Some((
+                field(6..10)? as u16,
+                (field(10..12)? * 100 / 255) as u8,
+                (field(12..14)? * 100 / 255) as u8,
+            )),
+            Scheme::V2 => Some((
+                field(0..4)? as u16,
+                (field(4..8)?.min(1000) / 10) as u8,
+                (field(8..12)?.min(1000) / 10) as u8,
+            )),
+        }
+    }
+}
+
+// ANCHOR - TuyaConfig
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct TuyaConfig {
+    pub enabled: bool,
+    #[serde(default)]
+    pub devices: Vec<TuyaDevice>,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    /// How long to listen for broadcasts when a device has no ip or version,
+    /// devices broadcast about every 5 seconds
+    #[serde(default = "default_scan_timeout")]
+    pub scan_timeout: u64,
+    #[serde(default = "default_request_timeout")]
+    pub request_timeout: u64,
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct TuyaDevice {
+    pub id: String,
+    /// The 16 character local key
+    pub key: String,
+    /// Found from the device broadcasts when left out
+    #[serde(default)]
+    pub ip: Option<IpAddr>,
+    /// Protocol version, 3.3, 3.4 or 3.5. Found from the device broadcasts
+    /// when left out
+    #[serde(default)]
+    pub version: Option<String>,
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+impl Default for TuyaConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            devices: Vec::new(),
+            port: default_port(),
+            scan_timeout: default_scan_timeout(),
+            request_timeout: default_request_timeout(),
+        }
+    }
+}
+
+fn default_port() -> u16 {
+    TUYA_PORT
+}
+
+fn default_scan_timeout() -> u64 {
+    6000
+}
+
+fn default_request_timeout() -> u64 {
+    2000
+}
+
+// ANCHOR - TuyaIntegration
+
+pub struct TuyaIntegration;
+
+#[async_trait]
+impl Integration for TuyaIntegration {
+    fn name(&self) -> String {
+        "tuya".to_string()
+    }
+
+    fn preflight(&self, config: &CuteLightsConfig) -> bool {
+        if !config.tuya.enabled {
+            return false;
+        }
+
+        if config.tuya.devices.is_empty() {
+            eprintln!("No Tuya devices configured");
+            return false;
+        }
+
+        true
+    }
+
+    async fn discover(&self, config: &CuteLightsConfig) -> anyhow::Result<Vec<Box<dyn Light>>> {
+        let needs_scan = config
+            .tuya
+            .devices
+            .iter()
+            .any(|device| device.ip.is_none() || device.version.is_none());
+        let broadcasts = if needs_scan {
+            listen_broadcasts(&config.tuya).await?
+        } else {
+            HashMap::new()
+        };
+
+        let mut lights = FutureBatch::new();
+        for device in &config.tuya.devices {
+            let broadcast = broadcasts.get(&device.id);
+            let Some(ip) = device.ip.or(broadcast.map(|b| b.ip)) else {
+                eprintln!("Tuya device {} was not found, set its ip", device.id);
+                continue;
+            };
+            let version = device
+                .version
+                .as_deref()
+                .or(broadcast.map(|b| b.version.as_str()))
+                .unwrap_or("3.3");
+            let version = match Version::parse(version) {
+                Ok(version) => version,
+                Err(e) => {
+                    eprintln!("Skipping Tuya device {}: {}", device.id, e);
+                    continue;
+                }
+            };
+            let product_key = broadcast.and_then(|b| b.product_key.clone());
+
+            let (device, config) = (device.clone(), config.tuya.clone());
+            lights.push(async move {
+                match TuyaLight::new(device.clone(), ip, version, product_key, &config).await {
+                    Ok(light) => Some(Box::new(light) as Box<dyn Light>),
+                    Err(e) => {
+                        eprintln!("Failed to connect to Tuya device {}: {}", device.id, e);
+                        None
+                    }
+                }
+            });
+        }
+
+        Ok(lights.run().await.into_iter().flatten().collect())
+    }
+}
+
+// ANCHOR - Discovery
+
+/// Listens on the broadcast ports for `scan_timeout` and returns what every
+/// device announced, by device id.
+pub async fn listen_broadcasts(config: &TuyaConfig) -> anyhow::Result<HashMap<String, Broadcast>> {
+    let mut sockets = Vec::new();
+    for port in BROADCAST_PORTS {
+        match bind_reusable(port) {
+            Ok(socket) => sockets.push(socket),
+            Err(e) => eprintln!("Failed to listen for Tuya broadcasts on {}: {}", port, e),
+        }
+    }
+
+    let deadline = Instant::now() + Duration::from_millis(config.scan_timeout);
+    let mut found: HashMap<String, Broadcast> = HashMap::new();
+    let mut listeners = FutureBatch::new();
+    for socket in sockets {
+        listeners.push(async move {
+            let mut found = Vec::new();
+            let mut buf = [0; 1024];
+            while let Ok(res) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await
+            {
+                match res {
+                    Ok((size, _)) => {
+                        if let Some(broadcast) = decode_broadcast(&buf[..size]) {
+                            found.push(broadcast);
+                        }
+                    }
+                    Err(e) => eprintln!("Failed to receive Tuya broadcast: {}", e),
+                }
+            }
+            found
+        });
+    }
+
+    for broadcast in listeners.run().await.into_iter().flatten() {
+        found.insert(broadcast.gw_id.clone(), broadcast);
+    }
+    Ok(found)
+}
+
+// Other Tuya apps on the same machine listen on these ports too
+fn bind_reusable(port: u16) -> anyhow::Result<UdpSocket> {
+    let socket = socket2::Socket::new(
+        socket2::Domain::IPV4,
+        socket2::Type::DGRAM,
+        Some(socket2::Protocol::UDP),
+    )?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::from(([0, 0, 0, 0], port)).into())?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+fn decode_broadcast(buf: &[u8]) -> Option<Broadcast> {
+    let version = match u32::from_be_bytes(buf.get(..4)?.try_into().ok()?) {
+        PREFIX_6699 => Version::V35,
+        _ => Version::V33,
+    };
+    let frame = decode_frame(version, &UDP_KEY, buf).ok()?;
+    serde_json::from_slice(&frame.payload).ok()
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Broadcast {
+    pub ip: IpAddr,
+    #[serde(rename = "gwId")]
+    pub gw_id: String,
+    #[serde(default = "default_broadcast_version")]
+    pub version: String,
+    #[serde(rename = "productKey")]
+    pub product_key: Option<String>,
+}
+
+fn default_broadcast_version() -> String {
+    "3.3".to_string()
+}
+
+// ANCHOR - Protocol
+
+const PREFIX_55AA: u32 = 0x0000_55AA;
+const SUFFIX_55AA: u32 = 0x0000_AA55;
+const PREFIX_6699: u32 = 0x0000_6699;
+const SUFFIX_6699: u32 = 0x0000_9966;
+const MAX_FRAME: usize = 64 * 1024;
+
+const SESS_KEY_NEG_START: u32 = 3;
+const SESS_KEY_NEG_RESP: u32 = 4;
+const SESS_KEY_NEG_FINISH: u32 = 5;
+const CONTROL: u32 = 7;
+const STATUS: u32 = 8;
+const DP_QUERY: u32 = 10;
+const CONTROL_NEW: u32 = 13;
+const DP_QUERY_NEW: u32 = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Version {
+    V33,
+    V34,
+    V35,
+}
+
+impl Version {
+    pub fn parse(version: &str) -> anyhow::Result<Version> {
+        match version {
+            "3.3" => Ok(Version::V33),
+            "3.4" => Ok(Version::V34),
+            "3.5" => Ok(Version::V35),
+            _ => Err(anyhow::anyhow!("Unsupported Tuya protocol {}", version)),
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Version::V33 => "3.3",
+            Version::V34 => "3.4",
+            Version::V35 => "3.5",
+        }
+    }
+
+    /// The `3.x` plus 12 zero bytes some commands carry ahead of their data.
+    fn header(self) -> [u8; 15] {
+        let mut header = [0; 15];
+        header[..3].copy_from_slice(self.as_str().as_bytes());
+        header
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Frame {
+    seqno: u32,
+    cmd: u32,
+    retcode: Option<u32>,
+    payload: Vec<u8>,
+}
+
+fn needs_version_header(cmd: u32) -> bool {
+    cmd == CONTROL || cmd == CONTROL_NEW
+}
+
+/// Encodes a client frame. 3.3 encrypts with AES-ECB and ends in a CRC32,
+/// 3.4 encrypts with AES-ECB and ends in an HMAC, 3.5 uses AES-GCM in the
+/// 6699 framing.
+fn encode_frame(version: Version, key: &[u8; 16], seqno: u32, cmd: u32, body: &[u8]) -> Vec<u8> {
+    let mut plain = Vec::with_capacity(body.len() + 15);
+    if needs_version_header(cmd) && version != Version::V33 {
+        plain.extend_from_slice(&version.header());
+    }
+    plain.extend_from_slice(body);
+
+    let mut buf = Vec::new();
+    match version {
+        Version::V33 | Version::V34 => {
+            let mut data = Vec::new();
+            if needs_version_header(cmd) && version == Version::V33 {
+                data.extend_from_slice(&version.header());
+            }
+            data.extend_from_slice(&ecb_encrypt(key, &plain));
+
+            let trailer = if version == Version::V33 { 4 } else { 32 };
+            buf.extend_from_slice(&PREFIX_55AA.to_be_bytes());
+            buf.extend_from_slice(&seqno.to_be_bytes());
+            buf.extend_from_slice(&cmd.to_be_bytes());
+            buf.extend_from_slice(&((data.len() + trailer + 4) as u32).to_be_bytes());
+            buf.extend_from_slice(&data);
+            if version == Version::V33 {
+                buf.extend_from_slice(&crc32fast::hash(&buf).to_be_bytes());
+            } else {
+                let mac = hmac_sha256(key, &buf);
+                buf.extend_from_slice(&mac);
+            }
+            buf.extend_from_slice(&SUFFIX_55AA.to_be_bytes());
+        }
+        Version::V35 => {
+            let iv: [u8; 12] = random_bytes();
+            buf.extend_from_slice(&PREFIX_6699.to_be_bytes());
+            buf.extend_from_slice(&[0, 0]);
+            buf.extend_from_slice(&seqno.to_be_bytes());
+            buf.extend_from_slice(&cmd.to_be_bytes());
+            buf.extend_from_slice(&((12 + plain.len() + 16) as u32).to_be_bytes());
+            let sealed = gcm_encrypt(key, &iv, &buf[4..], &plain);
+            buf.extend_from_slice(&iv);
+            buf.extend_from_slice(&sealed);
+            buf.extend_from_slice(&SUFFIX_6699.to_be_bytes());
+        }
+    }
+    buf
+}
+
+/// Decodes a frame from a device, checking its CRC, HMAC or GCM tag.
+fn decode_frame(version: Version, key: &[u8; 16], buf: &[u8]) -> anyhow::Result<Frame> {
+    let word = |at: usize| -> anyhow::Result<u32> {
+        let bytes = buf
+            .get(at..at + 4)
+            .ok_or_else(|| anyhow::anyhow!("Tuya frame is truncated"))?;
+        Ok(u32::from_be_bytes(bytes.try_into()?))
+    };
+
+    match word(0)? {
+        PREFIX_55AA => {
+            let (seqno, cmd, len) = (word(4)?, word(8)?, word(12)? as usize);
+            if buf.len() != 16 + len || word(buf.len() - 4)? != SUFFIX_55AA {
+                return Err(anyhow::anyhow!("Tuya frame length does not match"));
+            }
+            // Broadcasts always use a CRC, even from 3.4 devices
+            let hmac = version == Version::V34 && key != &UDP_KEY;
+            let trailer = if hmac { 32 } else { 4 };
+            if len < trailer + 4 {
+                return Err(anyhow::anyhow!("Tuya frame is truncated"));
+            }
+            let end = buf.len() - 4 - trailer;
+            if hmac {
+                if buf[end..end + 32] != hmac_sha256(key, &buf[..end]) {
+                    return Err(anyhow::anyhow!("Tuya frame has a bad HMAC"));
+                }
+            } else if word(end)? != crc32fast::hash(&buf[..end]) {
+                return Err(anyhow::anyhow!("Tuya frame has a bad CRC"));
+            }
+
+            let (retcode, mut data) = split_retcode(&buf[16..end]);
+            if version == Version::V33 && data.starts_with(version.header().as_slice()) {
+                data = &data[15..];
+            }
+            let payload = if data.is_empty() || data[0] == b'{' {
+                // Empty acknowledgements and 3.1 broadcasts are not encrypted
+                data.to_vec()
+            } else {
+                strip_version_header(ecb_decrypt(key, data)?)
+            };
+            Ok(Frame {
+                seqno,
+                cmd,
+                retcode,
+                payload,
+            })
+        }
+        PREFIX_6699 => {
+            let (seqno, cmd) = (word(6)?, word(10)?);
+            let len = word(14)? as usize;
+            if buf.len() != 18 + len + 4 || word(buf.len() - 4)? != SUFFIX_6699 || len < 28 {
+                return Err(anyhow::anyhow!("Tuya frame length does not match"));
+            }
+            let iv: [u8; 12] = buf[18..30].try_into()?;
+            let plain = gcm_decrypt(key, &iv, &buf[4..18], &buf[30..18 + len])?;
+            let (retcode, data) = split_retcode(&plain);
+            Ok(Frame {
+                seqno,
+                cmd,
+                retcode,
+                payload: strip_version_header(data.to_vec()),
+            })
+        }
+        prefix => Err(anyhow::anyhow!(
+            "Unexpected Tuya frame prefix {:08x}",
+            prefix
+        )),
+    }
+}
+
+/// Device frames start with a 4 byte return code, client frames do not.
+/// Like every other implementation this guesses from the top three bytes.
+fn split_retcode(data: &[u8]) -> (Option<u32>, &[u8]) {
+    match data.get(..4) {
+        Some([0, 0, 0, code]) => (Some(*code as u32), &data[4..]),
+        _ => (None, data),
+    }
+}
+
+fn strip_version_header(mut data: Vec<u8>) -> Vec<u8> {
+    if data.len() >= 15 && data.starts_with(b"3.") && data[3..15].iter().all(|b| *b == 0) {
+        data.drain(..15);
+    }
+    data
+}
+
+fn derive_session_key(
+    version: Version,
+    local_key: &[u8; 16],
+    local_nonce: &[u8; 16],
+    remote_nonce: &[u8; 16],
+) -> [u8; 16] {
+    let mut mixed = [0; 16];
+    for i in 0..16 {
+        mixed[i] = local_nonce[i] ^ remote_nonce[i];
+    }
+    match version {
+        Version::V35 => {
+            let iv: [u8; 12] = local_nonce[..12].try_into().unwrap();
+            let sealed = gcm_encrypt(local_key, &iv, &[], &mixed);
+            sealed[..16].try_into().unwrap()
+        }
+        _ => {
+            let cipher = Aes128::new(GenericArray::from_slice(local_key));
+            let mut block = GenericArray::clone_from_slice(&mixed);
+            cipher.encrypt_block(&mut block);
+            block.into()
+        }
+    }
+}
+
+fn ecb_encrypt(key: &[u8; 16], data: &[u8]) -> Vec<u8> {
+    let cipher = Aes128::new(GenericArray::from_slice(key));
+    let pad = 16 - data.len() % 16;
+    let mut buf = data.to_vec();
+    buf.extend(std::iter::repeat_n(pad as u8, pad));
+    for block in buf.chunks_mut(16) {
+        cipher.encrypt_block(GenericArray::from_mut_slice(block));
+    }
+    buf
+}
+
+fn ecb_decrypt(key: &[u8; 16], data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    if data.is_empty() || !data.len().is_multiple_of(16) {
+        return Err(anyhow::anyhow!(
+            "Tuya payload is not a whole number of blocks"
+        ));
+    }
+    let cipher = Aes128::new(GenericArray::from_slice(key));
+    let mut buf = data.to_vec();
+    for block in buf.chunks_mut(16) {
+        cipher.decrypt_block(GenericArray::from_mut_slice(block));
+    }
+    let pad = *buf.last().unwrap_or(&0) as usize;
+    if pad == 0 || pad > 16 || buf[buf.len() - pad..].iter().any(|b| *b as usize != pad) {
+        return Err(anyhow::anyhow!(
+            "Tuya payload has bad padding, check the local key"
+        ));
+    }
+    buf.truncate(buf.len() - pad);
+    Ok(buf)
+}
+
+fn gcm_encrypt(key: &[u8; 16], iv: &[u8; 12], aad: &[u8], data: &[u8]) -> Vec<u8> {
+    let cipher = Aes128Gcm::new(GenericArray::from_slice(key));
+    cipher
+        .encrypt(Nonce::from_slice(iv), Payload { msg: data, aad })
+        .expect("AES-GCM encryption does not fail for short messages")
+}
+
+fn gcm_decrypt(
+    key: &[u8; 16],
+    iv: &[u8; 12],
+    aad: &[u8],
+    sealed: &[u8],
+) -> anyhow::Result<Vec<u8>> {
+    let cipher = Aes128Gcm::new(GenericArray::from_slice(key));
+    cipher
+        .decrypt(Nonce::from_slice(iv), Payload { msg: sealed, aad })
+        .map_err(|_| anyhow::anyhow!("Tuya frame failed authentication, check the local key"))
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut mac =
+        <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+/// Nonces and IVs must be unpredictable, so these come from the OS.
+fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0; N];
+    OsRng.fill_bytes(&mut bytes);
+    bytes
+}
+
+fn timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &[u8; 16] = b"abcdefghijklmnop";
+
+    fn hex(text: &str) -> Vec<u8> {
+        (0..text.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    // The vectors below were produced independently with Python's
+    // cryptography package
+
+    #[test]
+    fn encodes_v33_frames_with_crc() {
+        let frame = encode_frame(Version::V33, KEY, 1, DP_QUERY, br#"{"gwId":"bf1234"}"#);
+
+        assert_eq!(
+            frame,
+            hex(
+                "000055aa000000010000000a00000028cacb3c71f405de627f6945953374e1c5\
+                 b1ba09c384749a8db5b9f42b6f99fa415b16eaf30000aa55"
+            )
+        );
+    }
+
+    #[test]
+    fn encodes_v34_frames_with_hmac() {
+        let frame = encode_frame(Version::V34, KEY, 2, CONTROL_NEW, br#"{"dps":{"20":true}}"#);
+
+        // The version header is encrypted along with the body
+        assert_eq!(
+            frame,
+            hex(
+                "000055aa000000020000000d00000054cf0e81dd9dec236f8a3e0ee3f290cd1a\
+                 7de00babc0ad5a07937ea85b61245504e26b3e550ffa386e2b04faf2aab38ef1\
+                 b65785b943712bfa682141c729c9b48801bb5010574f62f2d2c839a47213520f\
+                 0000aa55"
+            )
+        );
+    }
+
+    #[test]
+    fn decodes_device_frames_with_retcodes() {
+        let v33 = hex(
+            "000055aa00000004000000080000003b00000000332e3300000000000000000000\
+             0000e41e8d13f1d0f41f8e63a42902017352e26b3e550ffa386e2b04faf2aab38e\
+             f146e01eed0000aa55",
+        );
+        assert_eq!(
+            decode_frame(Version::V33, KEY, &v33).unwrap(),
+            Frame {
+                seqno: 4,
+                cmd: STATUS,
+                retcode: Some(0),
+                payload: br#"{"dps":{"1":true}}"#.to_vec(),
+            }
+        );
+
+        let v35 = hex(
+            "000066990000000000030000000800000034000102030405060708090a0ba7c29a\
+             714bd5a28cff2b3ccc4eed090711469285cf6f2c9eb580f85a09fe0e2c9daf82e3\
+             adef636c00009966",
+        );
+        assert_eq!(
+            decode_frame(Version::V35, KEY, &v35).unwrap(),
+            Frame {
+                seqno: 3,
+                cmd: STATUS,
+                retcode: Some(0),
+                payload: br#"{"dps":{"20":false}}"#.to_vec(),
+            }
+        );
+    }
+
+    #[test]
+    fn round_trips_every_version() {
+        let body = br#"{"devId":"bf1234","dps":{"20":true,"21":"colour"}}"#;
+        for version in [Version::V33, Version::V34, Version::V35] {
+            for cmd in [CONTROL, CONTROL_NEW, DP_QUERY, DP_QUERY_NEW] {
+                let frame = encode_frame(version, KEY, 7, cmd, body);
+
+                let (prefix, suffix) = match version {
+                    Version::V35 => (PREFIX_6699, SUFFIX_6699),
+                    _ => (PREFIX_55AA, SUFFIX_55AA),
+                };
+                assert_eq!(frame[..4], prefix.to_be_bytes(), "{:?} {}", version, cmd);
+                assert_eq!(frame[frame.len() - 4..], suffix.to_be_bytes());
+
+                let decoded = decode_frame(version, KEY, &frame).unwrap();
+                assert_eq!((decoded.seqno, decoded.cmd), (7, cmd));
+                assert_eq!(decoded.retcode, None, "{:?} {}", version, cmd);
+                assert_eq!(decoded.payload, body, "{:?} {}", version, cmd);
+            }
+        }
+    }
+
+    #[test]
+    fn picks_fresh_gcm_ivs() {
+        let first = encode_frame(Version::V35, KEY, 1, DP_QUERY_NEW, b"{}");
+        let second = encode_frame(Version::V35, KEY, 1, DP_QUERY_NEW, b"{}");
+
+        assert_ne!(first[18..30], second[18..30]);
+    }
+
+    #[test]
+    fn rejects_tampered_frames() {
+        let body = br#"{"dps":{"20":true}}"#;
+        for version in [Version::V33, Version::V34, Version::V35] {
+            let frame = encode_frame(version, KEY, 1, CONTROL_NEW, body);
+
+            // Flip a bit in the CRC, HMAC or GCM tag
+            let mut tampered = frame.clone();
+            let at = tampered.len() - 5;
+            tampered[at] ^= 1;
+            assert!(
+                decode_frame(version, KEY, &tampered).is_err(),
+                "{:?}",
+                version
+            );
+
+            assert!(decode_frame(version, b"ponmlkjihgfedcba", &frame).is_err());
+            assert!(decode_frame(version, KEY, &frame[..frame.len() - 1]).is_err());
+        }
+
+        let mut frame = encode_frame(Version::V34, KEY, 1, CONTROL_NEW, body);
+        let at = frame.len() - 20;
+        frame[at] ^= 1;
+        let error = decode_frame(Version::V34, KEY, &frame).unwrap_err();
+        assert!(error.to_string().contains("bad HMAC"), "{}", error);
+    }
+
+    #[test]
+    fn decodes_broadcasts() {
+        let payload = br#"{"ip":"192.168.1.20","gwId":"bf1234","version":"3.4"}"#;
+        let frame = encode_frame(Version::V33, &UDP_KEY, 0, 0x13, payload);
+
+        let broadcast = decode_broadcast(&frame).unwrap();
+        assert_eq!(broadcast.ip, "192.168.1.20".parse::<IpAddr>().unwrap());
+        assert_eq!(broadcast.gw_id, "bf1234");
+        assert_eq!(broadcast.version, "3.4");
+    }
+
+    #[test]
+    fn derives_session_keys() {
+        let local_nonce = b"0123456789abcdef";
+        let remote_nonce: [u8; 16] = std::array::from_fn(|i| i as u8);
+
+        assert_eq!(
+            derive_session_key(Version::V34, KEY, local_nonce, &remote_nonce).to_vec(),
+            hex("f05a25a9767ca4e9ec2769a9c08136eb")
+        );
+        assert_eq!(
+            derive_session_key(Version::V35, KEY, local_nonce, &remote_nonce).to_vec(),
+            hex("9c8fa45b83e7e228f7a73ef284c8d6fe")
+        );
+    }
+
+    #[test]
+    fn guesses_retcodes_from_leading_zeros() {
+        assert_eq!(split_retcode(&[0, 0, 0, 1, 7]), (Some(1), &[7][..]));
+        assert_eq!(split_retcode(&[0, 0, 0, 0]), (Some(0), &[][..]));
+        assert_eq!(
+            split_retcode(&[0, 0, 1, 0, 7]),
+            (None, &[0, 0, 1, 0, 7][..])
+        );
+        assert_eq!(split_retcode(b"{}"), (None, &b"{}"[..]));
+    }
+
+    #[test]
+    fn encodes_v2_colours() {
+        assert_eq!(Scheme::V2.encode_colour(120, 50, 100), "007801f403e8");
+        assert_eq!(
+            Scheme::V2.decode_colour("007801f403e8"),
+            Some((120, 50, 100))
+        );
+        assert_eq!(Scheme::V2.encode_colour(359, 0, 1), "01670000000a");
+        // Out of range saturation and value are capped
+        assert_eq!(
+            Scheme::V2.decode_colour("0000ffffffff"),
+            Some((0, 100, 100))
+        );
+        assert_eq!(Scheme::V2.decode_colour("0078"), None);
+    }
+
+    #[test]
+    fn encodes_v1_colours() {
+        assert_eq!(Scheme::V1.encode_colour(0, 100, 100), "ff00000000ffff");
+        assert_eq!(Scheme::V1.encode_colour(240, 50, 100), "8080ff00f07fff");
+        assert_eq!(
+            Scheme::V1.decode_colour("ff00000000ffff"),
+            Some((0, 100, 100))
+        );
+        assert_eq!(
+            Scheme::V1.decode_colour("8080ff00f07fff"),
+            Some((240, 49, 100))
+        );
+    }
+
+    #[test]
+    fn scales_brightness_and_temperature() {
+        assert_eq!(Scheme::V2.encode_bright(0), 10);
+        assert_eq!(Scheme::V2.encode_bright(50), 505);
+        assert_eq!(Scheme::V2.encode_bright(100), 1000);
+        assert_eq!(Scheme::V1.encode_bright(100), 255);
+        assert_eq!(Scheme::V2.decode_bright(505), 50);
+        assert_eq!(Scheme::V1.decode_bright(0), 0);
+
+        assert_eq!(Scheme::V2.encode_temp(WARM_KELVIN), 0);
+        assert_eq!(Scheme::V2.encode_temp(COOL_KELVIN), 1000);
+        assert_eq!(Scheme::V1.encode_temp(20000), 255);
+    }
+}
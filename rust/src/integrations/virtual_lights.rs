@@ -0,0 +1,609 @@
+use crate::config::CuteLightsConfig;
+use async_trait::async_trait;
+use std::{io::Write, time::Duration};
+
+use super::{DeviceInfo, Integration, Light};
+
+/// The latest commands sent to virtual lights, oldest first. Only kept when
+/// testing, where `test_support` reads them.
+#[cfg(any(test, feature = "test-support"))]
+static RECORDED: std::sync::Mutex<std::collections::VecDeque<RecordedCommand>> =
+    std::sync::Mutex::new(std::collections::VecDeque::new());
+
+#[cfg(any(test, feature = "test-support"))]
+const MAX_RECORDED: usize = 10_000;
+
+// ANCHOR - Recording
+
+/// One per `Light` setter, named after it as the record file spells it.
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VirtualCommand {
+    SetOn(bool),
+    SetColor(u8, u8, u8),
+    SetBrightness(u8),
+    SetColorTemperature(u16),
+    SetEffect(String),
+    SetSegmentColors(Vec<(u8, u8, u8)>),
+    SetRealtime(bool),
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct RecordedCommand {
+    pub light_id: String,
+    pub command: VirtualCommand,
+    /// False when the light rejected the command or a failure was simulated
+    pub succeeded: bool,
+}
+
+/// The commands virtual lights have received so far, up to the last 10000.
+#[cfg(any(test, feature = "test-support"))]
+pub fn recorded_commands() -> Vec<RecordedCommand> {
+    RECORDED
+        .lock()
+        .map(|recorded| recorded.iter().cloned().collect())
+        .unwrap_or_default()
+}
+
+#[cfg(any(test, feature = "test-support"))]
+pub fn clear_recorded_commands() {
+    if let Ok(mut recorded) = RECORDED.lock() {
+        recorded.clear();
+    }
+}
+
+#[cfg(any(test, feature = "test-support"))]
+fn push_recorded(
+    recorded: &mut std::collections::VecDeque<RecordedCommand>,
+    command: RecordedCommand,
+    max: usize,
+) {
+    if recorded.len() >= max {
+        recorded.pop_front();
+    }
+    recorded.push_back(command);
+}
+
+// ANCHOR - VirtualLight
+pub struct VirtualLight {
+    config: VirtualLightConfig,
+    id: String,
+    record_path: Option<String>,
+    rng: u64,
+    is_on: bool,
+    brightness: u8,
+    red: u8,
+    green: u8,
+    blue: u8,
+    segments: Vec<(u8, u8, u8)>,
+    realtime: bool,
+}
+
+impl VirtualLight {
+    fn new(config: &VirtualLightConfig, seed: u64, record_path: Option<String>) -> Self {
+        let id = config
+            .id
+            .clone()
+            .unwrap_or_else(|| config.name.to_lowercase().replace(' ', "_"));
+        Self {
+            rng: seed ^ fnv1a(&id),
+            id,
+            record_path,
+            is_on: config.on,
+            brightness: 100,
+            red: 255,
+            green: 255,
+            blue: 255,
+            segments: vec![(255, 255, 255); config.segments],
+            realtime: false,
+            config: config.clone(),
+        }
+    }
+
+    /// Waits out the simulated latency, then records the command and fails
+    /// it when the light does not support it or the dice say so. Failures
+    /// come from a generator seeded per light, so a given seed fails the
+    /// same commands every run.
+    async fn command(&mut self, command: VirtualCommand) -> anyhow::Result<()> {
+        if self.config.latency > 0 {
+            tokio::time::sleep(Duration::from_millis(self.config.latency)).await;
+        }
+
+        let unsupported = match &command {
+            VirtualCommand::SetColor(..) => !self.config.color,
+            VirtualCommand::SetColorTemperature(_) => !self.config.color_temperature,
+            VirtualCommand::SetEffect(effect) => !self.config.effects.contains(effect),
+            VirtualCommand::SetSegmentColors(colors) => {
+                !self.realtime && colors.len() > self.config.segments
+            }
+            _ => false,
+        };
+        let failed = next_random(&mut self.rng) < self.config.failure_rate;
+        self.record(RecordedCommand {
+            light_id: self.id(),
+            command: command.clone(),
+            succeeded: !unsupported && !failed,
+        });
+
+        if unsupported {
+            return Err(anyhow::anyhow!(
+                "{} does not support {:?}",
+                self.name(),
+                command
+            ));
+        }
+        if failed {
+            return Err(anyhow::anyhow!("Simulated failure of {}", self.name()));
+        }
+
+        match command {
+            VirtualCommand::SetOn(on) => self.is_on = on,
+            VirtualCommand::SetColor(red, green, blue) => {
+                (self.red, self.green, self.blue) = (red, green, blue);
+                self.segments.fill((red, green, blue));
+            }
+            VirtualCommand::SetBrightness(brightness) => self.brightness = brightness.min(100),
+            VirtualCommand::SetColorTemperature(_) | VirtualCommand::SetEffect(_) => {
+                self.is_on = true
+            }
+            VirtualCommand::SetSegmentColors(colors) => {
+                let count = colors.len().min(self.segments.len());
+                self.segments[..count].copy_from_slice(&colors[..count]);
+            }
+            VirtualCommand::SetRealtime(enabled) => self.realtime = enabled,
+        }
+        Ok(())
+    }
+
+    fn record(&self, recorded: RecordedCommand) {
+        if let Some(path) = &self.record_path {
+            let line = serde_json::to_string(&recorded).unwrap_or_default();
+            let written = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| writeln!(file, "{}", line));
+            if let Err(e) = written {
+                eprintln!("Failed to record to {}: {}", path, e);
+            }
+        }
+        #[cfg(any(test, feature = "test-support"))]
+        if let Ok(mut commands) = RECORDED.lock() {
+            push_recorded(&mut commands, recorded, MAX_RECORDED);
+        }
+    }
+}
+
+#[async_trait]
+impl Light for VirtualLight {
+    async fn set_on(&mut self, on: bool) -> anyhow::Result<()> {
+        self.command(VirtualCommand::SetOn(on)).await
+    }
+
+    async fn set_color(&mut self, red: u8, green: u8, blue: u8) -> anyhow::Result<()> {
+        self.command(VirtualCommand::SetColor(red, green, blue))
+            .await
+    }
+
+    async fn set_brightness(&mut self, brightness: u8) -> anyhow::Result<()> {
+        self.command(VirtualCommand::SetBrightness(brightness))
+            .await
+    }
+
+    fn is_on(&self) -> bool {
+        self.is_on
+    }
+
+    fn name(&self) -> String {
+        self.config.name.clone()
+    }
+
+    fn supports_color(&self) -> bool {
+        self.config.color
+    }
+
+    fn red(&self) -> u8 {
+        self.red
+    }
+
+    fn green(&self) -> u8 {
+        self.green
+    }
+
+    fn blue(&self) -> u8 {
+        self.blue
+    }
+
+    fn brightness(&self) -> u8 {
+        self.brightness
+    }
+
+    fn id(&self) -> String {
+        format!("virtual::{}", self.id)
+    }
+
+    fn device_info(&self) -> DeviceInfo {
+        DeviceInfo {
+            manufacturer: "cute_lights".to_string(),
+            model: Some("Virtual".to_string()),
+            firmware_version: Some(env!("CARGO_PKG_VERSION").to_string()),
+            hardware_version: None,
+        }
+    }
+
+    fn supports_color_temperature(&self) -> bool {
+        self.config.color_temperature
+    }
+
+    async fn set_color_temperature(&mut self, kelvin: u16) -> anyhow::Result<()> {
+        self.command(VirtualCommand::SetColorTemperature(kelvin))
+            .await
+    }
+
+    fn effects(&self) -> Vec<String> {
+        self.config.effects.clone()
+    }
+
+    async fn set_effect(&mut self, effect: &str) -> anyhow::Result<()> {
+        self.command(VirtualCommand::SetEffect(effect.to_string()))
+            .await
+    }
+
+    fn segment_count(&self) -> usize {
+        self.config.segments
+    }
+
+    async fn set_segment_colors(&mut self, colors: &[(u8, u8, u8)]) -> anyhow::Result<()> {
+        self.command(VirtualCommand::SetSegmentColors(colors.to_vec()))
+            .await
+    }
+
+    async fn set_realtime(&mut self, enabled: bool) -> anyhow::Result<()> {
+        self.command(VirtualCommand::SetRealtime(enabled)).await
+    }
+}
+
+/// SplitMix64, mapped to `[0, 1)`.
+fn next_random(state: &mut u64) -> f64 {
+    *state = state.wrapping_add(0x9e3779b97f4a7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^= z >> 31;
+    (z >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// A hash that stays the same across Rust versions, unlike `DefaultHasher`.
+fn fnv1a(value: &str) -> u64 {
+    value.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+// ANCHOR - VirtualConfig
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, Default)]
+pub struct VirtualConfig {
+    pub enabled: bool,
+    /// Seeds the simulated failures
+    #[serde(default)]
+    pub seed: u64,
+    /// How long discovery takes, in milliseconds
+    #[serde(default)]
+    pub discovery_latency: u64,
+    /// Appends every command as a JSON line to this file
+    #[serde(default)]
+    pub record_path: Option<String>,
+    #[serde(default)]
+    pub lights: Vec<VirtualLightConfig>,
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct VirtualLightConfig {
+    pub name: String,
+    /// Defaults to the name in lower case with underscores for spaces
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub on: bool,
+    #[serde(default = "default_color")]
+    pub color: bool,
+    #[serde(default)]
+    pub color_temperature: bool,
+    #[serde(default)]
+    pub effects: Vec<String>,
+    #[serde(default)]
+    pub segments: usize,
+    /// How long every command takes, in milliseconds
+    #[serde(default)]
+    pub latency: u64,
+    /// The share of commands that fail, from 0 to 1
+    #[serde(default)]
+    pub failure_rate: f64,
+}
+
+fn default_color() -> bool {
+    true
+}
+
+// ANCHOR - VirtualIntegration
+
+pub struct VirtualIntegration;
+
+#[async_trait]
+impl Integration for VirtualIntegration {
+    fn name(&self) -> String {
+        "virtual".to_string()
+    }
+
+    fn preflight(&self, config: &CuteLightsConfig) -> bool {
+        config.virtual_lights.enabled
+    }
+
+    async fn discover(&self, config: &CuteLightsConfig) -> anyhow::Result<Vec<Box<dyn Light>>> {
+        let config = &config.virtual_lights;
+        if config.discovery_latency > 0 {
+            tokio::time::sleep(Duration::from_millis(config.discovery_latency)).await;
+        }
+
+        let mut lights: Vec<Box<dyn Light>> = Vec::new();
+        for light in &config.lights {
+            lights.push(Box::new(VirtualLight::new(
+                light,
+                config.seed,
+                config.record_path.clone(),
+            )));
+        }
+        Ok(lights)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    fn light(name: &str) -> VirtualLightConfig {
+        VirtualLightConfig {
+            name: name.to_string(),
+            id: None,
+            on: false,
+            color: true,
+            color_temperature: false,
+            effects: Vec::new(),
+            segments: 0,
+            latency: 0,
+            failure_rate: 0.0,
+        }
+    }
+
+    async fn discover(config: VirtualConfig) -> Vec<Box<dyn Light>> {
+        let config = CuteLightsConfig {
+            virtual_lights: config,
+            ..Default::default()
+        };
+        VirtualIntegration.discover(&config).await.unwrap()
+    }
+
+    fn recorded_for(light: &dyn Light) -> Vec<(VirtualCommand, bool)> {
+        recorded_commands()
+            .into_iter()
+            .filter(|recorded| recorded.light_id == light.id())
+            .map(|recorded| (recorded.command, recorded.succeeded))
+            .collect()
+    }
+
+    #[test]
+    fn reads_config() {
+        let config: CuteLightsConfig = toml::from_str(
+            r#"
+            [kasa]
+            enabled = false
+            addresses = []
+
+            [govee]
+            enabled = false
+            addresses = []
+
+            [hue]
+            enabled = false
+
+            [virtual]
+            enabled = true
+            seed = 7
+
+            [[virtual.lights]]
+            name = "Desk Lamp"
+            color_temperature = true
+            effects = ["Candle"]
+            segments = 3
+            failure_rate = 0.25
+            "#,
+        )
+        .unwrap();
+        let config = &config.virtual_lights;
+        assert!(config.enabled);
+        assert_eq!(config.seed, 7);
+        assert!(config.lights[0].color);
+        assert_eq!(config.lights[0].segments, 3);
+        assert_eq!(config.lights[0].failure_rate, 0.25);
+    }
+
+    #[tokio::test]
+    async fn declares_lights_with_capabilities() {
+        let lights = discover(VirtualConfig {
+            enabled: true,
+            lights: vec![
+                VirtualLightConfig {
+                    color_temperature: true,
+                    effects: vec!["Candle".to_string()],
+                    segments: 3,
+                    on: true,
+                    ..light("Capabilities Strip")
+                },
+                VirtualLightConfig {
+                    id: Some("plain".to_string()),
+                    color: false,
+                    ..light("Capabilities Plain")
+                },
+            ],
+            ..Default::default()
+        })
+        .await;
+
+        let strip = &lights[0];
+        assert_eq!(strip.id(), "virtual::capabilities_strip");
+        assert_eq!(strip.name(), "Capabilities Strip");
+        assert!(strip.is_on());
+        assert!(strip.supports_color());
+        assert!(strip.supports_color_temperature());
+        assert_eq!(strip.effects(), ["Candle"]);
+        assert_eq!(strip.segment_count(), 3);
+
+        let plain = &lights[1];
+        assert_eq!(plain.id(), "virtual::plain");
+        assert!(!plain.is_on());
+        assert!(!plain.supports_color());
+        assert!(!plain.supports_color_temperature());
+    }
+
+    #[tokio::test]
+    async fn records_commands() {
+        let mut lights = discover(VirtualConfig {
+            enabled: true,
+            lights: vec![light("Recording Bulb")],
+            ..Default::default()
+        })
+        .await;
+        let bulb = &mut lights[0];
+
+        bulb.set_on(true).await.unwrap();
+        bulb.set_color(255, 0, 128).await.unwrap();
+        bulb.set_brightness(40).await.unwrap();
+        assert!(bulb.set_color_temperature(2700).await.is_err());
+        assert!(bulb.set_effect("Disco").await.is_err());
+
+        assert!(bulb.is_on());
+        assert_eq!((bulb.red(), bulb.green(), bulb.blue()), (255, 0, 128));
+        assert_eq!(bulb.brightness(), 40);
+        assert_eq!(
+            recorded_for(bulb.as_ref()),
+            [
+                (VirtualCommand::SetOn(true), true),
+                (VirtualCommand::SetColor(255, 0, 128), true),
+                (VirtualCommand::SetBrightness(40), true),
+                (VirtualCommand::SetColorTemperature(2700), false),
+                (VirtualCommand::SetEffect("Disco".to_string()), false),
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_the_latest_commands() {
+        let mut recorded = std::collections::VecDeque::new();
+        for brightness in 0..5 {
+            push_recorded(
+                &mut recorded,
+                RecordedCommand {
+                    light_id: "virtual::capped".to_string(),
+                    command: VirtualCommand::SetBrightness(brightness),
+                    succeeded: true,
+                },
+                3,
+            );
+        }
+        let commands: Vec<VirtualCommand> = recorded.into_iter().map(|r| r.command).collect();
+        assert_eq!(
+            commands,
+            [
+                VirtualCommand::SetBrightness(2),
+                VirtualCommand::SetBrightness(3),
+                VirtualCommand::SetBrightness(4),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn fails_the_same_commands_for_a_seed() {
+        let config = VirtualConfig {
+            enabled: true,
+            seed: 42,
+            lights: vec![VirtualLightConfig {
+                failure_rate: 0.5,
+                ..light("Flaky Bulb")
+            }],
+            ..Default::default()
+        };
+
+        let mut runs = Vec::new();
+        for _ in 0..2 {
+            let mut lights = discover(config.clone()).await;
+            let mut outcomes = Vec::new();
+            for brightness in 0..32 {
+                outcomes.push(lights[0].set_brightness(brightness).await.is_ok());
+            }
+            runs.push(outcomes);
+        }
+        assert_eq!(runs[0], runs[1]);
+        assert!(runs[0].contains(&true));
+        assert!(runs[0].contains(&false));
+
+        let mut lights = discover(VirtualConfig {
+            enabled: true,
+            lights: vec![VirtualLightConfig {
+                failure_rate: 1.0,
+                ..light("Broken Bulb")
+            }],
+            ..Default::default()
+        })
+        .await;
+        assert!(lights[0].set_on(true).await.is_err());
+        assert!(!lights[0].is_on());
+    }
+
+    #[tokio::test]
+    async fn simulates_latency() {
+        let mut lights = discover(VirtualConfig {
+            enabled: true,
+            lights: vec![VirtualLightConfig {
+                latency: 50,
+                ..light("Slow Bulb")
+            }],
+            ..Default::default()
+        })
+        .await;
+
+        let start = Instant::now();
+        lights[0].set_on(true).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn appends_commands_to_the_record_file() {
+        let path = std::env::temp_dir().join(format!("cute_lights_{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut lights = discover(VirtualConfig {
+            enabled: true,
+            record_path: Some(path.to_string_lossy().to_string()),
+            lights: vec![VirtualLightConfig {
+                segments: 2,
+                ..light("File Strip")
+            }],
+            ..Default::default()
+        })
+        .await;
+        lights[0]
+            .set_segment_colors(&[(1, 2, 3), (4, 5, 6)])
+            .await
+            .unwrap();
+
+        let recorded = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            recorded,
+            "{\"light_id\":\"virtual::file_strip\",\"command\":{\"set_segment_colors\":[[1,2,3],[4,5,6]]},\"succeeded\":true}\n"
+        );
+    }
+}
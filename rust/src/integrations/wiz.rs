@@ -0,0 +1,584 @@
+use crate::{
+    config::CuteLightsConfig,
+    utils::{future::FutureBatch, net},
+};
+use async_trait::async_trait;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::json;
+use std::{net::SocketAddr, time::Duration};
+use tokio::{net::UdpSocket, time::Instant};
+
+use super::{DeviceInfo, Integration, Light};
+
+const WIZ_PORT: u16 = 38899;
+
+// Scene ids as listed in the WiZ app
+const SCENES: [(u16, &str); 32] = [
+    (1, "Ocean"),
+    (2, "Romance"),
+    (3, "Sunset"),
+    (4, "Party"),
+    (5, "Fireplace"),
+    (6, "Cozy"),
+    (7, "Forest"),
+    (8, "Pastel Colors"),
+    (9, "Wake Up"),
+    (10, "Bedtime"),
+    (11, "Warm White"),
+    (12, "Daylight"),
+    (13, "Cool White"),
+    (14, "Night Light"),
+    (15, "Focus"),
+    (16, "Relax"),
+    (17, "True Colors"),
+    (18, "TV Time"),
+    (19, "Plant Growth"),
+    (20, "Spring"),
+    (21, "Summer"),
+    (22, "Fall"),
+    (23, "Deep Dive"),
+    (24, "Jungle"),
+    (25, "Mojito"),
+    (26, "Club"),
+    (27, "Christmas"),
+    (28, "Halloween"),
+    (29, "Candlelight"),
+    (30, "Golden White"),
+    (31, "Pulse"),
+    (32, "Steampunk"),
+];
+
+// ANCHOR - WizLight
+pub struct WizLight {
+    socket: UdpSocket,
+    request_timeout: Duration,
+    mac: String,
+    module_name: Option<String>,
+    fw_version: Option<String>,
+    is_on: bool,
+    brightness: u8,
+    red: u8,
+    green: u8,
+    blue: u8,
+}
+
+impl WizLight {
+    pub async fn new(addr: SocketAddr, config: &WizConfig) -> anyhow::Result<WizLight> {
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        socket.connect(addr).await?;
+
+        let mut light = WizLight {
+            socket,
+            request_timeout: Duration::from_millis(config.request_timeout),
+            mac: String::new(),
+            module_name: None,
+            fw_version: None,
+            is_on: false,
+            brightness: 0,
+            red: 0,
+            green: 0,
+            blue: 0,
+        };
+
+        let pilot: Pilot = light.request("getPilot", json!({})).await?;
+        light.mac = pilot.mac.clone();
+        light.update_from(&pilot);
+
+        // Older firmware does not know getSystemConfig
+        if let Ok(system) = light
+            .request::<SystemConfig>("getSystemConfig", json!({}))
+            .await
+        {
+            light.module_name = system.module_name;
+            light.fw_version = system.fw_version;
+        }
+
+        Ok(light)
+    }
+
+    fn update_from(&mut self, pilot: &Pilot) {
+        self.is_on = pilot.state;
+        if let Some(dimming) = pilot.dimming {
+            self.brightness = dimming;
+        }
+        if let (Some(r), Some(g), Some(b)) = (pilot.r, pilot.g, pilot.b) {
+            self.red = r;
+            self.green = g;
+            self.blue = b;
+        }
+    }
+
+    async fn set_pilot(&self, params: serde_json::Value) -> anyhow::Result<()> {
+        let result: SetPilotResult = self.request("setPilot", params).await?;
+        if !result.success {
+            return Err(anyhow::anyhow!("{} rejected setPilot", self.name()));
+        }
+        Ok(())
+    }
+
+    async fn request<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        params: serde_json::Value,
+    ) -> anyhow::Result<T> {
+        let msg = Request { method, params };
+        self.socket
+            .send(serde_json::to_string(&msg)?.as_bytes())
+            .await?;
+
+        let deadline = Instant::now() + self.request_timeout;
+        let mut buf = [0; 2048];
+        loop {
+            let size = tokio::time::timeout_at(deadline, self.socket.recv(&mut buf))
+                .await
+                .map_err(|_| anyhow::anyhow!("Timed out waiting for {}", method))??;
+
+            let response: Response = match serde_json::from_slice(&buf[..size]) {
+                Ok(response) => response,
+                Err(e) => {
+                    eprintln!("Ignoring unexpected WiZ datagram: {}", e);
+                    continue;
+                }
+            };
+            if response.method != method {
+                continue;
+            }
+            if let Some(error) = response.error {
+                return Err(anyhow::anyhow!("{} failed: {}", method, error.message));
+            }
+            let result = response
+                .result
+                .ok_or_else(|| anyhow::anyhow!("{} returned no result", method))?;
+            return Ok(serde_json::from_value(result)?);
+        }
+    }
+}
+
+#[async_trait]
+impl Light for WizLight {
+    async fn set_on(&mut self, on: bool) -> anyhow::Result<()> {
+        self.set_pilot(json!({ "state": on })).await?;
+        self.is_on = on;
+        Ok(())
+    }
+
+    async fn set_color(&mut self, red: u8, green: u8, blue: u8) -> anyhow::Result<()> {
+        self.set_pilot(json!({ "r": red, "g": green, "b": blue }))
+            .await?;
+        // Any setPilot other than switching off turns the bulb on
+        self.is_on = true;
+        self.red = red;
+        self.green = green;
+        self.blue = blue;
+        Ok(())
+    }
+
+    async fn set_brightness(&mut self, brightness: u8) -> anyhow::Result<()> {
+        // The bulbs refuse anything below 10%
+        let dimming = brightness.clamp(10, 100);
+        self.set_pilot(json!({ "dimming": dimming })).await?;
+        self.is_on = true;
+        self.brightness = dimming;
+        Ok(())
+    }
+
+    fn id(&self) -> String {
+        format!("wiz::{}", self.mac)
+    }
+
+    fn is_on(&self) -> bool {
+        self.is_on
+    }
+
+    fn name(&self) -> String {
+        format!("WiZ Light ({})", self.mac)
+    }
+
+    fn supports_color(&self) -> bool {
+        // Module names look like ESP01_SHRGB1C_31, ESP05_SHDW_21, ESP56_SHTW3_01
+        match &self.module_name {
+            Some(module) => module.contains("RGB"),
+            None => true,
+        }
+    }
+
+    fn red(&self) -> u8 {
+        self.red
+    }
+
+    fn green(&self) -> u8 {
+        self.green
+    }
+
+    fn blue(&self) -> u8 {
+        self.blue
+    }
+
+    fn brightness(&self) -> u8 {
+        self.brightness
+    }
+
+    fn device_info(&self) -> DeviceInfo {
+        DeviceInfo {
+            manufacturer: "WiZ".to_string(),
+            model: self.module_name.clone(),
+            firmware_version: self.fw_version.clone(),
+            hardware_version: None,
+        }
+    }
+
+    fn supports_color_temperature(&self) -> bool {
+        match &self.module_name {
+            Some(module) => module.contains("RGB") || module.contains("TW"),
+            None => true,
+        }
+    }
+
+    async fn set_color_temperature(&mut self, kelvin: u16) -> anyhow::Result<()> {
+        self.set_pilot(json!({ "temp": kelvin.clamp(2200, 6500) }))
+            .await?;
+        self.is_on = true;
+        Ok(())
+    }
+
+    fn effects(&self) -> Vec<String> {
+        SCENES.iter().map(|(_, name)| name.to_string()).collect()
+    }
+
+    async fn set_effect(&mut self, effect: &str) -> anyhow::Result<()> {
+        let (scene_id, _) = SCENES
+            .iter()
+            .find(|(_, name)| name.eq_ignore_ascii_case(effect))
+            .ok_or_else(|| anyhow::anyhow!("Unknown WiZ scene {}", effect))?;
+        self.set_pilot(json!({ "sceneId": scene_id })).await?;
+        self.is_on = true;
+        Ok(())
+    }
+}
+
+// ANCHOR - WizConfig
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct WizConfig {
+    pub enabled: bool,
+    /// Bulbs to query directly, in addition to the broadcast scan
+    #[serde(default)]
+    pub addresses: Vec<String>,
+    #[serde(default = "default_broadcast")]
+    pub broadcast: String,
+    #[serde(default = "default_scan_timeout")]
+    pub scan_timeout: u64,
+    #[serde(default = "default_request_timeout")]
+    pub request_timeout: u64,
+}
+
+impl Default for WizConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            addresses: Vec::new(),
+            broadcast: default_broadcast(),
+            scan_timeout: default_scan_timeout(),
+            request_timeout: default_request_timeout(),
+        }
+    }
+}
+
+fn default_broadcast() -> String {
+    format!("255.255.255.255:{}", WIZ_PORT)
+}
+
+fn default_scan_timeout() -> u64 {
+    2000
+}
+
+fn default_request_timeout() -> u64 {
+    1000
+}
+
+// ANCHOR - WizIntegration
+
+pub struct WizIntegration;
+
+#[async_trait]
+impl Integration for WizIntegration {
+    fn name(&self) -> String {
+        "wiz".to_string()
+    }
+
+    fn preflight(&self, config: &CuteLightsConfig) -> bool {
+        config.wiz.enabled
+    }
+
+    async fn discover(&self, config: &CuteLightsConfig) -> anyhow::Result<Vec<Box<dyn Light>>> {
+        let mut lights = FutureBatch::new();
+
+        for addr in discover_bulbs(&config.wiz).await? {
+            let config = config.wiz.clone();
+            lights.push(async move {
+                match WizLight::new(addr, &config).await {
+                    Ok(light) => Some(Box::new(light) as Box<dyn Light>),
+                    Err(e) => {
+                        eprintln!("Failed to connect to WiZ light at {}: {}", addr, e);
+                        None
+                    }
+                }
+            });
+        }
+
+        Ok(lights.run().await.into_iter().flatten().collect())
+    }
+}
+
+// ANCHOR - Discovery
+
+/// Broadcasts a `registration` and returns the address of every bulb that
+/// answered within `scan_timeout`.
+pub async fn discover_bulbs(config: &WizConfig) -> anyhow::Result<Vec<SocketAddr>> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    socket.set_broadcast(true)?;
+
+    let msg = serde_json::to_string(&Request {
+        method: "registration",
+        params: json!({
+            "phoneMac": "AAAAAAAAAAAA",
+            "register": false,
+            "phoneIp": "1.2.3.4",
+            "id": "1"
+        }),
+    })?;
+
+    socket.send_to(msg.as_bytes(), &config.broadcast).await?;
+    for address in &config.addresses {
+        let target = net::with_default_port(address, WIZ_PORT);
+        let result = socket.send_to(msg.as_bytes(), target.as_str()).await;
+        if let Err(e) = result {
+            eprintln!("Failed to query WiZ light at {}: {}", address, e);
+        }
+    }
+
+    let deadline = Instant::now() + Duration::from_millis(config.scan_timeout);
+    let mut found: Vec<SocketAddr> = Vec::new();
+    let mut buf = [0; 2048];
+
+    while let Ok(res) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let (size, from) = match res {
+            Ok(res) => res,
+            Err(e) => {
+                eprintln!("Failed to receive WiZ datagram: {}", e);
+                continue;
+            }
+        };
+
+        match serde_json::from_slice::<Response>(&buf[..size]) {
+            Ok(response) if response.method == "registration" && response.result.is_some() => {
+                if !found.contains(&from) {
+                    found.push(from);
+                }
+            }
+            Ok(_) => {}
+            Err(e) => eprintln!("Ignoring unexpected WiZ datagram: {}", e),
+        }
+    }
+
+    Ok(found)
+}
+
+// ANCHOR - Messages
+
+#[derive(Debug, Serialize)]
+struct Request<'a> {
+    method: &'a str,
+    params: serde_json::Value,
+}
+
+#[derive(Debug, Deserialize)]
+struct Response {
+    method: String,
+    result: Option<serde_json::Value>,
+    error: Option<ResponseError>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ResponseError {
+    message: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct SetPilotResult {
+    success: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct Pilot {
+    mac: String,
+    state: bool,
+    dimming: Option<u8>,
+    r: Option<u8>,
+    g: Option<u8>,
+    b: Option<u8>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SystemConfig {
+    #[serde(rename = "moduleName")]
+    module_name: Option<String>,
+    #[serde(rename = "fwVersion")]
+    fw_version: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use std::sync::{Arc, Mutex};
+
+    // ANCHOR - Fake Bulb
+
+    /// A WiZ bulb answering on a loopback port.
+    struct FakeBulb {
+        addr: SocketAddr,
+        on: Arc<Mutex<bool>>,
+        pilots: Arc<Mutex<Vec<Value>>>,
+    }
+
+    impl FakeBulb {
+        async fn spawn() -> FakeBulb {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let addr = socket.local_addr().unwrap();
+            let on = Arc::new(Mutex::new(false));
+            let pilots = Arc::new(Mutex::new(Vec::new()));
+
+            let (bulb_on, bulb_pilots) = (on.clone(), pilots.clone());
+            tokio::spawn(async move {
+                let mut buf = [0; 2048];
+                while let Ok((size, from)) = socket.recv_from(&mut buf).await {
+                    let request: Value = serde_json::from_slice(&buf[..size]).unwrap();
+                    let params = request["params"].clone();
+                    let result = match request["method"].as_str().unwrap() {
+                        "registration" => json!({ "mac": "a8bb50000001", "success": true }),
+                        "getPilot" => json!({
+                            "mac": "a8bb50000001",
+                            "state": *bulb_on.lock().unwrap(),
+                            "dimming": 40,
+                            "r": 255,
+                            "g": 0,
+                            "b": 0
+                        }),
+                        "getSystemConfig" => json!({
+                            "moduleName": "ESP01_SHRGB1C_31",
+                            "fwVersion": "1.25.0"
+                        }),
+                        "setPilot" => {
+                            *bulb_on.lock().unwrap() = params["state"].as_bool().unwrap_or(true);
+                            bulb_pilots.lock().unwrap().push(params);
+                            json!({ "success": true })
+                        }
+                        _ => continue,
+                    };
+                    let reply = json!({ "method": request["method"], "result": result });
+                    socket
+                        .send_to(reply.to_string().as_bytes(), from)
+                        .await
+                        .unwrap();
+                }
+            });
+
+            FakeBulb { addr, on, pilots }
+        }
+
+        fn config(&self) -> WizConfig {
+            WizConfig {
+                enabled: true,
+                broadcast: self.addr.to_string(),
+                scan_timeout: 200,
+                request_timeout: 200,
+                ..Default::default()
+            }
+        }
+
+        fn pilots(&self) -> Vec<Value> {
+            self.pilots.lock().unwrap().clone()
+        }
+    }
+
+    #[tokio::test]
+    async fn discovers_fake_bulb() {
+        let bulb = FakeBulb::spawn().await;
+        let config = CuteLightsConfig {
+            wiz: bulb.config(),
+            ..Default::default()
+        };
+
+        let lights = WizIntegration.discover(&config).await.unwrap();
+
+        assert_eq!(lights.len(), 1);
+        let light = &lights[0];
+        assert_eq!(light.id(), "wiz::a8bb50000001");
+        assert!(!light.is_on());
+        assert_eq!(light.brightness(), 40);
+        assert_eq!((light.red(), light.green(), light.blue()), (255, 0, 0));
+        assert!(light.supports_color());
+        assert_eq!(
+            light.device_info().firmware_version.as_deref(),
+            Some("1.25.0")
+        );
+    }
+
+    #[tokio::test]
+    async fn sends_set_pilot_payloads() {
+        let bulb = FakeBulb::spawn().await;
+        let mut light = WizLight::new(bulb.addr, &bulb.config()).await.unwrap();
+
+        light.set_on(true).await.unwrap();
+        light.set_on(false).await.unwrap();
+        light.set_color(0, 128, 255).await.unwrap();
+        light.set_brightness(5).await.unwrap();
+        light.set_color_temperature(9000).await.unwrap();
+
+        assert_eq!(
+            bulb.pilots(),
+            [
+                json!({ "state": true }),
+                json!({ "state": false }),
+                json!({ "r": 0, "g": 128, "b": 255 }),
+                json!({ "dimming": 10 }),
+                json!({ "temp": 6500 }),
+            ]
+        );
+        assert_eq!(light.brightness(), 10);
+        assert_eq!((light.red(), light.green(), light.blue()), (0, 128, 255));
+    }
+
+    #[tokio::test]
+    async fn sets_scenes_by_name() {
+        let bulb = FakeBulb::spawn().await;
+        let mut light = WizLight::new(bulb.addr, &bulb.config()).await.unwrap();
+
+        assert_eq!(light.effects().len(), 32);
+        light.set_effect("pastel colors").await.unwrap();
+        light.set_effect("Steampunk").await.unwrap();
+        assert!(light.set_effect("Disco").await.is_err());
+
+        assert_eq!(
+            bulb.pilots(),
+            [json!({ "sceneId": 8 }), json!({ "sceneId": 32 })]
+        );
+    }
+
+    #[tokio::test]
+    async fn tracks_bulbs_turning_on_with_other_changes() {
+        let bulb = FakeBulb::spawn().await;
+        let mut light = WizLight::new(bulb.addr, &bulb.config()).await.unwrap();
+        assert!(!light.is_on());
+
+        light.set_color_temperature(2700).await.unwrap();
+        assert!(light.is_on());
+        assert!(*bulb.on.lock().unwrap());
+
+        light.set_on(false).await.unwrap();
+        light.set_effect("Ocean").await.unwrap();
+        assert!(light.is_on());
+        assert!(*bulb.on.lock().unwrap());
+    }
+}
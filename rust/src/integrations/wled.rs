@@ -0,0 +1,646 @@
+use crate::{
+    config::CuteLightsConfig,
+    utils::{future::FutureBatch, mdns},
+};
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde_json::json;
+use std::{net::IpAddr, time::Duration};
+use tokio::net::UdpSocket;
+
+use super::{DeviceInfo, Integration, Light};
+
+const WLED_SERVICE: &str = "_wled._tcp.local.";
+const WLED_PORT: u16 = 80;
+
+// Palettes are listed after the effects, under this prefix
+const PALETTE_PREFIX: &str = "Palette: ";
+
+// Realtime UDP protocols, see https://kno.wled.ge/interfaces/udp-realtime/
+const DRGB: u8 = 2;
+const DNRGB: u8 = 4;
+const DRGB_MAX_LEDS: usize = 490;
+const DNRGB_MAX_LEDS: usize = 489;
+
+// ANCHOR - WledLight
+pub struct WledLight {
+    client: reqwest::Client,
+    base_url: String,
+    host: IpAddr,
+    realtime_timeout: u8,
+    realtime: Option<UdpSocket>,
+    info: Info,
+    effects: Vec<String>,
+    palettes: Vec<String>,
+    segments: Vec<u8>,
+    is_on: bool,
+    brightness: u8,
+    red: u8,
+    green: u8,
+    blue: u8,
+}
+
+impl WledLight {
+    pub async fn new(host: IpAddr, port: u16, config: &WledConfig) -> anyhow::Result<WledLight> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_millis(config.request_timeout))
+            .build()?;
+        let base_url = match host {
+            IpAddr::V4(addr) => format!("http://{}:{}", addr, port),
+            IpAddr::V6(addr) => format!("http://[{}]:{}", addr, port),
+        };
+
+        let body = client
+            .get(format!("{}/json", base_url))
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        let full: FullState = serde_json::from_str(&body)?;
+
+        let mut light = WledLight {
+            client,
+            base_url,
+            host,
+            realtime_timeout: config.realtime_timeout,
+            realtime: None,
+            info: full.info,
+            effects: full.effects,
+            palettes: full.palettes,
+            segments: Vec::new(),
+            is_on: false,
+            brightness: 0,
+            red: 0,
+            green: 0,
+            blue: 0,
+        };
+        light.update_from(&full.state);
+
+        Ok(light)
+    }
+
+    fn update_from(&mut self, state: &State) {
+        self.is_on = state.on;
+        self.brightness = (state.bri as u16 * 100 / 255) as u8;
+        self.segments = state.seg.iter().map(|seg| seg.id).collect();
+        if let Some([r, g, b, ..]) = state
+            .seg
+            .first()
+            .and_then(|seg| seg.col.first())
+            .map(|col| col.as_slice())
+        {
+            self.red = *r;
+            self.green = *g;
+            self.blue = *b;
+        }
+    }
+
+    async fn post_state(&self, body: serde_json::Value) -> anyhow::Result<()> {
+        self.client
+            .post(format!("{}/json/state", self.base_url))
+            .header("Content-Type", "application/json")
+            .body(body.to_string())
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Applies `segment` to every segment of the strip.
+    async fn post_segments(&self, segment: serde_json::Value) -> anyhow::Result<()> {
+        let segments: Vec<serde_json::Value> = self
+            .segments
+            .iter()
+            .map(|id| {
+                let mut seg = segment.clone();
+                seg["id"] = json!(id);
+                seg
+            })
+            .collect();
+        self.post_state(json!({ "seg": segments })).await
+    }
+
+    async fn stream(&self, socket: &UdpSocket, colors: &[(u8, u8, u8)]) -> anyhow::Result<()> {
+        for packet in realtime_packets(colors, self.realtime_timeout) {
+            socket.send(&packet).await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Light for WledLight {
+    async fn set_on(&mut self, on: bool) -> anyhow::Result<()> {
+        self.post_state(json!({ "on": on })).await?;
+        self.is_on = on;
+        Ok(())
+    }
+
+    async fn set_color(&mut self, red: u8, green: u8, blue: u8) -> anyhow::Result<()> {
+        self.post_segments(json!({ "col": [[red, green, blue]], "fx": 0 }))
+            .await?;
+        self.red = red;
+        self.green = green;
+        self.blue = blue;
+        Ok(())
+    }
+
+    async fn set_brightness(&mut self, brightness: u8) -> anyhow::Result<()> {
+        let brightness = brightness.min(100);
+        let bri = (brightness as u16 * 255 / 100) as u8;
+        self.post_state(json!({ "bri": bri })).await?;
+        self.brightness = brightness;
+        Ok(())
+    }
+
+    fn id(&self) -> String {
+        format!("wled::{}", self.info.mac)
+    }
+
+    fn is_on(&self) -> bool {
+        self.is_on
+    }
+
+    fn name(&self) -> String {
+        self.info.name.clone()
+    }
+
+    fn supports_color(&self) -> bool {
+        // Older firmware does not report capabilities, those only drove RGB strips
+        self.info.leds.lc.is_none_or(|lc| lc & LC_RGB != 0)
+    }
+
+    fn red(&self) -> u8 {
+        self.red
+    }
+
+    fn green(&self) -> u8 {
+        self.green
+    }
+
+    fn blue(&self) -> u8 {
+        self.blue
+    }
+
+    fn brightness(&self) -> u8 {
+        self.brightness
+    }
+
+    fn device_info(&self) -> DeviceInfo {
+        DeviceInfo {
+            manufacturer: self
+                .info
+                .brand
+                .clone()
+                .unwrap_or_else(|| "WLED".to_string()),
+            model: self.info.product.clone(),
+            firmware_version: Some(self.info.ver.clone()),
+            hardware_version: self.info.arch.clone(),
+        }
+    }
+
+    fn supports_color_temperature(&self) -> bool {
+        self.info.leds.lc.is_some_and(|lc| lc & LC_CCT != 0)
+    }
+
+    async fn set_color_temperature(&mut self, kelvin: u16) -> anyhow::Result<()> {
+        if !self.supports_color_temperature() {
+            return Err(anyhow::anyhow!(
+                "{} does not support color temperature",
+                self.name()
+            ));
+        }
+        // WLED takes values above 1900 as kelvin rather than a 0-255 blend
+        self.post_segments(json!({ "cct": kelvin.clamp(1900, 10091) }))
+            .await
+    }
+
+    fn effects(&self) -> Vec<String> {
+        self.effects
+            .iter()
+            .cloned()
+            .chain(
+                self.palettes
+                    .iter()
+                    .map(|palette| format!("{}{}", PALETTE_PREFIX, palette)),
+            )
+            .collect()
+    }
+
+    async fn set_effect(&mut self, effect: &str) -> anyhow::Result<()> {
+        if let Some(palette) = effect.strip_prefix(PALETTE_PREFIX) {
+            let index = self
+                .palettes
+                .iter()
+                .position(|name| name.eq_ignore_ascii_case(palette))
+                .ok_or_else(|| anyhow::anyhow!("Unknown WLED palette {}", palette))?;
+            return self.post_segments(json!({ "pal": index })).await;
+        }
+
+        let index = self
+            .effects
+            .iter()
+            .position(|name| name.eq_ignore_ascii_case(effect))
+            .ok_or_else(|| anyhow::anyhow!("Unknown WLED effect {}", effect))?;
+        self.post_segments(json!({ "fx": index })).await
+    }
+
+    fn segment_count(&self) -> usize {
+        self.segments.len()
+    }
+
+    async fn set_segment_colors(&mut self, colors: &[(u8, u8, u8)]) -> anyhow::Result<()> {
+        if let Some(socket) = &self.realtime {
+            return self.stream(socket, colors).await;
+        }
+
+        if colors.len() > self.segments.len() {
+            return Err(anyhow::anyhow!(
+                "{} has {} segments, got {} colors",
+                self.name(),
+                self.segments.len(),
+                colors.len()
+            ));
+        }
+        let segments: Vec<serde_json::Value> = self
+            .segments
+            .iter()
+            .zip(colors)
+            .map(|(id, (r, g, b))| json!({ "id": id, "col": [[r, g, b]], "fx": 0 }))
+            .collect();
+        self.post_state(json!({ "seg": segments })).await
+    }
+
+    async fn set_realtime(&mut self, enabled: bool) -> anyhow::Result<()> {
+        if enabled {
+            if self.realtime.is_none() {
+                let socket = UdpSocket::bind("0.0.0.0:0").await?;
+                socket.connect((self.host, self.info.udpport)).await?;
+                self.realtime = Some(socket);
+            }
+        } else if self.realtime.take().is_some() {
+            // Hand control back right away instead of waiting out the timeout
+            self.post_state(json!({ "live": false })).await?;
+        }
+        Ok(())
+    }
+}
+
+// ANCHOR - Realtime
+
+/// Splits a frame into realtime datagrams. Frames that fit in one packet use
+/// DRGB, longer ones are sent as DNRGB chunks with a start index.
+fn realtime_packets(colors: &[(u8, u8, u8)], timeout: u8) -> Vec<Vec<u8>> {
+    if colors.len() <= DRGB_MAX_LEDS {
+        let mut packet = Vec::with_capacity(2 + colors.len() * 3);
+        packet.push(DRGB);
+        packet.push(timeout);
+        for (r, g, b) in colors {
+            packet.extend_from_slice(&[*r, *g, *b]);
+        }
+        return vec![packet];
+    }
+
+    colors
+        .chunks(DNRGB_MAX_LEDS)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let start = (i * DNRGB_MAX_LEDS) as u16;
+            let mut packet = Vec::with_capacity(4 + chunk.len() * 3);
+            packet.push(DNRGB);
+            packet.push(timeout);
+            packet.extend_from_slice(&start.to_be_bytes());
+            for (r, g, b) in chunk {
+                packet.extend_from_slice(&[*r, *g, *b]);
+            }
+            packet
+        })
+        .collect()
+}
+
+// ANCHOR - WledConfig
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct WledConfig {
+    pub enabled: bool,
+    /// Controllers to query directly, as `host` or `host:port`
+    #[serde(default)]
+    pub addresses: Vec<String>,
+    /// Browse for `_wled._tcp` services over mDNS
+    #[serde(default = "default_mdns")]
+    pub mdns: bool,
+    #[serde(default = "default_scan_timeout")]
+    pub scan_timeout: u64,
+    #[serde(default = "default_request_timeout")]
+    pub request_timeout: u64,
+    /// Seconds WLED waits after the last realtime frame before resuming its effect
+    #[serde(default = "default_realtime_timeout")]
+    pub realtime_timeout: u8,
+}
+
+impl Default for WledConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            addresses: Vec::new(),
+            mdns: default_mdns(),
+            scan_timeout: default_scan_timeout(),
+            request_timeout: default_request_timeout(),
+            realtime_timeout: default_realtime_timeout(),
+        }
+    }
+}
+
+fn default_mdns() -> bool {
+    true
+}
+
+fn default_scan_timeout() -> u64 {
+    2000
+}
+
+fn default_request_timeout() -> u64 {
+    1000
+}
+
+fn default_realtime_timeout() -> u8 {
+    2
+}
+
+// ANCHOR - WledIntegration
+
+pub struct WledIntegration;
+
+#[async_trait]
+impl Integration for WledIntegration {
+    fn name(&self) -> String {
+        "wled".to_string()
+    }
+
+    fn preflight(&self, config: &CuteLightsConfig) -> bool {
+        config.wled.enabled
+    }
+
+    async fn discover(&self, config: &CuteLightsConfig) -> anyhow::Result<Vec<Box<dyn Light>>> {
+        let mut lights = FutureBatch::new();
+
+        let hosts = mdns::resolve_hosts(
+            &config.wled.addresses,
+            WLED_PORT,
+            config.wled.mdns.then_some(WLED_SERVICE),
+            Duration::from_millis(config.wled.scan_timeout),
+        )
+        .await;
+        for (host, port) in hosts {
+            let config = config.wled.clone();
+            lights.push(async move {
+                match WledLight::new(host, port, &config).await {
+                    Ok(light) => Some(Box::new(light) as Box<dyn Light>),
+                    Err(e) => {
+                        eprintln!("Failed to connect to WLED at {}:{}: {}", host, port, e);
+                        None
+                    }
+                }
+            });
+        }
+
+        let mut found: Vec<Box<dyn Light>> = Vec::new();
+        for light in lights.run().await.into_iter().flatten() {
+            // A controller listed in addresses is usually announced over mDNS too
+            if !found.iter().any(|l| l.id() == light.id()) {
+                found.push(light);
+            }
+        }
+        Ok(found)
+    }
+}
+
+// ANCHOR - Messages
+
+const LC_RGB: u8 = 0x01;
+const LC_CCT: u8 = 0x04;
+
+#[derive(Debug, Deserialize)]
+struct FullState {
+    state: State,
+    info: Info,
+    #[serde(default)]
+    effects: Vec<String>,
+    #[serde(default)]
+    palettes: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct State {
+    on: bool,
+    bri: u8,
+    #[serde(default)]
+    seg: Vec<Segment>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Segment {
+    #[serde(default)]
+    id: u8,
+    #[serde(default)]
+    col: Vec<Vec<u8>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Info {
+    ver: String,
+    name: String,
+    mac: String,
+    leds: Leds,
+    #[serde(default = "default_udp_port")]
+    udpport: u16,
+    arch: Option<String>,
+    brand: Option<String>,
+    product: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Leds {
+    lc: Option<u8>,
+}
+
+fn default_udp_port() -> u16 {
+    21324
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::http::FakeHttpServer;
+    use serde_json::Value;
+    use std::net::Ipv4Addr;
+
+    fn full_state(udp_port: u16) -> Value {
+        json!({
+            "state": {
+                "on": true,
+                "bri": 128,
+                "seg": [
+                    { "id": 0, "start": 0, "stop": 30, "col": [[255, 160, 0], [0, 0, 0], [0, 0, 0]] },
+                    { "id": 2, "start": 30, "stop": 60, "col": [[0, 0, 255]] }
+                ]
+            },
+            "info": {
+                "ver": "0.14.4",
+                "name": "Desk Strip",
+                "mac": "a8032a000001",
+                "leds": { "count": 60, "lc": 1 },
+                "udpport": udp_port,
+                "arch": "esp32",
+                "brand": "WLED",
+                "product": "FOSS"
+            },
+            "effects": ["Solid", "Blink", "Breathe"],
+            "palettes": ["Default", "* Random Cycle", "Party"]
+        })
+    }
+
+    async fn controller(udp_port: u16) -> (FakeHttpServer, WledLight) {
+        let state = full_state(udp_port);
+        let server = FakeHttpServer::start(move |request| match request.path.as_str() {
+            "/json" => (200, state.clone()),
+            "/json/state" => (200, json!({ "success": true })),
+            _ => (404, json!({ "error": 3 })),
+        })
+        .await
+        .unwrap();
+
+        let address = server.address();
+        let light = WledLight::new(address.ip(), address.port(), &WledConfig::default())
+            .await
+            .unwrap();
+        (server, light)
+    }
+
+    fn posted(server: &FakeHttpServer) -> Vec<Value> {
+        server
+            .requests()
+            .into_iter()
+            .filter(|request| request.method == "POST")
+            .filter_map(|request| request.body)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn reads_json_state() {
+        let (_server, light) = controller(21324).await;
+
+        assert_eq!(light.id(), "wled::a8032a000001");
+        assert_eq!(light.name(), "Desk Strip");
+        assert!(light.is_on());
+        assert_eq!(light.brightness(), 50);
+        assert_eq!((light.red(), light.green(), light.blue()), (255, 160, 0));
+        assert_eq!(light.segment_count(), 2);
+        assert!(light.supports_color());
+        assert!(!light.supports_color_temperature());
+        assert_eq!(
+            light.effects(),
+            [
+                "Solid",
+                "Blink",
+                "Breathe",
+                "Palette: Default",
+                "Palette: * Random Cycle",
+                "Palette: Party"
+            ]
+        );
+        let info = light.device_info();
+        assert_eq!(info.manufacturer, "WLED");
+        assert_eq!(info.firmware_version.as_deref(), Some("0.14.4"));
+    }
+
+    #[tokio::test]
+    async fn posts_state_changes() {
+        let (server, mut light) = controller(21324).await;
+
+        light.set_on(false).await.unwrap();
+        light.set_brightness(50).await.unwrap();
+        light.set_color(1, 2, 3).await.unwrap();
+        light.set_effect("breathe").await.unwrap();
+        light.set_effect("Palette: party").await.unwrap();
+        light.set_segment_colors(&[(4, 5, 6)]).await.unwrap();
+        assert!(light.set_effect("Fireworks").await.is_err());
+        assert!(light.set_effect("Palette: Ocean").await.is_err());
+        assert!(light.set_segment_colors(&[(0, 0, 0); 3]).await.is_err());
+
+        assert_eq!(
+            posted(&server),
+            [
+                json!({ "on": false }),
+                json!({ "bri": 127 }),
+                json!({ "seg": [
+                    { "id": 0, "col": [[1, 2, 3]], "fx": 0 },
+                    { "id": 2, "col": [[1, 2, 3]], "fx": 0 }
+                ] }),
+                json!({ "seg": [{ "id": 0, "fx": 2 }, { "id": 2, "fx": 2 }] }),
+                json!({ "seg": [{ "id": 0, "pal": 2 }, { "id": 2, "pal": 2 }] }),
+                json!({ "seg": [{ "id": 0, "col": [[4, 5, 6]], "fx": 0 }] }),
+            ]
+        );
+        assert!(!light.is_on());
+        assert_eq!(light.brightness(), 50);
+    }
+
+    #[test]
+    fn encodes_short_frames_as_drgb() {
+        assert_eq!(
+            realtime_packets(&[(1, 2, 3), (4, 5, 6)], 2),
+            [vec![DRGB, 2, 1, 2, 3, 4, 5, 6]]
+        );
+
+        let packets = realtime_packets(&[(7, 7, 7); DRGB_MAX_LEDS], 255);
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].len(), 2 + DRGB_MAX_LEDS * 3);
+        assert_eq!(packets[0][..2], [DRGB, 255]);
+    }
+
+    #[test]
+    fn splits_long_frames_into_dnrgb_chunks() {
+        let colors: Vec<(u8, u8, u8)> = (0..1000).map(|i| (i as u8, 0, 1)).collect();
+
+        let packets = realtime_packets(&colors, 5);
+
+        // 489 + 489 + 22 LEDs, each chunk carrying its start index
+        assert_eq!(packets.len(), 3);
+        let starts: Vec<u16> = packets
+            .iter()
+            .map(|packet| u16::from_be_bytes([packet[2], packet[3]]))
+            .collect();
+        assert_eq!(starts, [0, 489, 978]);
+        for packet in &packets {
+            assert_eq!(packet[..2], [DNRGB, 5]);
+        }
+        assert_eq!(packets[0].len(), 4 + DNRGB_MAX_LEDS * 3);
+        assert_eq!(packets[2].len(), 4 + 22 * 3);
+        assert_eq!(packets[1][4..7], [489u16 as u8, 0, 1]);
+    }
+
+    #[tokio::test]
+    async fn streams_realtime_frames() {
+        let device = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let (server, mut light) = controller(device.local_addr().unwrap().port()).await;
+
+        light.set_realtime(true).await.unwrap();
+        // Realtime frames may address more LEDs than there are segments
+        light
+            .set_segment_colors(&[(255, 0, 0), (0, 255, 0), (0, 0, 255)])
+            .await
+            .unwrap();
+
+        let mut buf = [0; 2048];
+        let size = tokio::time::timeout(Duration::from_secs(1), device.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(buf[..size], [DRGB, 2, 255, 0, 0, 0, 255, 0, 0, 0, 255]);
+
+        light.set_realtime(false).await.unwrap();
+        assert_eq!(posted(&server), [json!({ "live": false })]);
+    }
+}
@@ -0,0 +1,718 @@
+use crate::{
+    config::CuteLightsConfig,
+    utils::{future::FutureBatch, net},
+};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use socket2::SockRef;
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream, UdpSocket,
+    },
+    sync::oneshot,
+    task::JoinHandle,
+    time::Instant,
+};
+
+use super::{DeviceInfo, Integration, Light};
+
+const MULTICAST_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+const SSDP_PORT: u16 = 1982;
+
+type Pending = HashMap<u64, oneshot::Sender<anyhow::Result<serde_json::Value>>>;
+
+// ANCHOR - YeelightLight
+pub struct YeelightLight {
+    writer: OwnedWriteHalf,
+    pending: Arc<Mutex<Pending>>,
+    state: Arc<Mutex<YeelightState>>,
+    next_id: AtomicU64,
+    reader: JoinHandle<()>,
+    request_timeout: Duration,
+    transition: u32,
+    advertisement: Advertisement,
+}
+
+/// Kept current by the `props` notifications the bulb pushes after every change,
+/// including changes made from other apps.
+#[derive(Debug, Default, Clone)]
+struct YeelightState {
+    name: String,
+    is_on: bool,
+    brightness: u8,
+    red: u8,
+    green: u8,
+    blue: u8,
+}
+
+impl YeelightState {
+    fn apply(&mut self, props: &serde_json::Map<String, serde_json::Value>) {
+        for (key, value) in props {
+            // Notifications send numbers as strings, discovery as plain text
+            let text = match value {
+                serde_json::Value::String(text) => text.clone(),
+                other => other.to_string(),
+            };
+            match key.as_str() {
+                "power" => self.is_on = text == "on",
+                "bright" => {
+                    if let Ok(bright) = text.parse() {
+                        self.brightness = bright;
+                    }
+                }
+                "rgb" => {
+                    if let Ok(rgb) = text.parse::<u32>() {
+                        let [_, red, green, blue] = rgb.to_be_bytes();
+                        self.red = red;
+                        self.green = green;
+                        self.blue = blue;
+                    }
+                }
+                "name" => self.name = text,
+                _ => {}
+            }
+        }
+    }
+}
+
+impl YeelightLight {
+    pub async fn new(
+        advertisement: Advertisement,
+        config: &YeelightConfig,
+    ) -> anyhow::Result<YeelightLight> {
+        let stream = tokio::time::timeout(
+            Duration::from_millis(config.request_timeout),
+            TcpStream::connect(advertisement.location),
+        )
+        .await
+        .map_err(|_| anyhow::anyhow!("Timed out connecting to {}", advertisement.location))??;
+        let (read, writer) = stream.into_split();
+
+        let pending = Arc::new(Mutex::new(Pending::new()));
+        let state = Arc::new(Mutex::new(YeelightState::default()));
+        if let Ok(mut state) = state.lock() {
+            state.apply(&advertisement.props);
+        }
+
+        let reader = tokio::spawn(read_messages(read, pending.clone(), state.clone()));
+
+        let mut light = YeelightLight {
+            writer,
+            pending,
+            state,
+            next_id: AtomicU64::new(1),
+            reader,
+            request_timeout: Duration::from_millis(config.request_timeout),
+            transition: config.transition,
+            advertisement,
+        };
+
+        // The advertisement may come from a cache, read the real state
+        let props = ["power", "bright", "rgb", "name"];
+        let values = light.request("get_prop", json!(props)).await?;
+        if let Some(values) = values.as_array() {
+            let props = props
+                .iter()
+                .zip(values)
+                .map(|(key, value)| (key.to_string(), value.clone()))
+                .collect();
+            light.lock_state()?.apply(&props);
+        }
+
+        Ok(light)
+    }
+
+    /// Sends a command and waits for its `result`.
+    async fn request(
+        &mut self,
+        method: &str,
+        params: serde_json::Value,
+    ) -> anyhow::Result<serde_json::Value> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        self.lock_pending()?.insert(id, tx);
+
+        let mut line = serde_json::to_string(&Command { id, method, params })?;
+        line.push_str("\r\n");
+        if let Err(e) = self.writer.write_all(line.as_bytes()).await {
+            self.lock_pending()?.remove(&id);
+            return Err(e.into());
+        }
+
+        match tokio::time::timeout(self.request_timeout, rx).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => Err(anyhow::anyhow!("Connection to {} closed", self.name())),
+            Err(_) => {
+                self.lock_pending()?.remove(&id);
+                Err(anyhow::anyhow!("Timed out waiting for {}", method))
+            }
+        }
+    }
+
+    /// `[effect, duration]` as every setter expects it.
+    fn transition_params(&self) -> (&'static str, u32) {
+        if self.transition == 0 {
+            ("sudden", 0)
+        } else {
+            // Anything shorter is rejected
+            ("smooth", self.transition.max(30))
+        }
+    }
+
+    fn lock_state(&self) -> anyhow::Result<std::sync::MutexGuard<'_, YeelightState>> {
+        self.state
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Yeelight state poisoned"))
+    }
+
+    fn lock_pending(&self) -> anyhow::Result<std::sync::MutexGuard<'_, Pending>> {
+        self.pending
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Yeelight request table poisoned"))
+    }
+
+    fn snapshot(&self) -> YeelightState {
+        self.state
+            .lock()
+            .map(|state| state.clone())
+            .unwrap_or_default()
+    }
+
+    fn supports(&self, method: &str) -> bool {
+        self.advertisement.support.iter().any(|m| m == method)
+    }
+}
+
+impl Drop for YeelightLight {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+async fn read_messages(
+    read: OwnedReadHalf,
+    pending: Arc<Mutex<Pending>>,
+    state: Arc<Mutex<YeelightState>>,
+) {
+    let mut lines = BufReader::new(read).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let message: Message = match serde_json::from_str(&line) {
+            Ok(message) => message,
+            Err(e) => {
+                eprintln!("Ignoring unexpected Yeelight message: {}", e);
+                continue;
+            }
+        };
+
+        if let (Some("props"), Some(props)) = (message.method.as_deref(), &message.params) {
+            if let (Ok(mut state), Some(props)) = (state.lock(), props.as_object()) {
+                state.apply(props);
+            }
+            continue;
+        }
+
+        let Some(id) = message.id else {
+            continue;
+        };
+        let Some(tx) = pending.lock().ok().and_then(|mut p| p.remove(&id)) else {
+            continue;
+        };
+        let result = match (message.result, message.error) {
+            (_, Some(error)) => Err(anyhow::anyhow!(
+                "Yeelight error {}: {}",
+                error.code,
+                error.message
+            )),
+            (Some(result), None) => Ok(result),
+            (None, None) => Ok(serde_json::Value::Null),
+        };
+        let _ = tx.send(result);
+    }
+
+    // Fail everything still waiting instead of letting it time out
+    if let Ok(mut pending) = pending.lock() {
+        pending.clear();
+    }
+}
+
+#[async_trait]
+impl Light for YeelightLight {
+    async fn set_on(&mut self, on: bool) -> anyhow::Result<()> {
+        let (effect, duration) = self.transition_params();
+        let power = if on { "on" } else { "off" };
+        self.request("set_power", json!([power, effect, duration]))
+            .await?;
+        self.lock_state()?.is_on = on;
+        Ok(())
+    }
+
+    async fn set_color(&mut self, red: u8, green: u8, blue: u8) -> anyhow::Result<()> {
+        let (effect, duration) = self.transition_params();
+        let rgb = u32::from_be_bytes([0, red, green, blue]);
+        self.request("set_rgb", json!([rgb, effect, duration]))
+            .await?;
+        let mut state = self.lock_state()?;
+        state.red = red;
+        state.green = green;
+        state.blue = blue;
+        Ok(())
+    }
+
+    async fn set_brightness(&mut self, brightness: u8) -> anyhow::Result<()> {
+        let (effect, duration) = self.transition_params();
+        let brightness = brightness.clamp(1, 100);
+        self.request("set_bright", json!([brightness, effect, duration]))
+            .await?;
+        self.lock_state()?.brightness = brightness;
+        Ok(())
+    }
+
+    fn id(&self) -> String {
+        format!("yeelight::{}", self.advertisement.id)
+    }
+
+    fn is_on(&self) -> bool {
+        self.snapshot().is_on
+    }
+
+    fn name(&self) -> String {
+        let name = self.snapshot().name;
+        if name.is_empty() {
+            format!(
+                "Yeelight {} ({})",
+                self.advertisement.model, self.advertisement.id
+            )
+        } else {
+            name
+        }
+    }
+
+    fn supports_color(&self) -> bool {
+        self.supports("set_rgb")
+    }
+
+    fn red(&self) -> u8 {
+        self.snapshot().red
+    }
+
+    fn green(&self) -> u8 {
+        self.snapshot().green
+    }
+
+    fn blue(&self) -> u8 {
+        self.snapshot().blue
+    }
+
+    fn brightness(&self) -> u8 {
+        self.snapshot().brightness
+    }
+
+    fn device_info(&self) -> DeviceInfo {
+        DeviceInfo {
+            manufacturer: "Yeelight".to_string(),
+            model: Some(self.advertisement.model.clone()),
+            firmware_version: Some(self.advertisement.fw_ver.clone()),
+            hardware_version: None,
+        }
+    }
+
+    fn supports_color_temperature(&self) -> bool {
+        self.supports("set_ct_abx")
+    }
+
+    async fn set_color_temperature(&mut self, kelvin: u16) -> anyhow::Result<()> {
+        let (effect, duration) = self.transition_params();
+        let kelvin = kelvin.clamp(1700, 6500);
+        self.request("set_ct_abx", json!([kelvin, effect, duration]))
+            .await?;
+        Ok(())
+    }
+}
+
+// ANCHOR - YeelightConfig
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct YeelightConfig {
+    pub enabled: bool,
+    /// Bulbs to query directly, in addition to the multicast search
+    #[serde(default)]
+    pub addresses: Vec<String>,
+    #[serde(default = "default_scan_timeout")]
+    pub scan_timeout: u64,
+    #[serde(default = "default_request_timeout")]
+    pub request_timeout: u64,
+    /// Fade time in milliseconds, 0 switches instantly
+    #[serde(default = "default_transition")]
+    pub transition: u32,
+}
+
+impl Default for YeelightConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            addresses: Vec::new(),
+            scan_timeout: default_scan_timeout(),
+            request_timeout: default_request_timeout(),
+            transition: default_transition(),
+        }
+    }
+}
+
+fn default_scan_timeout() -> u64 {
+    2000
+}
+
+fn default_request_timeout() -> u64 {
+    1000
+}
+
+fn default_transition() -> u32 {
+    300
+}
+
+// ANCHOR - YeelightIntegration
+
+pub struct YeelightIntegration;
+
+#[async_trait]
+impl Integration for YeelightIntegration {
+    fn name(&self) -> String {
+        "yeelight".to_string()
+    }
+
+    fn preflight(&self, config: &CuteLightsConfig) -> bool {
+        config.yeelight.enabled
+    }
+
+    async fn discover(&self, config: &CuteLightsConfig) -> anyhow::Result<Vec<Box<dyn Light>>> {
+        let mut lights = FutureBatch::new();
+
+        for advertisement in search(&config.yeelight).await? {
+            let config = config.yeelight.clone();
+            lights.push(async move {
+                let location = advertisement.location;
+                match YeelightLight::new(advertisement, &config).await {
+                    Ok(light) => Some(Box::new(light) as Box<dyn Light>),
+                    Err(e) => {
+                        eprintln!("Failed to connect to Yeelight at {}: {}", location, e);
+                        None
+                    }
+                }
+            });
+        }
+
+        Ok(lights.run().await.into_iter().flatten().collect())
+    }
+}
+
+// ANCHOR - Discovery
+
+/// What a bulb answers to an `M-SEARCH`.
+#[derive(Debug, Clone)]
+pub struct Advertisement {
+    pub location: SocketAddr,
+    pub id: String,
+    pub model: String,
+    pub fw_ver: String,
+    pub support: Vec<String>,
+    /// Initial state headers like `power`, `bright` and `rgb`
+    pub props: serde_json::Map<String, serde_json::Value>,
+}
+
+impl Advertisement {
+    pub fn parse(response: &str) -> anyhow::Result<Advertisement> {
+        let mut headers = HashMap::new();
+        for line in response.lines().skip(1) {
+            if let Some((key, value)) = line.split_once(':') {
+                headers.insert(key.trim().to_ascii_lowercase(), value.trim().to_string());
+            }
+        }
+
+        let location = headers
+            .get("location")
+            .and_then(|l| l.strip_prefix("yeelight://"))
+            .ok_or_else(|| anyhow::anyhow!("Missing yeelight:// location"))?
+            .parse()?;
+        let header = |key: &str| headers.get(key).cloned().unwrap_or_default();
+
+        let props = ["power", "bright", "rgb", "name"]
+            .iter()
+            .filter_map(|key| {
+                headers
+                    .get(*key)
+                    .map(|value| (key.to_string(), json!(value)))
+            })
+            .collect();
+
+        Ok(Advertisement {
+            location,
+            id: header("id"),
+            model: header("model"),
+            fw_ver: header("fw_ver"),
+            support: header("support")
+                .split_whitespace()
+                .map(str::to_string)
+                .collect(),
+            props,
+        })
+    }
+}
+
+/// Multicasts an `M-SEARCH` (and unicasts it to the configured addresses)
+/// and collects the bulbs answering within `scan_timeout`.
+pub async fn search(config: &YeelightConfig) -> anyhow::Result<Vec<Advertisement>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    SockRef::from(&socket).set_multicast_ttl_v4(2)?;
+
+    let msg = format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {}:{}\r\nMAN: \"ssdp:discover\"\r\nST: wifi_bulb\r\n",
+        MULTICAST_GROUP, SSDP_PORT
+    );
+
+    socket
+        .send_to(msg.as_bytes(), (MULTICAST_GROUP, SSDP_PORT))
+        .await?;
+    for address in &config.addresses {
+        let target = net::with_default_port(address, SSDP_PORT);
+        let result = socket.send_to(msg.as_bytes(), target.as_str()).await;
+        if let Err(e) = result {
+            eprintln!("Failed to query Yeelight at {}: {}", address, e);
+        }
+    }
+
+    let deadline = Instant::now() + Duration::from_millis(config.scan_timeout);
+    let mut found: Vec<Advertisement> = Vec::new();
+    let mut buf = [0; 2048];
+
+    while let Ok(res) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let size = match res {
+            Ok((size, _)) => size,
+            Err(e) => {
+                eprintln!("Failed to receive Yeelight datagram: {}", e);
+                continue;
+            }
+        };
+
+        match Advertisement::parse(&String::from_utf8_lossy(&buf[..size])) {
+            Ok(advertisement) => {
+                if !found.iter().any(|known| known.id == advertisement.id) {
+                    found.push(advertisement);
+                }
+            }
+            Err(e) => eprintln!("Ignoring unexpected Yeelight datagram: {}", e),
+        }
+    }
+
+    Ok(found)
+}
+
+// ANCHOR - Messages
+
+#[derive(Debug, Serialize)]
+struct Command<'a> {
+    id: u64,
+    method: &'a str,
+    params: serde_json::Value,
+}
+
+/// Either a reply carrying `id` or a `props` notification.
+#[derive(Debug, Deserialize)]
+struct Message {
+    id: Option<u64>,
+    method: Option<String>,
+    params: Option<serde_json::Value>,
+    result: Option<serde_json::Value>,
+    error: Option<CommandError>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CommandError {
+    code: i64,
+    message: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use tokio::{io::Lines, net::TcpListener};
+
+    fn search_response(location: SocketAddr) -> String {
+        format!(
+            "HTTP/1.1 200 OK\r\n\
+             Cache-Control: max-age=3600\r\n\
+             Location: yeelight://{}\r\n\
+             id: 0x000000000015243f\r\n\
+             model: color\r\n\
+             fw_ver: 18\r\n\
+             support: get_prop set_power set_bright set_rgb set_ct_abx\r\n\
+             power: off\r\n\
+             bright: 100\r\n\
+             rgb: 255\r\n\
+             name: \r\n",
+            location
+        )
+    }
+
+    // ANCHOR - Fake Bulb
+
+    /// The bulb side of a connection, driven step by step by the test.
+    struct FakeBulb {
+        lines: Lines<BufReader<OwnedReadHalf>>,
+        writer: OwnedWriteHalf,
+    }
+
+    impl FakeBulb {
+        async fn command(&mut self) -> Value {
+            let line = self.lines.next_line().await.unwrap().unwrap();
+            serde_json::from_str(&line).unwrap()
+        }
+
+        async fn send(&mut self, message: Value) {
+            let line = format!("{}\r\n", message);
+            self.writer.write_all(line.as_bytes()).await.unwrap();
+        }
+    }
+
+    async fn connect() -> (YeelightLight, FakeBulb) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let response = search_response(listener.local_addr().unwrap());
+        let advertisement = Advertisement::parse(&response).unwrap();
+        let config = YeelightConfig {
+            request_timeout: 100,
+            ..Default::default()
+        };
+
+        let (light, bulb) = tokio::join!(YeelightLight::new(advertisement, &config), async {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (read, writer) = stream.into_split();
+            let mut bulb = FakeBulb {
+                lines: BufReader::new(read).lines(),
+                writer,
+            };
+            let command = bulb.command().await;
+            assert_eq!(command["method"], "get_prop");
+            bulb.send(json!({ "id": command["id"], "result": ["on", "50", "16711680", "Desk"] }))
+                .await;
+            bulb
+        });
+        (light.unwrap(), bulb)
+    }
+
+    #[test]
+    fn parses_search_response() {
+        let location: SocketAddr = "192.168.1.239:55443".parse().unwrap();
+        let advertisement = Advertisement::parse(&search_response(location)).unwrap();
+
+        assert_eq!(advertisement.location, location);
+        assert_eq!(advertisement.id, "0x000000000015243f");
+        assert_eq!(advertisement.model, "color");
+        assert_eq!(advertisement.fw_ver, "18");
+        assert_eq!(advertisement.support.len(), 5);
+        assert_eq!(advertisement.props["power"], "off");
+        assert_eq!(advertisement.props["rgb"], "255");
+
+        assert!(Advertisement::parse("HTTP/1.1 200 OK\r\nid: 1\r\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn reads_state_on_connect() {
+        let (light, _bulb) = connect().await;
+
+        // get_prop overrides the advertised state
+        assert_eq!(light.id(), "yeelight::0x000000000015243f");
+        assert_eq!(light.name(), "Desk");
+        assert!(light.is_on());
+        assert_eq!(light.brightness(), 50);
+        assert_eq!((light.red(), light.green(), light.blue()), (255, 0, 0));
+        assert!(light.supports_color());
+    }
+
+    #[tokio::test]
+    async fn matches_replies_by_id() {
+        let (mut light, mut bulb) = connect().await;
+
+        // The bulb sits on set_rgb until it times out
+        let (result, stale) = tokio::join!(light.set_color(0, 255, 0), async {
+            let command = bulb.command().await;
+            assert_eq!(command["method"], "set_rgb");
+            assert_eq!(command["params"], json!([65280, "smooth", 300]));
+            command["id"].clone()
+        });
+        assert!(result.is_err());
+        assert_eq!((light.red(), light.green(), light.blue()), (255, 0, 0));
+
+        // Its late answer must not be taken for the answer to set_bright
+        let (result, _) = tokio::join!(light.set_brightness(0), async {
+            let command = bulb.command().await;
+            assert_eq!(command["method"], "set_bright");
+            assert_eq!(command["params"], json!([1, "smooth", 300]));
+            assert_ne!(command["id"], stale);
+            bulb.send(json!({ "id": stale, "error": { "code": -1, "message": "late" } }))
+                .await;
+            bulb.send(json!({ "id": command["id"], "result": ["ok"] }))
+                .await;
+        });
+        result.unwrap();
+        assert_eq!(light.brightness(), 1);
+
+        let (result, _) = tokio::join!(light.set_on(false), async {
+            let command = bulb.command().await;
+            bulb.send(json!({
+                "id": command["id"],
+                "error": { "code": -5000, "message": "general error" }
+            }))
+            .await;
+        });
+        assert!(result.unwrap_err().to_string().contains("general error"));
+        assert!(light.is_on());
+    }
+
+    #[tokio::test]
+    async fn applies_props_notifications() {
+        let (light, mut bulb) = connect().await;
+
+        // Changes made from the Yeelight app arrive unprompted
+        bulb.send(json!({
+            "method": "props",
+            "params": { "power": "off", "bright": "20", "rgb": "65280", "name": "Lamp" }
+        }))
+        .await;
+
+        for _ in 0..50 {
+            if !light.is_on() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(!light.is_on());
+        assert_eq!(light.name(), "Lamp");
+        assert_eq!(light.brightness(), 20);
+        assert_eq!((light.red(), light.green(), light.blue()), (0, 255, 0));
+    }
+
+    #[tokio::test]
+    async fn fails_waiting_requests_when_the_bulb_disconnects() {
+        let (mut light, mut bulb) = connect().await;
+
+        let (result, _) = tokio::join!(light.set_on(false), async {
+            bulb.command().await;
+            drop(bulb);
+        });
+
+        let error = result.unwrap_err().to_string();
+        assert!(error.contains("closed"), "{}", error);
+    }
+}
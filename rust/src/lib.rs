@@ -3,8 +3,12 @@ mod discover;
 mod integrations;
 mod utils;
 
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
+
 pub type CuteResult<T> = std::result::Result<T, Box<dyn std::error::Error>>; // :3
 
-pub use discover::discover_lights;
-pub use integrations::Light;
+pub use config::CuteLightsConfig;
+pub use discover::{discover_lights, Discovery, DiscoveryEvent, DiscoveryStream, LightRegistry};
+pub use integrations::{DeviceInfo, Integration, Light, LightSink};
 pub use utils::future::FutureBatch;
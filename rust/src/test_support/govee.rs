@@ -0,0 +1,213 @@
+use serde_json::{json, Value};
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+};
+use tokio::{net::UdpSocket, task::JoinHandle};
+
+use super::Fault;
+
+/// The ports a Govee setup uses. Real devices take scans on 4001 and
+/// commands on 4003 and answer on 4002, tests pick unused ones instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GoveePorts {
+    pub scan: u16,
+    pub device: u16,
+    pub listen: u16,
+}
+
+impl GoveePorts {
+    /// Three ports that were free on loopback a moment ago.
+    pub fn unused() -> std::io::Result<Self> {
+        let sockets = [
+            std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?,
+            std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?,
+            std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?,
+        ];
+        Ok(Self {
+            scan: sockets[0].local_addr()?.port(),
+            device: sockets[1].local_addr()?.port(),
+            listen: sockets[2].local_addr()?.port(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GoveeDeviceState {
+    pub device: String,
+    pub sku: String,
+    pub on: bool,
+    pub brightness: u8,
+    pub color: (u8, u8, u8),
+    pub color_temperature: u32,
+}
+
+impl GoveeDeviceState {
+    /// A light strip that is on, white at full brightness.
+    pub fn new(device: &str, sku: &str) -> Self {
+        Self {
+            device: device.to_string(),
+            sku: sku.to_string(),
+            on: true,
+            brightness: 100,
+            color: (255, 255, 255),
+            color_temperature: 0,
+        }
+    }
+}
+
+struct Shared {
+    state: GoveeDeviceState,
+    requests: Vec<Value>,
+    fault: Fault,
+}
+
+/// A device answering the LAN API over UDP from its own address.
+pub struct FakeGoveeDevice {
+    shared: Arc<Mutex<Shared>>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl FakeGoveeDevice {
+    /// Binds the scan and command ports on `ip`, which the client must have
+    /// in its addresses. Loopback addresses other than 127.0.0.1 let several
+    /// devices run side by side on Linux.
+    pub async fn start(
+        ip: IpAddr,
+        ports: &GoveePorts,
+        state: GoveeDeviceState,
+    ) -> std::io::Result<Self> {
+        let scan = Arc::new(UdpSocket::bind((ip, ports.scan)).await?);
+        let device = Arc::new(UdpSocket::bind((ip, ports.device)).await?);
+        let shared = Arc::new(Mutex::new(Shared {
+            state,
+            requests: Vec::new(),
+            fault: Fault::None,
+        }));
+
+        let tasks = vec![
+            tokio::spawn(serve(
+                scan,
+                device.clone(),
+                ip,
+                ports.listen,
+                shared.clone(),
+            )),
+            tokio::spawn(serve(
+                device.clone(),
+                device,
+                ip,
+                ports.listen,
+                shared.clone(),
+            )),
+        ];
+        Ok(Self { shared, tasks })
+    }
+
+    pub fn state(&self) -> GoveeDeviceState {
+        lock(&self.shared).state.clone()
+    }
+
+    /// The `msg` of every datagram received so far, in order.
+    pub fn requests(&self) -> Vec<Value> {
+        lock(&self.shared).requests.clone()
+    }
+
+    pub fn set_fault(&self, fault: Fault) {
+        lock(&self.shared).fault = fault;
+    }
+}
+
+impl Drop for FakeGoveeDevice {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+fn lock(shared: &Mutex<Shared>) -> std::sync::MutexGuard<'_, Shared> {
+    shared.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Reads requests from `socket` and answers them from `reply_from`, so
+/// replies come from the device's address like they do on a real network.
+async fn serve(
+    socket: Arc<UdpSocket>,
+    reply_from: Arc<UdpSocket>,
+    ip: IpAddr,
+    listen_port: u16,
+    shared: Arc<Mutex<Shared>>,
+) {
+    let mut buf = [0; 4096];
+    loop {
+        let Ok((size, from)) = socket.recv_from(&mut buf).await else {
+            return;
+        };
+        let Ok(message) = serde_json::from_slice::<Value>(&buf[..size]) else {
+            continue;
+        };
+        let request = message["msg"].clone();
+
+        let (reply, fault) = {
+            let mut shared = lock(&shared);
+            shared.requests.push(request.clone());
+            let fault = shared.fault;
+            (handle(&mut shared.state, &request, ip), fault)
+        };
+        let client = SocketAddr::new(from.ip(), listen_port);
+        let datagram = match (fault, reply) {
+            (Fault::None, Some(reply)) => json!({ "msg": reply }).to_string(),
+            (Fault::Garbage, Some(_)) => "\u{1}not json".to_string(),
+            _ => continue,
+        };
+        let _ = reply_from.send_to(datagram.as_bytes(), client).await;
+    }
+}
+
+fn handle(state: &mut GoveeDeviceState, request: &Value, ip: IpAddr) -> Option<Value> {
+    let data = &request["data"];
+    match request["cmd"].as_str()? {
+        "scan" => Some(json!({
+            "cmd": "scan",
+            "data": {
+                "ip": ip,
+                "device": state.device,
+                "sku": state.sku,
+                "bleVersionHard": "3.01.01",
+                "bleVersionSoft": "1.03.01",
+                "wifiVersionHard": "1.00.10",
+                "wifiVersionSoft": "1.02.03"
+            }
+        })),
+        "devStatus" => Some(json!({
+            "cmd": "devStatus",
+            "data": {
+                "onOff": state.on as u8,
+                "brightness": state.brightness,
+                "color": { "r": state.color.0, "g": state.color.1, "b": state.color.2 },
+                "colorTemInKelvin": state.color_temperature
+            }
+        })),
+        "turn" => {
+            state.on = data["value"].as_u64()? == 1;
+            None
+        }
+        "brightness" => {
+            state.brightness = data["value"].as_u64()?.min(100) as u8;
+            None
+        }
+        "colorwc" => {
+            let color = &data["color"];
+            state.color = (
+                color["r"].as_u64()? as u8,
+                color["g"].as_u64()? as u8,
+                color["b"].as_u64()? as u8,
+            );
+            state.color_temperature = data["colorTemInKelvin"].as_u64().unwrap_or(0) as u32;
+            None
+        }
+        // Raw BLE packets for segments and streaming are only logged
+        _ => None,
+    }
+}
@@ -0,0 +1,265 @@
+use serde_json::{json, Map, Value};
+use std::{
+    collections::BTreeMap,
+    net::{Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    io::AsyncReadExt,
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+};
+
+use super::{
+    http::{read_request, write_response},
+    Fault,
+};
+
+/// A request the bridge received, `body` being its JSON if it had any.
+pub use super::http::HttpRequest as HueRequest;
+
+struct Shared {
+    lights: BTreeMap<String, Value>,
+    requests: Vec<HueRequest>,
+    fault: Fault,
+}
+
+/// A bridge serving the v1 `lights` API over HTTP to one user.
+pub struct FakeHueBridge {
+    address: SocketAddr,
+    shared: Arc<Mutex<Shared>>,
+    server: JoinHandle<()>,
+}
+
+impl FakeHueBridge {
+    pub async fn start(username: &str) -> std::io::Result<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let address = listener.local_addr()?;
+        let shared = Arc::new(Mutex::new(Shared {
+            lights: BTreeMap::new(),
+            requests: Vec::new(),
+            fault: Fault::None,
+        }));
+
+        let server = tokio::spawn(accept(listener, username.to_string(), shared.clone()));
+        Ok(Self {
+            address,
+            shared,
+            server,
+        })
+    }
+
+    /// `host:port` as the Hue config takes it for `bridge_ip`.
+    pub fn address(&self) -> String {
+        self.address.to_string()
+    }
+
+    /// Adds or replaces a light, see [`color_light`] and [`dimmable_light`].
+    pub fn add_light(&self, id: &str, light: Value) {
+        lock(&self.shared).lights.insert(id.to_string(), light);
+    }
+
+    pub fn remove_light(&self, id: &str) {
+        lock(&self.shared).lights.remove(id);
+    }
+
+    pub fn light(&self, id: &str) -> Option<Value> {
+        lock(&self.shared).lights.get(id).cloned()
+    }
+
+    /// Every request received so far, in order.
+    pub fn requests(&self) -> Vec<HueRequest> {
+        lock(&self.shared).requests.clone()
+    }
+
+    pub fn set_fault(&self, fault: Fault) {
+        lock(&self.shared).fault = fault;
+    }
+}
+
+impl Drop for FakeHueBridge {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+/// An extended color bulb as the bridge lists it.
+pub fn color_light(name: &str) -> Value {
+    json!({
+        "state": {
+            "on": true,
+            "bri": 254,
+            "hue": 8418,
+            "sat": 140,
+            "xy": [0.4573, 0.41],
+            "ct": 366,
+            "alert": "none",
+            "colormode": "ct",
+            "mode": "homeautomation",
+            "reachable": true
+        },
+        "type": "Extended color light",
+        "name": name,
+        "modelid": "LCA001",
+        "manufacturername": "Signify Netherlands B.V.",
+        "productname": "Hue color lamp",
+        "capabilities": {
+            "control": {
+                "colorgamuttype": "C",
+                "colorgamut": [[0.6915, 0.3083], [0.17, 0.7], [0.1532, 0.0475]],
+                "ct": { "min": 153, "max": 500 }
+            }
+        },
+        "uniqueid": "00:17:88:01:08:12:34:56-0b",
+        "swversion": "1.104.2"
+    })
+}
+
+/// A white bulb that only dims.
+pub fn dimmable_light(name: &str) -> Value {
+    json!({
+        "state": {
+            "on": false,
+            "bri": 127,
+            "alert": "none",
+            "mode": "homeautomation",
+            "reachable": true
+        },
+        "type": "Dimmable light",
+        "name": name,
+        "modelid": "LWB010",
+        "manufacturername": "Signify Netherlands B.V.",
+        "productname": "Hue white lamp",
+        "capabilities": { "control": {} },
+        "uniqueid": "00:17:88:01:02:65:43:21-0b",
+        "swversion": "1.90.1"
+    })
+}
+
+fn lock(shared: &Mutex<Shared>) -> std::sync::MutexGuard<'_, Shared> {
+    shared.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+async fn accept(listener: TcpListener, username: String, shared: Arc<Mutex<Shared>>) {
+    loop {
+        let Ok((stream, _)) = listener.accept().await else {
+            return;
+        };
+        tokio::spawn(serve(stream, username.clone(), shared.clone()));
+    }
+}
+
+/// Answers one request and closes the connection.
+async fn serve(mut stream: TcpStream, username: String, shared: Arc<Mutex<Shared>>) {
+    let Some(request) = read_request(&mut stream).await else {
+        return;
+    };
+
+    let (reply, fault) = {
+        let mut shared = lock(&shared);
+        shared.requests.push(request.clone());
+        let fault = shared.fault;
+        (handle(&mut shared.lights, &username, &request), fault)
+    };
+    let body = match fault {
+        Fault::None => reply.to_string(),
+        Fault::Silent => {
+            // Hold the connection open until the client gives up
+            let _ = stream.read(&mut [0; 1]).await;
+            return;
+        }
+        Fault::Disconnect => return,
+        Fault::Garbage => "<html>Bad gateway</html>".to_string(),
+    };
+
+    write_response(&mut stream, 200, &body).await;
+}
+
+fn error(kind: u32, address: &str, description: &str) -> Value {
+    json!([{ "error": { "type": kind, "address": address, "description": description } }])
+}
+
+fn handle(lights: &mut BTreeMap<String, Value>, username: &str, request: &HueRequest) -> Value {
+    let parts: Vec<&str> = request
+        .path
+        .trim_matches('/')
+        .split('/')
+        .filter(|part| !part.is_empty())
+        .collect();
+    let (user, resource) = match parts.as_slice() {
+        ["api", user, resource @ ..] => (*user, resource),
+        _ => return error(4, &request.path, "method not available"),
+    };
+    let address = format!("/{}", resource.join("/"));
+    if user != username {
+        return error(1, &address, "unauthorized user");
+    }
+
+    match (request.method.as_str(), resource) {
+        ("GET", ["lights"]) => Value::Object(
+            lights
+                .iter()
+                .map(|(id, light)| (id.clone(), light.clone()))
+                .collect(),
+        ),
+        ("GET", ["lights", id]) => match lights.get(*id) {
+            Some(light) => light.clone(),
+            None => error(3, &address, &format!("resource, /lights/{}, not available", id)),
+        },
+        ("PUT", ["lights", id, "state"]) => match lights.get_mut(*id) {
+            Some(light) => set_state(&mut light["state"], id, request.body.as_ref()),
+            None => error(3, &address, &format!("resource, /lights/{}, not available", id)),
+        },
+        _ => error(
+            4,
+            &address,
+            &format!(
+                "method, {}, not available for resource, {}",
+                request.method, address
+            ),
+        ),
+    }
+}
+
+/// Applies a state change the way the bridge does, key by key, refusing
+/// changes to lights that stay off.
+fn set_state(state: &mut Value, id: &str, body: Option<&Value>) -> Value {
+    let address = format!("/lights/{}/state", id);
+    let Some(changes) = body.and_then(Value::as_object) else {
+        return error(2, &address, "body contains invalid json");
+    };
+
+    let turns_on = changes.get("on") == Some(&json!(true));
+    let is_on = state["on"] == json!(true);
+    let mut results = Vec::new();
+    for (key, value) in changes {
+        let key_address = format!("{}/{}", address, key);
+        if key == "transitiontime" {
+            continue;
+        }
+        if state.get(key).is_none() {
+            results.push(error(6, &key_address, &format!("parameter, {}, not available", key))[0].clone());
+            continue;
+        }
+        if key != "on" && !is_on && !turns_on {
+            results.push(
+                error(
+                    201,
+                    &key_address,
+                    &format!("parameter, {}, is not modifiable. Device is set to off.", key),
+                )[0]
+                .clone(),
+            );
+            continue;
+        }
+
+        state[key.as_str()] = value.clone();
+        if key == "hue" || key == "sat" {
+            state["colormode"] = json!("hs");
+        }
+        let mut success = Map::new();
+        success.insert(key_address, value.clone());
+        results.push(json!({ "success": success }));
+    }
+    Value::Array(results)
+}
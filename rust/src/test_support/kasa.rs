@@ -0,0 +1,240 @@
+use serde_json::{json, Value};
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+};
+
+use super::Fault;
+
+const LIGHTING_SERVICE: &str = "smartlife.iot.smartbulb.lightingservice";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct KasaBulbState {
+    pub alias: String,
+    pub mac: String,
+    pub is_color: bool,
+    pub on: bool,
+    /// Degrees
+    pub hue: i64,
+    /// Percent
+    pub saturation: i64,
+    /// Percent
+    pub brightness: i64,
+}
+
+impl KasaBulbState {
+    /// A color bulb that is on, white at full brightness.
+    pub fn new(alias: &str, mac: &str) -> Self {
+        Self {
+            alias: alias.to_string(),
+            mac: mac.to_string(),
+            is_color: true,
+            on: true,
+            hue: 0,
+            saturation: 0,
+            brightness: 100,
+        }
+    }
+}
+
+struct Shared {
+    state: KasaBulbState,
+    requests: Vec<Value>,
+    fault: Fault,
+}
+
+/// A smart bulb answering the XOR obfuscated JSON protocol on TCP.
+pub struct FakeKasaBulb {
+    address: SocketAddr,
+    shared: Arc<Mutex<Shared>>,
+    server: JoinHandle<()>,
+}
+
+impl FakeKasaBulb {
+    pub async fn start(state: KasaBulbState) -> std::io::Result<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let address = listener.local_addr()?;
+        let shared = Arc::new(Mutex::new(Shared {
+            state,
+            requests: Vec::new(),
+            fault: Fault::None,
+        }));
+
+        let server = tokio::spawn(accept(listener, shared.clone()));
+        Ok(Self {
+            address,
+            shared,
+            server,
+        })
+    }
+
+    /// `host:port` as the Kasa config takes it.
+    pub fn address(&self) -> String {
+        self.address.to_string()
+    }
+
+    pub fn state(&self) -> KasaBulbState {
+        lock(&self.shared).state.clone()
+    }
+
+    /// Every request decoded so far, in order.
+    pub fn requests(&self) -> Vec<Value> {
+        lock(&self.shared).requests.clone()
+    }
+
+    pub fn set_fault(&self, fault: Fault) {
+        lock(&self.shared).fault = fault;
+    }
+}
+
+impl Drop for FakeKasaBulb {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+fn lock(shared: &Mutex<Shared>) -> std::sync::MutexGuard<'_, Shared> {
+    shared.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+async fn accept(listener: TcpListener, shared: Arc<Mutex<Shared>>) {
+    loop {
+        let Ok((stream, _)) = listener.accept().await else {
+            return;
+        };
+        tokio::spawn(serve(stream, shared.clone()));
+    }
+}
+
+/// Answers requests on one connection until the client closes it.
+async fn serve(mut stream: TcpStream, shared: Arc<Mutex<Shared>>) {
+    loop {
+        let Ok(len) = stream.read_u32().await else {
+            return;
+        };
+        let mut payload = vec![0; len as usize];
+        if stream.read_exact(&mut payload).await.is_err() {
+            return;
+        }
+        let Ok(request) = serde_json::from_slice::<Value>(&decrypt(&payload)) else {
+            return;
+        };
+
+        let (reply, fault) = {
+            let mut shared = lock(&shared);
+            shared.requests.push(request.clone());
+            let fault = shared.fault;
+            (handle(&mut shared.state, &request, fault), fault)
+        };
+        let frame = match fault {
+            Fault::None => encrypt(&reply.to_string()),
+            Fault::Silent => continue,
+            Fault::Disconnect => return,
+            Fault::Garbage => {
+                let mut frame = 16u32.to_be_bytes().to_vec();
+                frame.extend_from_slice(&[0xff; 16]);
+                frame
+            }
+        };
+        if stream.write_all(&frame).await.is_err() || fault == Fault::Garbage {
+            return;
+        }
+    }
+}
+
+fn handle(state: &mut KasaBulbState, request: &Value, fault: Fault) -> Value {
+    if !request["system"]["get_sysinfo"].is_null() {
+        return json!({ "system": { "get_sysinfo": sysinfo(state) } });
+    }
+
+    let change = &request[LIGHTING_SERVICE]["transition_light_state"];
+    if !change.is_null() {
+        // A faulty bulb does not apply anything
+        if fault == Fault::None {
+            if let Some(on) = change["on_off"].as_i64() {
+                state.on = on == 1;
+            }
+            if let Some(hue) = change["hue"].as_i64() {
+                state.hue = hue;
+            }
+            if let Some(saturation) = change["saturation"].as_i64() {
+                state.saturation = saturation;
+            }
+            if let Some(brightness) = change["brightness"].as_i64() {
+                state.brightness = brightness;
+            }
+        }
+        let mut light_state = light_state(state);
+        light_state["err_code"] = json!(0);
+        return json!({ LIGHTING_SERVICE: { "transition_light_state": light_state } });
+    }
+
+    let module = request
+        .as_object()
+        .and_then(|modules| modules.keys().next().cloned())
+        .unwrap_or_default();
+    json!({ module: { "err_code": -1, "err_msg": "module not support" } })
+}
+
+fn light_state(state: &KasaBulbState) -> Value {
+    let settings = json!({
+        "mode": "normal",
+        "hue": state.hue,
+        "saturation": state.saturation,
+        "color_temp": 0,
+        "brightness": state.brightness
+    });
+    // Bulbs that are off only report what they come back on with
+    if state.on {
+        let mut light_state = settings;
+        light_state["on_off"] = json!(1);
+        light_state
+    } else {
+        json!({ "on_off": 0, "dft_on_state": settings })
+    }
+}
+
+fn sysinfo(state: &KasaBulbState) -> Value {
+    json!({
+        "sw_ver": "1.8.11 Build 191113 Rel.105336",
+        "hw_ver": "2.0",
+        "model": "KL130(US)",
+        "description": "Smart Wi-Fi LED Bulb with Color Changing",
+        "alias": state.alias,
+        "mic_type": "IOT.SMARTBULB",
+        "mic_mac": state.mac,
+        "is_dimmable": 1,
+        "is_color": state.is_color as u8,
+        "is_variable_color_temp": 1,
+        "light_state": light_state(state),
+        "err_code": 0
+    })
+}
+
+/// Length prefixed, each byte XORed with the previous ciphertext byte.
+fn encrypt(plain: &str) -> Vec<u8> {
+    let mut key = 171;
+    let mut frame = (plain.len() as u32).to_be_bytes().to_vec();
+    for byte in plain.bytes() {
+        key ^= byte;
+        frame.push(key);
+    }
+    frame
+}
+
+fn decrypt(payload: &[u8]) -> Vec<u8> {
+    let mut key = 171;
+    payload
+        .iter()
+        .map(|&byte| {
+            let plain = key ^ byte;
+            key = byte;
+            plain
+        })
+        .collect()
+}
@@ -0,0 +1,28 @@
+//! Fake devices that speak the real protocols on loopback, for testing
+//! discovery and control without hardware. Enable the `test-support`
+//! feature to use them outside this crate.
+//!
+//! Every fake runs on the tokio runtime it was started on, keeps a log of
+//! the requests it decoded and stops when dropped.
+
+pub mod govee;
+pub mod http;
+pub mod hue;
+pub mod kasa;
+
+pub use crate::integrations::virtual_lights::{
+    clear_recorded_commands, recorded_commands, RecordedCommand, VirtualCommand,
+};
+
+/// How a fake misbehaves when it gets a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Fault {
+    #[default]
+    None,
+    /// Takes requests without ever answering
+    Silent,
+    /// Drops the connection instead of answering, silent over UDP
+    Disconnect,
+    /// Answers with bytes that are not the protocol
+    Garbage,
+}
@@ -1,13 +1,199 @@
-use colors_transform::{Color, Hsl, Rgb};
+/// Hue in degrees with saturation and brightness in percent, the HSV model
+/// most lights take.
+pub fn rgb_to_hsb(r: u8, g: u8, b: u8) -> (u16, u8, u8) {
+    let max = r.max(g).max(b) as f32;
+    let min = r.min(g).min(b) as f32;
+    let delta = max - min;
+    let (r, g, b) = (r as f32, g as f32, b as f32);
 
-pub fn rgb_to_hsv(r: u8, g: u8, b:u8) -> (i64, i64, i64) {
-    let rgb = Rgb::from(r as f32, g as f32, b as f32);
-    let hsv = rgb.to_hsl();
-    (hsv.get_hue() as i64, hsv.get_saturation() as i64, hsv.get_lightness() as i64)
+    let hue = if delta == 0.0 {
+        0.0
+    } else if max == r {
+        60.0 * ((g - b) / delta).rem_euclid(6.0)
+    } else if max == g {
+        60.0 * ((b - r) / delta + 2.0)
+    } else {
+        60.0 * ((r - g) / delta + 4.0)
+    };
+    let saturation = if max == 0.0 { 0.0 } else { delta / max * 100.0 };
+    let brightness = max / 255.0 * 100.0;
+
+    (
+        hue.round() as u16 % 360,
+        saturation.round() as u8,
+        brightness.round() as u8,
+    )
+}
+
+pub fn hsb_to_rgb(hue: u16, saturation: u8, brightness: u8) -> (u8, u8, u8) {
+    let s = saturation.min(100) as f32 / 100.0;
+    let v = brightness.min(100) as f32 / 100.0;
+    let h = (hue % 360) as f32 / 60.0;
+
+    let c = v * s;
+    let x = c * (1.0 - (h % 2.0 - 1.0).abs());
+    let (r, g, b) = match h as u8 {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+    let m = v - c;
+    let channel = |value: f32| ((value + m) * 255.0).round() as u8;
+    (channel(r), channel(g), channel(b))
+}
+
+/// CIE 1931 xy chromaticity of an sRGB color, what Zigbee lights take.
+pub fn rgb_to_xy(r: u8, g: u8, b: u8) -> (f64, f64) {
+    let linear = |value: u8| {
+        let value = value as f64 / 255.0;
+        if value > 0.04045 {
+            ((value + 0.055) / 1.055).powf(2.4)
+        } else {
+            value / 12.92
+        }
+    };
+    let (r, g, b) = (linear(r), linear(g), linear(b));
+
+    let x = r * 0.4124 + g * 0.3576 + b * 0.1805;
+    let y = r * 0.2126 + g * 0.7152 + b * 0.0722;
+    let z = r * 0.0193 + g * 0.1192 + b * 0.9505;
+    let sum = x + y + z;
+    if sum == 0.0 {
+        // Black has no chromaticity, use the D65 white point
+        return (0.3127, 0.3290);
+    }
+    (x / sum, y / sum)
+}
+
+/// The brightest sRGB color with the given xy chromaticity.
+pub fn xy_to_rgb(x: f64, y: f64) -> (u8, u8, u8) {
+    if y <= 0.0 {
+        return (0, 0, 0);
+    }
+    let (cx, cy, cz) = (x / y, 1.0, (1.0 - x - y) / y);
+
+    let r = (cx * 3.2406 - cy * 1.5372 - cz * 0.4986).max(0.0);
+    let g = (-cx * 0.9689 + cy * 1.8758 + cz * 0.0415).max(0.0);
+    let b = (cx * 0.0557 - cy * 0.2040 + cz * 1.0570).max(0.0);
+    let max = r.max(g).max(b);
+    if max == 0.0 {
+        return (0, 0, 0);
+    }
+
+    let gamma = |value: f64| {
+        let value = value / max;
+        let value = if value <= 0.0031308 {
+            value * 12.92
+        } else {
+            1.055 * value.powf(1.0 / 2.4) - 0.055
+        };
+        (value * 255.0).round().clamp(0.0, 255.0) as u8
+    };
+    (gamma(r), gamma(g), gamma(b))
 }
 
-pub fn hsv_to_rgb(h: i64, s: i64, v: i64) -> (u8, u8, u8) {
-    let hsv = Hsl::from(h as f32, s as f32, v as f32);
-    let rgb = hsv.to_rgb();
-    (rgb.get_red() as u8, rgb.get_green() as u8, rgb.get_blue() as u8)
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_known_colors_to_hsb() {
+        assert_eq!(rgb_to_hsb(255, 0, 0), (0, 100, 100));
+        assert_eq!(rgb_to_hsb(0, 255, 0), (120, 100, 100));
+        assert_eq!(rgb_to_hsb(0, 0, 255), (240, 100, 100));
+        assert_eq!(rgb_to_hsb(255, 255, 0), (60, 100, 100));
+        assert_eq!(rgb_to_hsb(255, 0, 255), (300, 100, 100));
+        assert_eq!(rgb_to_hsb(255, 255, 255), (0, 0, 100));
+        assert_eq!(rgb_to_hsb(128, 128, 128), (0, 0, 50));
+        assert_eq!(rgb_to_hsb(0, 0, 0), (0, 0, 0));
+        // Dark colors keep their full saturation
+        assert_eq!(rgb_to_hsb(128, 0, 0), (0, 100, 50));
+    }
+
+    #[test]
+    fn converts_known_colors_from_hsb() {
+        assert_eq!(hsb_to_rgb(0, 100, 100), (255, 0, 0));
+        assert_eq!(hsb_to_rgb(120, 100, 100), (0, 255, 0));
+        assert_eq!(hsb_to_rgb(240, 100, 100), (0, 0, 255));
+        assert_eq!(hsb_to_rgb(360, 100, 100), (255, 0, 0));
+        assert_eq!(hsb_to_rgb(200, 0, 100), (255, 255, 255));
+        assert_eq!(hsb_to_rgb(0, 0, 50), (128, 128, 128));
+        assert_eq!(hsb_to_rgb(90, 100, 0), (0, 0, 0));
+        // Out of range saturation and brightness are capped
+        assert_eq!(hsb_to_rgb(0, 255, 255), (255, 0, 0));
+    }
+
+    #[test]
+    fn round_trips_hsb() {
+        for rgb in [
+            (255, 0, 0),
+            (255, 128, 0),
+            (0, 255, 255),
+            (255, 255, 255),
+            (128, 128, 128),
+            (0, 0, 0),
+        ] {
+            let (h, s, b) = rgb_to_hsb(rgb.0, rgb.1, rgb.2);
+            assert_eq!(hsb_to_rgb(h, s, b), rgb, "{:?}", (h, s, b));
+        }
+
+        // Whole percents lose a little precision, never more than a step
+        for r in (0..=255).step_by(15) {
+            for g in (0..=255).step_by(15) {
+                for b in (0..=255).step_by(15) {
+                    let (h, s, v) = rgb_to_hsb(r, g, b);
+                    let back = hsb_to_rgb(h, s, v);
+                    for (before, after) in [(r, back.0), (g, back.1), (b, back.2)] {
+                        assert!(before.abs_diff(after) <= 3, "{:?} {:?}", (r, g, b), back);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn converts_primaries_to_xy() {
+        let close = |(x, y): (f64, f64), expected: (f64, f64)| {
+            (x - expected.0).abs() < 0.001 && (y - expected.1).abs() < 0.001
+        };
+        // The sRGB primaries and white point
+        assert!(close(rgb_to_xy(255, 0, 0), (0.64, 0.33)));
+        assert!(close(rgb_to_xy(0, 255, 0), (0.30, 0.60)));
+        assert!(close(rgb_to_xy(0, 0, 255), (0.15, 0.06)));
+        assert!(close(rgb_to_xy(255, 255, 255), (0.3127, 0.3290)));
+    }
+
+    #[test]
+    fn round_trips_xy() {
+        // Only chromaticity survives, so start from colors at full brightness
+        for rgb in [
+            (255, 0, 0),
+            (0, 255, 0),
+            (0, 0, 255),
+            (255, 255, 255),
+            (255, 128, 0),
+            (0, 128, 255),
+            (255, 0, 255),
+        ] {
+            let (x, y) = rgb_to_xy(rgb.0, rgb.1, rgb.2);
+            let back = xy_to_rgb(x, y);
+            for (before, after) in [(rgb.0, back.0), (rgb.1, back.1), (rgb.2, back.2)] {
+                assert!(before.abs_diff(after) <= 2, "{:?} {:?}", rgb, back);
+            }
+        }
+    }
+
+    #[test]
+    fn handles_black_and_zero_y() {
+        let (x, y) = rgb_to_xy(0, 0, 0);
+        assert!(x.is_finite() && y.is_finite());
+        assert_eq!((x, y), (0.3127, 0.3290));
+
+        assert_eq!(xy_to_rgb(0.3, 0.0), (0, 0, 0));
+        assert_eq!(xy_to_rgb(0.0, 0.0), (0, 0, 0));
+        assert_eq!(xy_to_rgb(0.3, -0.1), (0, 0, 0));
+    }
+}
@@ -31,6 +31,20 @@ where
         self.futures.spawn(future);
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.futures.is_empty()
+    }
+
+    /// The next future to finish, `None` once all of them have. Futures
+    /// that panicked are skipped.
+    pub async fn next(&mut self) -> Option<T> {
+        loop {
+            if let Ok(res) = self.futures.join_next().await? {
+                return Some(res);
+            }
+        }
+    }
+
     pub async fn run(mut self) -> Vec<T>
     where
         T: std::marker::Send,
@@ -45,3 +59,13 @@ where
         results
     }
 }
+
+impl<T> Default for FutureBatch<T>
+where
+    T: Send,
+    T: 'static,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
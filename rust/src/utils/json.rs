@@ -41,6 +41,6 @@ pub fn float(json: &serde_json::Value) -> std::result::Result<f64, JsonError> {
     json.as_f64().ok_or(JsonError {})
 }
 
-pub fn is_valid(js: &String) -> bool {
-    serde_json::from_str::<serde_json::Value>(&js).is_ok()
+pub fn is_valid(js: &str) -> bool {
+    serde_json::from_str::<serde_json::Value>(js).is_ok()
 }
\ No newline at end of file
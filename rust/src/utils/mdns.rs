@@ -0,0 +1,146 @@
+use std::{net::IpAddr, time::Duration};
+
+use mdns_sd::{ServiceDaemon, ServiceEvent};
+use tokio::time::Instant;
+
+use super::net;
+
+/// A service instance resolved over mDNS.
+#[derive(Debug, Clone)]
+pub struct MdnsService {
+    pub name: String,
+    pub addresses: Vec<IpAddr>,
+    pub port: u16,
+}
+
+impl MdnsService {
+    /// The first IPv4 address, falling back to whatever was announced.
+    pub fn address(&self) -> Option<IpAddr> {
+        self.addresses
+            .iter()
+            .find(|addr| addr.is_ipv4())
+            .or(self.addresses.first())
+            .copied()
+    }
+}
+
+/// Browses `service_type` (like `_wled._tcp.local.`) for `timeout` and
+/// returns every instance that resolved in that time.
+pub async fn browse(service_type: &str, timeout: Duration) -> anyhow::Result<Vec<MdnsService>> {
+    let daemon = ServiceDaemon::new()?;
+    let receiver = daemon.browse(service_type)?;
+
+    let deadline = Instant::now() + timeout;
+    let mut found: Vec<MdnsService> = Vec::new();
+
+    while let Ok(Ok(event)) = tokio::time::timeout_at(deadline, receiver.recv_async()).await {
+        let ServiceEvent::ServiceResolved(info) = event else {
+            continue;
+        };
+
+        let name = info
+            .get_fullname()
+            .trim_end_matches(service_type)
+            .trim_end_matches('.')
+            .to_string();
+        if found.iter().any(|service| service.name == name) {
+            continue;
+        }
+
+        found.push(MdnsService {
+            name,
+            addresses: info.get_addresses().iter().copied().collect(),
+            port: info.get_port(),
+        });
+    }
+
+    let _ = daemon.shutdown();
+    Ok(found)
+}
+
+/// Resolves `addresses` given as `host` or `host:port` and, when
+/// `service_type` is set, adds whatever answers on mDNS within `timeout`.
+/// Failures are logged and skipped so one bad entry does not hide the rest.
+pub async fn resolve_hosts(
+    addresses: &[String],
+    default_port: u16,
+    service_type: Option<&str>,
+    timeout: Duration,
+) -> Vec<(IpAddr, u16)> {
+    let mut found: Vec<(IpAddr, u16)> = Vec::new();
+
+    for address in addresses {
+        let target = net::with_default_port(address, default_port);
+        match tokio::net::lookup_host(&target).await {
+            Ok(mut addrs) => {
+                if let Some(addr) = addrs.next() {
+                    found.push((addr.ip(), addr.port()));
+                }
+            }
+            Err(e) => eprintln!("Failed to resolve {}: {}", address, e),
+        };
+    }
+
+    if let Some(service_type) = service_type {
+        for host in browse_hosts(service_type, "", timeout).await {
+            if !found.contains(&host) {
+                found.push(host);
+            }
+        }
+    }
+
+    found
+}
+
+/// Browses `service_type` and returns the address of every instance whose
+/// name starts with `name_prefix`, ignoring case. Useful for generic service
+/// types like `_http._tcp` that many devices share.
+pub async fn browse_hosts(
+    service_type: &str,
+    name_prefix: &str,
+    timeout: Duration,
+) -> Vec<(IpAddr, u16)> {
+    let services = match browse(service_type, timeout).await {
+        Ok(services) => services,
+        Err(e) => {
+            eprintln!("Failed to browse for {} over mDNS: {}", service_type, e);
+            return Vec::new();
+        }
+    };
+
+    let prefix = name_prefix.to_lowercase();
+    let mut found: Vec<(IpAddr, u16)> = Vec::new();
+    for service in services {
+        if !service.name.to_lowercase().starts_with(&prefix) {
+            continue;
+        }
+        if let Some(addr) = service.address() {
+            if !found.contains(&(addr, service.port)) {
+                found.push((addr, service.port));
+            }
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    #[tokio::test]
+    async fn resolves_configured_addresses() {
+        let addresses =
+            ["127.0.0.1", "127.0.0.2:80", "::1", "[::1]:80", "not a host"].map(str::to_string);
+        let found = resolve_hosts(&addresses, 9123, None, Duration::ZERO).await;
+        assert_eq!(
+            found,
+            [
+                (IpAddr::V4(Ipv4Addr::LOCALHOST), 9123),
+                (IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2)), 80),
+                (IpAddr::V6(Ipv6Addr::LOCALHOST), 9123),
+                (IpAddr::V6(Ipv6Addr::LOCALHOST), 80),
+            ]
+        );
+    }
+}
@@ -1,3 +1,5 @@
 pub mod future;
 pub mod json;
-pub mod color;
\ No newline at end of file
+pub mod color;
+pub mod mdns;
+pub mod net;
\ No newline at end of file
@@ -0,0 +1,56 @@
+use std::net::{IpAddr, SocketAddr};
+
+/// Turns an address given as `host`, `host:port`, an IP or a socket address
+/// into something `lookup_host` and `send_to` take, adding `default_port` when
+/// it has none. IPv6 literals are only read as carrying a port in
+/// `[addr]:port` form.
+pub fn with_default_port(address: &str, default_port: u16) -> String {
+    if address.parse::<SocketAddr>().is_ok() {
+        return address.to_string();
+    }
+    let ip = address.trim_start_matches('[').trim_end_matches(']');
+    if let Ok(ip) = ip.parse::<IpAddr>() {
+        return SocketAddr::new(ip, default_port).to_string();
+    }
+    match address.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') && port.parse::<u16>().is_ok() => {
+            address.to_string()
+        }
+        _ => format!("{}:{}", address, default_port),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adds_the_default_port_to_bare_hosts() {
+        assert_eq!(with_default_port("192.168.1.20", 9999), "192.168.1.20:9999");
+        assert_eq!(with_default_port("lamp.local", 9999), "lamp.local:9999");
+        assert_eq!(with_default_port("fe80::1", 9999), "[fe80::1]:9999");
+        assert_eq!(with_default_port("::1", 9999), "[::1]:9999");
+        assert_eq!(
+            with_default_port("[2001:db8::7]", 9999),
+            "[2001:db8::7]:9999"
+        );
+    }
+
+    #[test]
+    fn keeps_given_ports() {
+        assert_eq!(
+            with_default_port("192.168.1.20:80", 9999),
+            "192.168.1.20:80"
+        );
+        assert_eq!(with_default_port("lamp.local:80", 9999), "lamp.local:80");
+        assert_eq!(with_default_port("[fe80::1]:80", 9999), "[fe80::1]:80");
+    }
+
+    #[tokio::test]
+    async fn resolves_ipv6_literals() {
+        let mut addrs = tokio::net::lookup_host(with_default_port("::1", 9999))
+            .await
+            .unwrap();
+        assert_eq!(addrs.next(), Some("[::1]:9999".parse().unwrap()));
+    }
+}